@@ -0,0 +1,11 @@
+; Echoes the standard input with the host calls
+include "host.inc"
+
+loop:
+    EmuSignal SIG_GETCHAR
+    BranchCarry done
+    EmuSignal SIG_PUTCHAR
+    JumpAbs loop
+done:
+    LoadACImm 0
+    EmuSignal SIG_EXIT
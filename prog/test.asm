@@ -6,7 +6,7 @@ NoOp
 
 LoadACImm      %10101011
 AddImm         %01010101
-BranchZero     $05
+BranchZero     $03
 JumpAbs 0
 NoOp
 
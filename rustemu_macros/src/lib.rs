@@ -2,13 +2,13 @@ use proc_macro::TokenStream;
 use quote::quote;
 use syn::Variant;
 
-#[proc_macro_derive(EmuInstruction, attributes(opcode, asmstr, addrmode))]
+#[proc_macro_derive(EmuInstruction, attributes(opcode, asmstr, addrmode, cycles))]
 pub fn generate_vm_instruction_impl(input: TokenStream) -> TokenStream {
     let ast = syn::parse(input).unwrap();
     impl_instruction_struct(&ast, false)
 }
 
-#[proc_macro_derive(EmuInstructionStrict, attributes(opcode, asmstr, addrmode))]
+#[proc_macro_derive(EmuInstructionStrict, attributes(opcode, asmstr, addrmode, cycles))]
 pub fn generate_vm_instruction_impl_strict(input: TokenStream) -> TokenStream {
     let ast = syn::parse(input).unwrap();
     impl_instruction_struct(&ast, true)
@@ -24,6 +24,16 @@ fn get_opcode(x: &Variant) -> u8 {
     0
 }
 
+fn get_cycles(x: &Variant) -> u8 {
+    for attr in x.attrs.iter() {
+        if attr.path().is_ident("cycles") {
+            let value: syn::LitInt = attr.parse_args().unwrap();
+            return value.base10_parse().unwrap();
+        }
+    }
+    0
+}
+
 fn get_asmstr(x: &Variant) -> String {
     for attr in x.attrs.iter() {
         if attr.path().is_ident("asmstr") {
@@ -44,6 +54,24 @@ fn get_addrmode(x: &Variant) -> String {
     "".to_string()
 }
 
+fn get_addrmode_variant(addrmode: &str) -> proc_macro2::TokenStream {
+    match addrmode {
+        "imp" => quote! { AddrMode::Implied },
+        "imm" => quote! { AddrMode::Immediate },
+        "abs" => quote! { AddrMode::Absolute },
+        "abi" => quote! { AddrMode::AbsoluteIndirect },
+        "abx" => quote! { AddrMode::AbsoluteX },
+        "aby" => quote! { AddrMode::AbsoluteY },
+        "zpm" => quote! { AddrMode::ZeroPage },
+        "zpx" => quote! { AddrMode::ZeroPageX },
+        "zpy" => quote! { AddrMode::ZeroPageY },
+        "zxi" => quote! { AddrMode::ZeroPageXIndirect },
+        "zyi" => quote! { AddrMode::ZeroPageIndirectY },
+        "rel" => quote! { AddrMode::Relative },
+        _ => panic!("Unknown address mode {}", addrmode),
+    }
+}
+
 fn get_type_name(ty: &syn::Type) -> String {
     if let syn::Type::Path(x) = ty {
         x.path
@@ -69,6 +97,9 @@ fn impl_instruction_struct(ast: &syn::ItemEnum, strict_mode: bool) -> TokenStrea
     let mut already_parse_opcode: Vec<u8> = Vec::new();
 
     let mut field_size: Vec<_> = Vec::new();
+    let mut field_cycles: Vec<_> = Vec::new();
+    let mut field_mnemonic: Vec<_> = Vec::new();
+    let mut field_addr_mode: Vec<_> = Vec::new();
     let mut field_to_binary: Vec<_> = Vec::new();
     let mut field_from_binary: Vec<_> = Vec::new();
     let mut field_to_string: Vec<_> = Vec::new();
@@ -77,8 +108,9 @@ fn impl_instruction_struct(ast: &syn::ItemEnum, strict_mode: bool) -> TokenStrea
     for x in ast.variants.iter() {
         let field_name = &x.ident;
         let field_opcode: u8 = get_opcode(x);
-        let _field_asmstr: String = get_asmstr(x);
+        let field_asmstr: String = get_asmstr(x);
         let field_addrmode: String = get_addrmode(x);
+        let field_cycles_count: u8 = get_cycles(x);
         let field_param_type = get_operand_type(x);
 
         if strict_mode {
@@ -89,14 +121,33 @@ fn impl_instruction_struct(ast: &syn::ItemEnum, strict_mode: bool) -> TokenStrea
             if already_parse_opcode.contains(&field_opcode) {
                 panic!("The opcode of {} has already been parsed", field_name)
             }
+
+            if field_cycles_count == 0 {
+                panic!("The cycle count of {} is missing", field_name)
+            }
         }
         already_parse_opcode.push(field_opcode);
 
-        match field_param_type {
+        let field_addrmode_variant = get_addrmode_variant(&field_addrmode);
+        let field_pattern = match field_param_type {
+            None => quote! { Instruction::#field_name },
+            Some(_) => quote! { Instruction::#field_name(_) },
+        };
+        field_mnemonic.push(quote! {
+            #field_pattern => #field_asmstr
+        });
+        field_addr_mode.push(quote! {
+            #field_pattern => #field_addrmode_variant
+        });
+
+        match field_param_type.clone() {
             None => {
                 field_size.push(quote! {
                     Instruction::#field_name => 1
                 });
+                field_cycles.push(quote! {
+                    Instruction::#field_name => #field_cycles_count
+                });
                 field_from_binary.push(quote! {
                     #field_opcode => Ok(Self::#field_name)
                 });
@@ -122,6 +173,9 @@ fn impl_instruction_struct(ast: &syn::ItemEnum, strict_mode: bool) -> TokenStrea
                 field_size.push(quote! {
                     Instruction::#field_name(_) => 2
                 });
+                field_cycles.push(quote! {
+                    Instruction::#field_name(_) => #field_cycles_count
+                });
                 field_from_binary.push(quote! {
                     #field_opcode => {
                         if value.len() < 2 {
@@ -156,6 +210,9 @@ fn impl_instruction_struct(ast: &syn::ItemEnum, strict_mode: bool) -> TokenStrea
                 field_size.push(quote! {
                     Instruction::#field_name(_) => 3
                 });
+                field_cycles.push(quote! {
+                    Instruction::#field_name(_) => #field_cycles_count
+                });
                 field_from_binary.push(quote! {
                     #field_opcode => {
                         if value.len() < 3 {
@@ -202,6 +259,24 @@ fn impl_instruction_struct(ast: &syn::ItemEnum, strict_mode: bool) -> TokenStrea
                     #(#field_size,)*
                 }
             }
+
+            pub fn cycles(self) -> u8 {
+                match self {
+                    #(#field_cycles,)*
+                }
+            }
+
+            pub fn mnemonic(self) -> &'static str {
+                match self {
+                    #(#field_mnemonic,)*
+                }
+            }
+
+            pub fn addr_mode(self) -> AddrMode {
+                match self {
+                    #(#field_addr_mode,)*
+                }
+            }
         }
 
         impl TryFrom<&[u8]> for Instruction {
@@ -288,9 +363,7 @@ fn impl_instruction_struct(ast: &syn::ItemEnum, strict_mode: bool) -> TokenStrea
                     .map_err(|_| format!("Error converting u8 from hex string")),
                 '%' => u8::from_str_radix(&num_str[1..], 2)
                     .map_err(|_| format!("Error converting u8 from binary string")),
-                _ => {
-                    u8::from_str_radix(num_str, 10).map_err(|_| format!("Error converting u8 from string"))
-                }
+                _ => num_str.parse::<u8>().map_err(|_| format!("Error converting u8 from string")),
             }
         }
         
@@ -305,7 +378,7 @@ fn impl_instruction_struct(ast: &syn::ItemEnum, strict_mode: bool) -> TokenStrea
                     .map_err(|_| format!("Error converting u16 from hex string")),
                 '%' => u16::from_str_radix(&num_str[1..], 2)
                     .map_err(|_| format!("Error converting u16 from binary string")),
-                _ => u16::from_str_radix(num_str, 10)
+                _ => num_str.parse::<u16>()
                     .map_err(|_| format!("Error converting u16 from string")),
             }
         }
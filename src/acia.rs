@@ -0,0 +1,369 @@
+//! MOS 6551 Asynchronous Communications Interface Adapter.
+//!
+//! | Offset | Read             | Write             |
+//! |--------|------------------|-------------------|
+//! | `$0`   | Receive data     | Transmit data     |
+//! | `$1`   | Status           | Programmed reset  |
+//! | `$2`   | Command          | Command           |
+//! | `$3`   | Control          | Control           |
+//!
+//! The serial line is a host byte stream : the standard input and output, a PTY or any
+//! serial device opened as a file, or a Unix socket. A byte takes the time of a frame at
+//! the baud rate of the control register, counted on the CPU clock, 1 MHz by default
+//! (115200 bauds with the external clock). The receiver is flow controlled : the next byte of the host is
+//! only taken once the program read the previous one, so a pasted text is not lost.
+//!
+//! The received bytes go through `HostPort::poll`, so the runs can be replayed : only the
+//! frames which bring a byte are recorded.
+
+use std::{
+    collections::VecDeque,
+    fs::OpenOptions,
+    io::{self, Read, Write},
+    path::Path,
+    sync::mpsc::Receiver,
+};
+
+use crate::{
+    bus::{Device, HostPort},
+    console::{read_in_background, write_output},
+    state::{StateReader, StateWriter},
+};
+
+pub const ACIA_SIZE: u16 = 0x4;
+/// Channel of the received bytes for the record and replay
+pub const ACIA_CHANNEL: u16 = 0x0300;
+/// Clock of the CPU when not given, to turn the baud rates into cycles
+pub const DEFAULT_CLOCK_HZ: u64 = 1_000_000;
+
+pub const REG_DATA: u16 = 0x0;
+pub const REG_STATUS: u16 = 0x1;
+pub const REG_COMMAND: u16 = 0x2;
+pub const REG_CONTROL: u16 = 0x3;
+
+pub const STATUS_OVERRUN: u8 = 0b0000_0100;
+pub const STATUS_RDRF: u8 = 0b0000_1000;
+pub const STATUS_TDRE: u8 = 0b0001_0000;
+pub const STATUS_IRQ: u8 = 0b1000_0000;
+
+const COMMAND_DTR: u8 = 0b0000_0001;
+const COMMAND_RX_IRQ_DISABLE: u8 = 0b0000_0010;
+const COMMAND_TX_CONTROL: u8 = 0b0000_1100;
+const COMMAND_TX_IRQ: u8 = 0b0000_0100;
+const COMMAND_ECHO: u8 = 0b0001_0000;
+const COMMAND_PARITY: u8 = 0b0010_0000;
+
+// Baud rates of the control register, the first one is the external clock
+const BAUD_RATES: [u64; 16] = [
+    115200, 50, 75, 110, 135, 150, 300, 600, 1200, 1800, 2400, 3600, 4800, 7200, 9600, 19200,
+];
+
+pub struct Acia {
+    source: Option<Receiver<u8>>,
+    pending: VecDeque<u8>,
+    output: Box<dyn Write>,
+    rdr: u8,
+    status: u8,
+    command: u8,
+    control: u8,
+    // Cycles left before the receiver looks for the next byte and before the transmitter is empty
+    rx_cycles: u64,
+    tx_cycles: u64,
+    clock_hz: u64,
+}
+
+impl Acia {
+    /// ACIA without any connection, the transmitted bytes are dropped
+    pub fn new() -> Self {
+        Self {
+            source: None,
+            pending: VecDeque::new(),
+            output: Box::new(io::sink()),
+            rdr: 0,
+            status: STATUS_TDRE,
+            command: 0,
+            control: 0,
+            rx_cycles: 0,
+            tx_cycles: 0,
+            clock_hz: DEFAULT_CLOCK_HZ,
+        }
+    }
+
+    /// Serial line given as `stdio`, `unix:PATH` for a Unix socket, or the path of a device
+    pub fn open(spec: &str) -> Result<Self, String> {
+        match spec {
+            "stdio" => Ok(Self::stdio()),
+            _ => match spec.strip_prefix("unix:") {
+                #[cfg(unix)]
+                Some(path) => Self::connect_unix(path),
+                #[cfg(not(unix))]
+                Some(_) => Err("Unix sockets are not available on this platform".to_string()),
+                None => Self::open_device(spec),
+            },
+        }
+    }
+
+    /// Serial line on the standard input and output
+    pub fn stdio() -> Self {
+        Self::new().with_stream(io::stdin(), Box::new(io::stdout()))
+    }
+
+    /// Serial line on a PTY or a serial device, like the end of a `socat` PTY pair
+    pub fn open_device<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let error = |err: io::Error| format!("Can not open {} : {}", path.display(), err);
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(error)?;
+        let input = device.try_clone().map_err(error)?;
+        Ok(Self::new().with_stream(input, Box::new(device)))
+    }
+
+    /// Serial line on a Unix socket, a terminal can listen on it with
+    /// `socat UNIX-LISTEN:path -,raw,echo=0`
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let error = |err: io::Error| format!("Can not connect to {} : {}", path.display(), err);
+        let socket = std::os::unix::net::UnixStream::connect(path).map_err(error)?;
+        let input = socket.try_clone().map_err(error)?;
+        Ok(Self::new().with_stream(input, Box::new(socket)))
+    }
+
+    /// Receives from `input`, read on a thread, and transmits to `output`
+    pub fn with_stream<R: Read + Send + 'static>(
+        mut self,
+        input: R,
+        output: Box<dyn Write>,
+    ) -> Self {
+        self.source = Some(read_in_background(input));
+        self.output = output;
+        self
+    }
+
+    /// Clock of the CPU, which times the frames
+    pub fn with_clock(mut self, clock_hz: u64) -> Self {
+        self.clock_hz = clock_hz;
+        self
+    }
+
+    /// Queues bytes on the serial line, after the ones already waiting
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.pending.extend(bytes);
+    }
+
+    /// CPU cycles taken by a frame : start bit, data bits, parity and stop bits
+    pub fn frame_cycles(&self) -> u64 {
+        let data_bits = 8 - ((self.control >> 5) & 0b11) as u64;
+        let parity = (self.command & COMMAND_PARITY > 0) as u64;
+        let stop_bits = match self.control & 0x80 > 0 {
+            true => 2,
+            false => 1,
+        };
+        let baud = BAUD_RATES[(self.control & 0x0F) as usize];
+        ((1 + data_bits + parity + stop_bits) * self.clock_hz).div_ceil(baud)
+    }
+
+    fn transmit(&mut self, value: u8) {
+        write_output(self.output.as_mut(), value);
+    }
+
+    fn receive(&mut self, host: &mut HostPort) {
+        let pending = &mut self.pending;
+        if let Some(source) = self.source.as_ref() {
+            pending.extend(source.try_iter());
+        }
+        let Some(value) = host.poll(ACIA_CHANNEL, || pending.pop_front()) else {
+            return;
+        };
+        self.rdr = value;
+        self.status |= STATUS_RDRF;
+
+        if self.command & (COMMAND_DTR | COMMAND_RX_IRQ_DISABLE) == COMMAND_DTR {
+            self.status |= STATUS_IRQ;
+        }
+        if self.command & COMMAND_ECHO > 0 && self.command & COMMAND_TX_CONTROL == 0 {
+            self.transmit(self.rdr);
+        }
+    }
+}
+
+impl Default for Acia {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Acia {
+    fn size(&self) -> u16 {
+        ACIA_SIZE
+    }
+
+    fn read(&mut self, offset: u16, _host: &mut HostPort) -> u8 {
+        match offset {
+            REG_DATA => {
+                self.status &= !(STATUS_RDRF | STATUS_OVERRUN);
+                self.rdr
+            }
+            REG_STATUS => {
+                let status = self.status;
+                self.status &= !STATUS_IRQ;
+                status
+            }
+            _ => self.peek(offset),
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            REG_DATA => self.rdr,
+            REG_STATUS => self.status,
+            REG_COMMAND => self.command,
+            _ => self.control,
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset {
+            REG_DATA => {
+                self.transmit(value);
+                self.status &= !STATUS_TDRE;
+                self.tx_cycles = self.frame_cycles();
+            }
+            REG_STATUS => {
+                self.command &= 0b1110_0000;
+                self.status &= !STATUS_OVERRUN;
+            }
+            REG_COMMAND => self.command = value,
+            _ => self.control = value,
+        }
+    }
+
+    fn tick(&mut self, cycles: u64, host: &mut HostPort) {
+        if self.status & STATUS_TDRE == 0 {
+            self.tx_cycles = self.tx_cycles.saturating_sub(cycles);
+            if self.tx_cycles == 0 {
+                self.status |= STATUS_TDRE;
+                if self.command & COMMAND_TX_CONTROL == COMMAND_TX_IRQ {
+                    self.status |= STATUS_IRQ;
+                }
+            }
+        }
+
+        if self.command & COMMAND_DTR > 0 && self.status & STATUS_RDRF == 0 {
+            self.rx_cycles = self.rx_cycles.saturating_sub(cycles);
+            if self.rx_cycles == 0 {
+                self.rx_cycles = self.frame_cycles();
+                self.receive(host);
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.status & STATUS_IRQ > 0
+    }
+
+    // The bytes waiting on the host side of the line are not part of the machine
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bytes(&[self.rdr, self.status, self.command, self.control]);
+        writer.put_u64(self.rx_cycles);
+        writer.put_u64(self.tx_cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        [self.rdr, self.status, self.command, self.control] =
+            reader.get_bytes(4)?.try_into().unwrap();
+        self.rx_cycles = reader.get_u64()?;
+        self.tx_cycles = reader.get_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::assemble, console::testing::SharedOutput, isa::Register, replay::InputMode, Vm,
+    };
+
+    #[test]
+    fn test_acia_registers() {
+        let mut acia = Acia::new();
+        let mut input_mode = InputMode::Live;
+        let mut host = HostPort::new(0, &mut input_mode);
+
+        // 9600 bauds, 8N1 : 10 bits of 104.2 cycles
+        acia.write(REG_CONTROL, 0x9E);
+        assert_eq!(acia.frame_cycles(), 1146);
+        acia.write(REG_CONTROL, 0x1E);
+        assert_eq!(acia.frame_cycles(), 1042);
+
+        // The receiver is off until DTR is set
+        acia.push_input(b"ok");
+        acia.tick(2000, &mut host);
+        assert_eq!(acia.peek(REG_STATUS), STATUS_TDRE);
+        acia.write(REG_COMMAND, 0x09);
+        acia.tick(1, &mut host);
+        assert_eq!(
+            acia.read(REG_STATUS, &mut host),
+            STATUS_IRQ | STATUS_RDRF | STATUS_TDRE
+        );
+        assert!(!acia.irq());
+
+        // Flow controlled, the next byte waits for the read of the data
+        acia.tick(5000, &mut host);
+        assert_eq!(acia.read(REG_DATA, &mut host), b'o');
+        acia.tick(1000, &mut host);
+        assert_eq!(acia.peek(REG_STATUS), STATUS_TDRE);
+        acia.tick(42, &mut host);
+        assert!(acia.irq());
+        assert_eq!(acia.read(REG_DATA, &mut host), b'k');
+
+        acia.write(REG_STATUS, 0);
+        assert_eq!(acia.peek(REG_COMMAND), 0);
+    }
+
+    #[test]
+    fn test_acia_echo_program() {
+        // Echoes the received bytes with the receive interrupt
+        let source = "
+DATA = $8800
+STATUS = $8801
+COMMAND = $8802
+CONTROL = $8803
+    LoadACImm $10
+    StoreACAbs CONTROL
+    LoadACImm $09
+    StoreACAbs COMMAND
+    ClrIntDis
+wait:
+    JumpAbs wait
+handler:
+    LoadACAbs STATUS
+    LoadACAbs DATA
+    StoreACAbs DATA
+    RetInt
+";
+        let program = assemble(source, "acia.asm").unwrap();
+        let handler = program.symbols.address_of("handler").unwrap();
+        let output = SharedOutput::default();
+        let mut acia = Acia::new().with_stream(&b"hello"[..], Box::new(output.clone()));
+        acia.push_input(b"> ");
+
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &program.bytes);
+        vm.copy_memory(0xFFFE, &handler.to_le_bytes());
+        vm.set_register(Register::SP, 0xFF);
+        vm.map_device(0x8800, acia).unwrap();
+
+        // The bytes of the thread arrive at any time, wait for them
+        let mut cycles = 0;
+        while output.0.borrow().len() < 7 && cycles < 10_000_000 {
+            vm.cycle().unwrap();
+            cycles += 1;
+        }
+        assert_eq!(output.0.borrow().as_slice(), b"> hello");
+    }
+}
@@ -0,0 +1,331 @@
+//! Two pass assembler for the instruction syntax of `Instruction`.
+//!
+//! On top of one instruction per line, it accepts `;` comments, label definitions
+//! (`loop:` alone or before an instruction), constants (`SIG_EXIT = $00`) and labels or
+//! constants as operands, `<name` and `>name` giving their low and high byte. The operand
+//! of a relative branch is turned into the offset to the label. `include "host.inc"`
+//! inserts another file, its path is relative to the file including it.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::{
+    isa::{AddrMode, Instruction, ParsingError},
+    symbols::{parse_number, SymbolTable},
+};
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+pub struct Program {
+    pub bytes: Vec<u8>,
+    pub symbols: SymbolTable,
+}
+
+struct SourceLine {
+    file: String,
+    line: usize,
+    text: String,
+}
+
+struct PendingInstruction<'a> {
+    file: &'a str,
+    line: usize,
+    addr: u16,
+    mnemonic: &'a str,
+    operand: Option<&'a str>,
+    instruction: Instruction,
+}
+
+fn is_identifier(value: &str) -> bool {
+    let mut chars = value.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Name used by an operand, with the `<` or `>` byte selector if any
+fn operand_symbol(operand: &str) -> Option<(Option<char>, &str)> {
+    let (selector, name) = match operand.strip_prefix(['<', '>']) {
+        Some(name) => (operand.chars().next(), name),
+        None => (None, operand),
+    };
+    is_identifier(name).then_some((selector, name))
+}
+
+fn parsing_error(line: usize, err: ParsingError) -> String {
+    match err {
+        ParsingError::NonBlockingError(msg) | ParsingError::BlockingError(msg) => {
+            format!("Line {} : {}", line, msg)
+        }
+    }
+}
+
+/// Lines of the source with the included files inserted
+fn load_source(
+    source: &str,
+    file: &str,
+    depth: usize,
+    lines: &mut Vec<SourceLine>,
+) -> Result<(), String> {
+    for (idx, text) in source.lines().enumerate() {
+        let line = idx + 1;
+        let code = text.split(';').next().unwrap_or_default().trim();
+
+        let Some(path) = code.strip_prefix("include ") else {
+            lines.push(SourceLine {
+                file: file.to_string(),
+                line,
+                text: text.to_string(),
+            });
+            continue;
+        };
+
+        let path = path
+            .trim()
+            .strip_prefix('"')
+            .and_then(|path| path.strip_suffix('"'))
+            .ok_or(format!("Line {} : wrong include {}", line, path.trim()))?;
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(format!("Line {} : too many nested includes", line));
+        }
+
+        let included = Path::new(file).parent().unwrap_or(Path::new("")).join(path);
+        let included_name = included.to_string_lossy().to_string();
+        let included_source = fs::read_to_string(&included)
+            .map_err(|err| format!("Line {} : {} : {}", line, included_name, err))?;
+        load_source(&included_source, &included_name, depth + 1, lines)
+            .map_err(|err| format!("{} : {}", included_name, err))?;
+    }
+    Ok(())
+}
+
+/// Assembles a source file loaded at address 0, `file` is used for the line information
+/// and to find the included files
+pub fn assemble(source: &str, file: &str) -> Result<Program, String> {
+    let mut lines = Vec::new();
+    load_source(source, file, 0, &mut lines)?;
+
+    let mut symbols = SymbolTable::new();
+    let mut constants: HashMap<&str, u16> = HashMap::new();
+    let mut pending = Vec::new();
+    let mut addr: u16 = 0;
+
+    // First pass, find the address of every label and the value of every constant
+    for source_line in lines.iter() {
+        let line = source_line.line;
+        let mut tokens: Vec<&str> = source_line
+            .text
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect();
+
+        if let [name, "=", value] = tokens.as_slice() {
+            if !is_identifier(name) {
+                return Err(format!("Line {} : wrong constant name {}", line, name));
+            }
+            if constants.contains_key(name) || symbols.address_of(name).is_some() {
+                return Err(format!("Line {} : {} already defined", line, name));
+            }
+            let value =
+                parse_number(value).ok_or(format!("Line {} : wrong value {}", line, value))?;
+            constants.insert(name, value);
+            continue;
+        }
+
+        if let Some(label) = tokens.first().and_then(|token| token.strip_suffix(':')) {
+            if !is_identifier(label) {
+                return Err(format!("Line {} : wrong label name {}", line, label));
+            }
+            if symbols.address_of(label).is_some() || constants.contains_key(label) {
+                return Err(format!("Line {} : label {} already defined", line, label));
+            }
+            symbols.insert_label(label, addr);
+            tokens.remove(0);
+        }
+
+        if tokens.is_empty() {
+            continue;
+        }
+
+        let mnemonic = tokens[0];
+        let operand = tokens.get(1).copied();
+
+        // The size does not depend on the operand value, names are replaced by 0 for now
+        let probe = match operand {
+            Some(operand) if operand_symbol(operand).is_some() => {
+                let mut probe_tokens = tokens.clone();
+                probe_tokens[1] = "0";
+                probe_tokens.join(" ")
+            }
+            _ => tokens.join(" "),
+        };
+        let instruction = probe
+            .parse::<Instruction>()
+            .map_err(|err| parsing_error(line, err))?;
+
+        pending.push(PendingInstruction {
+            file: &source_line.file,
+            line,
+            addr,
+            mnemonic,
+            operand,
+            instruction,
+        });
+        addr = addr
+            .checked_add(instruction.size() as u16)
+            .ok_or(format!("Line {} : program bigger than 64K", line))?;
+    }
+
+    // Second pass, resolve the labels and constants used as operands
+    let mut bytes = Vec::new();
+    for ins in pending {
+        let instruction = match ins.operand.and_then(operand_symbol) {
+            Some((selector, name)) => {
+                let is_constant = constants.contains_key(name);
+                let target = constants
+                    .get(name)
+                    .copied()
+                    .or(symbols.address_of(name))
+                    .ok_or(format!("Line {} : unknown label {}", ins.line, name))?;
+
+                let value = if let Some(selector) = selector {
+                    match selector {
+                        '<' => target & 0x00FF,
+                        _ => target >> 8,
+                    }
+                } else if ins.instruction.addr_mode() == AddrMode::Relative && !is_constant {
+                    let offset = target as i32 - (ins.addr as i32 + 2);
+                    if !(-128..=127).contains(&offset) {
+                        return Err(format!(
+                            "Line {} : label {} is too far for a branch",
+                            ins.line, name
+                        ));
+                    }
+                    offset as i8 as u8 as u16
+                } else if ins.instruction.size() == 2 && target > 0xFF {
+                    let kind = if is_constant { "constant" } else { "label" };
+                    return Err(format!(
+                        "Line {} : {} {} is not in the zero page",
+                        ins.line, kind, name
+                    ));
+                } else {
+                    target
+                };
+
+                format!("{} {}", ins.mnemonic, value)
+                    .parse::<Instruction>()
+                    .map_err(|err| parsing_error(ins.line, err))?
+            }
+            None => ins.instruction,
+        };
+
+        symbols.insert_line(ins.addr, ins.line, ins.file);
+        bytes.append(&mut Into::<Vec<u8>>::into(instruction));
+    }
+
+    Ok(Program { bytes, symbols })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_labels() {
+        let source = "\
+; Count to 5
+start:
+    LoadXImm 0          ; X = 0
+loop: IncX
+    StoreXZp counter
+    CmpXImm 5
+    BranchNotZero loop
+    JumpAbs end
+    NoOp
+end:
+    Jam
+counter:
+";
+        let program = assemble(source, "count.asm").unwrap();
+
+        assert_eq!(
+            program.bytes,
+            vec![
+                0xA2, 0x00, 0xE8, 0x86, 0x0E, 0xE0, 0x05, 0xD0, 0xF9, 0x4C, 0x0D, 0x00, 0xEA, 0xF2
+            ]
+        );
+        assert_eq!(program.symbols.address_of("start"), Some(0x0000));
+        assert_eq!(program.symbols.address_of("loop"), Some(0x0002));
+        assert_eq!(program.symbols.address_of("end"), Some(0x000D));
+        assert_eq!(program.symbols.address_of("counter"), Some(0x000E));
+        assert_eq!(program.symbols.line_of(0x0002).unwrap().line, 4);
+        assert_eq!(program.symbols.line_of(0x000D).unwrap().line, 11);
+        assert_eq!(program.symbols.line_of(0x000D).unwrap().file, "count.asm");
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(
+            assemble("JumpAbs nowhere", "").err().unwrap(),
+            "Line 1 : unknown label nowhere"
+        );
+        assert!(assemble("a:\na:", "").is_err());
+        assert!(assemble("1a: NoOp", "").is_err());
+        assert!(assemble("NoOp\nUnknown 12", "")
+            .err()
+            .unwrap()
+            .starts_with("Line 2"));
+        assert!(assemble("far: LoadACZp far\nLoadACZp far", "").is_ok());
+
+        let mut far = "start:\n".to_string();
+        far.push_str(&"NoOp\n".repeat(300));
+        far.push_str("BranchZero start\nStoreACZp start\nStoreACZp end\nend:");
+        assert_eq!(
+            assemble(&far, "").err().unwrap(),
+            "Line 302 : label start is too far for a branch"
+        );
+        far = far.replace("BranchZero start\n", "");
+        assert_eq!(
+            assemble(&far, "").err().unwrap(),
+            "Line 303 : label end is not in the zero page"
+        );
+    }
+
+    #[test]
+    fn test_assemble_constants_and_includes() {
+        let source = "\
+VALUE = $1234
+ZP = 16
+start:
+    LoadACImm <VALUE
+    LoadXImm >VALUE
+    StoreACZp ZP
+    LoadACImm <start
+    BranchZero VALUE_LOW
+";
+        assert_eq!(
+            assemble(source, "").err().unwrap(),
+            "Line 8 : unknown label VALUE_LOW"
+        );
+
+        let program = assemble(&source.replace("VALUE_LOW", "ZP"), "").unwrap();
+        assert_eq!(
+            program.bytes,
+            vec![0xA9, 0x34, 0xA2, 0x12, 0x85, 0x10, 0xA9, 0x00, 0xF0, 0x10]
+        );
+        assert_eq!(program.symbols.address_of("VALUE"), None);
+        assert!(assemble("ZP = 1\nZP = 2", "").is_err());
+        assert!(assemble("LoadACZp VALUE\nVALUE = $1234", "")
+            .err()
+            .unwrap()
+            .ends_with("constant VALUE is not in the zero page"));
+
+        let file = format!("{}/prog/main.asm", env!("CARGO_MANIFEST_DIR"));
+        let program = assemble("include \"host.inc\"\nEmuSignal SIG_PUTS", &file).unwrap();
+        assert_eq!(program.bytes, vec![0xFF, 0x02]);
+        assert_eq!(program.symbols.line_of(0).unwrap().file, file);
+        assert!(assemble("include \"missing.inc\"", &file).is_err());
+    }
+}
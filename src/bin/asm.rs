@@ -1,29 +1,30 @@
-use rustemu::isa::{Instruction, ParsingError};
+use rustemu::asm::assemble;
 use std::{
     env,
     fs::{self},
     io::Write,
 };
 
-fn main() -> () {
+fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
-        println!("You need to provide a asm file! : {} example.asm", args[0]);
+        println!(
+            "You need to provide a asm file! : {} example.asm [example.sym]",
+            args[0]
+        );
         return;
     }
 
-    let mut binary_data = Vec::<u8>::new();
+    let source = fs::read_to_string(args[1].as_str()).unwrap();
+    let program = assemble(&source, args[1].as_str()).unwrap_or_else(|err| panic!("{}", err));
 
-    for line in fs::read_to_string(args[1].as_str()).unwrap().lines() {
-        let res = line.parse::<Instruction>();
-
-        match res {
-            Ok(ins) => binary_data.append(Into::<Vec<u8>>::into(ins).as_mut()),
-            Err(ParsingError::NonBlockingError(_)) => continue,
-            Err(ParsingError::BlockingError(err)) => panic!("{}", err),
-        }
+    // The symbols are only written when a path is given
+    if let Some(symbols_path) = args.get(2) {
+        fs::write(symbols_path, program.symbols.to_string()).unwrap();
     }
 
-    std::io::stdout().write_all(binary_data.as_slice()).unwrap();
+    std::io::stdout()
+        .write_all(program.bytes.as_slice())
+        .unwrap();
 }
@@ -0,0 +1,50 @@
+use std::{
+    io::{self, BufReader},
+    sync::mpsc::{self, TryRecvError},
+    thread,
+};
+
+use rustemu::dap::{read_message, write_message, DapServer};
+
+/// Debug Adapter Protocol server over stdio, launched by the editor.
+/// The launch request takes the `program` binary, the `symbols` file written by the
+/// assembler and `stopOnEntry` (true by default).
+fn main() {
+    let (sender, receiver) = mpsc::channel();
+
+    // Requests are read on their own thread so a pause can arrive while the program runs
+    thread::spawn(move || {
+        let mut reader = BufReader::new(io::stdin());
+        while let Ok(Some(message)) = read_message(&mut reader) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut server = DapServer::new();
+    let mut stdout = io::stdout();
+
+    while !server.is_terminated() {
+        let request = if server.is_running() {
+            match receiver.try_recv() {
+                Ok(request) => Some(request),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match receiver.recv() {
+                Ok(request) => Some(request),
+                Err(_) => break,
+            }
+        };
+
+        let messages = match request {
+            Some(request) => server.handle(&request),
+            None => server.run_slice(),
+        };
+        for message in messages.iter() {
+            write_message(&mut stdout, message).unwrap();
+        }
+    }
+}
@@ -1,17 +1,48 @@
-use rustemu::isa::Instruction;
+use clap::Parser;
+use rustemu::{debugger::disassemble_bytes, isa::Instruction, symbols::parse_number};
 use std::{
-    cmp::min, env, fs::{self}
+    cmp::min, fs::{self}
 };
 
-fn main() -> () {
-    let args: Vec<String> = env::args().collect();
+/// Disassembles a binary file, bank by bank for the ROM images of the mappers
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Path to the binary file
+    prog_file_path: String,
 
-    if args.len() < 2 {
-        println!("You need to provide a bin file! : {} example.bin", args[0]);
+    /// Address of the first byte, the addresses are shown when it is given
+    #[arg(long, value_parser = parse_address)]
+    base: Option<u16>,
+
+    /// Size of the banks of the image, each one is disassembled from --base
+    #[arg(long, value_parser = parse_address, requires = "base")]
+    bank_size: Option<u16>,
+}
+
+fn parse_address(value: &str) -> Result<u16, String> {
+    parse_number(value).ok_or(format!("Invalid address {}", value))
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let prog = fs::read(args.prog_file_path.as_str()).unwrap();
+
+    if let Some(base) = args.base {
+        let bank_size = args.bank_size.map_or(prog.len(), usize::from).max(1);
+        let banked = args.bank_size.is_some();
+        for (bank, bytes) in prog.chunks(bank_size).enumerate() {
+            if banked {
+                println!("; bank {:02x}", bank);
+            }
+            for line in disassemble_bytes(bytes, base, banked.then_some(bank)) {
+                println!("{}", line);
+            }
+        }
         return;
     }
 
-    let prog = fs::read(args[1].as_str()).unwrap();
     let mut idx = 0;
     while idx < prog.len() {
         let raw_bytes = &prog[idx..(min(idx+3, prog.len()))];
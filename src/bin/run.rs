@@ -1,53 +1,404 @@
 use std::{
-    fs,
-    io::{self, Read, Write},
+    fs::{self, File},
+    io::{self, BufRead, BufWriter, Write},
+    net::TcpListener,
+    process, thread,
+    time::{Duration, Instant},
 };
 
 use clap::Parser;
-use rustemu::Vm;
+use rustemu::{
+    acia::Acia,
+    bus::{AccessPolicy, FaultKind},
+    console::Console,
+    debugger::Debugger,
+    gdb::GdbStub,
+    host::HostAbi,
+    machine::MachineConfig,
+    replay::{InputLog, InputMode},
+    screen::{TextScreen, SCREEN_COLUMNS, SCREEN_ROWS},
+    symbols::{parse_number, SymbolTable},
+    tui, Vm,
+};
 
 /// Simple program to emulate a 6502 CPU
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     /// Path to the binary file
-    #[arg(short, long)]
-    prog_file_path: String,
+    #[arg(short, long, required_unless_present_any = ["load_state", "machine"])]
+    prog_file_path: Option<String>,
+
+    /// Address where the binary file is loaded, and where it starts without a machine file
+    #[arg(long, value_parser = parse_address, default_value = "0")]
+    load_address: u16,
+
+    /// Machine description in TOML : memory map, ROM images, devices and CPU
+    #[arg(long)]
+    machine: Option<String>,
 
-    /// Launch in step by step mode
+    /// What to do when the program writes to the ROM of the machine : ignore, log or fault
+    #[arg(long)]
+    rom_write: Option<AccessPolicy>,
+
+    /// What to do when the program reaches unmapped memory : ignore, log or fault
+    #[arg(long)]
+    unmapped_access: Option<AccessPolicy>,
+
+    /// Launch the interactive debugger, type help for the list of commands
     #[arg(short, long, default_value_t = false)]
     debug: bool,
+
+    /// Launch the full screen debugger
+    #[arg(long, default_value_t = false, conflicts_with = "debug")]
+    tui: bool,
+
+    /// Symbol file written by the assembler, gives the labels to the debugger
+    #[arg(long)]
+    symbols: Option<String>,
+
+    /// Wait for a GDB remote protocol client on this address, like 127.0.0.1:1234
+    #[arg(long, conflicts_with = "debug")]
+    gdb: Option<String>,
+
+    /// Perform every bus cycle of the NMOS 6502, including dummy reads and writes
+    #[arg(long, default_value_t = false)]
+    cycle_accurate: bool,
+
+    /// Resume the machine from a save state, the program is loaded on top of it if given
+    #[arg(long)]
+    load_state: Option<String>,
+
+    /// Save the state of the machine to this file when it stops
+    #[arg(long)]
+    save_state: Option<String>,
+
+    /// Stop the machine once this number of cycles has elapsed
+    #[arg(long)]
+    max_cycles: Option<u64>,
+
+    /// Write one line per instruction to this file, in the format of the nestest log,
+    /// when the machine runs without a debugger
+    #[arg(long, conflicts_with_all = ["debug", "tui", "gdb"])]
+    trace: Option<String>,
+
+    /// Write the code coverage in the lcov format, the source lines are taken from --symbols
+    #[arg(long)]
+    coverage: Option<String>,
+
+    /// Write the disassembly of the program annotated with the execution counts
+    #[arg(long)]
+    listing: Option<String>,
+
+    /// Write a profile of the cycles spent in the instructions, routines and loops
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Write the cycles of every call stack in the folded format of the flame graph tools
+    #[arg(long)]
+    folded: Option<String>,
+
+    /// Log every value given by the host, with its cycle and the final state hash
+    #[arg(long)]
+    record: Option<String>,
+
+    /// Take the values given by the host from a recorded log and check the final state hash,
+    /// the run stops and fails at the first divergence
+    #[arg(long, conflicts_with = "record")]
+    replay: Option<String>,
+
+    /// Directory where the program can open files with the host calls
+    #[arg(long)]
+    sandbox: Option<String>,
+
+    /// Map the console device, at $f000 by default as in py65
+    #[arg(long, value_parser = parse_address, num_args = 0..=1, default_missing_value = "$f000")]
+    console: Option<u16>,
+
+    /// Map a 6551 ACIA at this address
+    #[arg(long, value_parser = parse_address)]
+    acia: Option<u16>,
+
+    /// Host side of the ACIA : stdio, unix:PATH for a Unix socket, or the path of a PTY
+    #[arg(long, default_value = "stdio", requires = "acia")]
+    serial: String,
+
+    /// Map a text screen of 40 columns and 25 rows, at $0400 by default
+    #[arg(long, value_parser = parse_address, num_args = 0..=1, default_missing_value = "$0400")]
+    screen: Option<u16>,
+
+    /// Write the text screen to this file when the machine stops, a PNG for a .png file
+    #[arg(long)]
+    screen_dump: Option<String>,
+
+    /// Do not print the instructions and the registers while running
+    #[arg(short, long, default_value_t = false)]
+    quiet: bool,
+}
+
+fn parse_address(value: &str) -> Result<u16, String> {
+    parse_number(value).ok_or(format!("Invalid address {}", value))
+}
+
+fn print_memory_log(vm: &mut Vm) {
+    for fault in vm.take_memory_log() {
+        println!("{}", fault);
+    }
 }
 
-fn pause() {
-    let mut stdin = io::stdin();
+fn debug(vm: &mut Vm, symbols: SymbolTable) {
+    let mut debugger = Debugger::new(symbols);
     let mut stdout = io::stdout();
+    let mut lines = io::stdin().lock().lines();
+
+    println!("{}", debugger.location(vm));
+    while !debugger.is_finished() {
+        print!("(rustemu) ");
+        stdout.flush().unwrap();
 
-    // We want the cursor to stay at the end of the line, so we print without a newline and flush manually.
-    //write!(stdout, "Press any key to continue...").unwrap();
-    stdout.flush().unwrap();
+        let line = match lines.next() {
+            Some(line) => line.unwrap(),
+            None => break,
+        };
 
-    // Read a single byte and discard
-    let _ = stdin.read(&mut [0u8]).unwrap();
+        match debugger.execute(vm, &line) {
+            Ok(text) if text.is_empty() => {}
+            Ok(text) => println!("{}", text),
+            Err(err) => println!("{}", err),
+        }
+    }
 }
 
-fn main() -> () {
+fn main() {
     let args = Args::parse();
 
-    let prog = fs::read(args.prog_file_path.as_str()).unwrap();
+    let machine = args
+        .machine
+        .as_ref()
+        .map(|path| MachineConfig::from_file(path).unwrap_or_else(|err| panic!("{}", err)));
+    let mut vm = match machine.as_ref() {
+        Some(machine) => machine.build().unwrap_or_else(|err| panic!("{}", err)),
+        None => Vm::new(),
+    };
+    if args.cycle_accurate {
+        vm.set_cycle_accurate(true);
+    }
+    if let Some(policy) = args.rom_write {
+        vm.set_access_policy(FaultKind::RomWrite, policy);
+    }
+    if let Some(policy) = args.unmapped_access {
+        vm.set_access_policy(FaultKind::Unmapped, policy);
+    }
+    let clock_hz = machine.as_ref().and_then(|machine| machine.clock_hz());
 
-    let mut vm = Vm::new();
-    vm.copy_memory(0, &prog);
+    if let Some(path) = args.load_state.as_ref() {
+        let state = fs::read(path).unwrap();
+        vm.load_state(&state).unwrap();
+    }
 
-    while !vm.halt {
-        let res = vm.cycle();
-        match res {
-            Ok(_) => {}
-            Err(err) => println!("{}", err),
+    let mut prog_len = None;
+    if let Some(path) = args.prog_file_path.as_ref() {
+        let prog = fs::read(path).unwrap();
+        vm.copy_memory(args.load_address as usize, &prog);
+        prog_len = Some(prog.len());
+        if machine.is_none() && args.load_state.is_none() {
+            vm.set_pc(args.load_address);
         }
+    }
+
+    let symbols = match args.symbols.as_ref() {
+        Some(path) => fs::read_to_string(path)
+            .unwrap()
+            .parse::<SymbolTable>()
+            .unwrap_or_else(|err| panic!("{}", err)),
+        None => SymbolTable::new(),
+    };
+
+    if args.coverage.is_some() || args.listing.is_some() {
+        vm.enable_coverage();
+    }
+    if args.profile.is_some() || args.folded.is_some() {
+        vm.enable_profiler();
+    }
+
+    let host = match args.sandbox.as_ref() {
+        Some(path) => HostAbi::new().with_sandbox(path),
+        None => HostAbi::new(),
+    };
+    let host = host.install(&mut vm);
+
+    if let Some(addr) = args.console {
+        vm.map_device(addr, Console::stdio())
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+    if let Some(addr) = args.screen {
+        TextScreen::new()
+            .map(&mut vm, addr)
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+    // Address, columns and rows of the screen to dump
+    let screen = match args.screen {
+        Some(addr) => Some((addr, SCREEN_COLUMNS, SCREEN_ROWS)),
+        None => machine
+            .as_ref()
+            .and_then(|machine| machine.screen().unwrap_or_else(|err| panic!("{}", err))),
+    };
+    if args.screen_dump.is_some() && screen.is_none() {
+        panic!("No screen to dump, map one with --screen or in the machine file");
+    }
+    if let Some(addr) = args.acia {
+        Acia::open(&args.serial)
+            .and_then(|acia| vm.map_device(addr, acia))
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
 
-        if args.debug {
-            pause();
+    if args.record.is_some() {
+        vm.set_input_mode(InputMode::Record(InputLog::new()));
+    }
+    if let Some(path) = args.replay.as_ref() {
+        let log = fs::read_to_string(path)
+            .unwrap()
+            .parse::<InputLog>()
+            .unwrap_or_else(|err| panic!("{}", err));
+        vm.set_input_mode(InputMode::Replay { log, next: 0 });
+    }
+
+    // First error of a replay, the run fails
+    let mut replay_error = None;
+    let mut replay_failed = false;
+
+    if let Some(addr) = args.gdb.as_ref() {
+        let listener = TcpListener::bind(addr).unwrap();
+        println!("Waiting for a GDB client on {}", addr);
+        let (mut stream, client) = listener.accept().unwrap();
+        println!("GDB client connected from {}", client);
+        GdbStub::new()
+            .serve(&mut vm, &mut stream)
+            .unwrap_or_else(|err| println!("{}", err));
+    } else if args.debug || args.tui {
+        if args.tui {
+            tui::run(&mut vm, symbols.clone()).unwrap();
+        } else {
+            debug(&mut vm, symbols.clone());
         }
+    } else {
+        let mut trace = args
+            .trace
+            .as_ref()
+            .map(|path| BufWriter::new(File::create(path).unwrap()));
+
+        // Emulated time is kept behind the real time when the machine gives its clock
+        let started = (Instant::now(), vm.cycle_count());
+
+        while !vm.halt {
+            if let Some(clock_hz) = clock_hz {
+                let cycles = (vm.cycle_count() - started.1) as u128;
+                let emulated =
+                    Duration::from_nanos((cycles * 1_000_000_000 / clock_hz as u128) as u64);
+                if let Some(ahead) = emulated.checked_sub(started.0.elapsed()) {
+                    if ahead > Duration::from_millis(10) {
+                        thread::sleep(ahead);
+                    }
+                }
+            }
+
+            if args
+                .max_cycles
+                .is_some_and(|max_cycles| vm.cycle_count() >= max_cycles)
+            {
+                break;
+            }
+
+            if !args.quiet {
+                if let Ok(instruction) = vm.decode_at(vm.get_pc()) {
+                    println!("{}", instruction);
+                }
+            }
+
+            if let Some(trace) = trace.as_mut() {
+                if let Ok(line) = rustemu::trace::trace_line(&vm) {
+                    writeln!(trace, "{}", line).unwrap();
+                }
+            }
+
+            let res = vm.cycle();
+            match res {
+                Ok(_) if !args.quiet => println!("{}", vm),
+                Ok(_) => {}
+                // The replay can not go on once it diverged
+                Err(err) if args.replay.is_some() => {
+                    replay_error = Some(err);
+                    break;
+                }
+                Err(err) => println!("{}", err),
+            }
+            print_memory_log(&mut vm);
+        }
+    }
+    print_memory_log(&mut vm);
+
+    if let Some(coverage) = vm.coverage() {
+        if let Some(path) = args.coverage.as_ref() {
+            fs::write(path, coverage.lcov(&vm, &symbols)).unwrap();
+        }
+        if let Some(path) = args.listing.as_ref() {
+            // The loaded program, or what was executed when resuming a save state
+            let range = match prog_len {
+                Some(len) if len > 0 => {
+                    let start = args.load_address;
+                    Some(start..=start.saturating_add((len - 1).min(0xFFFF) as u16))
+                }
+                _ => coverage.executed_range(),
+            };
+            let listing = range
+                .map(|range| coverage.annotated_listing(&vm, &symbols, range))
+                .unwrap_or_default();
+            fs::write(path, listing).unwrap();
+        }
+    }
+
+    if let Some(profiler) = vm.profiler() {
+        if let Some(path) = args.profile.as_ref() {
+            fs::write(path, profiler.report(&vm, &symbols, 50)).unwrap();
+        }
+        if let Some(path) = args.folded.as_ref() {
+            fs::write(path, profiler.folded(&symbols)).unwrap();
+        }
+    }
+
+    if let Some(path) = args.record.as_ref() {
+        let log = vm.finish_recording().unwrap_or_default();
+        fs::write(path, log.to_string()).unwrap();
+        println!("State hash {:016x}", vm.state_hash());
+    }
+    if args.replay.is_some() {
+        match replay_error.map_or_else(|| vm.check_replay(), Err) {
+            Ok(()) => println!(
+                "Replay matches the recording, state hash {:016x}",
+                vm.state_hash()
+            ),
+            Err(err) => {
+                println!("{}", err);
+                replay_failed = true;
+            }
+        }
+    }
+
+    if let Some((path, (addr, columns, rows))) = args.screen_dump.as_ref().zip(screen) {
+        TextScreen::capture(&vm, addr, columns, rows)
+            .save(path)
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    if let Some(path) = args.save_state.as_ref() {
+        fs::write(path, vm.save_state()).unwrap();
+    }
+
+    if replay_failed {
+        process::exit(1);
+    }
+    let exit_code = host.borrow().exit_code();
+    if let Some(code) = exit_code {
+        process::exit(code as i32);
     }
 }
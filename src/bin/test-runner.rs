@@ -0,0 +1,69 @@
+use std::{fs, path::Path, process::ExitCode};
+
+use clap::Parser;
+use rustemu::test_runner::{junit_xml, SuiteResult, TestResult, TestSpec};
+
+/// Run the unit tests of assembly routines described in TOML specs
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Test specs, the program files are relative to the spec
+    #[arg(required = true)]
+    specs: Vec<String>,
+
+    /// Write the results in the JUnit XML format to this file
+    #[arg(long)]
+    junit: Option<String>,
+}
+
+fn run_spec(path: &str) -> Result<Vec<TestResult>, String> {
+    let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let spec: TestSpec = toml::from_str(&text).map_err(|err| err.to_string())?;
+    let base_dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let (program, symbols) = spec.load_program(base_dir)?;
+    Ok(spec.run(&program, &symbols))
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let mut suites = Vec::new();
+
+    for path in args.specs.iter() {
+        let results = run_spec(path).unwrap_or_else(|err| {
+            // A spec which can not be loaded counts as one failed case
+            vec![TestResult {
+                name: "load".to_string(),
+                cycles: 0,
+                failure: Some(err),
+            }]
+        });
+
+        for result in results.iter() {
+            match result.failure.as_ref() {
+                None => println!(
+                    "PASS {} :: {} ({} cycles)",
+                    path, result.name, result.cycles
+                ),
+                Some(failure) => println!("FAIL {} :: {} : {}", path, result.name, failure),
+            }
+        }
+        suites.push(SuiteResult {
+            name: path.clone(),
+            results,
+        });
+    }
+
+    let total: usize = suites.iter().map(|suite| suite.results.len()).sum();
+    let failures: usize = suites.iter().map(|suite| suite.failures()).sum();
+    println!("\n{} passed, {} failed", total - failures, failures);
+
+    if let Some(path) = args.junit.as_ref() {
+        fs::write(path, junit_xml(&suites)).unwrap();
+    }
+
+    if failures == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,77 @@
+use std::{fs, process::ExitCode};
+
+use clap::Parser;
+use rustemu::trace::{diff_traces, parse_trace, TraceLine};
+
+/// Find the first instruction where two trace files diverge
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Reference trace, written with the --trace option of run or by another emulator
+    left: String,
+
+    /// Trace to compare with the reference
+    right: String,
+
+    /// Number of lines shown before and after the first divergence
+    #[arg(short, long, default_value_t = 5)]
+    context: usize,
+}
+
+fn read_trace(path: &str) -> Vec<TraceLine> {
+    let text = fs::read_to_string(path).unwrap_or_else(|err| panic!("{} : {}", path, err));
+    parse_trace(&text).unwrap_or_else(|err| panic!("{} : {}", path, err))
+}
+
+fn print_lines(name: &str, lines: &[TraceLine], start: usize, end: usize, mark: usize) {
+    println!("{}", name);
+    for (index, line) in lines.iter().enumerate().take(end).skip(start) {
+        let marker = if index == mark { '>' } else { ' ' };
+        println!("{} {:>8}  {}", marker, index + 1, line);
+    }
+    if lines.len() <= mark {
+        println!("> {:>8}  <end of trace>", lines.len() + 1);
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let left = read_trace(&args.left);
+    let right = read_trace(&args.right);
+
+    let diff = diff_traces(&left, &right);
+    let Some((index, kinds, registers)) = diff.first.as_ref() else {
+        println!("The traces are identical ({} instructions)", left.len());
+        return ExitCode::SUCCESS;
+    };
+
+    let kinds: Vec<String> = kinds.iter().map(|kind| kind.to_string()).collect();
+    print!(
+        "First divergence at instruction {} : {}",
+        index + 1,
+        kinds.join(", ")
+    );
+    if !registers.is_empty() {
+        print!(" ({})", registers.join(", "));
+    }
+    println!("\n");
+
+    let start = index.saturating_sub(args.context);
+    let end = index + args.context + 1;
+    print_lines(&args.left, &left, start, end, *index);
+    println!();
+    print_lines(&args.right, &right, start, end, *index);
+
+    println!(
+        "\nSummary over {} aligned instructions",
+        left.len().min(right.len())
+    );
+    for (kind, count, first) in diff.counts.iter() {
+        println!("  {:<10} {:>8} lines, first at {}", kind, count, first + 1);
+    }
+    for (register, count) in diff.registers.iter() {
+        println!("  {:<10} {:>8} lines", register, count);
+    }
+
+    ExitCode::FAILURE
+}
@@ -0,0 +1,647 @@
+//! Breakpoints and watchpoints checked by the Vm while it runs.
+//!
+//! A breakpoint fires when the PC reaches its address, a watchpoint when the CPU reads or
+//! writes in its range. Both can have a condition on the registers and memory, like
+//! `A == $10 && X > 3`, and a hit count : they only fire once the condition has been true
+//! that many times.
+
+use std::{fmt, ops::RangeInclusive, str::FromStr};
+
+use crate::{
+    bus::{BusAccess, BusCycle, MemoryFault},
+    isa::{Register, RegisterFlag},
+    symbols::parse_number,
+    Vm,
+};
+
+pub type BreakpointId = usize;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum BreakpointKind {
+    Execute(u16),
+    Watch(RangeInclusive<u16>, WatchKind),
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Breakpoint {
+    pub kind: BreakpointKind,
+    pub condition: Option<Condition>,
+    /// Number of hits needed before firing, 0 and 1 both fire on the first hit
+    pub hit_count: u64,
+    hits: u64,
+}
+
+impl Breakpoint {
+    pub fn execute(addr: u16) -> Self {
+        Self::new(BreakpointKind::Execute(addr))
+    }
+
+    pub fn watch(range: RangeInclusive<u16>, kind: WatchKind) -> Self {
+        Self::new(BreakpointKind::Watch(range, kind))
+    }
+
+    fn new(kind: BreakpointKind) -> Self {
+        Self {
+            kind,
+            condition: None,
+            hit_count: 0,
+            hits: 0,
+        }
+    }
+
+    pub fn with_condition(mut self, condition: Condition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+
+    pub fn with_hit_count(mut self, hit_count: u64) -> Self {
+        self.hit_count = hit_count;
+        self
+    }
+
+    /// Number of times the breakpoint was reached with its condition true
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    fn matches_access(&self, access: &BusCycle) -> bool {
+        match &self.kind {
+            BreakpointKind::Watch(range, kind) => {
+                range.contains(&access.addr)
+                    && match kind {
+                        WatchKind::Read => access.access == BusAccess::Read,
+                        WatchKind::Write => access.access == BusAccess::Write,
+                        WatchKind::Access => true,
+                    }
+            }
+            BreakpointKind::Execute(_) => false,
+        }
+    }
+
+    // Counts a hit if the condition is true, returns true if the breakpoint fires
+    fn hit(&mut self, vm: &Vm) -> bool {
+        if self
+            .condition
+            .as_ref()
+            .is_some_and(|condition| !condition.evaluate(vm))
+        {
+            return false;
+        }
+
+        self.hits += 1;
+        self.hits >= self.hit_count
+    }
+}
+
+/// The breakpoint that stopped the Vm
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct BreakHit {
+    pub id: BreakpointId,
+    /// Address of the next instruction
+    pub pc: u16,
+    /// Memory access which triggered a watchpoint
+    pub access: Option<BusCycle>,
+}
+
+/// Why `Vm::run_until` returned
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum StopReason {
+    Breakpoint(BreakHit),
+    /// A signal handler asked for a break, with the number of the signal
+    Signal(u8),
+    /// An access to the ROM or to unmapped memory faulted, the machine is halted
+    MemoryFault(MemoryFault),
+    Halted,
+    CycleLimit,
+}
+
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    breakpoints: Vec<(BreakpointId, Breakpoint)>,
+    next_id: BreakpointId,
+    has_watchpoints: bool,
+    /// Watchpoints triggered by the current instruction, with the first matching access
+    accesses: Vec<(BreakpointId, BusCycle)>,
+}
+
+impl Breakpoints {
+    pub fn add(&mut self, breakpoint: Breakpoint) -> BreakpointId {
+        self.next_id += 1;
+        self.breakpoints.push((self.next_id, breakpoint));
+        self.update_has_watchpoints();
+        self.next_id
+    }
+
+    pub fn remove(&mut self, id: BreakpointId) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|(bp_id, _)| *bp_id != id);
+        self.update_has_watchpoints();
+        len != self.breakpoints.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.update_has_watchpoints();
+    }
+
+    pub fn get(&self, id: BreakpointId) -> Option<&Breakpoint> {
+        self.breakpoints
+            .iter()
+            .find(|(bp_id, _)| *bp_id == id)
+            .map(|(_, breakpoint)| breakpoint)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (BreakpointId, &Breakpoint)> {
+        self.breakpoints
+            .iter()
+            .map(|(id, breakpoint)| (*id, breakpoint))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    fn update_has_watchpoints(&mut self) {
+        self.has_watchpoints = self
+            .breakpoints
+            .iter()
+            .any(|(_, breakpoint)| matches!(breakpoint.kind, BreakpointKind::Watch(..)));
+    }
+
+    pub(crate) fn begin_instruction(&mut self) {
+        self.accesses.clear();
+    }
+
+    pub(crate) fn record_access(&mut self, access: BusCycle) {
+        if !self.has_watchpoints {
+            return;
+        }
+
+        for (id, breakpoint) in self.breakpoints.iter() {
+            if breakpoint.matches_access(&access)
+                && !self.accesses.iter().any(|(hit_id, _)| hit_id == id)
+            {
+                self.accesses.push((*id, access));
+            }
+        }
+    }
+
+    /// Checks the breakpoints once an instruction is done, the first one firing is returned
+    pub(crate) fn check(&mut self, vm: &Vm) -> Option<BreakHit> {
+        let pc = vm.get_pc();
+        let mut fired = None;
+
+        for (id, breakpoint) in self.breakpoints.iter_mut() {
+            let access = match breakpoint.kind {
+                BreakpointKind::Execute(addr) if addr == pc => None,
+                BreakpointKind::Execute(_) => continue,
+                BreakpointKind::Watch(..) => {
+                    match self.accesses.iter().find(|(hit_id, _)| hit_id == id) {
+                        Some((_, access)) => Some(*access),
+                        None => continue,
+                    }
+                }
+            };
+
+            if breakpoint.hit(vm) && fired.is_none() {
+                fired = Some(BreakHit {
+                    id: *id,
+                    pc,
+                    access,
+                });
+            }
+        }
+
+        fired
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Operand {
+    Register(Register),
+    Pc,
+    Flag(RegisterFlag),
+    Memory(u16),
+    /// Bank of the mapper seen at the PC
+    Bank,
+    Constant(u16),
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// Boolean expression on the state of the Vm.
+///
+/// Operands are the registers (`A`, `X`, `Y`, `SP`, `SR`, `PC`), the flags
+/// (`N`, `V`, `B`, `D`, `I`, `Z`, `C`), a memory byte (`[$10]`), the bank seen at the
+/// PC (`BANK`, $ffff outside of the mappers) or a number.
+/// They are compared with `==`, `!=`, `<`, `<=`, `>`, `>=` and combined with
+/// `&&`, `||`, `!` and parenthesis. An operand alone is true when it is not 0.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Condition {
+    Compare(Operand, Comparison, Operand),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+impl Operand {
+    fn value(self, vm: &Vm) -> u16 {
+        match self {
+            Operand::Register(register) => vm.get_register(register) as u16,
+            Operand::Pc => vm.get_pc(),
+            Operand::Flag(flag) => vm.get_flag(flag) as u16,
+            Operand::Memory(addr) => vm.read_memory(addr).unwrap_or_default() as u16,
+            Operand::Bank => vm.bank_at(vm.get_pc()).map_or(u16::MAX, |bank| bank as u16),
+            Operand::Constant(value) => value,
+        }
+    }
+}
+
+impl Condition {
+    pub fn evaluate(&self, vm: &Vm) -> bool {
+        match self {
+            Condition::Compare(left, comparison, right) => {
+                let (left, right) = (left.value(vm), right.value(vm));
+                match comparison {
+                    Comparison::Equal => left == right,
+                    Comparison::NotEqual => left != right,
+                    Comparison::Less => left < right,
+                    Comparison::LessOrEqual => left <= right,
+                    Comparison::Greater => left > right,
+                    Comparison::GreaterOrEqual => left >= right,
+                }
+            }
+            Condition::Not(condition) => !condition.evaluate(vm),
+            Condition::And(left, right) => left.evaluate(vm) && right.evaluate(vm),
+            Condition::Or(left, right) => left.evaluate(vm) || right.evaluate(vm),
+        }
+    }
+}
+
+const OPERATORS: [&str; 14] = [
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "(", ")", "[", "]", "=",
+];
+
+fn tokenize(value: &str) -> Result<Vec<&str>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = value.trim_start();
+
+    while !rest.is_empty() {
+        if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(&rest[..op.len()]);
+            rest = &rest[op.len()..];
+        } else {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '$' || c == '%' || c == '_'))
+                .unwrap_or(rest.len());
+            if len == 0 {
+                return Err(format!("Unexpected character in condition : {}", rest));
+            }
+            tokens.push(&rest[..len]);
+            rest = &rest[len..];
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+struct ConditionParser<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+}
+
+impl<'a> ConditionParser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        let token = self
+            .peek()
+            .ok_or("Unexpected end of condition".to_string())?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!(
+                "Expected {} in condition, found {}",
+                expected, token
+            )),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Condition, String> {
+        let mut condition = self.parse_and()?;
+        while self.peek() == Some("||") {
+            self.pos += 1;
+            condition = Condition::Or(Box::new(condition), Box::new(self.parse_and()?));
+        }
+        Ok(condition)
+    }
+
+    fn parse_and(&mut self) -> Result<Condition, String> {
+        let mut condition = self.parse_unary()?;
+        while self.peek() == Some("&&") {
+            self.pos += 1;
+            condition = Condition::And(Box::new(condition), Box::new(self.parse_unary()?));
+        }
+        Ok(condition)
+    }
+
+    fn parse_unary(&mut self) -> Result<Condition, String> {
+        match self.peek() {
+            Some("!") => {
+                self.pos += 1;
+                Ok(Condition::Not(Box::new(self.parse_unary()?)))
+            }
+            Some("(") => {
+                self.pos += 1;
+                let condition = self.parse_or()?;
+                self.expect(")")?;
+                Ok(condition)
+            }
+            _ => self.parse_comparison(),
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Condition, String> {
+        let left = self.parse_operand()?;
+        let comparison = match self.peek() {
+            Some("==") | Some("=") => Comparison::Equal,
+            Some("!=") => Comparison::NotEqual,
+            Some("<") => Comparison::Less,
+            Some("<=") => Comparison::LessOrEqual,
+            Some(">") => Comparison::Greater,
+            Some(">=") => Comparison::GreaterOrEqual,
+            _ => {
+                return Ok(Condition::Compare(
+                    left,
+                    Comparison::NotEqual,
+                    Operand::Constant(0),
+                ))
+            }
+        };
+        self.pos += 1;
+
+        Ok(Condition::Compare(left, comparison, self.parse_operand()?))
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, String> {
+        let token = self.next()?;
+        if token == "[" {
+            let addr = self.next()?;
+            let addr =
+                parse_number(addr).ok_or(format!("Wrong address in condition : {}", addr))?;
+            self.expect("]")?;
+            return Ok(Operand::Memory(addr));
+        }
+
+        let operand = match token.to_lowercase().as_str() {
+            "a" | "ac" => Operand::Register(Register::AC),
+            "x" => Operand::Register(Register::X),
+            "y" => Operand::Register(Register::Y),
+            "sp" => Operand::Register(Register::SP),
+            "sr" => Operand::Register(Register::SR),
+            "pc" => Operand::Pc,
+            "bank" => Operand::Bank,
+            "n" => Operand::Flag(RegisterFlag::Negative),
+            "v" => Operand::Flag(RegisterFlag::Overflow),
+            "b" => Operand::Flag(RegisterFlag::Break),
+            "d" => Operand::Flag(RegisterFlag::Decimal),
+            "i" => Operand::Flag(RegisterFlag::Interrupt),
+            "z" => Operand::Flag(RegisterFlag::Zero),
+            "c" => Operand::Flag(RegisterFlag::Carry),
+            _ => Operand::Constant(
+                parse_number(token).ok_or(format!("Unknown operand in condition : {}", token))?,
+            ),
+        };
+        Ok(operand)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parser = ConditionParser {
+            tokens: tokenize(value)?,
+            pos: 0,
+        };
+
+        let condition = parser.parse_or()?;
+        match parser.peek() {
+            Some(token) => Err(format!("Unexpected {} in condition", token)),
+            None => Ok(condition),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(Register::AC) => write!(f, "A"),
+            Operand::Register(register) => write!(f, "{:?}", register),
+            Operand::Pc => write!(f, "PC"),
+            Operand::Flag(flag) => {
+                let name = match flag {
+                    RegisterFlag::Negative => "N",
+                    RegisterFlag::Overflow => "V",
+                    RegisterFlag::Break => "B",
+                    RegisterFlag::Decimal => "D",
+                    RegisterFlag::Interrupt => "I",
+                    RegisterFlag::Zero => "Z",
+                    RegisterFlag::Carry => "C",
+                };
+                write!(f, "{}", name)
+            }
+            Operand::Memory(addr) => write!(f, "[${:04x}]", addr),
+            Operand::Bank => write!(f, "BANK"),
+            Operand::Constant(value) if *value > 0xFF => write!(f, "${:04x}", value),
+            Operand::Constant(value) => write!(f, "${:02x}", value),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Compare(left, comparison, right) => {
+                let comparison = match comparison {
+                    Comparison::Equal => "==",
+                    Comparison::NotEqual => "!=",
+                    Comparison::Less => "<",
+                    Comparison::LessOrEqual => "<=",
+                    Comparison::Greater => ">",
+                    Comparison::GreaterOrEqual => ">=",
+                };
+                write!(f, "{} {} {}", left, comparison, right)
+            }
+            Condition::Not(condition) => write!(f, "!({})", condition),
+            Condition::And(left, right) => write!(f, "({} && {})", left, right),
+            Condition::Or(left, right) => write!(f, "({} || {})", left, right),
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            BreakpointKind::Execute(addr) => write!(f, "break ${:04x}", addr)?,
+            BreakpointKind::Watch(range, kind) => {
+                let kind = match kind {
+                    WatchKind::Read => "read",
+                    WatchKind::Write => "write",
+                    WatchKind::Access => "access",
+                };
+                write!(
+                    f,
+                    "watch {} ${:04x}-${:04x}",
+                    kind,
+                    range.start(),
+                    range.end()
+                )?
+            }
+        }
+        if let Some(condition) = self.condition.as_ref() {
+            write!(f, " if {}", condition)?;
+        }
+        if self.hit_count > 1 {
+            write!(f, " after {} hits", self.hit_count)?;
+        }
+        write!(f, " (hits {})", self.hits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LDX #0 / loop: INX / TXA / STA $10,X / CPX #5 / BNE loop / JAM
+    const PROG: [u8; 11] = [
+        0xA2, 0x00, 0xE8, 0x8A, 0x95, 0x10, 0xE0, 0x05, 0xD0, 0xF8, 0xF2,
+    ];
+
+    fn new_vm() -> Vm {
+        let mut vm = Vm::new();
+        vm.copy_memory(0x0200, &PROG);
+        vm.set_pc(0x0200);
+        vm
+    }
+
+    #[test]
+    fn test_condition_parse() {
+        let condition: Condition = "A == $10 && X > 3".parse().unwrap();
+        assert_eq!(
+            condition,
+            Condition::And(
+                Box::new(Condition::Compare(
+                    Operand::Register(Register::AC),
+                    Comparison::Equal,
+                    Operand::Constant(0x10)
+                )),
+                Box::new(Condition::Compare(
+                    Operand::Register(Register::X),
+                    Comparison::Greater,
+                    Operand::Constant(3)
+                ))
+            )
+        );
+        assert_eq!(condition.to_string(), "(A == $10 && X > $03)");
+
+        let condition: Condition = "!(c || [$0010]>=%11) && pc!=$0200".parse().unwrap();
+        assert_eq!(
+            condition.to_string(),
+            "(!((C != $00 || [$0010] >= $03)) && PC != $0200)"
+        );
+
+        assert!("A ==".parse::<Condition>().is_err());
+        assert!("(A == 1".parse::<Condition>().is_err());
+        assert!("A == 1 X".parse::<Condition>().is_err());
+        assert!("Q == 1".parse::<Condition>().is_err());
+        assert!("A # 1".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn test_run_until_breakpoints() {
+        let mut vm = new_vm();
+        let loop_id = vm
+            .add_breakpoint(Breakpoint::execute(0x0202).with_condition("X >= 2".parse().unwrap()));
+
+        let reason = vm.run_until(u64::MAX).unwrap();
+        assert_eq!(
+            reason,
+            StopReason::Breakpoint(BreakHit {
+                id: loop_id,
+                pc: 0x0202,
+                access: None
+            })
+        );
+        assert_eq!(vm.get_register(Register::X), 2);
+        assert_eq!(
+            vm.last_break(),
+            Some(&BreakHit {
+                id: loop_id,
+                pc: 0x0202,
+                access: None
+            })
+        );
+
+        vm.remove_breakpoint(loop_id);
+        let watch_id = vm
+            .add_breakpoint(Breakpoint::watch(0x0014..=0x0020, WatchKind::Write).with_hit_count(2));
+
+        let reason = vm.run_until(u64::MAX).unwrap();
+        let access = BusCycle {
+            addr: 0x0015,
+            value: 5,
+            access: BusAccess::Write,
+        };
+        assert_eq!(
+            reason,
+            StopReason::Breakpoint(BreakHit {
+                id: watch_id,
+                pc: 0x0206,
+                access: Some(access)
+            })
+        );
+        assert_eq!(vm.breakpoint(watch_id).unwrap().hits(), 2);
+
+        assert_eq!(vm.run_until(u64::MAX).unwrap(), StopReason::Halted);
+        assert_eq!(vm.last_break(), None);
+    }
+
+    #[test]
+    fn test_run_until_limit_and_reads() {
+        let mut vm = new_vm();
+        assert_eq!(vm.run_until(10).unwrap(), StopReason::CycleLimit);
+        assert!(vm.cycle_count() >= 10);
+
+        // The opcode fetch is a read of the watched range
+        let id = vm.add_breakpoint(Breakpoint::watch(0x0208..=0x0208, WatchKind::Read));
+        vm.add_breakpoint(Breakpoint::watch(0x0208..=0x0208, WatchKind::Write));
+        let StopReason::Breakpoint(hit) = vm.run_until(u64::MAX).unwrap() else {
+            panic!("The watchpoint did not fire");
+        };
+        assert_eq!(hit.id, id);
+        assert_eq!(hit.access.unwrap().value, 0xD0);
+    }
+}
@@ -0,0 +1,738 @@
+use std::{cell::RefCell, fmt, rc::Rc, str::FromStr};
+
+use serde::Deserialize;
+
+use crate::{
+    replay::InputMode,
+    state::{StateReader, StateWriter},
+};
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum BusAccess {
+    Read,
+    Write,
+}
+
+/// One cycle of activity on the address and data buses
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct BusCycle {
+    pub addr: u16,
+    pub value: u8,
+    pub access: BusAccess,
+}
+
+/// Access to the host given to the devices, the values go through the
+/// record and replay of `Vm::host_input`
+pub struct HostPort<'a> {
+    cycle: u64,
+    input_mode: &'a mut InputMode,
+    error: Option<String>,
+}
+
+impl<'a> HostPort<'a> {
+    pub fn new(cycle: u64, input_mode: &'a mut InputMode) -> Self {
+        Self {
+            cycle,
+            input_mode,
+            error: None,
+        }
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    /// Value given by the host on a channel, 0 once the replay diverged
+    pub fn input<F>(&mut self, channel: u16, live: F) -> u8
+    where
+        F: FnOnce() -> u8,
+    {
+        if self.error.is_some() {
+            return 0;
+        }
+        self.input_mode
+            .input(self.cycle, channel, live)
+            .unwrap_or_else(|err| {
+                self.error = Some(err);
+                0
+            })
+    }
+
+    /// Value given by the host on a channel only when `live` has something new, like a
+    /// change of state. Unlike `input` nothing is logged when there is nothing new, the
+    /// devices poll the host after every instruction without filling the record.
+    pub fn poll<F>(&mut self, channel: u16, live: F) -> Option<u8>
+    where
+        F: FnOnce() -> Option<u8>,
+    {
+        if self.error.is_some() {
+            return None;
+        }
+        self.input_mode
+            .poll(self.cycle, channel, live)
+            .unwrap_or_else(|err| {
+                self.error = Some(err);
+                None
+            })
+    }
+
+    /// Replay divergence hit by the devices, it stops the instruction
+    pub fn into_error(self) -> Option<String> {
+        self.error
+    }
+}
+
+/// Hardware mapped in the address space, the offsets are relative to the start of its mapping
+pub trait Device {
+    /// Number of addresses taken by the device
+    fn size(&self) -> u16;
+
+    /// Read done by the CPU, may change the state of the device
+    fn read(&mut self, offset: u16, host: &mut HostPort) -> u8;
+
+    /// Read without any side effect, for debuggers and tooling
+    fn peek(&self, offset: u16) -> u8;
+
+    fn write(&mut self, offset: u16, value: u8);
+
+    /// Called after every instruction with the cycles it took
+    fn tick(&mut self, _cycles: u64, _host: &mut HostPort) {}
+
+    /// State of the IRQ line of the device
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Bank seen at an offset, for the bank switching devices of `mapper`
+    fn bank(&self, _offset: u16) -> Option<usize> {
+        None
+    }
+
+    /// Saves the registers and the memory of the device, nothing for the stateless ones
+    fn save_state(&self, _writer: &mut StateWriter) {}
+
+    /// Restores what `save_state` wrote, the configuration of the device is kept
+    fn load_state(&mut self, _reader: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A device shared with the host, which keeps a handle to inspect or drive it
+impl<D: Device> Device for Rc<RefCell<D>> {
+    fn size(&self) -> u16 {
+        self.borrow().size()
+    }
+
+    fn read(&mut self, offset: u16, host: &mut HostPort) -> u8 {
+        self.borrow_mut().read(offset, host)
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        self.borrow().peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        self.borrow_mut().write(offset, value)
+    }
+
+    fn tick(&mut self, cycles: u64, host: &mut HostPort) {
+        self.borrow_mut().tick(cycles, host)
+    }
+
+    fn irq(&self) -> bool {
+        self.borrow().irq()
+    }
+
+    fn bank(&self, offset: u16) -> Option<usize> {
+        self.borrow().bank(offset)
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.borrow().save_state(writer)
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.borrow_mut().load_state(reader)
+    }
+}
+
+/// CPU input driven by the IRQ output of a device
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IrqLine {
+    #[default]
+    Irq,
+    Nmi,
+    #[serde(rename = "none")]
+    Disconnected,
+}
+
+/// What answers the CPU on the addresses without device
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MemoryAttribute {
+    #[default]
+    Ram,
+    /// Reads the memory, the writes are lost
+    ReadOnly,
+    /// Nothing is connected, the reads give $ff from the pull-up resistors
+    Unmapped,
+    /// Nothing is connected, the reads give the last value seen on the data bus
+    OpenBus,
+}
+
+/// Access of the CPU which the memory does not allow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    RomWrite,
+    Unmapped,
+}
+
+/// What the `Vm` does on a faulty access
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessPolicy {
+    /// Behaves as the hardware, the access has no effect
+    #[default]
+    Ignore,
+    /// Keeps the access for `Vm::take_memory_log`
+    Log,
+    /// Halts the machine after the instruction, with a `MemoryFault`
+    Fault,
+}
+
+impl FromStr for AccessPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "ignore" => Ok(AccessPolicy::Ignore),
+            "log" => Ok(AccessPolicy::Log),
+            "fault" => Ok(AccessPolicy::Fault),
+            _ => Err(format!(
+                "Unknown access policy {}, expected ignore, log or fault",
+                value
+            )),
+        }
+    }
+}
+
+/// Faulty access of the CPU, with the instruction which did it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryFault {
+    pub kind: FaultKind,
+    pub access: BusAccess,
+    pub addr: u16,
+    /// Address of the instruction
+    pub pc: u16,
+}
+
+impl From<MemoryFault> for String {
+    fn from(fault: MemoryFault) -> Self {
+        fault.to_string()
+    }
+}
+
+struct Mapping {
+    start: u16,
+    end: u16,
+    device: Box<dyn Device>,
+    line: IrqLine,
+}
+
+/// Addresses repeating a smaller region, as with an incomplete address decoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Mirror {
+    start: u16,
+    end: u16,
+    source: u16,
+    size: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Region {
+    start: u16,
+    end: u16,
+    attribute: MemoryAttribute,
+}
+
+/// Everything the CPU can reach through its 16 bits address space, the devices
+/// are mapped over the RAM
+pub struct Bus {
+    memory: Box<[u8; 64 * 1024]>,
+    devices: Vec<Mapping>,
+    mirrors: Vec<Mirror>,
+    regions: Vec<Region>,
+    // Last value read or written, floating on the open bus
+    data_bus: u8,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self {
+            memory: Box::new([0; 64 * 1024]),
+            devices: Vec::new(),
+            mirrors: Vec::new(),
+            regions: Vec::new(),
+            data_bus: 0,
+        }
+    }
+
+    /// Maps a device from an address, it can not overlap another device
+    pub fn map<D: Device + 'static>(&mut self, start: u16, device: D) -> Result<(), String> {
+        let end = match device.size() {
+            0 => return Err("A device must take at least one address".to_string()),
+            size => start
+                .checked_add(size - 1)
+                .ok_or(format!("Device at ${:04x} does not fit in memory", start))?,
+        };
+        if let Some(other) = self
+            .devices
+            .iter()
+            .find(|other| start <= other.end && other.start <= end)
+        {
+            return Err(format!(
+                "Device at ${:04x}-${:04x} overlaps the one at ${:04x}-${:04x}",
+                start, end, other.start, other.end
+            ));
+        }
+
+        self.devices.push(Mapping {
+            start,
+            end,
+            device: Box::new(device),
+            line: IrqLine::Irq,
+        });
+        Ok(())
+    }
+
+    /// Connects the IRQ output of the device mapped from an address to a CPU input
+    pub fn wire(&mut self, start: u16, line: IrqLine) -> bool {
+        match self
+            .devices
+            .iter_mut()
+            .find(|mapping| mapping.start == start)
+        {
+            Some(mapping) => {
+                mapping.line = line;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes `start..=end` repeat the `size` addresses from `source`, devices included
+    pub fn mirror(&mut self, start: u16, end: u16, source: u16, size: u16) -> Result<(), String> {
+        if end < start || size == 0 || source.checked_add(size - 1).is_none() {
+            return Err(format!(
+                "Invalid mirror of ${:04x} at ${:04x}-${:04x}",
+                source, start, end
+            ));
+        }
+        if let Some(other) = self
+            .mirrors
+            .iter()
+            .find(|other| start <= other.end && other.start <= end)
+        {
+            return Err(format!(
+                "Mirror at ${:04x}-${:04x} overlaps the one at ${:04x}-${:04x}",
+                start, end, other.start, other.end
+            ));
+        }
+
+        self.mirrors.push(Mirror {
+            start,
+            end,
+            source,
+            size,
+        });
+        Ok(())
+    }
+
+    /// Gives an attribute to the memory of `start..=end`, over the ones given before.
+    /// The memory is RAM by default, the mirrors and the devices come first.
+    pub fn set_attribute(
+        &mut self,
+        start: u16,
+        end: u16,
+        attribute: MemoryAttribute,
+    ) -> Result<(), String> {
+        if end < start {
+            return Err(format!("Invalid region ${:04x}-${:04x}", start, end));
+        }
+        self.regions.push(Region {
+            start,
+            end,
+            attribute,
+        });
+        Ok(())
+    }
+
+    /// Attribute of the memory reached from an address, devices aside
+    pub fn attribute(&self, addr: u16) -> MemoryAttribute {
+        self.attribute_at(self.resolve(addr))
+    }
+
+    fn attribute_at(&self, addr: u16) -> MemoryAttribute {
+        self.regions
+            .iter()
+            .rev()
+            .find(|region| (region.start..=region.end).contains(&addr))
+            .map_or(MemoryAttribute::Ram, |region| region.attribute)
+    }
+
+    /// Fault of an access of the CPU, the devices accept every access
+    pub fn fault(&self, addr: u16, access: BusAccess) -> Option<FaultKind> {
+        if self.regions.is_empty() || self.is_mapped(addr) {
+            return None;
+        }
+        match (self.attribute(addr), access) {
+            (MemoryAttribute::Ram, _) | (MemoryAttribute::ReadOnly, BusAccess::Read) => None,
+            (MemoryAttribute::ReadOnly, BusAccess::Write) => Some(FaultKind::RomWrite),
+            (MemoryAttribute::Unmapped | MemoryAttribute::OpenBus, _) => Some(FaultKind::Unmapped),
+        }
+    }
+
+    /// Address really reached through the mirrors
+    pub fn resolve(&self, addr: u16) -> u16 {
+        match self
+            .mirrors
+            .iter()
+            .find(|mirror| (mirror.start..=mirror.end).contains(&addr))
+        {
+            Some(mirror) => mirror.source + (addr - mirror.start) % mirror.size,
+            None => addr,
+        }
+    }
+
+    /// Removes the device mapped from an address
+    pub fn unmap(&mut self, start: u16) -> bool {
+        let len = self.devices.len();
+        self.devices.retain(|mapping| mapping.start != start);
+        self.devices.len() != len
+    }
+
+    /// Address ranges of the mapped devices
+    pub fn mappings(&self) -> Vec<(u16, u16)> {
+        self.devices
+            .iter()
+            .map(|mapping| (mapping.start, mapping.end))
+            .collect()
+    }
+
+    pub fn is_mapped(&self, addr: u16) -> bool {
+        self.device(self.resolve(addr)).is_some()
+    }
+
+    fn device(&self, addr: u16) -> Option<&Mapping> {
+        self.devices
+            .iter()
+            .find(|mapping| (mapping.start..=mapping.end).contains(&addr))
+    }
+
+    fn device_mut(&mut self, addr: u16) -> Option<&mut Mapping> {
+        self.devices
+            .iter_mut()
+            .find(|mapping| (mapping.start..=mapping.end).contains(&addr))
+    }
+
+    /// Read done by the CPU, may have side effects on the mapped hardware
+    pub fn read(&mut self, addr: u16, host: &mut HostPort) -> u8 {
+        let addr = self.resolve(addr);
+        let value = match self.device_mut(addr) {
+            Some(mapping) => mapping.device.read(addr - mapping.start, host),
+            None => self.memory_value(addr),
+        };
+        self.data_bus = value;
+        value
+    }
+
+    /// Read without any side effect, for debuggers and tooling
+    pub fn peek(&self, addr: u16) -> u8 {
+        let addr = self.resolve(addr);
+        match self.device(addr) {
+            Some(mapping) => mapping.device.peek(addr - mapping.start),
+            None => self.memory_value(addr),
+        }
+    }
+
+    fn memory_value(&self, addr: u16) -> u8 {
+        match self.attribute_at(addr) {
+            MemoryAttribute::Ram | MemoryAttribute::ReadOnly => self.memory[addr as usize],
+            MemoryAttribute::Unmapped => 0xFF,
+            MemoryAttribute::OpenBus => self.data_bus,
+        }
+    }
+
+    /// Write done by the CPU, only the RAM and the devices keep it
+    pub fn write(&mut self, addr: u16, value: u8) {
+        let addr = self.resolve(addr);
+        let writable = self.attribute_at(addr) == MemoryAttribute::Ram;
+        self.data_bus = value;
+        match self.device_mut(addr) {
+            Some(mapping) => mapping.device.write(addr - mapping.start, value),
+            None if writable => self.memory[addr as usize] = value,
+            None => {}
+        }
+    }
+
+    /// Bank of the device seen at an address, for the debuggers
+    pub fn bank(&self, addr: u16) -> Option<usize> {
+        let addr = self.resolve(addr);
+        self.device(addr)
+            .and_then(|mapping| mapping.device.bank(addr - mapping.start))
+    }
+
+    /// Writes the memory under the devices, to load programs and ROM images
+    pub fn load(&mut self, addr: u16, value: u8) {
+        self.memory[self.resolve(addr) as usize] = value
+    }
+
+    /// Lets the devices count the cycles of the last instruction
+    pub fn tick(&mut self, cycles: u64, host: &mut HostPort) {
+        for mapping in self.devices.iter_mut() {
+            mapping.device.tick(cycles, host);
+        }
+    }
+
+    /// True when a device holds the IRQ input of the CPU
+    pub fn irq(&self) -> bool {
+        self.line(IrqLine::Irq)
+    }
+
+    /// True when a device holds the NMI input of the CPU
+    pub fn nmi(&self) -> bool {
+        self.line(IrqLine::Nmi)
+    }
+
+    fn line(&self, line: IrqLine) -> bool {
+        self.devices
+            .iter()
+            .any(|mapping| mapping.line == line && mapping.device.irq())
+    }
+
+    /// Last value seen on the data bus
+    pub fn data_bus(&self) -> u8 {
+        self.data_bus
+    }
+
+    pub fn set_data_bus(&mut self, value: u8) {
+        self.data_bus = value
+    }
+
+    /// Saves the RAM, the addresses of the devices then a chunk for each one of them
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.begin_chunk(b"RAM ");
+        writer.put_bytes(self.memory.as_slice());
+        self.save_mappings(writer);
+    }
+
+    /// Restores a state saved with the same devices, mapped at the same addresses
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        let mut ram = reader.chunk(b"RAM ")?;
+        let memory = ram.get_bytes(self.memory.len())?;
+        self.load_mappings(reader)?;
+        self.memory.copy_from_slice(memory);
+        Ok(())
+    }
+
+    /// State of the devices alone, without the RAM
+    pub fn save_devices(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        self.save_mappings(&mut writer);
+        writer.finish()
+    }
+
+    pub fn load_devices(&mut self, data: &[u8]) -> Result<(), String> {
+        self.load_mappings(&mut StateReader::new(data)?)
+    }
+
+    fn save_mappings(&self, writer: &mut StateWriter) {
+        writer.begin_chunk(b"MAP ");
+        writer.put_u16(self.devices.len() as u16);
+        for mapping in self.devices.iter() {
+            writer.put_u16(mapping.start);
+        }
+        for mapping in self.devices.iter() {
+            writer.begin_chunk(b"DEV ");
+            mapping.device.save_state(writer);
+        }
+    }
+
+    fn load_mappings(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        let mut map = reader.chunk(b"MAP ")?;
+        let starts = (0..map.get_u16()?)
+            .map(|_| map.get_u16())
+            .collect::<Result<Vec<u16>, String>>()?;
+        let mapped: Vec<u16> = self.devices.iter().map(|mapping| mapping.start).collect();
+        if starts != mapped {
+            return Err(format!(
+                "The save state has devices at {}, the machine at {}",
+                addresses(&starts),
+                addresses(&mapped)
+            ));
+        }
+
+        for mapping in self.devices.iter_mut() {
+            let mut chunk = reader.chunk(b"DEV ")?;
+            mapping
+                .device
+                .load_state(&mut chunk)
+                .map_err(|err| format!("Device at ${:04x} : {}", mapping.start, err))?;
+        }
+        Ok(())
+    }
+}
+
+fn addresses(starts: &[u16]) -> String {
+    match starts.is_empty() {
+        true => "none".to_string(),
+        false => starts
+            .iter()
+            .map(|start| format!("${:04x}", start))
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for BusAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusAccess::Read => write!(f, "read"),
+            BusAccess::Write => write!(f, "write"),
+        }
+    }
+}
+
+impl fmt::Display for MemoryFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            FaultKind::RomWrite => write!(f, "Write to the ROM at ${:04x}", self.addr),
+            FaultKind::Unmapped => write!(f, "Unmapped {} at ${:04x}", self.access, self.addr),
+        }?;
+        write!(f, " by the instruction at ${:04x}", self.pc)
+    }
+}
+
+impl fmt::Display for BusCycle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "0x{:04x} 0x{:02x} {}",
+            self.addr, self.value, self.access
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Register(u8);
+
+    impl Device for Register {
+        fn size(&self) -> u16 {
+            2
+        }
+
+        fn read(&mut self, offset: u16, host: &mut HostPort) -> u8 {
+            host.input(offset, || self.0)
+        }
+
+        fn peek(&self, _offset: u16) -> u8 {
+            self.0
+        }
+
+        fn write(&mut self, _offset: u16, value: u8) {
+            self.0 = value
+        }
+    }
+
+    #[test]
+    fn test_device_mapping() {
+        let mut bus = Bus::new();
+        let mut input_mode = InputMode::Live;
+        bus.load(0x1000, 0xAA);
+        bus.map(0x1000, Register(0x42)).unwrap();
+        assert!(bus.map(0x0FFF, Register(0)).is_err());
+        assert!(bus.map(0xFFFF, Register(0)).is_err());
+        assert_eq!(bus.mappings(), vec![(0x1000, 0x1001)]);
+
+        bus.write(0x1001, 0x43);
+        assert_eq!(
+            bus.read(0x1000, &mut HostPort::new(0, &mut input_mode)),
+            0x43
+        );
+        assert_eq!(bus.peek(0x1002), 0x00);
+
+        assert!(bus.wire(0x1000, IrqLine::Nmi));
+        assert!(!bus.wire(0x2000, IrqLine::Nmi));
+
+        // Every 4 bytes from $2000 show the device and the RAM after it
+        bus.mirror(0x2000, 0x2FFF, 0x1000, 4).unwrap();
+        assert!(bus.mirror(0x2FFF, 0x3000, 0x1000, 4).is_err());
+        bus.load(0x1002, 0xBB);
+        assert_eq!(bus.peek(0x2401), 0x43);
+        assert_eq!(bus.peek(0x2FFE), 0xBB);
+        bus.write(0x2004, 0x44);
+        assert_eq!(bus.peek(0x1001), 0x44);
+
+        assert!(bus.unmap(0x1000));
+        assert_eq!(bus.peek(0x1000), 0xAA);
+        assert!(!bus.is_mapped(0x1000));
+    }
+
+    #[test]
+    fn test_memory_attributes() {
+        let mut bus = Bus::new();
+        let mut input_mode = InputMode::Live;
+        bus.set_attribute(0x0000, 0xFFFF, MemoryAttribute::Unmapped)
+            .unwrap();
+        bus.set_attribute(0x0000, 0x0FFF, MemoryAttribute::Ram)
+            .unwrap();
+        bus.set_attribute(0x8000, 0x8FFF, MemoryAttribute::OpenBus)
+            .unwrap();
+        bus.set_attribute(0xF000, 0xFFFF, MemoryAttribute::ReadOnly)
+            .unwrap();
+        assert!(bus.set_attribute(2, 1, MemoryAttribute::Ram).is_err());
+        bus.map(0x4000, Register(0x42)).unwrap();
+
+        // The ROM is loaded but not written by the CPU
+        bus.load(0xF000, 0x11);
+        bus.write(0xF000, 0x22);
+        assert_eq!(bus.peek(0xF000), 0x11);
+        assert_eq!(bus.fault(0xF000, BusAccess::Read), None);
+        assert_eq!(
+            bus.fault(0xF000, BusAccess::Write),
+            Some(FaultKind::RomWrite)
+        );
+
+        bus.write(0x0010, 0x33);
+        assert_eq!(bus.peek(0x0010), 0x33);
+        assert_eq!(bus.peek(0x2000), 0xFF);
+        assert_eq!(
+            bus.fault(0x2000, BusAccess::Read),
+            Some(FaultKind::Unmapped)
+        );
+        assert_eq!(bus.fault(0x4001, BusAccess::Write), None);
+
+        // The open bus gives back the last value
+        bus.read(0xF000, &mut HostPort::new(0, &mut input_mode));
+        assert_eq!(bus.peek(0x8123), 0x11);
+        assert_eq!(
+            bus.fault(0x8123, BusAccess::Read),
+            Some(FaultKind::Unmapped)
+        );
+
+        // A mirror has the attribute of its source
+        bus.mirror(0x1000, 0x1FFF, 0x0000, 0x1000).unwrap();
+        assert_eq!(bus.attribute(0x1010), MemoryAttribute::Ram);
+        assert_eq!(bus.peek(0x1010), 0x33);
+    }
+}
@@ -0,0 +1,261 @@
+//! Memory mapped console, with the registers of the py65 monitor.
+//!
+//! | Offset | Register | Access                                                         |
+//! |--------|----------|----------------------------------------------------------------|
+//! | `$0`   | STATUS   | Bit 0 set when a character is waiting, bit 1 always set        |
+//! | `$1`   | PUTC     | Writing prints the character                                   |
+//! | `$4`   | GETC     | Reading takes the waiting character, or 0 when there is none   |
+//!
+//! Mapped at `$F000`, PUTC and GETC are at `$F001` and `$F004` as in py65. Reads never
+//! block : the input is read by a thread and queued until the program takes it. The
+//! values read by the program go through `HostPort::input` so the runs can be replayed.
+//! STATUS is polled in a loop by the programs, so only its changes are recorded : they
+//! are taken after every instruction and the register gives the last one.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use crate::{
+    bus::{Device, HostPort},
+    state::{StateReader, StateWriter},
+};
+
+pub const CONSOLE_SIZE: u16 = 0x10;
+/// Address used by the py65 monitor
+pub const CONSOLE_DEFAULT_ADDR: u16 = 0xF000;
+/// Channel of the values read by the program, for the record and replay
+pub const CONSOLE_CHANNEL: u16 = 0x0100;
+
+pub const REG_STATUS: u16 = 0x0;
+pub const REG_PUTC: u16 = 0x1;
+pub const REG_GETC: u16 = 0x4;
+
+pub const STATUS_INPUT_READY: u8 = 0b0000_0001;
+pub const STATUS_OUTPUT_READY: u8 = 0b0000_0010;
+
+/// Reads a host stream on a thread, the bytes can be taken without blocking
+pub fn read_in_background<R: Read + Send + 'static>(mut input: R) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = [0; 256];
+        while let Ok(len @ 1..) = input.read(&mut buffer) {
+            if buffer[..len].iter().any(|byte| sender.send(*byte).is_err()) {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Writes a byte of the program to a host stream, a closed stream is not an error
+/// of the program
+pub fn write_output(output: &mut dyn Write, value: u8) {
+    let _ = output.write_all(&[value]).and_then(|_| output.flush());
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::{
+        cell::RefCell,
+        io::{self, Write},
+        rc::Rc,
+    };
+
+    /// Output kept in memory, shared with the test which checks it
+    #[derive(Clone, Default)]
+    pub struct SharedOutput(pub Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+pub struct Console {
+    source: Option<Receiver<u8>>,
+    pending: VecDeque<u8>,
+    /// Input ready bit given to the program, updated on the changes
+    ready: bool,
+    output: Box<dyn Write>,
+}
+
+impl Console {
+    /// Console printing on the standard output, without input
+    pub fn new() -> Self {
+        Self {
+            source: None,
+            pending: VecDeque::new(),
+            ready: false,
+            output: Box::new(io::stdout()),
+        }
+    }
+
+    /// Console on the standard input and output
+    pub fn stdio() -> Self {
+        Self::new().with_input(io::stdin())
+    }
+
+    /// Reads the input on a thread, the program gets the bytes as they arrive
+    pub fn with_input<R: Read + Send + 'static>(mut self, input: R) -> Self {
+        self.source = Some(read_in_background(input));
+        self
+    }
+
+    pub fn with_output(mut self, output: Box<dyn Write>) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Queues characters for the program, after the ones already waiting
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.pending.extend(bytes);
+    }
+
+    fn receive(&mut self) {
+        if let Some(source) = self.source.as_ref() {
+            self.pending.extend(source.try_iter());
+        }
+    }
+
+    fn status(&self) -> u8 {
+        match self.ready {
+            true => STATUS_OUTPUT_READY | STATUS_INPUT_READY,
+            false => STATUS_OUTPUT_READY,
+        }
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Console {
+    fn size(&self) -> u16 {
+        CONSOLE_SIZE
+    }
+
+    fn read(&mut self, offset: u16, host: &mut HostPort) -> u8 {
+        match offset {
+            REG_GETC => host.input(CONSOLE_CHANNEL, || {
+                self.receive();
+                self.pending.pop_front().unwrap_or(0)
+            }),
+            _ => self.peek(offset),
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            REG_STATUS => self.status(),
+            REG_GETC => self.pending.front().copied().unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        if offset == REG_PUTC {
+            write_output(self.output.as_mut(), value);
+        }
+    }
+
+    fn tick(&mut self, _cycles: u64, host: &mut HostPort) {
+        let status = host.poll(CONSOLE_CHANNEL, || {
+            self.receive();
+            let ready = !self.pending.is_empty();
+            (ready != self.ready).then_some(ready as u8)
+        });
+        if let Some(status) = status {
+            self.ready = status != 0;
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bool(self.ready);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.ready = reader.get_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{testing::SharedOutput, *};
+    use crate::{
+        asm::assemble,
+        replay::{InputLog, InputMode},
+        Vm,
+    };
+
+    // Echoes the input in upper case until a '.'
+    const ECHO: &str = "
+STATUS = $F000
+PUTC = $F001
+GETC = $F004
+wait:
+    LoadACAbs STATUS
+    AndImm 1
+    BranchZero wait
+    LoadACAbs GETC
+    CmpACImm $2E
+    BranchZero done
+    AndImm $DF
+    StoreACAbs PUTC
+    JumpAbs wait
+done:
+    Jam
+";
+
+    fn run_echo(mode: InputMode, input: &[u8]) -> (Vm, Vec<u8>) {
+        let output = SharedOutput::default();
+        let mut console = Console::new().with_output(Box::new(output.clone()));
+        console.push_input(input);
+
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &assemble(ECHO, "echo.asm").unwrap().bytes);
+        vm.map_device(CONSOLE_DEFAULT_ADDR, console).unwrap();
+        vm.set_input_mode(mode);
+        while !vm.halt {
+            vm.cycle().unwrap();
+        }
+        let output = output.0.borrow().clone();
+        (vm, output)
+    }
+
+    #[test]
+    fn test_console_echo() {
+        let (vm, output) = run_echo(InputMode::Live, b"abc.d");
+        assert_eq!(output, b"ABC");
+        assert_eq!(
+            vm.read_memory(0xF000),
+            Some(STATUS_OUTPUT_READY | STATUS_INPUT_READY)
+        );
+        assert_eq!(vm.read_memory(0xF004), Some(b'd'));
+        assert_eq!(vm.read_memory(0xF00F), Some(0));
+    }
+
+    #[test]
+    fn test_console_replay() {
+        let (mut vm, _) = run_echo(InputMode::Record(InputLog::new()), b"hi.");
+        let log = vm.finish_recording().unwrap();
+        // The 3 characters read, and the status set then cleared, not the polls
+        assert_eq!(log.events.len(), 5);
+
+        let (vm, output) = run_echo(InputMode::Replay { log, next: 0 }, b"");
+        assert_eq!(output, b"HI");
+        assert_eq!(vm.check_replay(), Ok(()));
+    }
+}
@@ -0,0 +1,252 @@
+//! Code coverage of the executed program.
+//!
+//! The Vm counts how many times each instruction is executed and, for the branches, how
+//! many times they are taken or not. The result is exported as an annotated listing or as
+//! an lcov report mapped to the source lines through the symbol file.
+
+use std::{collections::BTreeMap, fmt::Write, ops::RangeInclusive};
+
+use crate::{debugger::disassemble, isa::AddrMode, symbols::SymbolTable, Vm};
+
+/// Outcomes of a branch instruction
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    executed: BTreeMap<u16, u64>,
+    branches: BTreeMap<u16, BranchCoverage>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_instruction(&mut self, addr: u16) {
+        *self.executed.entry(addr).or_default() += 1;
+    }
+
+    pub fn record_branch(&mut self, addr: u16, taken: bool) {
+        let branch = self.branches.entry(addr).or_default();
+        if taken {
+            branch.taken += 1;
+        } else {
+            branch.not_taken += 1;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.executed.clear();
+        self.branches.clear();
+    }
+
+    /// Number of times the instruction at this address was executed
+    pub fn hits(&self, addr: u16) -> u64 {
+        self.executed.get(&addr).copied().unwrap_or_default()
+    }
+
+    pub fn executed(&self) -> impl Iterator<Item = (u16, u64)> + '_ {
+        self.executed.iter().map(|(addr, count)| (*addr, *count))
+    }
+
+    pub fn branch(&self, addr: u16) -> Option<BranchCoverage> {
+        self.branches.get(&addr).copied()
+    }
+
+    pub fn branches(&self) -> impl Iterator<Item = (u16, BranchCoverage)> + '_ {
+        self.branches.iter().map(|(addr, branch)| (*addr, *branch))
+    }
+
+    /// Lowest and highest addresses of the executed instructions
+    pub fn executed_range(&self) -> Option<RangeInclusive<u16>> {
+        let first = *self.executed.keys().next()?;
+        let last = *self.executed.keys().next_back()?;
+        Some(first..=last)
+    }
+
+    /// Disassembly of the memory range with the execution count of every instruction,
+    /// `#####` marks the instructions never executed
+    pub fn annotated_listing(
+        &self,
+        vm: &Vm,
+        symbols: &SymbolTable,
+        range: RangeInclusive<u16>,
+    ) -> String {
+        let mut listing = String::new();
+        let mut addr = *range.start();
+
+        while addr <= *range.end() {
+            let line = disassemble(vm, addr, 1).remove(0);
+
+            if let Some(label) = symbols.label_at(addr) {
+                writeln!(listing, "{}:", label).unwrap();
+            }
+            let count = match self.hits(addr) {
+                0 => "#####".to_string(),
+                count => count.to_string(),
+            };
+            let mut text = format!("{:>9}  {:<36}", count, line.to_string());
+
+            let is_branch = line
+                .instruction
+                .is_some_and(|instruction| instruction.addr_mode() == AddrMode::Relative);
+            if is_branch {
+                let branch = self.branch(addr).unwrap_or_default();
+                write!(
+                    text,
+                    "taken {}, not taken {}",
+                    branch.taken, branch.not_taken
+                )
+                .unwrap();
+            }
+            writeln!(listing, "{}", text.trim_end()).unwrap();
+
+            match addr.checked_add(line.bytes.len() as u16) {
+                Some(next) => addr = next,
+                None => break,
+            }
+        }
+
+        listing
+    }
+
+    /// Report in the lcov tracefile format, one record per source file of the symbol table
+    pub fn lcov(&self, vm: &Vm, symbols: &SymbolTable) -> String {
+        // Execution count and branch outcomes of every source line
+        let mut files: BTreeMap<&str, BTreeMap<usize, (u64, Vec<BranchCoverage>)>> =
+            BTreeMap::new();
+        for info in symbols.lines() {
+            let entry = files
+                .entry(info.file.as_str())
+                .or_default()
+                .entry(info.line)
+                .or_default();
+            entry.0 = entry.0.max(self.hits(info.addr));
+
+            let is_branch = vm
+                .decode_at(info.addr)
+                .is_ok_and(|instruction| instruction.addr_mode() == AddrMode::Relative);
+            if is_branch {
+                entry.1.push(self.branch(info.addr).unwrap_or_default());
+            }
+        }
+
+        let mut report = String::new();
+        for (file, lines) in files {
+            writeln!(report, "TN:").unwrap();
+            writeln!(report, "SF:{}", file).unwrap();
+
+            let (mut branches_found, mut branches_hit) = (0, 0);
+            for (line, (count, branches)) in lines.iter() {
+                for (block, branch) in branches.iter().enumerate() {
+                    for (index, outcome) in [branch.taken, branch.not_taken].iter().enumerate() {
+                        branches_found += 1;
+                        if *count == 0 {
+                            writeln!(report, "BRDA:{},{},{},-", line, block, index).unwrap();
+                        } else {
+                            if *outcome > 0 {
+                                branches_hit += 1;
+                            }
+                            writeln!(report, "BRDA:{},{},{},{}", line, block, index, outcome)
+                                .unwrap();
+                        }
+                    }
+                }
+            }
+            writeln!(report, "BRF:{}", branches_found).unwrap();
+            writeln!(report, "BRH:{}", branches_hit).unwrap();
+
+            for (line, (count, _)) in lines.iter() {
+                writeln!(report, "DA:{},{}", line, count).unwrap();
+            }
+            writeln!(report, "LF:{}", lines.len()).unwrap();
+            writeln!(
+                report,
+                "LH:{}",
+                lines.values().filter(|(count, _)| *count > 0).count()
+            )
+            .unwrap();
+            writeln!(report, "end_of_record").unwrap();
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    const SOURCE: &str = "
+    LoadXImm 0
+loop:
+    IncX
+    CmpXImm 3
+    BranchNotZero loop
+    BranchZero done
+    NoOp
+done:
+    Jam
+    ";
+
+    fn run_with_coverage() -> (Vm, SymbolTable) {
+        let program = assemble(SOURCE, "loop.asm").unwrap();
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &program.bytes);
+        vm.enable_coverage();
+        while !vm.halt {
+            vm.cycle().unwrap();
+        }
+        (vm, program.symbols)
+    }
+
+    #[test]
+    fn test_coverage_counts() {
+        let (vm, _) = run_with_coverage();
+        let coverage = vm.coverage().unwrap();
+
+        assert_eq!(coverage.hits(0x0000), 1);
+        assert_eq!(coverage.hits(0x0002), 3);
+        assert_eq!(
+            coverage.branch(0x0005),
+            Some(BranchCoverage {
+                taken: 2,
+                not_taken: 1
+            })
+        );
+        assert_eq!(
+            coverage.branch(0x0007),
+            Some(BranchCoverage {
+                taken: 1,
+                not_taken: 0
+            })
+        );
+        assert_eq!(coverage.hits(0x0009), 0);
+        assert_eq!(coverage.executed_range(), Some(0x0000..=0x000a));
+    }
+
+    #[test]
+    fn test_coverage_reports() {
+        let (vm, symbols) = run_with_coverage();
+        let coverage = vm.coverage().unwrap();
+
+        let listing = coverage.annotated_listing(&vm, &symbols, 0x0000..=0x000a);
+        assert!(listing.contains("loop:\n        3  $0002"));
+        assert!(listing.contains("taken 2, not taken 1"));
+        assert!(listing.contains("    #####  $0009"));
+
+        let lcov = coverage.lcov(&vm, &symbols);
+        assert!(lcov.starts_with("TN:\nSF:loop.asm\n"));
+        assert!(lcov.contains("BRDA:6,0,0,2\nBRDA:6,0,1,1\n"));
+        assert!(lcov.contains("BRDA:7,0,1,0\n"));
+        assert!(lcov.contains("DA:4,3\n"));
+        assert!(lcov.contains("DA:8,0\n"));
+        assert!(lcov.contains("BRF:4\nBRH:3\n"));
+        assert!(lcov.contains("LF:7\nLH:6\nend_of_record\n"));
+    }
+}
@@ -0,0 +1,735 @@
+//! Debug Adapter Protocol server, to debug the assembler sources from an editor.
+//!
+//! The server only handles the decoded messages, the transport (`Content-Length` headers
+//! over stdio) is done by `read_message` and `write_message`. The program runs by slices of
+//! cycles so a pause request can be handled while it is running.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    breakpoints::{Breakpoint, BreakpointId, Condition},
+    debugger::{call_sites, StepTarget},
+    isa::{Register, RegisterFlag},
+    symbols::SymbolTable,
+    Vm,
+};
+
+const THREAD_ID: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+const FLAGS_REFERENCE: u64 = 2;
+
+/// Number of cycles run before looking for new requests
+const SLICE_CYCLES: u64 = 10_000;
+
+/// Lines are searched forward for an instruction when a breakpoint is set on a line without one
+const MAX_LINE_SHIFT: usize = 32;
+
+pub fn read_message<R: BufRead>(reader: &mut R) -> Result<Option<Value>, String> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        let len = reader
+            .read_line(&mut header)
+            .map_err(|err| err.to_string())?;
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            content_length = Some(
+                value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| format!("Wrong Content-Length : {}", value))?,
+            );
+        }
+    }
+
+    let len = content_length.ok_or("Missing Content-Length header".to_string())?;
+    let mut content = vec![0u8; len];
+    reader
+        .read_exact(&mut content)
+        .map_err(|err| err.to_string())?;
+    serde_json::from_slice(&content)
+        .map(Some)
+        .map_err(|err| err.to_string())
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Value) -> Result<(), String> {
+    let content = message.to_string();
+    write!(
+        writer,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    )
+    .and_then(|_| writer.flush())
+    .map_err(|err| err.to_string())
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum RunMode {
+    Continue,
+    /// Run until the target of a next or a step out
+    Step(StepTarget),
+}
+
+#[derive(Default)]
+pub struct DapServer {
+    vm: Vm,
+    symbols: SymbolTable,
+    /// Files of the symbol table and where they are on disk
+    sources: Vec<(String, PathBuf)>,
+    source_breakpoints: HashMap<PathBuf, Vec<BreakpointId>>,
+    stop_on_entry: bool,
+    running: Option<RunMode>,
+    terminated: bool,
+    seq: u64,
+}
+
+impl DapServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    /// True while the program runs, `run_slice` must then be called between requests
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /// True once the client disconnected
+    pub fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+
+    /// Handles one request and returns the messages to send back
+    pub fn handle(&mut self, request: &Value) -> Vec<Value> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let mut events = Vec::new();
+
+        let result = match command {
+            "initialize" => {
+                events.push(self.event("initialized", json!({})));
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsConditionalBreakpoints": true,
+                    "supportsHitConditionalBreakpoints": true,
+                    "supportsReadMemoryRequest": true,
+                }))
+            }
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    events.push(self.stopped_event("entry", None));
+                } else {
+                    self.running = Some(RunMode::Continue);
+                }
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "6502" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(json!({
+                "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Flags", "variablesReference": FLAGS_REFERENCE, "expensive": false },
+                ]
+            })),
+            "variables" => Ok(self.variables(args["variablesReference"].as_u64())),
+            "readMemory" => self.read_memory(args),
+            "continue" => {
+                self.running = Some(RunMode::Continue);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                self.running = StepTarget::next(&self.vm).map(RunMode::Step);
+                if self.running.is_none() {
+                    events.extend(self.step_instruction());
+                }
+                Ok(json!({}))
+            }
+            "stepIn" => {
+                events.extend(self.step_instruction());
+                Ok(json!({}))
+            }
+            "stepOut" => {
+                self.running = Some(RunMode::Step(StepTarget::finish(&self.vm)));
+                Ok(json!({}))
+            }
+            "pause" => {
+                if self.running.take().is_some() {
+                    events.push(self.stopped_event("pause", None));
+                }
+                Ok(json!({}))
+            }
+            "disconnect" => {
+                self.terminated = true;
+                self.running = None;
+                Ok(json!({}))
+            }
+            _ => Err(format!("Unsupported request : {}", command)),
+        };
+
+        let mut messages = vec![self.response(request, result)];
+        messages.append(&mut events);
+        self.numbered(messages)
+    }
+
+    /// Runs the program for a slice of cycles, returns the events if it stopped
+    pub fn run_slice(&mut self) -> Vec<Value> {
+        let events = self.run_cycles();
+        self.numbered(events)
+    }
+
+    fn run_cycles(&mut self) -> Vec<Value> {
+        let Some(mode) = self.running else {
+            return Vec::new();
+        };
+        let limit = self.vm.cycle_count() + SLICE_CYCLES;
+
+        while self.vm.cycle_count() < limit {
+            if self.vm.halt {
+                self.running = None;
+                return self.terminated_events();
+            }
+
+            let instruction = self.vm.decode_at(self.vm.get_pc());
+            if let Err(err) = self.vm.cycle() {
+                self.running = None;
+                return vec![self.stopped_event("exception", Some(err))];
+            }
+
+            let done = match mode {
+                RunMode::Continue => false,
+                RunMode::Step(target) => {
+                    instruction.is_ok_and(|instruction| target.reached(&self.vm, instruction))
+                }
+            };
+
+            if done {
+                self.running = None;
+                return vec![self.stopped_event("step", None)];
+            }
+            if let Some(hit) = self.vm.last_break() {
+                let id = hit.id;
+                self.running = None;
+                return vec![self.stopped_event("breakpoint", Some(format!("#{}", id)))];
+            }
+            if let Some(signal) = self.vm.signal_break() {
+                self.running = None;
+                return vec![self.stopped_event("pause", Some(format!("Signal ${:02x}", signal)))];
+            }
+        }
+
+        Vec::new()
+    }
+
+    fn step_instruction(&mut self) -> Vec<Value> {
+        if self.vm.halt {
+            return self.terminated_events();
+        }
+        match self.vm.cycle() {
+            Ok(()) if self.vm.halt => self.terminated_events(),
+            Ok(()) => vec![self.stopped_event("step", None)],
+            Err(err) => vec![self.stopped_event("exception", Some(err))],
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or("The program to debug is missing".to_string())?;
+        let prog = fs::read(program).map_err(|err| format!("{} : {}", program, err))?;
+        self.vm.copy_memory(0, &prog);
+        // Stack pointer left by the reset sequence, the call stack stays below $01ff
+        self.vm.set_register(Register::SP, 0xFD);
+
+        if let Some(path) = args["symbols"].as_str() {
+            let text = fs::read_to_string(path).map_err(|err| format!("{} : {}", path, err))?;
+            self.symbols = text.parse()?;
+
+            // The sources are relative to where the assembler ran, or to the symbol file
+            let symbols_dir = Path::new(path).parent().unwrap_or(Path::new("."));
+            let mut files: Vec<&str> = self
+                .symbols
+                .lines()
+                .iter()
+                .map(|info| info.file.as_str())
+                .collect();
+            files.sort_unstable();
+            files.dedup();
+            self.sources = files
+                .into_iter()
+                .map(|file| {
+                    let resolved = [Path::new(file).to_path_buf(), symbols_dir.join(file)]
+                        .into_iter()
+                        .find_map(|candidate| candidate.canonicalize().ok())
+                        .unwrap_or(PathBuf::from(file));
+                    (file.to_string(), resolved)
+                })
+                .collect();
+        }
+
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(true);
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"]
+            .as_str()
+            .ok_or("The source path is missing".to_string())?;
+        let path = Path::new(path)
+            .canonicalize()
+            .unwrap_or(PathBuf::from(path));
+
+        for id in self.source_breakpoints.remove(&path).unwrap_or_default() {
+            self.vm.remove_breakpoint(id);
+        }
+
+        let file = self
+            .sources
+            .iter()
+            .find(|(_, resolved)| *resolved == path)
+            .map(|(file, _)| file.clone());
+
+        let mut ids = Vec::new();
+        let mut breakpoints = Vec::new();
+        for requested in args["breakpoints"].as_array().cloned().unwrap_or_default() {
+            let line = requested["line"].as_u64().unwrap_or_default() as usize;
+            let placed = file.as_ref().and_then(|file| {
+                (line..line + MAX_LINE_SHIFT).find_map(|line| {
+                    self.symbols
+                        .addresses_of_line(file, line)
+                        .first()
+                        .map(|addr| (line, *addr))
+                })
+            });
+
+            let Some((line, addr)) = placed else {
+                breakpoints.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "No instruction on this line",
+                }));
+                continue;
+            };
+
+            match new_breakpoint(addr, &requested) {
+                Ok(breakpoint) => {
+                    let id = self.vm.add_breakpoint(breakpoint);
+                    ids.push(id);
+                    breakpoints.push(json!({ "id": id, "verified": true, "line": line }));
+                }
+                Err(err) => breakpoints.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": err,
+                })),
+            }
+        }
+
+        self.source_breakpoints.insert(path, ids);
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn stack_trace(&self) -> Value {
+        let mut addresses = vec![self.vm.get_pc()];
+        addresses.extend(call_sites(&self.vm));
+
+        let frames: Vec<Value> = addresses
+            .iter()
+            .enumerate()
+            .map(|(id, addr)| {
+                let name = match self.symbols.nearest_label(*addr) {
+                    Some((label, _)) => label.to_string(),
+                    None => format!("${:04x}", addr),
+                };
+                let mut frame = json!({
+                    "id": id,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:04x}", addr),
+                });
+                if let Some(info) = self.symbols.line_of(*addr) {
+                    let path = self
+                        .sources
+                        .iter()
+                        .find(|(file, _)| *file == info.file)
+                        .map(|(_, resolved)| resolved.to_string_lossy().to_string())
+                        .unwrap_or(info.file.clone());
+                    frame["line"] = json!(info.line);
+                    frame["column"] = json!(1);
+                    frame["source"] = json!({
+                        "name": Path::new(&info.file).file_name().map(|name| name.to_string_lossy()),
+                        "path": path,
+                    });
+                }
+                frame
+            })
+            .collect();
+
+        json!({ "stackFrames": frames, "totalFrames": addresses.len() })
+    }
+
+    fn variables(&self, reference: Option<u64>) -> Value {
+        let variable = |name: &str, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+
+        let variables: Vec<Value> = match reference {
+            Some(REGISTERS_REFERENCE) => {
+                let mut variables: Vec<Value> = [
+                    ("A", Register::AC),
+                    ("X", Register::X),
+                    ("Y", Register::Y),
+                    ("SP", Register::SP),
+                    ("SR", Register::SR),
+                ]
+                .iter()
+                .map(|(name, register)| {
+                    variable(name, format!("${:02x}", self.vm.get_register(*register)))
+                })
+                .collect();
+                variables.push(variable("PC", format!("${:04x}", self.vm.get_pc())));
+                variables.push(variable("cycles", self.vm.cycle_count().to_string()));
+                variables
+            }
+            Some(FLAGS_REFERENCE) => [
+                ("N", RegisterFlag::Negative),
+                ("V", RegisterFlag::Overflow),
+                ("B", RegisterFlag::Break),
+                ("D", RegisterFlag::Decimal),
+                ("I", RegisterFlag::Interrupt),
+                ("Z", RegisterFlag::Zero),
+                ("C", RegisterFlag::Carry),
+            ]
+            .iter()
+            .map(|(name, flag)| variable(name, (self.vm.get_flag(*flag) as u8).to_string()))
+            .collect(),
+            _ => Vec::new(),
+        };
+
+        json!({ "variables": variables })
+    }
+
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let reference = args["memoryReference"]
+            .as_str()
+            .ok_or("The memory reference is missing".to_string())?;
+        let addr = reference
+            .strip_prefix("0x")
+            .and_then(|addr| u16::from_str_radix(addr, 16).ok())
+            .ok_or(format!("Wrong memory reference : {}", reference))?;
+        let addr = (addr as i64 + args["offset"].as_i64().unwrap_or_default()) as u16;
+        let count = args["count"].as_u64().unwrap_or_default() as usize;
+
+        // The address space wraps around, but a read stops at its end
+        let count = count.min(0x10000 - addr as usize);
+        let data: Vec<u8> = (0..count)
+            .map(|idx| {
+                self.vm
+                    .read_memory(addr.wrapping_add(idx as u16))
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        Ok(json!({
+            "address": format!("0x{:04x}", addr),
+            "data": to_base64(&data),
+        }))
+    }
+
+    /// Gives the sequence numbers, in the order the messages are sent
+    fn numbered(&mut self, mut messages: Vec<Value>) -> Vec<Value> {
+        for message in messages.iter_mut() {
+            self.seq += 1;
+            message["seq"] = json!(self.seq);
+        }
+        messages
+    }
+
+    fn response(&self, request: &Value, result: Result<Value, String>) -> Value {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        response
+    }
+
+    fn event(&self, event: &str, body: Value) -> Value {
+        json!({
+            "type": "event",
+            "event": event,
+            "body": body,
+        })
+    }
+
+    fn stopped_event(&self, reason: &str, text: Option<String>) -> Value {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body)
+    }
+
+    fn terminated_events(&self) -> Vec<Value> {
+        vec![
+            self.event("exited", json!({ "exitCode": 0 })),
+            self.event("terminated", json!({})),
+        ]
+    }
+}
+
+fn new_breakpoint(addr: u16, requested: &Value) -> Result<Breakpoint, String> {
+    let mut breakpoint = Breakpoint::execute(addr);
+    if let Some(condition) = requested["condition"].as_str() {
+        breakpoint = breakpoint.with_condition(condition.parse::<Condition>()?);
+    }
+    if let Some(hit_condition) = requested["hitCondition"].as_str() {
+        let hit_count = hit_condition
+            .trim_start_matches(">=")
+            .trim()
+            .parse()
+            .map_err(|_| format!("Wrong hit count : {}", hit_condition))?;
+        breakpoint = breakpoint.with_hit_count(hit_count);
+    }
+    Ok(breakpoint)
+}
+
+fn to_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let value = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                encoded.push(ALPHABET[(value >> (18 - 6 * idx) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::asm::assemble;
+
+    const SOURCE: &str = "\
+start:
+    LoadXImm 0
+loop:
+    JumpSubAbs double
+    IncX
+    CmpXImm 3
+    BranchNotZero loop
+    Jam
+double:
+    TransXAC
+    StoreACZp $80
+    RetSub
+";
+
+    struct Session {
+        server: DapServer,
+        seq: u64,
+        dir: PathBuf,
+    }
+
+    impl Session {
+        fn launch(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("rustemu_dap_{}", name));
+            fs::create_dir_all(&dir).unwrap();
+            let source_path = dir.join("double.asm");
+            fs::write(&source_path, SOURCE).unwrap();
+
+            // The symbols refer to the source relatively to the symbol file
+            let program = assemble(SOURCE, "double.asm").unwrap();
+            fs::write(dir.join("double.bin"), &program.bytes).unwrap();
+            fs::write(dir.join("double.sym"), program.symbols.to_string()).unwrap();
+
+            let mut session = Self {
+                server: DapServer::new(),
+                seq: 0,
+                dir,
+            };
+            let messages = session.request("initialize", json!({ "adapterID": "rustemu" }));
+            assert_eq!(messages[1]["event"], "initialized");
+            let program = session.dir.join("double.bin");
+            let symbols = session.dir.join("double.sym");
+            let messages =
+                session.request("launch", json!({ "program": program, "symbols": symbols }));
+            assert_eq!(messages[0]["success"], true);
+            session
+        }
+
+        fn request(&mut self, command: &str, arguments: Value) -> Vec<Value> {
+            self.seq += 1;
+            let request = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            let messages = self.server.handle(&request);
+            assert_eq!(messages[0]["request_seq"], self.seq);
+            assert!(messages
+                .windows(2)
+                .all(|pair| pair[0]["seq"].as_u64() < pair[1]["seq"].as_u64()));
+            messages
+        }
+
+        /// Runs until the program stops and returns the events
+        fn wait(&mut self) -> Vec<Value> {
+            let mut events = Vec::new();
+            while self.server.is_running() {
+                events.extend(self.server.run_slice());
+            }
+            events
+        }
+    }
+
+    #[test]
+    fn test_dap_breakpoints_and_stepping() {
+        let mut session = Session::launch("stepping");
+        let source = session.dir.join("double.asm");
+
+        let messages = session.request(
+            "setBreakpoints",
+            json!({
+                "source": { "path": source },
+                "breakpoints": [{ "line": 9 }, { "line": 13 }, { "line": 5, "condition": "X ==" }],
+            }),
+        );
+        let breakpoints = &messages[0]["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[0]["line"], 10);
+        assert_eq!(breakpoints[1]["verified"], false);
+        assert_eq!(breakpoints[2]["verified"], false);
+
+        let messages = session.request("configurationDone", json!({}));
+        assert_eq!(messages[1]["body"]["reason"], "entry");
+
+        session.request("continue", json!({ "threadId": 1 }));
+        let events = session.wait();
+        assert_eq!(events[0]["body"]["reason"], "breakpoint");
+
+        let messages = session.request("stackTrace", json!({ "threadId": 1 }));
+        let frames = &messages[0]["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "double");
+        assert_eq!(frames[0]["line"], 10);
+        assert_eq!(
+            frames[0]["source"]["path"],
+            source.canonicalize().unwrap().to_string_lossy().to_string()
+        );
+        assert_eq!(frames[1]["name"], "loop");
+        assert_eq!(frames[1]["line"], 4);
+
+        session.request("stepOut", json!({ "threadId": 1 }));
+        assert_eq!(session.wait()[0]["body"]["reason"], "step");
+        assert_eq!(session.server.vm().get_pc(), 0x0005);
+
+        let messages = session.request("stepIn", json!({ "threadId": 1 }));
+        assert_eq!(messages[1]["body"]["reason"], "step");
+        session.request("next", json!({ "threadId": 1 }));
+        session.request("next", json!({ "threadId": 1 }));
+        assert_eq!(session.server.vm().get_pc(), 0x0002);
+
+        // Stepping over the call does not stop on the breakpoint inside it
+        session.request(
+            "setBreakpoints",
+            json!({ "source": { "path": source }, "breakpoints": [] }),
+        );
+        session.request("next", json!({ "threadId": 1 }));
+        assert_eq!(session.wait()[0]["body"]["reason"], "step");
+        assert_eq!(session.server.vm().get_pc(), 0x0005);
+
+        session.request("continue", json!({ "threadId": 1 }));
+        let events = session.wait();
+        assert_eq!(events[0]["event"], "exited");
+        assert_eq!(events[1]["event"], "terminated");
+    }
+
+    #[test]
+    fn test_dap_variables_and_memory() {
+        let mut session = Session::launch("variables");
+        session.request("configurationDone", json!({}));
+        session.request("stepIn", json!({ "threadId": 1 }));
+
+        let messages = session.request("variables", json!({ "variablesReference": 1 }));
+        let variables = &messages[0]["body"]["variables"];
+        assert_eq!(variables[0]["name"], "A");
+        assert_eq!(variables[5]["name"], "PC");
+        assert_eq!(variables[5]["value"], "$0002");
+
+        let messages = session.request("variables", json!({ "variablesReference": 2 }));
+        let variables = &messages[0]["body"]["variables"];
+        assert_eq!(variables[5]["name"], "Z");
+        assert_eq!(variables[5]["value"], "1");
+
+        let messages = session.request(
+            "readMemory",
+            json!({ "memoryReference": "0x0000", "offset": 2, "count": 4 }),
+        );
+        assert_eq!(messages[0]["body"]["address"], "0x0002");
+        assert_eq!(
+            messages[0]["body"]["data"],
+            to_base64(&[0x20, 0x0B, 0x00, 0xE8])
+        );
+
+        let messages = session.request("evaluate", json!({ "expression": "A" }));
+        assert_eq!(messages[0]["success"], false);
+    }
+
+    #[test]
+    fn test_dap_transport() {
+        assert_eq!(to_base64(b"Man"), "TWFu");
+        assert_eq!(to_base64(b"Ma"), "TWE=");
+        assert_eq!(to_base64(b"M"), "TQ==");
+
+        let mut output = Vec::new();
+        write_message(&mut output, &json!({ "seq": 1 })).unwrap();
+        assert_eq!(output, b"Content-Length: 9\r\n\r\n{\"seq\":1}");
+
+        let mut input = Cursor::new(output);
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({ "seq": 1 })));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+        assert!(read_message(&mut Cursor::new(b"\r\n{}".to_vec())).is_err());
+    }
+}
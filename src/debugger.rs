@@ -0,0 +1,884 @@
+//! Command interpreter of the interactive debugger.
+//!
+//! Every command returns the text to show, an empty line repeats the last command.
+//! Addresses can be given as numbers (`$0200`, `0x0200`, `512`) or as labels of the symbol table.
+
+use std::fmt::{self, Write};
+
+use crate::{
+    breakpoints::{
+        BreakHit, Breakpoint, BreakpointKind, Comparison, Condition, Operand, WatchKind,
+    },
+    isa::{Instruction, Register, RegisterFlag},
+    symbols::{parse_number, SymbolTable},
+    Vm, STACK_PAGE,
+};
+
+const JSR_OPCODE: u8 = 0x20;
+
+const HELP: &str = "\
+step, s [N]            execute N instructions (1 by default)
+next, n                execute one instruction, a subroutine call is run until it returns
+finish, f              run until the current subroutine returns
+continue, c            run until a breakpoint or the end of the program
+break, b ADDR [if C]   add a breakpoint, C is a condition like A == $10 && X > 3,
+                       BB:ADDR only breaks when the bank BB is seen at ADDR
+watch ADDR[-END] [read|write|access] [if C]
+                       add a watchpoint on memory writes (by default) or reads
+delete ID              remove a breakpoint or a watchpoint
+breaks                 list the breakpoints and watchpoints
+regs, r                show the registers
+set REG|FLAG VALUE     change a register (a x y sp sr pc) or a flag (n v b d i z c)
+mem, x ADDR [LEN]      show the memory
+poke ADDR VALUE...     write bytes in memory
+disas, d [ADDR] [N]    disassemble N instructions, around PC by default
+bt                     show the subroutine calls found on the stack
+help, h                show this help
+quit, q                leave the debugger
+An empty line repeats the last command.";
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    pub symbols: SymbolTable,
+    last_command: Option<String>,
+    finished: bool,
+}
+
+impl Debugger {
+    pub fn new(symbols: SymbolTable) -> Self {
+        Self {
+            symbols,
+            ..Default::default()
+        }
+    }
+
+    /// True once the user asked to quit
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Runs one line typed by the user and returns the text to show
+    pub fn execute(&mut self, vm: &mut Vm, line: &str) -> Result<String, String> {
+        let line = line.trim();
+        let line = if line.is_empty() {
+            match self.last_command.clone() {
+                Some(last) => last,
+                None => return Ok(String::new()),
+            }
+        } else {
+            self.last_command = Some(line.to_string());
+            line.to_string()
+        };
+
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let args = &tokens[1..];
+
+        match tokens[0] {
+            "step" | "s" => {
+                let count = match args.first() {
+                    Some(count) => parse_number(count).ok_or(format!("Wrong count : {}", count))?,
+                    None => 1,
+                };
+                self.step(vm, count as usize)
+            }
+            "next" | "n" => self.next(vm),
+            "finish" | "f" => self.finish(vm),
+            "continue" | "c" => self.run(vm, |_, _| false),
+            "break" | "b" => {
+                let (args, mut condition) = split_condition(&line)?;
+                let arg = args.get(1).ok_or("An address is needed".to_string())?;
+                let addr = match arg.split_once(':') {
+                    Some((bank, addr)) => {
+                        let bank = u16::from_str_radix(bank, 16)
+                            .map_err(|_| format!("Wrong bank : {}", bank))?;
+                        let in_bank = Condition::Compare(
+                            Operand::Bank,
+                            Comparison::Equal,
+                            Operand::Constant(bank),
+                        );
+                        condition = Some(match condition {
+                            Some(condition) => {
+                                Condition::And(Box::new(in_bank), Box::new(condition))
+                            }
+                            None => in_bank,
+                        });
+                        self.symbols.parse_address(addr)?
+                    }
+                    None => self.symbols.parse_address(arg)?,
+                };
+                let mut breakpoint = Breakpoint::execute(addr);
+                breakpoint.condition = condition;
+                let id = vm.add_breakpoint(breakpoint);
+                Ok(format!("#{} {}", id, self.describe_breakpoint(vm, id)))
+            }
+            "watch" => {
+                let (args, condition) = split_condition(&line)?;
+                let range = args.get(1).ok_or("An address is needed".to_string())?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (
+                        self.symbols.parse_address(start)?,
+                        self.symbols.parse_address(end)?,
+                    ),
+                    None => {
+                        let addr = self.symbols.parse_address(range)?;
+                        (addr, addr)
+                    }
+                };
+                let kind = match args.get(2).copied() {
+                    Some("read") | Some("r") => WatchKind::Read,
+                    Some("write") | Some("w") | None => WatchKind::Write,
+                    Some("access") | Some("rw") => WatchKind::Access,
+                    Some(kind) => return Err(format!("Unknown watch kind : {}", kind)),
+                };
+                let mut breakpoint = Breakpoint::watch(start..=end, kind);
+                breakpoint.condition = condition;
+                let id = vm.add_breakpoint(breakpoint);
+                Ok(format!("#{} {}", id, self.describe_breakpoint(vm, id)))
+            }
+            "delete" => {
+                let id = args
+                    .first()
+                    .ok_or("A breakpoint number is needed".to_string())?;
+                let id = id
+                    .trim_start_matches('#')
+                    .parse()
+                    .map_err(|_| format!("Wrong breakpoint number : {}", id))?;
+                if vm.remove_breakpoint(id) {
+                    Ok(format!("Breakpoint #{} deleted", id))
+                } else {
+                    Err(format!("No breakpoint #{}", id))
+                }
+            }
+            "breaks" => Ok(vm
+                .breakpoints()
+                .iter()
+                .map(|(id, _)| format!("#{} {}", id, self.describe_breakpoint(vm, id)))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            "regs" | "r" => Ok(format_registers(vm)),
+            "set" => self.set(vm, args),
+            "mem" | "x" => {
+                let addr = self.address_arg(args)?;
+                let len = match args.get(1) {
+                    Some(len) => parse_number(len).ok_or(format!("Wrong length : {}", len))?,
+                    None => 16,
+                };
+                Ok(format_memory(vm, addr, len))
+            }
+            "poke" => {
+                let addr = self.address_arg(args)?;
+                if args.len() < 2 {
+                    return Err("Usage : poke ADDR VALUE...".to_string());
+                }
+                for (idx, value) in args[1..].iter().enumerate() {
+                    let value = parse_byte(value)?;
+                    vm.write_memory(addr.wrapping_add(idx as u16), value)?;
+                }
+                Ok(format_memory(vm, addr, args.len() as u16 - 1))
+            }
+            "disas" | "d" => {
+                let count = match args.get(1) {
+                    Some(count) => parse_number(count).ok_or(format!("Wrong count : {}", count))?,
+                    None => 8,
+                };
+                let start = match args.first() {
+                    Some(addr) => self.symbols.parse_address(addr)?,
+                    None => self.start_before(vm, vm.get_pc(), 3),
+                };
+                self.disassemble(vm, start, count as usize)
+            }
+            "bt" => Ok(self.backtrace(vm)),
+            "help" | "h" => Ok(HELP.to_string()),
+            "quit" | "q" => {
+                self.finished = true;
+                Ok(String::new())
+            }
+            command => Err(format!("Unknown command : {}, try help", command)),
+        }
+    }
+
+    /// Location of the next instruction, with its source line when known
+    pub fn location(&self, vm: &Vm) -> String {
+        let pc = vm.get_pc();
+        let mut text = self.describe(vm, pc);
+        if let Ok(instruction) = vm.decode_at(pc) {
+            write!(text, "  {}", instruction).unwrap();
+        }
+        if let Some(info) = self.symbols.line_of(pc) {
+            write!(text, "  ({}:{})", info.file, info.line).unwrap();
+        }
+        text
+    }
+
+    /// Address with its label, and the bank seen there when it is in the window of a mapper
+    pub fn describe(&self, vm: &Vm, addr: u16) -> String {
+        match vm.bank_at(addr) {
+            Some(bank) => format!("{:02x}:{}", bank, self.symbols.describe(addr)),
+            None => self.symbols.describe(addr),
+        }
+    }
+
+    fn describe_breakpoint(&self, vm: &Vm, id: usize) -> String {
+        let Some(breakpoint) = vm.breakpoint(id) else {
+            return String::new();
+        };
+
+        let mut text = match &breakpoint.kind {
+            BreakpointKind::Execute(addr) => format!("break {}", self.symbols.describe(*addr)),
+            BreakpointKind::Watch(range, kind) => {
+                let kind = match kind {
+                    WatchKind::Read => "read",
+                    WatchKind::Write => "write",
+                    WatchKind::Access => "access",
+                };
+                if range.start() == range.end() {
+                    format!("watch {} {}", kind, self.symbols.describe(*range.start()))
+                } else {
+                    format!(
+                        "watch {} {} - {}",
+                        kind,
+                        self.symbols.describe(*range.start()),
+                        self.symbols.describe(*range.end())
+                    )
+                }
+            }
+        };
+        if let Some(condition) = breakpoint.condition.as_ref() {
+            write!(text, " if {}", condition).unwrap();
+        }
+        write!(text, " (hits {})", breakpoint.hits()).unwrap();
+        text
+    }
+
+    fn describe_hit(&self, hit: &BreakHit) -> String {
+        match hit.access {
+            Some(access) => format!(
+                "Watchpoint #{} {} of ${:02x} at {}",
+                hit.id,
+                access.access,
+                access.value,
+                self.symbols.describe(access.addr)
+            ),
+            None => format!("Breakpoint #{}", hit.id),
+        }
+    }
+
+    fn address_arg(&self, args: &[&str]) -> Result<u16, String> {
+        let addr = args.first().ok_or("An address is needed".to_string())?;
+        self.symbols.parse_address(addr)
+    }
+
+    /// Executes instructions until `stop` returns true for the instruction just executed,
+    /// a breakpoint is reached or the machine halts
+    fn run<F>(&self, vm: &mut Vm, mut stop: F) -> Result<String, String>
+    where
+        F: FnMut(&Vm, Instruction) -> bool,
+    {
+        loop {
+            if vm.halt {
+                return Ok(format!("Machine halted at {}", self.location(vm)));
+            }
+
+            let instruction = vm.decode_at(vm.get_pc())?;
+            vm.cycle()?;
+
+            if stop(vm, instruction) {
+                return Ok(self.location(vm));
+            }
+            if let Some(hit) = vm.last_break() {
+                return Ok(format!(
+                    "{}, at {}",
+                    self.describe_hit(hit),
+                    self.location(vm)
+                ));
+            }
+            if let Some(signal) = vm.signal_break() {
+                return Ok(format!(
+                    "Signal ${:02x} break, at {}",
+                    signal,
+                    self.location(vm)
+                ));
+            }
+        }
+    }
+
+    fn step(&self, vm: &mut Vm, count: usize) -> Result<String, String> {
+        let mut remaining = count.max(1);
+        self.run(vm, |_, _| {
+            remaining -= 1;
+            remaining == 0
+        })
+    }
+
+    fn next(&self, vm: &mut Vm) -> Result<String, String> {
+        match StepTarget::next(vm) {
+            Some(target) => self.run(vm, |vm, instruction| target.reached(vm, instruction)),
+            None => self.step(vm, 1),
+        }
+    }
+
+    fn finish(&self, vm: &mut Vm) -> Result<String, String> {
+        let target = StepTarget::finish(vm);
+        self.run(vm, |vm, instruction| target.reached(vm, instruction))
+    }
+
+    fn set(&self, vm: &mut Vm, args: &[&str]) -> Result<String, String> {
+        let (name, value) = match args {
+            [name, value] => (name.to_lowercase(), *value),
+            _ => return Err("Usage : set REG|FLAG VALUE".to_string()),
+        };
+
+        let register = match name.as_str() {
+            "a" | "ac" => Some(Register::AC),
+            "x" => Some(Register::X),
+            "y" => Some(Register::Y),
+            "sp" => Some(Register::SP),
+            "sr" => Some(Register::SR),
+            _ => None,
+        };
+        let flag = match name.as_str() {
+            "n" => Some(RegisterFlag::Negative),
+            "v" => Some(RegisterFlag::Overflow),
+            "b" => Some(RegisterFlag::Break),
+            "d" => Some(RegisterFlag::Decimal),
+            "i" => Some(RegisterFlag::Interrupt),
+            "z" => Some(RegisterFlag::Zero),
+            "c" => Some(RegisterFlag::Carry),
+            _ => None,
+        };
+
+        if let Some(register) = register {
+            vm.set_register(register, parse_byte(value)?);
+        } else if let Some(flag) = flag {
+            vm.set_flag(flag, parse_byte(value)? != 0);
+        } else if name == "pc" {
+            vm.set_pc(self.symbols.parse_address(value)?);
+        } else {
+            return Err(format!("Unknown register or flag : {}", name));
+        }
+
+        Ok(format_registers(vm))
+    }
+
+    /// Where to start disassembling to show up to `count` instructions before `addr`,
+    /// the line information of the assembler is used when there is some
+    pub fn start_before(&self, vm: &Vm, addr: u16, count: usize) -> u16 {
+        if self.symbols.line_of(addr).is_none() {
+            return find_start_before(vm, addr, count);
+        }
+
+        let mut previous: Vec<u16> = self
+            .symbols
+            .lines()
+            .iter()
+            .map(|info| info.addr)
+            .filter(|line_addr| *line_addr < addr && addr - line_addr <= count as u16 * 3)
+            .collect();
+        previous.sort_unstable();
+        previous.dedup();
+
+        previous
+            .iter()
+            .rev()
+            .nth(count.saturating_sub(1))
+            .or(previous.first())
+            .copied()
+            .unwrap_or(addr)
+    }
+
+    fn disassemble(&self, vm: &Vm, start: u16, count: usize) -> Result<String, String> {
+        let mut text = String::new();
+
+        for line in disassemble(vm, start, count) {
+            if let Some(label) = self.symbols.label_at(line.addr) {
+                writeln!(text, "{}:", label).unwrap();
+            }
+
+            let marker = if line.addr == vm.get_pc() { "=>" } else { "  " };
+            writeln!(text, "{} {}", marker, line).unwrap();
+        }
+
+        Ok(text.trim_end().to_string())
+    }
+
+    fn backtrace(&self, vm: &Vm) -> String {
+        let mut text = format!("#0 {}", self.describe(vm, vm.get_pc()));
+        for (frame, call_site) in call_sites(vm).into_iter().enumerate() {
+            write!(
+                text,
+                "\n#{} {} called from {}",
+                frame + 1,
+                self.describe(vm, call_site.wrapping_add(3)),
+                self.describe(vm, call_site)
+            )
+            .unwrap();
+        }
+        text
+    }
+}
+
+/// One decoded instruction, or a byte which is not an instruction
+pub struct DisassemblyLine {
+    pub addr: u16,
+    /// Bank seen at the address, in the window of a mapper
+    pub bank: Option<usize>,
+    pub bytes: Vec<u8>,
+    pub instruction: Option<Instruction>,
+}
+
+impl DisassemblyLine {
+    fn decode(addr: u16, bank: Option<usize>, raw_bytes: &[u8]) -> Self {
+        match Instruction::try_from(raw_bytes) {
+            Ok(instruction) => DisassemblyLine {
+                addr,
+                bank,
+                bytes: instruction.into(),
+                instruction: Some(instruction),
+            },
+            Err(_) => DisassemblyLine {
+                addr,
+                bank,
+                bytes: raw_bytes[..1].to_vec(),
+                instruction: None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for DisassemblyLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(bank) = self.bank {
+            write!(f, "{:02x}:", bank)?;
+        }
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        match self.instruction {
+            Some(instruction) => write!(
+                f,
+                "${:04x}  {:<8}  {}",
+                self.addr,
+                bytes.join(" "),
+                instruction
+            ),
+            None => write!(f, "${:04x}  {:<8}  ???", self.addr, bytes.join(" ")),
+        }
+    }
+}
+
+pub fn disassemble(vm: &Vm, start: u16, count: usize) -> Vec<DisassemblyLine> {
+    let mut lines = Vec::new();
+    let mut addr = start;
+
+    for _ in 0..count {
+        let raw_bytes =
+            [0, 1, 2].map(|idx| vm.read_memory(addr.wrapping_add(idx)).unwrap_or_default());
+        let line = DisassemblyLine::decode(addr, vm.bank_at(addr), &raw_bytes);
+        addr = addr.wrapping_add(line.bytes.len() as u16);
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// Disassembles bytes which are not in memory, like the banks of a ROM image, as if
+/// they were from `base`
+pub fn disassemble_bytes(bytes: &[u8], base: u16, bank: Option<usize>) -> Vec<DisassemblyLine> {
+    let mut lines = Vec::new();
+    let mut idx = 0;
+
+    while idx < bytes.len() {
+        let raw_bytes = &bytes[idx..(idx + 3).min(bytes.len())];
+        let line = DisassemblyLine::decode(base.wrapping_add(idx as u16), bank, raw_bytes);
+        idx += line.bytes.len();
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// Where `next` and `finish` stop, for every debugger
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum StepTarget {
+    /// PC back after a JSR, with the stack pointer of the call
+    Return { addr: u16, sp: u8 },
+    /// A RTS leaving the subroutine which had this stack pointer
+    Caller { sp: u8 },
+}
+
+impl StepTarget {
+    /// Target of `next` at PC, none when it is not a subroutine call
+    pub fn next(vm: &Vm) -> Option<Self> {
+        let pc = vm.get_pc();
+        match vm.decode_at(pc) {
+            Ok(Instruction::JumpSubAbs(_)) => Some(StepTarget::Return {
+                addr: pc.wrapping_add(3),
+                sp: vm.get_register(Register::SP),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Target of `finish` in the current subroutine
+    pub fn finish(vm: &Vm) -> Self {
+        StepTarget::Caller {
+            sp: vm.get_register(Register::SP),
+        }
+    }
+
+    /// True once the instruction just executed reached the target
+    pub fn reached(&self, vm: &Vm, instruction: Instruction) -> bool {
+        let sp = vm.get_register(Register::SP);
+        match *self {
+            StepTarget::Return { addr, sp: call_sp } => vm.get_pc() == addr && sp == call_sp,
+            StepTarget::Caller { sp: frame_sp } => {
+                // The stack may wrap around, the caller is the frame above
+                instruction == Instruction::RetSub && (sp.wrapping_sub(frame_sp) as i8) > 0
+            }
+        }
+    }
+}
+
+/// Walks the stack looking for return addresses pushed by a JSR and returns the address
+/// of the calls, innermost first. Data pushed on the stack can look like a return address
+/// so this is only a best guess
+pub fn call_sites(vm: &Vm) -> Vec<u16> {
+    let mut calls = Vec::new();
+    let mut sp = vm.get_register(Register::SP) as u16 + 1;
+
+    while sp < 0xFF {
+        let low = vm.read_memory(STACK_PAGE + sp).unwrap_or_default();
+        let high = vm.read_memory(STACK_PAGE + sp + 1).unwrap_or_default();
+        let call_site = u16::from_le_bytes([low, high]).wrapping_sub(2);
+
+        if vm.read_memory(call_site) == Some(JSR_OPCODE) {
+            calls.push(call_site);
+            sp += 2;
+        } else {
+            sp += 1;
+        }
+    }
+
+    calls
+}
+
+/// Splits the `if` condition at the end of a command
+fn split_condition(line: &str) -> Result<(Vec<&str>, Option<Condition>), String> {
+    let (command, condition) = match line.split_once(" if ") {
+        Some((command, condition)) => (command, Some(condition.parse::<Condition>()?)),
+        None => (line, None),
+    };
+    Ok((command.split_whitespace().collect(), condition))
+}
+
+fn parse_byte(value: &str) -> Result<u8, String> {
+    parse_number(value)
+        .and_then(|value| u8::try_from(value).ok())
+        .ok_or(format!("Wrong byte value : {}", value))
+}
+
+/// Without line information, the farthest start decoding cleanly into `addr` is taken
+fn find_start_before(vm: &Vm, addr: u16, count: usize) -> u16 {
+    for back in (1..=(count as u16 * 3)).rev() {
+        let start = addr.wrapping_sub(back);
+        let mut current = start;
+        let mut decoded = 0;
+
+        while current != addr && decoded < count {
+            match vm.decode_at(current) {
+                Ok(instruction) if (instruction.size() as u16) <= addr.wrapping_sub(current) => {
+                    current = current.wrapping_add(instruction.size() as u16);
+                    decoded += 1;
+                }
+                _ => break,
+            }
+        }
+
+        if current == addr {
+            return start;
+        }
+    }
+
+    addr
+}
+
+fn format_registers(vm: &Vm) -> String {
+    let flags: String = [
+        (RegisterFlag::Negative, 'n'),
+        (RegisterFlag::Overflow, 'v'),
+        (RegisterFlag::Break, 'b'),
+        (RegisterFlag::Decimal, 'd'),
+        (RegisterFlag::Interrupt, 'i'),
+        (RegisterFlag::Zero, 'z'),
+        (RegisterFlag::Carry, 'c'),
+    ]
+    .iter()
+    .map(|(flag, name)| {
+        if vm.get_flag(*flag) {
+            name.to_ascii_uppercase()
+        } else {
+            *name
+        }
+    })
+    .collect();
+
+    format!(
+        "A ${:02x}  X ${:02x}  Y ${:02x}  SP ${:02x}  PC ${:04x}  SR ${:02x} [{}]  cycles {}",
+        vm.get_register(Register::AC),
+        vm.get_register(Register::X),
+        vm.get_register(Register::Y),
+        vm.get_register(Register::SP),
+        vm.get_pc(),
+        vm.get_register(Register::SR),
+        flags,
+        vm.cycle_count()
+    )
+}
+
+fn format_memory(vm: &Vm, addr: u16, len: u16) -> String {
+    let mut text = String::new();
+    let mut line_start = addr;
+    let end = addr as u32 + len as u32;
+
+    while (line_start as u32) < end {
+        let line_len = (end - line_start as u32).min(16) as u16;
+        let bytes: Vec<String> = (0..line_len)
+            .map(|idx| {
+                format!(
+                    "{:02x}",
+                    vm.read_memory(line_start.wrapping_add(idx))
+                        .unwrap_or_default()
+                )
+            })
+            .collect();
+        writeln!(text, "${:04x}  {}", line_start, bytes.join(" ")).unwrap();
+
+        match line_start.checked_add(16) {
+            Some(next) => line_start = next,
+            None => break,
+        }
+    }
+
+    text.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::assemble,
+        mapper::{Banked, Fixed16K},
+    };
+
+    const SOURCE: &str = "\
+start:
+    LoadXImm 0
+loop:
+    JumpSubAbs double
+    IncX
+    CmpXImm 3
+    BranchNotZero loop
+    Jam
+double:
+    TransXAC
+    AddImm 0
+    StoreACZp $80
+    RetSub
+";
+
+    fn new_debugger() -> (Debugger, Vm) {
+        let program = assemble(SOURCE, "double.asm").unwrap();
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &program.bytes);
+        vm.set_register(Register::SP, 0xFF);
+        (Debugger::new(program.symbols), vm)
+    }
+
+    #[test]
+    fn test_debugger_stepping() {
+        let (mut debugger, mut vm) = new_debugger();
+
+        debugger.execute(&mut vm, "step 2").unwrap();
+        assert_eq!(vm.get_pc(), debugger.symbols.address_of("double").unwrap());
+
+        let bt = debugger.execute(&mut vm, "bt").unwrap();
+        assert_eq!(
+            bt,
+            "#0 $000b <double>\n#1 $0005 <loop+3> called from $0002 <loop>"
+        );
+
+        debugger.execute(&mut vm, "finish").unwrap();
+        assert_eq!(vm.get_pc(), 0x0005);
+        assert_eq!(vm.get_register(Register::SP), 0xFF);
+
+        debugger.execute(&mut vm, "n").unwrap();
+        debugger.execute(&mut vm, "").unwrap();
+        debugger.execute(&mut vm, "").unwrap();
+        assert_eq!(vm.get_pc(), 0x0002);
+
+        // Stepping over the subroutine
+        let location = debugger.execute(&mut vm, "").unwrap();
+        assert_eq!(location, "$0005 <loop+3>  IncX  (double.asm:5)");
+        assert_eq!(vm.read_memory(0x80), Some(1));
+
+        debugger.execute(&mut vm, "c").unwrap();
+        assert!(vm.halt);
+        assert_eq!(vm.read_memory(0x80), Some(2));
+        assert!(debugger
+            .execute(&mut vm, "s")
+            .unwrap()
+            .starts_with("Machine halted"));
+    }
+
+    #[test]
+    fn test_debugger_stack_wrap() {
+        // Without a machine file SP starts at $00, the JSR wraps it to $fe
+        let (mut debugger, mut vm) = new_debugger();
+        vm.set_register(Register::SP, 0x00);
+
+        debugger.execute(&mut vm, "step 2").unwrap();
+        assert_eq!(vm.get_register(Register::SP), 0xFE);
+        debugger.execute(&mut vm, "finish").unwrap();
+        assert_eq!(vm.get_pc(), 0x0005);
+        assert_eq!(vm.get_register(Register::SP), 0x00);
+
+        debugger.execute(&mut vm, "step 3").unwrap();
+        debugger.execute(&mut vm, "next").unwrap();
+        assert_eq!(vm.get_pc(), 0x0005);
+        assert_eq!(vm.read_memory(0x80), Some(1));
+    }
+
+    #[test]
+    fn test_debugger_breakpoints() {
+        let (mut debugger, mut vm) = new_debugger();
+
+        debugger.execute(&mut vm, "b double").unwrap();
+        assert_eq!(
+            debugger.execute(&mut vm, "breaks").unwrap(),
+            "#1 break $000b <double> (hits 0)"
+        );
+
+        for expected in [0, 1, 2] {
+            let location = debugger.execute(&mut vm, "c").unwrap();
+            assert!(location.starts_with("Breakpoint #1, at $000b <double>"));
+            assert_eq!(vm.get_register(Register::X), expected);
+        }
+
+        // The first step never stops on the breakpoint of the current instruction
+        debugger.execute(&mut vm, "s").unwrap();
+        assert_eq!(vm.get_pc(), 0x000c);
+
+        debugger.execute(&mut vm, "delete 1").unwrap();
+        assert!(debugger.execute(&mut vm, "delete 1").is_err());
+        debugger.execute(&mut vm, "c").unwrap();
+        assert!(vm.halt);
+    }
+
+    #[test]
+    fn test_debugger_conditions_and_watchpoints() {
+        let (mut debugger, mut vm) = new_debugger();
+
+        debugger.execute(&mut vm, "b loop if X == 2").unwrap();
+        debugger
+            .execute(&mut vm, "watch $80 write if A != 0")
+            .unwrap();
+        assert_eq!(
+            debugger.execute(&mut vm, "breaks").unwrap(),
+            "#1 break $0002 <loop> if X == $02 (hits 0)\n#2 watch write $0080 <double+117> if A != $00 (hits 0)"
+        );
+
+        let location = debugger.execute(&mut vm, "c").unwrap();
+        assert!(location.starts_with("Watchpoint #2 write of $01 at $0080 <double+117>, at $0010"));
+
+        let location = debugger.execute(&mut vm, "c").unwrap();
+        assert!(location.starts_with("Breakpoint #1, at $0002 <loop>"));
+        assert_eq!(vm.get_register(Register::X), 2);
+
+        assert!(debugger.execute(&mut vm, "b loop if X ==").is_err());
+        assert!(debugger.execute(&mut vm, "watch $80 sometimes").is_err());
+    }
+
+    #[test]
+    fn test_debugger_inspect() {
+        let (mut debugger, mut vm) = new_debugger();
+
+        debugger.execute(&mut vm, "set x $42").unwrap();
+        debugger.execute(&mut vm, "set c 1").unwrap();
+        debugger.execute(&mut vm, "set pc loop").unwrap();
+        assert_eq!(
+            debugger.execute(&mut vm, "r").unwrap(),
+            "A $00  X $42  Y $00  SP $ff  PC $0002  SR $01 [nvbdizC]  cycles 0"
+        );
+        assert!(debugger.execute(&mut vm, "set x 300").is_err());
+        assert!(debugger.execute(&mut vm, "set q 1").is_err());
+
+        debugger.execute(&mut vm, "poke $80 1 2 $ff").unwrap();
+        assert_eq!(
+            debugger.execute(&mut vm, "x $80 4").unwrap(),
+            "$0080  01 02 ff 00"
+        );
+
+        assert_eq!(
+            debugger.execute(&mut vm, "d").unwrap(),
+            "start:\n   $0000  a2 00     LoadXImm $00\n\
+             loop:\n=> $0002  20 0b 00  JumpSubAbs $000b\n   $0005  e8        IncX\n   \
+             $0006  e0 03     CmpXImm $03\n   $0008  d0 f8     BranchNotZero $f8\n   \
+             $000a  f2        Jam\n\
+             double:\n   $000b  8a        TransXAC\n   $000c  69 00     AddImm $00"
+        );
+
+        assert_eq!(find_start_before(&vm, 0x0005, 2), 0x0000);
+
+        assert!(debugger.execute(&mut vm, "nothing").is_err());
+        debugger.execute(&mut vm, "q").unwrap();
+        assert!(debugger.is_finished());
+    }
+
+    #[test]
+    fn test_debugger_banks() {
+        let program = assemble(
+            "
+    LoadACImm 0
+    StoreACAbs $8000
+    JumpSubAbs $8000
+    LoadACImm 1
+    StoreACAbs $8000
+    JumpSubAbs $8000
+    Jam
+",
+            "banks.asm",
+        )
+        .unwrap();
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &program.bytes);
+        vm.set_register(Register::SP, 0xFF);
+        // RTS at the start of every bank
+        let mut image = vec![0xEA; 4 * 0x4000];
+        for bank in 0..4 {
+            image[bank * 0x4000] = 0x60;
+        }
+        Banked::rom(image, Box::new(Fixed16K::new()))
+            .unwrap()
+            .map(&mut vm, 0x8000, None)
+            .unwrap();
+        let mut debugger = Debugger::new(SymbolTable::default());
+
+        debugger.execute(&mut vm, "b 01:$8000").unwrap();
+        assert_eq!(
+            debugger.execute(&mut vm, "breaks").unwrap(),
+            "#1 break $8000 if BANK == $01 (hits 0)"
+        );
+        assert_eq!(
+            debugger.execute(&mut vm, "c").unwrap(),
+            "Breakpoint #1, at 01:$8000  RetSub"
+        );
+        assert_eq!(vm.get_register(Register::AC), 1);
+        assert_eq!(
+            debugger.execute(&mut vm, "d $8000 1").unwrap(),
+            "=> 01:$8000  60        RetSub"
+        );
+        assert_eq!(
+            debugger.execute(&mut vm, "bt").unwrap(),
+            "#0 01:$8000\n#1 $0010 called from $000d"
+        );
+        assert!(debugger.execute(&mut vm, "b zz:$8000").is_err());
+    }
+}
@@ -0,0 +1,604 @@
+//! Stub of the GDB remote serial protocol, to drive the Vm from a GDB compatible front-end.
+//!
+//! The registers are sent in the order of the target description : `a`, `x`, `y`, `sp`
+//! and `sr` on 8 bits, then `pc` on 16 bits. Software breakpoints (`Z0`) and watchpoints
+//! (`Z2` to `Z4`) use the breakpoints of the Vm.
+
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+};
+
+use crate::{
+    breakpoints::{Breakpoint, BreakpointId, StopReason, WatchKind},
+    isa::Register,
+    Vm,
+};
+
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rustemu.m6502.core">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="sr" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTERS: [Register; 5] = [
+    Register::AC,
+    Register::X,
+    Register::Y,
+    Register::SP,
+    Register::SR,
+];
+const PC_REGNUM: usize = 5;
+
+/// Number of cycles run between two checks of an interrupt from the client
+const INTERRUPT_CHECK_CYCLES: u64 = 10_000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// Stream to the client
+pub trait Connection: Read + Write {
+    /// Checks without blocking if the client asked to stop the running program
+    fn interrupted(&mut self) -> bool;
+}
+
+impl Connection for TcpStream {
+    fn interrupted(&mut self) -> bool {
+        let mut byte = [0u8];
+        if self.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let peeked = self.peek(&mut byte);
+        let _ = self.set_nonblocking(false);
+
+        if matches!(peeked, Ok(1)) && byte[0] == 0x03 {
+            return self.read_exact(&mut byte).is_ok();
+        }
+        false
+    }
+}
+
+enum Packet {
+    Command(String),
+    Interrupt,
+    Closed,
+}
+
+pub struct GdbStub {
+    breakpoints: HashMap<(char, u16), BreakpointId>,
+    no_ack: bool,
+}
+
+impl GdbStub {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: HashMap::new(),
+            no_ack: false,
+        }
+    }
+
+    /// Serves the client until it detaches, kills the program or closes the connection
+    pub fn serve<C: Connection>(&mut self, vm: &mut Vm, conn: &mut C) -> Result<(), String> {
+        loop {
+            let command = match read_packet(conn, self.no_ack)? {
+                Packet::Command(command) => command,
+                Packet::Interrupt => {
+                    send_packet(conn, &format!("S{:02x}", SIGINT))?;
+                    continue;
+                }
+                Packet::Closed => break,
+            };
+
+            // A kill has no reply
+            if command == "k" {
+                break;
+            }
+
+            let reply = self
+                .handle(vm, conn, &command)
+                .unwrap_or_else(|_| "E01".to_string());
+            send_packet(conn, &reply)?;
+
+            if command == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+            if command == "D" {
+                break;
+            }
+        }
+
+        for (_, id) in self.breakpoints.drain() {
+            vm.remove_breakpoint(id);
+        }
+        Ok(())
+    }
+
+    fn handle<C: Connection>(
+        &mut self,
+        vm: &mut Vm,
+        conn: &mut C,
+        command: &str,
+    ) -> Result<String, String> {
+        let reply = match command.as_bytes().first() {
+            Some(b'?') => format!("S{:02x}", SIGTRAP),
+            Some(b'g') => {
+                let mut reply: String = REGISTERS
+                    .iter()
+                    .map(|register| format!("{:02x}", vm.get_register(*register)))
+                    .collect();
+                reply.push_str(&to_hex(&vm.get_pc().to_le_bytes()));
+                reply
+            }
+            Some(b'G') => {
+                let bytes = from_hex(&command[1..])?;
+                if bytes.len() != REGISTERS.len() + 2 {
+                    return Ok("E01".to_string());
+                }
+                for (register, value) in REGISTERS.iter().zip(bytes.iter()) {
+                    vm.set_register(*register, *value);
+                }
+                vm.set_pc(u16::from_le_bytes([bytes[5], bytes[6]]));
+                "OK".to_string()
+            }
+            Some(b'p') => match usize::from_str_radix(&command[1..], 16) {
+                Ok(PC_REGNUM) => to_hex(&vm.get_pc().to_le_bytes()),
+                Ok(regnum) if regnum < REGISTERS.len() => {
+                    format!("{:02x}", vm.get_register(REGISTERS[regnum]))
+                }
+                _ => "E01".to_string(),
+            },
+            Some(b'P') => {
+                let Some((regnum, value)) = command[1..].split_once('=') else {
+                    return Ok("E01".to_string());
+                };
+                let regnum = usize::from_str_radix(regnum, 16).map_err(|err| err.to_string())?;
+                let bytes = from_hex(value)?;
+                match (regnum, bytes.as_slice()) {
+                    (PC_REGNUM, [low, high]) => vm.set_pc(u16::from_le_bytes([*low, *high])),
+                    (regnum, [value]) if regnum < REGISTERS.len() => {
+                        vm.set_register(REGISTERS[regnum], *value)
+                    }
+                    _ => return Ok("E01".to_string()),
+                }
+                "OK".to_string()
+            }
+            Some(b'm') => {
+                let (addr, len) = parse_addr_len(&command[1..])?;
+                // The length comes from the client, there is no more than the address space
+                if len > 0x10000 {
+                    return Ok("E01".to_string());
+                }
+                let bytes: Vec<u8> = (0..len)
+                    .map(|idx| {
+                        vm.read_memory(addr.wrapping_add(idx as u16))
+                            .unwrap_or_default()
+                    })
+                    .collect();
+                to_hex(&bytes)
+            }
+            Some(b'M') => {
+                let Some((addr_len, data)) = command[1..].split_once(':') else {
+                    return Ok("E01".to_string());
+                };
+                let (addr, len) = parse_addr_len(addr_len)?;
+                let bytes = from_hex(data)?;
+                if bytes.len() != len {
+                    return Ok("E01".to_string());
+                }
+                for (idx, value) in bytes.iter().enumerate() {
+                    vm.write_memory(addr.wrapping_add(idx as u16), *value)?;
+                }
+                "OK".to_string()
+            }
+            Some(b's') => {
+                if vm.halt {
+                    return Ok("W00".to_string());
+                }
+                match vm.cycle() {
+                    Ok(()) => self.stop_reply(vm),
+                    Err(_) => format!("S{:02x}", SIGILL),
+                }
+            }
+            Some(b'c') => self.resume(vm, conn),
+            Some(b'Z') | Some(b'z') => self.update_breakpoint(vm, command),
+            Some(b'D') => "OK".to_string(),
+            Some(b'H') => "OK".to_string(),
+            Some(b'T') => "OK".to_string(),
+            _ => self.handle_query(command),
+        };
+
+        Ok(reply)
+    }
+
+    fn handle_query(&self, command: &str) -> String {
+        if command.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+".to_string();
+        }
+        if let Some(args) = command.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_addr_len(args) {
+                Ok((offset, len)) => {
+                    let data = TARGET_XML.as_bytes();
+                    let start = (offset as usize).min(data.len());
+                    let end = (start + len).min(data.len());
+                    let prefix = if end == data.len() { 'l' } else { 'm' };
+                    format!("{}{}", prefix, escape(&data[start..end]))
+                }
+                Err(_) => "E01".to_string(),
+            };
+        }
+
+        match command {
+            "QStartNoAckMode" => "OK",
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        }
+        .to_string()
+    }
+
+    fn resume<C: Connection>(&mut self, vm: &mut Vm, conn: &mut C) -> String {
+        loop {
+            let limit = vm.cycle_count() + INTERRUPT_CHECK_CYCLES;
+            match vm.run_until(limit) {
+                Ok(StopReason::CycleLimit) => {
+                    if conn.interrupted() {
+                        return format!("S{:02x}", SIGINT);
+                    }
+                }
+                Ok(_) => return self.stop_reply(vm),
+                Err(_) => return format!("S{:02x}", SIGILL),
+            }
+        }
+    }
+
+    fn stop_reply(&self, vm: &Vm) -> String {
+        if vm.halt {
+            return "W00".to_string();
+        }
+
+        let Some(hit) = vm.last_break() else {
+            return format!("S{:02x}", SIGTRAP);
+        };
+        let kind = self
+            .breakpoints
+            .iter()
+            .find(|(_, id)| **id == hit.id)
+            .map(|((kind, _), _)| *kind);
+        match (kind, hit.access) {
+            (Some('2'), Some(access)) => format!("T{:02x}watch:{:04x};", SIGTRAP, access.addr),
+            (Some('3'), Some(access)) => format!("T{:02x}rwatch:{:04x};", SIGTRAP, access.addr),
+            (Some('4'), Some(access)) => format!("T{:02x}awatch:{:04x};", SIGTRAP, access.addr),
+            _ => format!("T{:02x}swbreak:;", SIGTRAP),
+        }
+    }
+
+    fn update_breakpoint(&mut self, vm: &mut Vm, command: &str) -> String {
+        let insert = command.starts_with('Z');
+        let mut fields = command[1..].split(',');
+        let (Some(kind), Some(addr), Some(len)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return "E01".to_string();
+        };
+        let (Ok(addr), Ok(len)) = (u16::from_str_radix(addr, 16), u16::from_str_radix(len, 16))
+        else {
+            return "E01".to_string();
+        };
+        let kind = kind.chars().next().unwrap_or_default();
+        let end = addr.saturating_add(len.max(1) - 1);
+
+        let breakpoint = match kind {
+            '0' | '1' => Breakpoint::execute(addr),
+            '2' => Breakpoint::watch(addr..=end, WatchKind::Write),
+            '3' => Breakpoint::watch(addr..=end, WatchKind::Read),
+            '4' => Breakpoint::watch(addr..=end, WatchKind::Access),
+            _ => return String::new(),
+        };
+
+        if insert {
+            self.breakpoints
+                .entry((kind, addr))
+                .or_insert_with(|| vm.add_breakpoint(breakpoint));
+        } else if let Some(id) = self.breakpoints.remove(&(kind, addr)) {
+            vm.remove_breakpoint(id);
+        }
+        "OK".to_string()
+    }
+}
+
+impl Default for GdbStub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn io_error(err: std::io::Error) -> String {
+    format!("GDB connection error : {}", err)
+}
+
+fn read_byte<C: Connection>(conn: &mut C) -> Result<Option<u8>, String> {
+    let mut byte = [0u8];
+    loop {
+        match conn.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(io_error(err)),
+        }
+    }
+}
+
+fn read_packet<C: Connection>(conn: &mut C, no_ack: bool) -> Result<Packet, String> {
+    loop {
+        match read_byte(conn)? {
+            None => return Ok(Packet::Closed),
+            Some(0x03) => return Ok(Packet::Interrupt),
+            Some(b'$') => {}
+            // Acknowledgments and noise between packets
+            Some(_) => continue,
+        }
+
+        let mut data = Vec::new();
+        loop {
+            match read_byte(conn)? {
+                None => return Ok(Packet::Closed),
+                Some(b'#') => break,
+                Some(byte) => data.push(byte),
+            }
+        }
+
+        let mut checksum = [0u8; 2];
+        for digit in checksum.iter_mut() {
+            *digit = read_byte(conn)?.unwrap_or_default();
+        }
+        let valid = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+            == Some(compute_checksum(&data));
+
+        if !no_ack {
+            conn.write_all(if valid { b"+" } else { b"-" })
+                .map_err(io_error)?;
+        }
+        if valid || no_ack {
+            return Ok(Packet::Command(String::from_utf8_lossy(&data).to_string()));
+        }
+    }
+}
+
+fn send_packet<C: Connection>(conn: &mut C, data: &str) -> Result<(), String> {
+    let packet = format!("${}#{:02x}", data, compute_checksum(data.as_bytes()));
+    conn.write_all(packet.as_bytes()).map_err(io_error)?;
+    conn.flush().map_err(io_error)
+}
+
+fn compute_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn escape(data: &[u8]) -> String {
+    let mut escaped = String::new();
+    for byte in data {
+        match byte {
+            b'#' | b'$' | b'}' | b'*' => {
+                escaped.push('}');
+                escaped.push((byte ^ 0x20) as char);
+            }
+            _ => escaped.push(*byte as char),
+        }
+    }
+    escaped
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(value: &str) -> Result<Vec<u8>, String> {
+    // The packets may hold any byte, the digit pairs must not split a character
+    if !value.is_ascii() || !value.len().is_multiple_of(2) {
+        return Err(format!("Wrong hexadecimal data : {}", value));
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|idx| {
+            u8::from_str_radix(&value[idx..idx + 2], 16)
+                .map_err(|_| format!("Wrong hexadecimal data : {}", value))
+        })
+        .collect()
+}
+
+fn parse_addr_len(value: &str) -> Result<(u16, usize), String> {
+    let (addr, len) = value
+        .split_once(',')
+        .ok_or(format!("Wrong address and length : {}", value))?;
+    let addr = u32::from_str_radix(addr, 16).map_err(|err| err.to_string())?;
+    let len = usize::from_str_radix(len, 16).map_err(|err| err.to_string())?;
+    Ok((addr as u16, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    struct ScriptedClient {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for ScriptedClient {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for ScriptedClient {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for ScriptedClient {
+        fn interrupted(&mut self) -> bool {
+            false
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, compute_checksum(data.as_bytes()))
+    }
+
+    /// Sends the commands and returns the replies, without the acknowledgments
+    fn run_script(vm: &mut Vm, commands: &[&str]) -> Vec<String> {
+        let mut input = packet("QStartNoAckMode");
+        input.push('+');
+        for command in commands {
+            input.push_str(&packet(command));
+        }
+
+        let mut client = ScriptedClient {
+            input: Cursor::new(input.into_bytes()),
+            output: Vec::new(),
+        };
+        GdbStub::new().serve(vm, &mut client).unwrap();
+
+        let output = String::from_utf8(client.output).unwrap();
+        let replies: Vec<String> = output
+            .trim_start_matches('+')
+            .split('$')
+            .skip(1)
+            .map(|reply| {
+                let (data, checksum) = reply.split_once('#').unwrap();
+                assert_eq!(
+                    u8::from_str_radix(checksum, 16).unwrap(),
+                    compute_checksum(data.as_bytes())
+                );
+                data.to_string()
+            })
+            .collect();
+
+        assert_eq!(replies[0], "OK");
+        replies[1..].to_vec()
+    }
+
+    // LDX #0 / loop: INX / TXA / STA $10,X / CPX #5 / BNE loop / JAM
+    const PROG: [u8; 11] = [
+        0xA2, 0x00, 0xE8, 0x8A, 0x95, 0x10, 0xE0, 0x05, 0xD0, 0xF8, 0xF2,
+    ];
+
+    #[test]
+    fn test_gdb_registers_and_memory() {
+        let mut vm = Vm::new();
+        let replies = run_script(
+            &mut vm,
+            &[
+                "qSupported:multiprocess+;swbreak+",
+                "qXfer:features:read:target.xml:0,15",
+                "G0102031f2000c0",
+                "g",
+                "P5=3412",
+                "P1=aa",
+                "p5",
+                "p1",
+                "p9",
+                "M0200,3:a9428d",
+                "m01ff,4",
+                "vMustReplyEmpty",
+                "D",
+            ],
+        );
+
+        assert_eq!(
+            replies,
+            vec![
+                "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+",
+                "m<?xml version=\"1.0\"?>",
+                "OK",
+                "0102031f2000c0",
+                "OK",
+                "OK",
+                "3412",
+                "aa",
+                "E01",
+                "OK",
+                "00a9428d",
+                "",
+                "OK",
+            ]
+        );
+        assert_eq!(vm.get_pc(), 0x1234);
+        assert_eq!(vm.get_register(Register::SP), 0x1F);
+        assert_eq!(vm.read_memory(0x0202), Some(0x8D));
+    }
+
+    #[test]
+    fn test_gdb_execution() {
+        let mut vm = Vm::new();
+        vm.copy_memory(0x0200, &PROG);
+        vm.set_pc(0x0200);
+
+        let replies = run_script(
+            &mut vm,
+            &[
+                "s", "Z0,202,1", "c", "c", "p1", "z0,202,1", "Z2,14,1", "c", "z2,14,1", "c", "k",
+            ],
+        );
+
+        assert_eq!(
+            replies,
+            vec![
+                "S05",
+                "OK",
+                "T05swbreak:;",
+                "T05swbreak:;",
+                "02",
+                "OK",
+                "OK",
+                "T05watch:0014;",
+                "OK",
+                "W00",
+            ]
+        );
+        assert!(vm.breakpoints().is_empty());
+    }
+
+    #[test]
+    fn test_gdb_framing() {
+        assert_eq!(escape(b"a#b}"), "a}\x03b}]");
+        assert_eq!(from_hex("0aff").unwrap(), vec![0x0A, 0xFF]);
+        assert!(from_hex("0af").is_err());
+        assert!(from_hex("0\u{fffd}").is_err());
+        assert_eq!(
+            run_script(
+                &mut Vm::new(),
+                &["m12,zz", "m0,ffffffff", "P1=0\u{fffd}", "D"]
+            ),
+            vec!["E01", "E01", "E01", "OK"]
+        );
+
+        // A packet with a wrong checksum is refused and sent again
+        let mut client = ScriptedClient {
+            input: Cursor::new(format!("$g#00{}", packet("k")).into_bytes()),
+            output: Vec::new(),
+        };
+        GdbStub::new().serve(&mut Vm::new(), &mut client).unwrap();
+        assert_eq!(String::from_utf8(client.output).unwrap(), "-+");
+    }
+}
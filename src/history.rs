@@ -0,0 +1,237 @@
+use std::{collections::VecDeque, mem::size_of};
+
+/// What is needed to undo one instruction
+pub struct UndoRecord {
+    pub registers: [u8; 8],
+    pub cycles: u64,
+    pub halt: bool,
+    pub nmi_pending: bool,
+    pub nmi_line: bool,
+    pub data_bus: u8,
+    /// Overwritten memory bytes, in the order of the writes
+    pub memory: Vec<(u16, u8)>,
+    /// State of the devices before the first write to one of them, see `Bus::save_devices`
+    pub devices: Option<Vec<u8>>,
+}
+
+impl UndoRecord {
+    fn size(&self) -> usize {
+        size_of::<UndoRecord>()
+            + self.memory.capacity() * size_of::<(u16, u8)>()
+            + self
+                .devices
+                .as_ref()
+                .map_or(0, |devices| devices.capacity())
+    }
+}
+
+/// Undo records of the last instructions, bounded by an approximate memory budget in bytes
+pub struct History {
+    records: VecDeque<UndoRecord>,
+    memory_budget: usize,
+    used: usize,
+}
+
+impl History {
+    pub fn new(memory_budget: usize) -> Self {
+        Self {
+            records: VecDeque::new(),
+            memory_budget,
+            used: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn memory_budget(&self) -> usize {
+        self.memory_budget
+    }
+
+    /// Starts the record of a new instruction, with the CPU state before it
+    /// and no memory write yet
+    pub fn begin(&mut self, record: UndoRecord) {
+        self.used += record.size();
+        self.records.push_back(record);
+        self.trim();
+    }
+
+    /// Keeps the previous value of a memory byte written by the current instruction
+    pub fn record_write(&mut self, addr: u16, old_value: u8) {
+        if let Some(record) = self.records.back_mut() {
+            self.used -= record.size();
+            record.memory.push((addr, old_value));
+            self.used += record.size();
+            self.trim();
+        }
+    }
+
+    /// Keeps the state of the devices when the current instruction writes to them,
+    /// only the state before the first write is needed
+    pub fn record_devices<F>(&mut self, save: F)
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        if let Some(record) = self
+            .records
+            .back_mut()
+            .filter(|record| record.devices.is_none())
+        {
+            self.used -= record.size();
+            record.devices = Some(save());
+            self.used += record.size();
+            self.trim();
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<UndoRecord> {
+        let record = self.records.pop_back()?;
+        self.used -= record.size();
+        Some(record)
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.used = 0;
+    }
+
+    // Forget the oldest instructions, the current one is always kept
+    fn trim(&mut self) {
+        while self.used > self.memory_budget && self.records.len() > 1 {
+            let record = self.records.pop_front().unwrap();
+            self.used -= record.size();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        asm::assemble,
+        isa::Register,
+        mapper::{Banked, RamBanks},
+        screen::TextScreen,
+        Vm,
+    };
+
+    // LDX #0 / loop: INX / TXA / STA $10,X / CPX #5 / BNE loop / JAM
+    const PROG: [u8; 11] = [
+        0xA2, 0x00, 0xE8, 0x8A, 0x95, 0x10, 0xE0, 0x05, 0xD0, 0xF8, 0xF2,
+    ];
+
+    fn new_vm() -> Vm {
+        let mut vm = Vm::new();
+        vm.copy_memory(0x0200, &PROG);
+        vm.set_pc(0x0200);
+        vm
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut vm = new_vm();
+        vm.enable_history(1024 * 1024);
+
+        let mut states = vec![vm.save_state()];
+        while !vm.halt {
+            vm.cycle().unwrap();
+            states.push(vm.save_state());
+        }
+        assert_eq!(vm.read_memory(0x0015), Some(0x05));
+        assert_eq!(vm.history_len(), states.len() - 1);
+
+        assert_eq!(vm.step_back(1), 1);
+        assert_eq!(vm.save_state(), states[states.len() - 2]);
+        assert!(!vm.halt);
+
+        assert_eq!(vm.step_back(4), 4);
+        assert_eq!(vm.save_state(), states[states.len() - 6]);
+
+        assert_eq!(vm.step_back(1000), states.len() - 6);
+        assert_eq!(vm.save_state(), states[0]);
+        assert_eq!(vm.step_back(1), 0);
+    }
+
+    #[test]
+    fn test_step_back_devices() {
+        // Banked RAM at $6000 selected at $5fff, screen at $0400
+        let source = "
+    LoadACImm $11
+    StoreACAbs $6000
+    LoadACImm 2
+    StoreACAbs $5fff
+    LoadACImm $22
+    StoreACAbs $6000
+    StoreACAbs $0400
+    Jam
+";
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &assemble(source, "devices.asm").unwrap().bytes);
+        Banked::ram(4, Box::new(RamBanks::new(0x1000)))
+            .unwrap()
+            .map(&mut vm, 0x6000, Some(0x5FFF))
+            .unwrap();
+        TextScreen::new().map(&mut vm, 0x0400).unwrap();
+        vm.enable_history(1024 * 1024);
+
+        let mut states = vec![vm.save_state()];
+        while !vm.halt {
+            vm.cycle().unwrap();
+            states.push(vm.save_state());
+        }
+        assert_eq!(vm.bank_at(0x6000), Some(2));
+        assert_eq!(vm.read_memory(0x0400), Some(0x22));
+
+        for state in states.iter().rev().skip(1) {
+            assert_eq!(vm.step_back(1), 1);
+            assert_eq!(&vm.save_state(), state);
+        }
+        assert_eq!(vm.bank_at(0x6000), Some(0));
+        assert_eq!(vm.read_memory(0x6000), Some(0x00));
+        assert_eq!(vm.read_memory(0x0400), Some(b' '));
+
+        // Devices mapped again since the writes can not be restored
+        for _ in 0..4 {
+            vm.cycle().unwrap();
+        }
+        vm.unmap_device(0x0400);
+        assert_eq!(vm.step_back(4), 0);
+        assert_eq!(vm.history_len(), 0);
+    }
+
+    #[test]
+    fn test_run_back_to() {
+        let mut vm = new_vm();
+        vm.enable_history(1024 * 1024);
+        while !vm.halt {
+            vm.cycle().unwrap();
+        }
+
+        assert!(vm.run_back_to(|vm| vm.get_register(Register::X) == 2));
+        assert_eq!(vm.get_register(Register::X), 2);
+        assert_eq!(vm.read_memory(0x0013), Some(0x00));
+
+        assert!(!vm.run_back_to(|vm| vm.get_register(Register::X) == 0x42));
+        assert_eq!(vm.get_pc(), 0x0200);
+    }
+
+    #[test]
+    fn test_history_budget() {
+        let mut vm = new_vm();
+        vm.enable_history(200);
+        while !vm.halt {
+            vm.cycle().unwrap();
+        }
+
+        let kept = vm.history_len();
+        assert!(kept > 0 && kept < 27);
+        assert_eq!(vm.step_back(100), kept);
+
+        vm.disable_history();
+        assert_eq!(vm.history_len(), 0);
+    }
+}
@@ -0,0 +1,500 @@
+//! Standard host calls on top of the `EmuSignal` instruction.
+//!
+//! | Signal | Name        | Call                                                                  |
+//! |--------|-------------|-----------------------------------------------------------------------|
+//! | `$00`  | EXIT        | Halts the machine with the exit code in A                             |
+//! | `$01`  | PUTCHAR     | Prints the character in A                                             |
+//! | `$02`  | PUTS        | Prints the zero terminated string at A (low) X (high)                 |
+//! | `$03`  | GETCHAR     | Reads a character in A, carry set and A = 0 at the end of the input   |
+//! | `$04`  | CYCLES      | Writes the cycle count at A/X, 8 bytes little endian                  |
+//! | `$05`  | TIME        | Writes the host time at A/X, milliseconds since 1970, 8 bytes         |
+//! | `$06`  | OPEN        | Opens the file named at A/X in the sandbox with the mode in Y,        |
+//! |        |             | the handle is returned in A                                           |
+//! | `$07`  | READ        | Reads from the handle in Y, A/X points to the buffer address and the  |
+//! |        |             | length (2 bytes each), the length is replaced by the bytes read       |
+//! | `$08`  | WRITE       | Writes to the handle in Y, with the same parameters as READ           |
+//! | `$09`  | CLOSE       | Closes the handle in Y                                                |
+//!
+//! The modes of OPEN are 0 to read, 1 to create or truncate and write, 2 to append.
+//! The file calls clear the carry on success, or set it with an error code in A.
+//! File names are relative to the sandbox directory and can not leave it.
+//!
+//! Every value coming from the host goes through `Vm::host_input` on the channel of its
+//! signal, so the runs using these calls can be recorded and replayed. The constants are
+//! available for the assembler in `prog/host.inc`.
+
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    isa::{Register, RegisterFlag},
+    signals::SignalAction,
+    Vm,
+};
+
+pub const SIG_EXIT: u8 = 0x00;
+pub const SIG_PUTCHAR: u8 = 0x01;
+pub const SIG_PUTS: u8 = 0x02;
+pub const SIG_GETCHAR: u8 = 0x03;
+pub const SIG_CYCLES: u8 = 0x04;
+pub const SIG_TIME: u8 = 0x05;
+pub const SIG_OPEN: u8 = 0x06;
+pub const SIG_READ: u8 = 0x07;
+pub const SIG_WRITE: u8 = 0x08;
+pub const SIG_CLOSE: u8 = 0x09;
+
+pub const MODE_READ: u8 = 0;
+pub const MODE_WRITE: u8 = 1;
+pub const MODE_APPEND: u8 = 2;
+
+pub const ERR_NO_SANDBOX: u8 = 1;
+pub const ERR_NOT_FOUND: u8 = 2;
+pub const ERR_DENIED: u8 = 3;
+pub const ERR_BAD_HANDLE: u8 = 4;
+pub const ERR_IO: u8 = 5;
+pub const ERR_TOO_MANY_FILES: u8 = 6;
+
+const MAX_FILES: usize = 16;
+// Results of the file calls, the handle or the error code with this bit
+const ERROR_BIT: u8 = 0x80;
+
+/// Host side of the calls : the console, the sandbox and the open files
+pub struct HostAbi {
+    input: Box<dyn Read>,
+    output: Box<dyn Write>,
+    sandbox: Option<PathBuf>,
+    files: Vec<Option<File>>,
+    exit_code: Option<u8>,
+}
+
+impl HostAbi {
+    /// Console on the standard input and output, without access to files
+    pub fn new() -> Self {
+        Self {
+            input: Box::new(io::stdin()),
+            output: Box::new(io::stdout()),
+            sandbox: None,
+            files: Vec::new(),
+            exit_code: None,
+        }
+    }
+
+    /// Directory where the files are opened
+    pub fn with_sandbox<P: AsRef<Path>>(mut self, sandbox: P) -> Self {
+        self.sandbox = Some(sandbox.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn with_input(mut self, input: Box<dyn Read>) -> Self {
+        self.input = input;
+        self
+    }
+
+    pub fn with_output(mut self, output: Box<dyn Write>) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Exit code given by the program with the EXIT call
+    pub fn exit_code(&self) -> Option<u8> {
+        self.exit_code
+    }
+
+    /// Defines the handlers of the calls on the Vm, the host stays shared with them
+    pub fn install(self, vm: &mut Vm) -> Rc<RefCell<HostAbi>> {
+        let host = Rc::new(RefCell::new(self));
+        let calls: [(u8, HostCall); 10] = [
+            (SIG_EXIT, exit),
+            (SIG_PUTCHAR, putchar),
+            (SIG_PUTS, puts),
+            (SIG_GETCHAR, getchar),
+            (SIG_CYCLES, cycles),
+            (SIG_TIME, time),
+            (SIG_OPEN, open),
+            (SIG_READ, read),
+            (SIG_WRITE, write),
+            (SIG_CLOSE, close),
+        ];
+        for (signal, call) in calls {
+            let host = host.clone();
+            vm.set_signal_handler(signal, move |vm: &mut Vm| call(vm, &mut host.borrow_mut()));
+        }
+        host
+    }
+
+    fn sandboxed_path(&self, name: &str) -> Result<PathBuf, u8> {
+        let sandbox = self.sandbox.as_ref().ok_or(ERR_NO_SANDBOX)?;
+        let path = Path::new(name);
+        let is_inside = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if name.is_empty() || !is_inside {
+            return Err(ERR_DENIED);
+        }
+        Ok(sandbox.join(path))
+    }
+
+    fn open_file(&mut self, name: &str, mode: u8) -> Result<u8, u8> {
+        let path = self.sandboxed_path(name)?;
+        let mut options = OpenOptions::new();
+        match mode {
+            MODE_READ => options.read(true),
+            MODE_WRITE => options.write(true).create(true).truncate(true),
+            MODE_APPEND => options.append(true).create(true),
+            _ => return Err(ERR_DENIED),
+        };
+        let file = options.open(path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => ERR_NOT_FOUND,
+            io::ErrorKind::PermissionDenied => ERR_DENIED,
+            _ => ERR_IO,
+        })?;
+
+        // Handles start at 1, 0 is never valid
+        let slot = match self.files.iter().position(|file| file.is_none()) {
+            Some(slot) => slot,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(ERR_TOO_MANY_FILES),
+        };
+        self.files[slot] = Some(file);
+        Ok(slot as u8 + 1)
+    }
+
+    fn file(&mut self, handle: u8) -> Result<&mut File, u8> {
+        (handle as usize)
+            .checked_sub(1)
+            .and_then(|slot| self.files.get_mut(slot))
+            .and_then(|file| file.as_mut())
+            .ok_or(ERR_BAD_HANDLE)
+    }
+}
+
+impl Default for HostAbi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn pointer(vm: &Vm) -> u16 {
+    u16::from_le_bytes([vm.get_register(Register::AC), vm.get_register(Register::X)])
+}
+
+fn read_string(vm: &Vm, addr: u16) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut addr = addr;
+    while let Some(byte) = vm.read_memory(addr).filter(|byte| *byte != 0) {
+        bytes.push(byte);
+        addr = addr.wrapping_add(1);
+        if bytes.len() > 0xFFFF {
+            break;
+        }
+    }
+    bytes
+}
+
+fn read_u16(vm: &Vm, addr: u16) -> u16 {
+    u16::from_le_bytes([
+        vm.read_memory(addr).unwrap_or_default(),
+        vm.read_memory(addr.wrapping_add(1)).unwrap_or_default(),
+    ])
+}
+
+fn write_bytes(vm: &mut Vm, addr: u16, bytes: &[u8]) -> Result<(), String> {
+    for (offset, byte) in bytes.iter().enumerate() {
+        vm.write_memory(addr.wrapping_add(offset as u16), *byte)?;
+    }
+    Ok(())
+}
+
+/// Sets the carry and A from a file call result coming through the host input
+fn set_result(vm: &mut Vm, result: u8) {
+    if result & ERROR_BIT > 0 {
+        vm.set_register(Register::AC, result & !ERROR_BIT);
+        vm.set_flag(RegisterFlag::Carry, true);
+    } else {
+        vm.set_register(Register::AC, result);
+        vm.set_flag(RegisterFlag::Carry, false);
+    }
+}
+
+fn encode_result(result: Result<u8, u8>) -> u8 {
+    match result {
+        Ok(value) => value,
+        Err(code) => code | ERROR_BIT,
+    }
+}
+
+/// Bytes given by the host, the length comes first on the channel
+fn host_bytes<F>(vm: &mut Vm, channel: u8, live: F) -> Result<Vec<u8>, String>
+where
+    F: FnOnce() -> Vec<u8>,
+{
+    let mut data = Vec::new();
+    let low = vm.host_input(channel as u16, || {
+        data = live();
+        data.len() as u8
+    })?;
+    let high = vm.host_input(channel as u16, || (data.len() >> 8) as u8)?;
+    let len = u16::from_le_bytes([low, high]) as usize;
+
+    (0..len)
+        .map(|idx| {
+            vm.host_input(channel as u16, || {
+                data.get(idx).copied().unwrap_or_default()
+            })
+        })
+        .collect()
+}
+
+type HostCall = fn(&mut Vm, &mut HostAbi) -> Result<SignalAction, String>;
+
+fn exit(vm: &mut Vm, host: &mut HostAbi) -> Result<SignalAction, String> {
+    host.exit_code = Some(vm.get_register(Register::AC));
+    host.output.flush().map_err(|err| err.to_string())?;
+    Ok(SignalAction::Halt)
+}
+
+fn putchar(vm: &mut Vm, host: &mut HostAbi) -> Result<SignalAction, String> {
+    let char = vm.get_register(Register::AC);
+    host.output
+        .write_all(&[char])
+        .and_then(|_| host.output.flush())
+        .map_err(|err| err.to_string())?;
+    Ok(SignalAction::Continue)
+}
+
+fn puts(vm: &mut Vm, host: &mut HostAbi) -> Result<SignalAction, String> {
+    let text = read_string(vm, pointer(vm));
+    host.output
+        .write_all(&text)
+        .and_then(|_| host.output.flush())
+        .map_err(|err| err.to_string())?;
+    Ok(SignalAction::Continue)
+}
+
+fn getchar(vm: &mut Vm, host: &mut HostAbi) -> Result<SignalAction, String> {
+    let mut char = [0];
+    let available = vm.host_input(SIG_GETCHAR as u16, || {
+        host.input.read(&mut char).is_ok_and(|len| len == 1) as u8
+    })?;
+    let char = match available {
+        0 => 0,
+        _ => vm.host_input(SIG_GETCHAR as u16, || char[0])?,
+    };
+    vm.set_register(Register::AC, char);
+    vm.set_flag(RegisterFlag::Carry, available == 0);
+    Ok(SignalAction::Continue)
+}
+
+fn cycles(vm: &mut Vm, _: &mut HostAbi) -> Result<SignalAction, String> {
+    let addr = pointer(vm);
+    write_bytes(vm, addr, &vm.cycle_count().to_le_bytes())?;
+    Ok(SignalAction::Continue)
+}
+
+fn time(vm: &mut Vm, _: &mut HostAbi) -> Result<SignalAction, String> {
+    let addr = pointer(vm);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+        .to_le_bytes();
+    let mut bytes = [0; 8];
+    for (idx, byte) in bytes.iter_mut().enumerate() {
+        *byte = vm.host_input(SIG_TIME as u16, || now[idx])?;
+    }
+    write_bytes(vm, addr, &bytes)?;
+    Ok(SignalAction::Continue)
+}
+
+fn open(vm: &mut Vm, host: &mut HostAbi) -> Result<SignalAction, String> {
+    let name = String::from_utf8_lossy(&read_string(vm, pointer(vm))).to_string();
+    let mode = vm.get_register(Register::Y);
+    let result = vm.host_input(SIG_OPEN as u16, || {
+        encode_result(host.open_file(&name, mode))
+    })?;
+    set_result(vm, result);
+    Ok(SignalAction::Continue)
+}
+
+fn read(vm: &mut Vm, host: &mut HostAbi) -> Result<SignalAction, String> {
+    let block = pointer(vm);
+    let buffer = read_u16(vm, block);
+    let len = read_u16(vm, block.wrapping_add(2)) as usize;
+    let handle = vm.get_register(Register::Y);
+
+    let mut result = 0;
+    let data = host_bytes(vm, SIG_READ, || {
+        let mut data = vec![0; len];
+        match host
+            .file(handle)
+            .and_then(|file| file.read(&mut data).map_err(|_| ERR_IO))
+        {
+            Ok(count) => data.truncate(count),
+            Err(code) => {
+                result = code | ERROR_BIT;
+                data.clear();
+            }
+        }
+        data
+    })?;
+    let result = vm.host_input(SIG_READ as u16, || result)?;
+
+    write_bytes(vm, buffer, &data)?;
+    write_bytes(
+        vm,
+        block.wrapping_add(2),
+        &(data.len() as u16).to_le_bytes(),
+    )?;
+    set_result(vm, result);
+    Ok(SignalAction::Continue)
+}
+
+fn write(vm: &mut Vm, host: &mut HostAbi) -> Result<SignalAction, String> {
+    let block = pointer(vm);
+    let buffer = read_u16(vm, block);
+    let len = read_u16(vm, block.wrapping_add(2));
+    let handle = vm.get_register(Register::Y);
+    let data: Vec<u8> = (0..len)
+        .map(|offset| {
+            vm.read_memory(buffer.wrapping_add(offset))
+                .unwrap_or_default()
+        })
+        .collect();
+
+    let result = vm.host_input(SIG_WRITE as u16, || {
+        encode_result(
+            host.file(handle)
+                .and_then(|file| file.write_all(&data).map_err(|_| ERR_IO))
+                .map(|_| 0),
+        )
+    })?;
+    if result & ERROR_BIT > 0 {
+        write_bytes(vm, block.wrapping_add(2), &[0, 0])?;
+    }
+    set_result(vm, result);
+    Ok(SignalAction::Continue)
+}
+
+fn close(vm: &mut Vm, host: &mut HostAbi) -> Result<SignalAction, String> {
+    let handle = vm.get_register(Register::Y);
+    let result = vm.host_input(SIG_CLOSE as u16, || {
+        encode_result(host.file(handle).map(|_| 0))
+    })?;
+    if result & ERROR_BIT == 0 {
+        // When replaying, the file may not have been opened
+        if let Some(file) = (handle as usize)
+            .checked_sub(1)
+            .and_then(|slot| host.files.get_mut(slot))
+        {
+            *file = None;
+        }
+    }
+    set_result(vm, result);
+    Ok(SignalAction::Continue)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+    use crate::{asm::assemble, console::testing::SharedOutput};
+
+    #[test]
+    fn test_console_calls() {
+        let source = format!(
+            "include \"{}/prog/host.inc\"
+    LoadACImm $00
+    LoadXImm $02
+    EmuSignal SIG_PUTS
+    EmuSignal SIG_GETCHAR
+    EmuSignal SIG_PUTCHAR
+    EmuSignal SIG_GETCHAR
+    LoadACImm $10
+    LoadXImm $02
+    EmuSignal SIG_CYCLES
+    LoadACImm 3
+    EmuSignal SIG_EXIT
+    Jam
+",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let program = assemble(&source, "console.asm").unwrap();
+        let output = SharedOutput::default();
+
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &program.bytes);
+        vm.copy_memory(0x0200, b"hi \0");
+        let host = HostAbi::new()
+            .with_input(Box::new(&b"a"[..]))
+            .with_output(Box::new(output.clone()))
+            .install(&mut vm);
+        while !vm.halt {
+            vm.cycle().unwrap();
+        }
+
+        assert_eq!(output.0.borrow().as_slice(), b"hi a");
+        assert!(vm.get_flag(RegisterFlag::Carry));
+        assert_eq!(vm.read_memory(0x0210), Some(18));
+        assert_eq!(host.borrow().exit_code(), Some(3));
+        assert_eq!(vm.get_pc(), 0x0016);
+    }
+
+    #[test]
+    fn test_file_calls() {
+        let sandbox = env::temp_dir().join("rustemu_host_sandbox");
+        fs::create_dir_all(&sandbox).unwrap();
+        fs::write(sandbox.join("in.txt"), b"data").unwrap();
+
+        let mut vm = Vm::new();
+        HostAbi::new().with_sandbox(&sandbox).install(&mut vm);
+        vm.copy_memory(0x0200, b"in.txt\0../out.txt\0out.txt\0");
+        // Parameter block of READ and WRITE : buffer $0300, length 16
+        vm.copy_memory(0x0280, &[0x00, 0x03, 0x10, 0x00]);
+
+        let call = |vm: &mut Vm, signal: u8, a: u8, x: u8, y: u8| {
+            vm.set_register(Register::AC, a);
+            vm.set_register(Register::X, x);
+            vm.set_register(Register::Y, y);
+            vm.copy_memory(0, &[0xFF, signal]);
+            vm.set_pc(0);
+            vm.cycle().unwrap();
+            (
+                vm.get_register(Register::AC),
+                vm.get_flag(RegisterFlag::Carry),
+            )
+        };
+
+        assert_eq!(call(&mut vm, SIG_OPEN, 0x00, 0x02, MODE_READ), (1, false));
+        assert_eq!(call(&mut vm, SIG_READ, 0x80, 0x02, 1), (0, false));
+        assert_eq!(vm.read_memory(0x0282), Some(4));
+        assert_eq!(vm.read_memory(0x0300), Some(b'd'));
+        assert_eq!(call(&mut vm, SIG_CLOSE, 0, 0, 1), (0, false));
+        assert_eq!(call(&mut vm, SIG_CLOSE, 0, 0, 1), (ERR_BAD_HANDLE, true));
+
+        assert_eq!(
+            call(&mut vm, SIG_OPEN, 0x07, 0x02, MODE_WRITE),
+            (ERR_DENIED, true)
+        );
+        assert_eq!(call(&mut vm, SIG_OPEN, 0x12, 0x02, MODE_WRITE), (1, false));
+        assert_eq!(call(&mut vm, SIG_WRITE, 0x80, 0x02, 1), (0, false));
+        assert_eq!(call(&mut vm, SIG_CLOSE, 0, 0, 1), (0, false));
+        assert_eq!(fs::read(sandbox.join("out.txt")).unwrap(), b"data");
+
+        let mut vm = Vm::new();
+        HostAbi::new().install(&mut vm);
+        vm.copy_memory(0x0200, b"in.txt\0");
+        assert_eq!(
+            call(&mut vm, SIG_OPEN, 0x00, 0x02, MODE_READ),
+            (ERR_NO_SANDBOX, true)
+        );
+    }
+}
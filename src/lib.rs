@@ -1,15 +1,73 @@
-use std::{cmp::min, collections::HashMap, fmt};
+use std::{collections::HashMap, fmt};
 
-use isa::{Instruction, Register, RegisterFlag};
+use breakpoints::{BreakHit, Breakpoint, BreakpointId, Breakpoints, StopReason};
+use bus::{
+    AccessPolicy, Bus, BusAccess, BusCycle, Device, FaultKind, HostPort, IrqLine, MemoryAttribute,
+    MemoryFault,
+};
+use coverage::Coverage;
+use history::{History, UndoRecord};
+use isa::{AddrMode, Instruction, Register, RegisterFlag};
+use profiler::Profiler;
+use replay::{InputLog, InputMode};
+use signals::{SignalAction, SignalHandler};
+use state::{StateReader, StateWriter};
 
+pub mod acia;
+pub mod asm;
+pub mod breakpoints;
+pub mod bus;
+pub mod console;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod gdb;
+pub mod history;
+pub mod host;
 pub mod isa;
+pub mod machine;
+pub mod mapper;
+pub mod profiler;
+pub mod replay;
+pub mod riot;
+pub mod screen;
+pub mod signals;
+pub mod state;
+pub mod symbols;
+pub mod test_runner;
+pub mod trace;
+pub mod tui;
+pub mod via;
 
 type SignalFunction = fn(&mut Vm) -> Result<(), String>;
 
+const STACK_PAGE: u16 = 0x0100;
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
 pub struct Vm {
     registers: [u8; 8],
-    memory: [u8; 64 * 1024],
-    signal_handlers: HashMap<u8, SignalFunction>,
+    bus: Bus,
+    signal_handlers: HashMap<u8, Box<dyn SignalHandler>>,
+    signal_break: Option<u8>,
+    nmi_pending: bool,
+    // Level of the NMI input at the last instruction, the NMI is edge triggered
+    nmi_line: bool,
+    cycles: u64,
+    cycle_accurate: bool,
+    bus_cycles: Vec<BusCycle>,
+    history: Option<History>,
+    coverage: Option<Coverage>,
+    profiler: Option<Profiler>,
+    input_mode: InputMode,
+    device_error: Option<String>,
+    rom_write_policy: AccessPolicy,
+    unmapped_policy: AccessPolicy,
+    memory_fault: Option<MemoryFault>,
+    memory_log: Vec<MemoryFault>,
+    breakpoints: Breakpoints,
+    last_break: Option<BreakHit>,
     pub halt: bool,
 }
 
@@ -17,14 +75,155 @@ impl Vm {
     pub fn new() -> Self {
         Self {
             registers: [0; 8],
-            memory: [0; 64 * 1024],
+            bus: Bus::new(),
             signal_handlers: HashMap::new(),
+            signal_break: None,
+            nmi_pending: false,
+            nmi_line: false,
+            cycles: 0,
+            cycle_accurate: false,
+            bus_cycles: Vec::new(),
+            history: None,
+            coverage: None,
+            profiler: None,
+            input_mode: InputMode::Live,
+            device_error: None,
+            rom_write_policy: AccessPolicy::Ignore,
+            unmapped_policy: AccessPolicy::Ignore,
+            memory_fault: None,
+            memory_log: Vec::new(),
+            breakpoints: Breakpoints::default(),
+            last_break: None,
             halt: false,
         }
     }
 
     pub fn define_handler(&mut self, index: u8, f: SignalFunction) {
-        self.signal_handlers.insert(index, f);
+        self.set_signal_handler(index, move |vm: &mut Vm| {
+            f(vm).map(|_| SignalAction::Continue)
+        });
+    }
+
+    /// Handler of a signal, a closure or any type implementing `SignalHandler`
+    pub fn set_signal_handler<H>(&mut self, index: u8, handler: H)
+    where
+        H: SignalHandler + 'static,
+    {
+        self.signal_handlers.insert(index, Box::new(handler));
+    }
+
+    pub fn remove_signal_handler(&mut self, index: u8) -> bool {
+        self.signal_handlers.remove(&index).is_some()
+    }
+
+    /// Signal whose handler asked for a break during the last instruction
+    pub fn signal_break(&self) -> Option<u8> {
+        self.signal_break
+    }
+
+    /// Requests an IRQ, it is serviced before the next instruction once the
+    /// interrupt disable flag is clear
+    pub fn request_irq(&mut self) {
+        self.registers[Register::IRQ as usize] = 1;
+    }
+
+    /// Requests an NMI, serviced before the next instruction
+    pub fn request_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    /// Reset sequence of the CPU : the stack pointer goes down by 3 without any write,
+    /// interrupts are disabled and the program starts at the reset vector
+    pub fn reset(&mut self) {
+        let sp = self.get_register(Register::SP).wrapping_sub(3);
+        self.set_register(Register::SP, sp);
+        self.set_flag(RegisterFlag::Interrupt, true);
+        self.registers[Register::IRQ as usize] = 0;
+        self.nmi_pending = false;
+        let vector =
+            u16::from_le_bytes([self.bus.peek(RESET_VECTOR), self.bus.peek(RESET_VECTOR + 1)]);
+        self.set_pc(vector);
+        self.cycles += 7;
+        self.halt = false;
+    }
+
+    /// True when an IRQ was requested or a device holds its IRQ line
+    pub fn is_irq_pending(&self) -> bool {
+        self.registers[Register::IRQ as usize] != 0 || self.bus.irq()
+    }
+
+    /// Maps a device over the memory from an address, see `bus::Device`
+    pub fn map_device<D: Device + 'static>(&mut self, start: u16, device: D) -> Result<(), String> {
+        self.bus.map(start, device)
+    }
+
+    pub fn unmap_device(&mut self, start: u16) -> bool {
+        self.bus.unmap(start)
+    }
+
+    /// Connects the IRQ output of a device to the IRQ or NMI input, or to nothing
+    pub fn wire_device(&mut self, start: u16, line: IrqLine) -> bool {
+        self.bus.wire(start, line)
+    }
+
+    /// Makes `start..=end` repeat the `size` addresses from `source`
+    pub fn mirror_memory(
+        &mut self,
+        start: u16,
+        end: u16,
+        source: u16,
+        size: u16,
+    ) -> Result<(), String> {
+        self.bus.mirror(start, end, source, size)
+    }
+
+    /// First and last address of every mapped device
+    pub fn device_mappings(&self) -> Vec<(u16, u16)> {
+        self.bus.mappings()
+    }
+
+    /// Makes `start..=end` RAM, ROM, unmapped or open bus, see `bus::MemoryAttribute`
+    pub fn set_memory_attribute(
+        &mut self,
+        start: u16,
+        end: u16,
+        attribute: MemoryAttribute,
+    ) -> Result<(), String> {
+        self.bus.set_attribute(start, end, attribute)
+    }
+
+    pub fn memory_attribute(&self, addr: u16) -> MemoryAttribute {
+        self.bus.attribute(addr)
+    }
+
+    /// Bank seen at an address when it is in the window of a mapper
+    pub fn bank_at(&self, addr: u16) -> Option<usize> {
+        self.bus.bank(addr)
+    }
+
+    /// What to do when the program writes to the ROM or reaches unmapped memory
+    pub fn set_access_policy(&mut self, kind: FaultKind, policy: AccessPolicy) {
+        match kind {
+            FaultKind::RomWrite => self.rom_write_policy = policy,
+            FaultKind::Unmapped => self.unmapped_policy = policy,
+        }
+    }
+
+    pub fn access_policy(&self, kind: FaultKind) -> AccessPolicy {
+        match kind {
+            FaultKind::RomWrite => self.rom_write_policy,
+            FaultKind::Unmapped => self.unmapped_policy,
+        }
+    }
+
+    /// Fault which halted the machine during the last instruction
+    pub fn memory_fault(&self) -> Option<&MemoryFault> {
+        self.memory_fault.as_ref()
+    }
+
+    /// Takes the faulty accesses kept with `AccessPolicy::Log`
+    pub fn take_memory_log(&mut self) -> Vec<MemoryFault> {
+        std::mem::take(&mut self.memory_log)
     }
 
     pub fn get_flag(&self, register_flag: RegisterFlag) -> bool {
@@ -46,321 +245,1406 @@ impl Vm {
         self.registers[register as usize]
     }
 
-    pub fn set_register(&mut self, register: Register, value: u8) -> () {
+    pub fn set_register(&mut self, register: Register, value: u8) {
         //println!("REG write {:?}, {}", register, value);
         self.registers[register as usize] = value
     }
 
-    pub fn read_memory(&self, addr: u16) -> Option<u8> {
-        if (addr as usize) < self.memory.len() {
-            Some(self.memory[addr as usize])
-        } else {
-            None
+    pub fn get_pc(&self) -> u16 {
+        (self.get_register(Register::PCH) as u16) << 8 | (self.get_register(Register::PCL) as u16)
+    }
+
+    pub fn set_pc(&mut self, addr: u16) {
+        self.set_register(Register::PCH, ((addr & 0xFF00) >> 8) as u8);
+        self.set_register(Register::PCL, (addr & 0xFF) as u8);
+    }
+
+    /// Number of CPU cycles elapsed since the creation of the VM
+    pub fn cycle_count(&self) -> u64 {
+        self.cycles
+    }
+
+    /// In cycle accurate mode every bus cycle of an instruction is performed,
+    /// including the dummy reads and writes of the NMOS 6502, and recorded
+    pub fn set_cycle_accurate(&mut self, value: bool) {
+        self.cycle_accurate = value;
+        self.bus_cycles.clear();
+    }
+
+    pub fn is_cycle_accurate(&self) -> bool {
+        self.cycle_accurate
+    }
+
+    /// Bus activity of the last instruction, only recorded in cycle accurate mode
+    pub fn bus_cycles(&self) -> &[BusCycle] {
+        &self.bus_cycles
+    }
+
+    /// Records undo information for every instruction, the oldest ones are
+    /// dropped when the history uses more than `memory_budget` bytes. The state of the
+    /// devices is kept for the instructions writing to them, like the mapped RAM
+    pub fn enable_history(&mut self, memory_budget: usize) {
+        self.history = Some(History::new(memory_budget));
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    /// Number of instructions that can be undone
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, |history| history.len())
+    }
+
+    /// Undoes the last `count` instructions, returns how many were undone
+    pub fn step_back(&mut self, count: usize) -> usize {
+        for idx in 0..count {
+            if !self.undo() {
+                return idx;
+            }
+        }
+        count
+    }
+
+    /// Undoes instructions until the predicate is true, returns false if the
+    /// beginning of the history is reached without a match
+    pub fn run_back_to<F>(&mut self, mut predicate: F) -> bool
+    where
+        F: FnMut(&Vm) -> bool,
+    {
+        while self.undo() {
+            if predicate(self) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn undo(&mut self) -> bool {
+        let Some(record) = self.history.as_mut().and_then(|history| history.pop()) else {
+            return false;
+        };
+
+        // The devices were mapped again since the write, the history ends there
+        if let Some(devices) = record.devices.as_ref() {
+            if self.bus.load_devices(devices).is_err() {
+                if let Some(history) = self.history.as_mut() {
+                    history.clear();
+                }
+                return false;
+            }
+        }
+        for (addr, value) in record.memory.iter().rev() {
+            self.bus.load(*addr, *value);
+        }
+        self.registers = record.registers;
+        self.cycles = record.cycles;
+        self.halt = record.halt;
+        self.nmi_pending = record.nmi_pending;
+        self.nmi_line = record.nmi_line;
+        self.bus.set_data_bus(record.data_bus);
+        self.bus_cycles.clear();
+        self.last_break = None;
+        self.signal_break = None;
+        true
+    }
+
+    /// Counts the executed instructions and the outcomes of the branches from now on
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    pub fn disable_coverage(&mut self) {
+        self.coverage = None;
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Attributes the cycles to the instructions, routines and loops from now on
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn disable_profiler(&mut self) {
+        self.profiler = None;
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn set_input_mode(&mut self, mode: InputMode) {
+        self.input_mode = mode;
+    }
+
+    pub fn input_mode(&self) -> &InputMode {
+        &self.input_mode
+    }
+
+    /// Value given by the host on a channel, `live` is only called when not replaying.
+    /// A replay fails if the program asks for its input on another channel or cycle.
+    pub fn host_input<F>(&mut self, channel: u16, live: F) -> Result<u8, String>
+    where
+        F: FnOnce() -> u8,
+    {
+        self.input_mode.input(self.cycles, channel, live)
+    }
+
+    /// Hash of the save state, two machines with the same hash are in the same state
+    pub fn state_hash(&self) -> u64 {
+        replay::state_hash(&self.save_state())
+    }
+
+    /// Ends a recording, the log gets the final state hash
+    pub fn finish_recording(&mut self) -> Option<InputLog> {
+        if !matches!(self.input_mode, InputMode::Record(_)) {
+            return None;
+        }
+        let final_state = (self.cycles, self.state_hash());
+        match std::mem::take(&mut self.input_mode) {
+            InputMode::Record(mut log) => {
+                log.final_state = Some(final_state);
+                Some(log)
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks that a replay used the whole log and ended in the recorded state
+    pub fn check_replay(&self) -> Result<(), String> {
+        let InputMode::Replay { log, next } = &self.input_mode else {
+            return Err("No replay in progress".to_string());
+        };
+        if *next < log.events.len() {
+            return Err(format!(
+                "Replay diverged : {} recorded inputs were not used",
+                log.events.len() - next
+            ));
+        }
+        match log.final_state {
+            Some((cycle, hash)) if cycle != self.cycles || hash != self.state_hash() => {
+                Err(format!(
+                    "Replay diverged : state hash {:016x} at cycle {}, recorded {:016x} at cycle {}",
+                    self.state_hash(),
+                    self.cycles,
+                    hash,
+                    cycle
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> BreakpointId {
+        self.breakpoints.add(breakpoint)
+    }
+
+    pub fn remove_breakpoint(&mut self, id: BreakpointId) -> bool {
+        self.breakpoints.remove(id)
+    }
+
+    pub fn breakpoint(&self, id: BreakpointId) -> Option<&Breakpoint> {
+        self.breakpoints.get(id)
+    }
+
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    /// Breakpoint which fired during the last instruction
+    pub fn last_break(&self) -> Option<&BreakHit> {
+        self.last_break.as_ref()
+    }
+
+    /// Runs until a breakpoint fires, the machine halts or the cycle count reaches the limit
+    pub fn run_until(&mut self, cycle_limit: u64) -> Result<StopReason, String> {
+        loop {
+            if self.halt {
+                return Ok(StopReason::Halted);
+            }
+            if self.cycles >= cycle_limit {
+                return Ok(StopReason::CycleLimit);
+            }
+
+            if let Err(err) = self.cycle() {
+                return match self.memory_fault {
+                    Some(fault) => Ok(StopReason::MemoryFault(fault)),
+                    None => Err(err),
+                };
+            }
+
+            if let Some(hit) = self.last_break {
+                return Ok(StopReason::Breakpoint(hit));
+            }
+            if let Some(signal) = self.signal_break {
+                return Ok(StopReason::Signal(signal));
+            }
         }
     }
 
+    pub fn read_memory(&self, addr: u16) -> Option<u8> {
+        Some(self.bus.peek(addr))
+    }
+
+    /// Decodes the instruction stored at an address without executing it
+    pub fn decode_at(&self, addr: u16) -> Result<Instruction, String> {
+        let raw_bytes = [
+            self.bus.peek(addr),
+            self.bus.peek(addr.wrapping_add(1)),
+            self.bus.peek(addr.wrapping_add(2)),
+        ];
+
+        Instruction::try_from(raw_bytes.as_slice())
+            .map_err(|err| format!("Wrong binary format : {}", err))
+    }
+
+    /// Writes as the CPU does, the ROM and the unmapped addresses are refused
     pub fn write_memory(&mut self, addr: u16, value: u8) -> Result<(), String> {
-        println!("MEM write 0x{:04x}, 0x{:02x}", addr, value);
-        if (addr as usize) < self.memory.len() {
-            self.memory[addr as usize] = value;
-            Ok(())
-        } else {
-            Err(format!("Wrong memory write address 0x{:02x}", addr))
+        match self.bus.fault(addr, BusAccess::Write) {
+            Some(FaultKind::RomWrite) => Err(format!("Address ${:04x} is read-only", addr)),
+            Some(FaultKind::Unmapped) => Err(format!("Address ${:04x} is unmapped", addr)),
+            None => {
+                self.store(addr, value);
+                Ok(())
+            }
         }
     }
 
-    pub fn copy_memory(&mut self, from_addr: usize, value: &[u8]) -> () {
+    /// Loads bytes in the RAM, under the mapped devices
+    pub fn copy_memory(&mut self, from_addr: usize, value: &[u8]) {
         for (idx, addr) in (from_addr..from_addr + value.len()).enumerate() {
-            self.memory[addr] = value[idx]
+            self.bus.load(addr as u16, value[idx])
         }
     }
 
+    /// Snapshot of the whole machine, signal handlers are not part of it
+    /// and must be defined again on the restored VM
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+
+        writer.begin_chunk(b"CPU ");
+        writer.put_bytes(&self.registers);
+        writer.put_u64(self.cycles);
+        writer.put_bool(self.halt);
+        writer.put_bool(self.nmi_pending);
+        writer.put_bool(self.nmi_line);
+        writer.put_u8(self.bus.data_bus());
+
+        self.bus.save_state(&mut writer);
+
+        writer.finish()
+    }
+
+    /// Restores a snapshot of a machine with the same devices, the VM is left
+    /// unchanged when the state can not be loaded
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let backup = self.save_state();
+        self.restore_state(data).inspect_err(|_| {
+            // A state saved by this machine always loads back
+            let _ = self.restore_state(&backup);
+        })
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(data)?;
+
+        let mut cpu = reader.chunk(b"CPU ")?;
+        let registers = cpu.get_bytes(self.registers.len())?;
+        let cycles = cpu.get_u64()?;
+        let halt = cpu.get_bool()?;
+        let nmi_pending = cpu.get_bool()?;
+        let nmi_line = cpu.get_bool()?;
+        let data_bus = cpu.get_u8()?;
+
+        self.bus.load_state(&mut reader)?;
+
+        self.registers.copy_from_slice(registers);
+        self.cycles = cycles;
+        self.halt = halt;
+        self.nmi_pending = nmi_pending;
+        self.nmi_line = nmi_line;
+        self.bus.set_data_bus(data_bus);
+        self.bus_cycles.clear();
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        Ok(())
+    }
+
     pub fn cycle(&mut self) -> Result<(), String> {
-        let mut pc: usize = (self.get_register(Register::PCH) as usize) << 8
-            | (self.get_register(Register::PCL) as usize);
+        let opcode_addr = self.get_pc();
+        let cycles_before = self.cycles;
+        let sp_before = self.get_register(Register::SP);
+        self.bus_cycles.clear();
+        self.breakpoints.begin_instruction();
+        self.last_break = None;
+        self.signal_break = None;
+        self.device_error = None;
+        self.memory_fault = None;
 
-        let raw_bytes = &self.memory[pc..(min(pc + 3, self.memory.len()))];
+        if let Some(history) = self.history.as_mut() {
+            history.begin(UndoRecord {
+                registers: self.registers,
+                cycles: self.cycles,
+                halt: self.halt,
+                nmi_pending: self.nmi_pending,
+                nmi_line: self.nmi_line,
+                data_bus: self.bus.data_bus(),
+                memory: Vec::new(),
+                devices: None,
+            });
+        }
+
+        // A pending interrupt is serviced as a step of its own
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.interrupt(NMI_VECTOR)?;
+            self.tick_devices(self.cycles - cycles_before)?;
+            self.check_breakpoints();
+            return Ok(());
+        }
+        if self.is_irq_pending() && !self.get_flag(RegisterFlag::Interrupt) {
+            self.registers[Register::IRQ as usize] = 0;
+            self.interrupt(IRQ_VECTOR)?;
+            self.tick_devices(self.cycles - cycles_before)?;
+            self.check_breakpoints();
+            return Ok(());
+        }
 
-        let instruction = Instruction::try_from(raw_bytes)
+        let raw_bytes = [
+            self.read_byte(opcode_addr),
+            self.bus.peek(opcode_addr.wrapping_add(1)),
+            self.bus.peek(opcode_addr.wrapping_add(2)),
+        ];
+
+        let instruction = Instruction::try_from(raw_bytes.as_slice())
             .map_err(|err| format!("Wrong binary format : {}", err))?;
 
-        pc += instruction.size();
-        println!("{}", instruction);
+        // Operand fetch, single byte instructions still read the next byte.
+        // JSR only fetches its high byte after pushing the return address.
+        match instruction.size() {
+            1 => self.dummy_read(opcode_addr.wrapping_add(1)),
+            2 => {
+                self.read_byte(opcode_addr.wrapping_add(1));
+            }
+            _ => {
+                self.read_byte(opcode_addr.wrapping_add(1));
+                if !matches!(instruction, Instruction::JumpSubAbs(_)) {
+                    self.read_byte(opcode_addr.wrapping_add(2));
+                }
+            }
+        }
+
+        let mut pc = opcode_addr.wrapping_add(instruction.size() as u16);
+        self.cycles += instruction.cycles() as u64;
+        let cycles_after_fetch = self.cycles;
 
         match instruction {
-            Instruction::LoadACImm(op) => {
-                self.set_register(Register::AC, op);
-                self.update_flag_zero(op.into());
-                self.update_flag_negative(op.into());
-            }
-            Instruction::LoadACAbs(_) => todo!(),
-            Instruction::LoadACAbsX(_) => todo!(),
-            Instruction::LoadACAbsY(_) => todo!(),
-            Instruction::LoadACZp(_) => todo!(),
-            Instruction::LoadACZpX(_) => todo!(),
-            Instruction::LoadACZpXInd(_) => todo!(),
-            Instruction::LoadACZpYInd(_) => todo!(),
-            Instruction::LoadXImm(_) => todo!(),
-            Instruction::LoadXAbs(_) => todo!(),
-            Instruction::LoadXAbsY(_) => todo!(),
-            Instruction::LoadXZp(_) => todo!(),
-            Instruction::LoadXZpY(_) => todo!(),
-            Instruction::LoadYImm(_) => todo!(),
-            Instruction::LoadYAbs(_) => todo!(),
-            Instruction::LoadYAbsX(_) => todo!(),
-            Instruction::LoadYZp(_) => todo!(),
-            Instruction::LoadYZpX(_) => todo!(),
+            // Load
+            Instruction::LoadACImm(op) => self.load_register(Register::AC, op),
+            Instruction::LoadACAbs(op) => {
+                let value = self.read_byte(op);
+                self.load_register(Register::AC, value)
+            }
+            Instruction::LoadACAbsX(op) => {
+                let value = self.read_absolute_x(op);
+                self.load_register(Register::AC, value)
+            }
+            Instruction::LoadACAbsY(op) => {
+                let value = self.read_absolute_y(op);
+                self.load_register(Register::AC, value)
+            }
+            Instruction::LoadACZp(op) => {
+                let value = self.read_byte(op.into());
+                self.load_register(Register::AC, value)
+            }
+            Instruction::LoadACZpX(op) => {
+                let value = self.read_zeropage_x(op);
+                self.load_register(Register::AC, value)
+            }
+            Instruction::LoadACZpXInd(op) => {
+                let value = self.read_zeropage_x_indirect(op);
+                self.load_register(Register::AC, value)
+            }
+            Instruction::LoadACZpYInd(op) => {
+                let value = self.read_zeropage_indirect_y(op);
+                self.load_register(Register::AC, value)
+            }
+            Instruction::LoadXImm(op) => self.load_register(Register::X, op),
+            Instruction::LoadXAbs(op) => {
+                let value = self.read_byte(op);
+                self.load_register(Register::X, value)
+            }
+            Instruction::LoadXAbsY(op) => {
+                let value = self.read_absolute_y(op);
+                self.load_register(Register::X, value)
+            }
+            Instruction::LoadXZp(op) => {
+                let value = self.read_byte(op.into());
+                self.load_register(Register::X, value)
+            }
+            Instruction::LoadXZpY(op) => {
+                let value = self.read_zeropage_y(op);
+                self.load_register(Register::X, value)
+            }
+            Instruction::LoadYImm(op) => self.load_register(Register::Y, op),
+            Instruction::LoadYAbs(op) => {
+                let value = self.read_byte(op);
+                self.load_register(Register::Y, value)
+            }
+            Instruction::LoadYAbsX(op) => {
+                let value = self.read_absolute_x(op);
+                self.load_register(Register::Y, value)
+            }
+            Instruction::LoadYZp(op) => {
+                let value = self.read_byte(op.into());
+                self.load_register(Register::Y, value)
+            }
+            Instruction::LoadYZpX(op) => {
+                let value = self.read_zeropage_x(op);
+                self.load_register(Register::Y, value)
+            }
+
+            // Store
             Instruction::StoreACAbs(op) => {
                 let value = self.get_register(Register::AC);
-                self.write_memory(op, value)?
+                self.write_byte(op, value)?
+            }
+            Instruction::StoreACAbsX(op) => {
+                let addr = self.decode_absolute_x(op, BusAccess::Write);
+                let value = self.get_register(Register::AC);
+                self.write_byte(addr, value)?
+            }
+            Instruction::StoreACAbsY(op) => {
+                let addr = self.decode_absolute_y(op, BusAccess::Write);
+                let value = self.get_register(Register::AC);
+                self.write_byte(addr, value)?
             }
-            Instruction::StoreACAbsX(_) => todo!(),
-            Instruction::StoreACAbsY(_) => todo!(),
             Instruction::StoreACZp(op) => {
                 let value = self.get_register(Register::AC);
-                self.write_memory(op.into(), value)?
-            }
-            Instruction::StoreACZpX(_) => todo!(),
-            Instruction::StoreACZpXInd(_) => todo!(),
-            Instruction::StoreACZpYInd(_) => todo!(),
-            Instruction::StoreXAbs(_) => todo!(),
-            Instruction::StoreXZp(_) => todo!(),
-            Instruction::StoreXZpY(_) => todo!(),
-            Instruction::StoreYAbs(_) => todo!(),
-            Instruction::StoreYZp(_) => todo!(),
-            Instruction::StoreYZpX(_) => todo!(),
-            Instruction::TransACX => todo!(),
-            Instruction::TransACY => todo!(),
-            Instruction::TransSPX => todo!(),
-            Instruction::TransXAC => todo!(),
-            Instruction::TransYAC => todo!(),
-            Instruction::TransXSP => todo!(),
-            Instruction::PushAC => todo!(),
-            Instruction::PushSR => todo!(),
-            Instruction::PullAC => todo!(),
-            Instruction::PullSR => todo!(),
-            Instruction::ArmLShfAC => {
+                self.write_byte(op.into(), value)?
+            }
+            Instruction::StoreACZpX(op) => {
+                let addr = self.decode_zeropage_x(op);
                 let value = self.get_register(Register::AC);
-                let res = (value as u16) << 1;
-                self.update_flag_carry(res);
-                self.set_register(Register::AC, (res & 0xFF) as u8);
-            }
-            Instruction::ArmLShfAbs(_) => todo!(),
-            Instruction::ArmLShfAbsX(_) => todo!(),
-            Instruction::ArmLShfZp(_) => todo!(),
-            Instruction::ArmLShfZpX(_) => todo!(),
-            Instruction::LogRShfAC => todo!(),
-            Instruction::LogRShfAbs(_) => todo!(),
-            Instruction::LogRShfAbsX(_) => todo!(),
-            Instruction::LogRShfZp(_) => todo!(),
-            Instruction::LogRShfZpX(_) => todo!(),
-            Instruction::LRotAC => todo!(),
-            Instruction::LRotAbs(_) => todo!(),
-            Instruction::LRotAbsX(_) => todo!(),
-            Instruction::LRotZp(_) => todo!(),
-            Instruction::LRotZpX(_) => todo!(),
-            Instruction::RRotAC => todo!(),
-            Instruction::RRotAbs(_) => todo!(),
-            Instruction::RRotAbsX(_) => todo!(),
-            Instruction::RRotZp(_) => todo!(),
-            Instruction::RRotZpX(_) => todo!(),
-            Instruction::AndImm(_) => todo!(),
-            Instruction::AndAbs(_) => todo!(),
-            Instruction::AndAbsX(_) => todo!(),
-            Instruction::AndAbsY(_) => todo!(),
-            Instruction::AndZp(_) => todo!(),
-            Instruction::AndZpX(_) => todo!(),
-            Instruction::AndZpXInd(_) => todo!(),
-            Instruction::AndZpYInd(_) => todo!(),
-            Instruction::BitAbs(_) => todo!(),
-            Instruction::BitZp(_) => todo!(),
-            Instruction::EorImm(_) => todo!(),
-            Instruction::EorAbs(_) => todo!(),
-            Instruction::EorAbsX(_) => todo!(),
-            Instruction::EorAbsY(_) => todo!(),
-            Instruction::EorZp(_) => todo!(),
-            Instruction::EorZpX(_) => todo!(),
-            Instruction::EorZpXInd(_) => todo!(),
-            Instruction::EorZpYInd(_) => todo!(),
-            Instruction::OrImm(_) => todo!(),
-            Instruction::OrAbs(_) => todo!(),
-            Instruction::OrAbsX(_) => todo!(),
-            Instruction::OrAbsY(_) => todo!(),
-            Instruction::OrZp(_) => todo!(),
-            Instruction::OrZpX(_) => todo!(),
-            Instruction::OrZpXInd(_) => todo!(),
-            Instruction::OrZpYInd(_) => todo!(),
-            Instruction::AddImm(op) => {
-                let carry = self.get_flag(RegisterFlag::Carry);
-                let acc = self.get_register(Register::AC);
-                let res = (acc as u16) + (op as u16) + (carry as u16);
-
-                // Set flags
-                self.update_flag_carry(res);
-                self.update_flag_zero(res);
-                self.update_flag_overflow(acc as u16, res);
-                self.update_flag_negative(res);
-
-                self.set_register(Register::AC, (res & 0xFF) as u8)
-            },
+                self.write_byte(addr, value)?
+            }
+            Instruction::StoreACZpXInd(op) => {
+                let addr = self.decode_zeropage_x_indirect(op);
+                let value = self.get_register(Register::AC);
+                self.write_byte(addr, value)?
+            }
+            Instruction::StoreACZpYInd(op) => {
+                let addr = self.decode_zeropage_indirect_y(op, BusAccess::Write);
+                let value = self.get_register(Register::AC);
+                self.write_byte(addr, value)?
+            }
+            Instruction::StoreXAbs(op) => {
+                let value = self.get_register(Register::X);
+                self.write_byte(op, value)?
+            }
+            Instruction::StoreXZp(op) => {
+                let value = self.get_register(Register::X);
+                self.write_byte(op.into(), value)?
+            }
+            Instruction::StoreXZpY(op) => {
+                let addr = self.decode_zeropage_y(op);
+                let value = self.get_register(Register::X);
+                self.write_byte(addr, value)?
+            }
+            Instruction::StoreYAbs(op) => {
+                let value = self.get_register(Register::Y);
+                self.write_byte(op, value)?
+            }
+            Instruction::StoreYZp(op) => {
+                let value = self.get_register(Register::Y);
+                self.write_byte(op.into(), value)?
+            }
+            Instruction::StoreYZpX(op) => {
+                let addr = self.decode_zeropage_x(op);
+                let value = self.get_register(Register::Y);
+                self.write_byte(addr, value)?
+            }
+
+            // Transfert
+            Instruction::TransACX => {
+                self.load_register(Register::X, self.get_register(Register::AC))
+            }
+            Instruction::TransACY => {
+                self.load_register(Register::Y, self.get_register(Register::AC))
+            }
+            Instruction::TransSPX => {
+                self.load_register(Register::X, self.get_register(Register::SP))
+            }
+            Instruction::TransXAC => {
+                self.load_register(Register::AC, self.get_register(Register::X))
+            }
+            Instruction::TransYAC => {
+                self.load_register(Register::AC, self.get_register(Register::Y))
+            }
+            Instruction::TransXSP => {
+                self.set_register(Register::SP, self.get_register(Register::X))
+            }
+
+            // Stack
+            Instruction::PushAC => self.push(self.get_register(Register::AC))?,
+            Instruction::PushSR => self.push(self.get_register(Register::SR) | 0b0011_0000)?,
+            Instruction::PullAC => {
+                self.dummy_read_stack();
+                let value = self.pull();
+                self.load_register(Register::AC, value)
+            }
+            Instruction::PullSR => {
+                self.dummy_read_stack();
+                let value = self.pull();
+                self.load_status(value)
+            }
+
+            // Shift
+            Instruction::ArmLShfAC => {
+                let value = self.shift_left(self.get_register(Register::AC));
+                self.set_register(Register::AC, value);
+            }
+            Instruction::ArmLShfAbs(op) => self.modify_memory(op, Vm::shift_left)?,
+            Instruction::ArmLShfAbsX(op) => {
+                let addr = self.decode_absolute_x(op, BusAccess::Write);
+                self.modify_memory(addr, Vm::shift_left)?
+            }
+            Instruction::ArmLShfZp(op) => self.modify_memory(op.into(), Vm::shift_left)?,
+            Instruction::ArmLShfZpX(op) => {
+                let addr = self.decode_zeropage_x(op);
+                self.modify_memory(addr, Vm::shift_left)?
+            }
+            Instruction::LogRShfAC => {
+                let value = self.shift_right(self.get_register(Register::AC));
+                self.set_register(Register::AC, value);
+            }
+            Instruction::LogRShfAbs(op) => self.modify_memory(op, Vm::shift_right)?,
+            Instruction::LogRShfAbsX(op) => {
+                let addr = self.decode_absolute_x(op, BusAccess::Write);
+                self.modify_memory(addr, Vm::shift_right)?
+            }
+            Instruction::LogRShfZp(op) => self.modify_memory(op.into(), Vm::shift_right)?,
+            Instruction::LogRShfZpX(op) => {
+                let addr = self.decode_zeropage_x(op);
+                self.modify_memory(addr, Vm::shift_right)?
+            }
+            Instruction::LRotAC => {
+                let value = self.rotate_left(self.get_register(Register::AC));
+                self.set_register(Register::AC, value);
+            }
+            Instruction::LRotAbs(op) => self.modify_memory(op, Vm::rotate_left)?,
+            Instruction::LRotAbsX(op) => {
+                let addr = self.decode_absolute_x(op, BusAccess::Write);
+                self.modify_memory(addr, Vm::rotate_left)?
+            }
+            Instruction::LRotZp(op) => self.modify_memory(op.into(), Vm::rotate_left)?,
+            Instruction::LRotZpX(op) => {
+                let addr = self.decode_zeropage_x(op);
+                self.modify_memory(addr, Vm::rotate_left)?
+            }
+            Instruction::RRotAC => {
+                let value = self.rotate_right(self.get_register(Register::AC));
+                self.set_register(Register::AC, value);
+            }
+            Instruction::RRotAbs(op) => self.modify_memory(op, Vm::rotate_right)?,
+            Instruction::RRotAbsX(op) => {
+                let addr = self.decode_absolute_x(op, BusAccess::Write);
+                self.modify_memory(addr, Vm::rotate_right)?
+            }
+            Instruction::RRotZp(op) => self.modify_memory(op.into(), Vm::rotate_right)?,
+            Instruction::RRotZpX(op) => {
+                let addr = self.decode_zeropage_x(op);
+                self.modify_memory(addr, Vm::rotate_right)?
+            }
+
+            // Logic
+            Instruction::AndImm(op) => self.and(op),
+            Instruction::AndAbs(op) => {
+                let value = self.read_byte(op);
+                self.and(value)
+            }
+            Instruction::AndAbsX(op) => {
+                let value = self.read_absolute_x(op);
+                self.and(value)
+            }
+            Instruction::AndAbsY(op) => {
+                let value = self.read_absolute_y(op);
+                self.and(value)
+            }
+            Instruction::AndZp(op) => {
+                let value = self.read_byte(op.into());
+                self.and(value)
+            }
+            Instruction::AndZpX(op) => {
+                let value = self.read_zeropage_x(op);
+                self.and(value)
+            }
+            Instruction::AndZpXInd(op) => {
+                let value = self.read_zeropage_x_indirect(op);
+                self.and(value)
+            }
+            Instruction::AndZpYInd(op) => {
+                let value = self.read_zeropage_indirect_y(op);
+                self.and(value)
+            }
+            Instruction::BitAbs(op) => {
+                let value = self.read_byte(op);
+                self.bit_test(value)
+            }
+            Instruction::BitZp(op) => {
+                let value = self.read_byte(op.into());
+                self.bit_test(value)
+            }
+            Instruction::EorImm(op) => self.eor(op),
+            Instruction::EorAbs(op) => {
+                let value = self.read_byte(op);
+                self.eor(value)
+            }
+            Instruction::EorAbsX(op) => {
+                let value = self.read_absolute_x(op);
+                self.eor(value)
+            }
+            Instruction::EorAbsY(op) => {
+                let value = self.read_absolute_y(op);
+                self.eor(value)
+            }
+            Instruction::EorZp(op) => {
+                let value = self.read_byte(op.into());
+                self.eor(value)
+            }
+            Instruction::EorZpX(op) => {
+                let value = self.read_zeropage_x(op);
+                self.eor(value)
+            }
+            Instruction::EorZpXInd(op) => {
+                let value = self.read_zeropage_x_indirect(op);
+                self.eor(value)
+            }
+            Instruction::EorZpYInd(op) => {
+                let value = self.read_zeropage_indirect_y(op);
+                self.eor(value)
+            }
+            Instruction::OrImm(op) => self.or(op),
+            Instruction::OrAbs(op) => {
+                let value = self.read_byte(op);
+                self.or(value)
+            }
+            Instruction::OrAbsX(op) => {
+                let value = self.read_absolute_x(op);
+                self.or(value)
+            }
+            Instruction::OrAbsY(op) => {
+                let value = self.read_absolute_y(op);
+                self.or(value)
+            }
+            Instruction::OrZp(op) => {
+                let value = self.read_byte(op.into());
+                self.or(value)
+            }
+            Instruction::OrZpX(op) => {
+                let value = self.read_zeropage_x(op);
+                self.or(value)
+            }
+            Instruction::OrZpXInd(op) => {
+                let value = self.read_zeropage_x_indirect(op);
+                self.or(value)
+            }
+            Instruction::OrZpYInd(op) => {
+                let value = self.read_zeropage_indirect_y(op);
+                self.or(value)
+            }
+
+            // Arithmetic
+            Instruction::AddImm(op) => self.add_with_carry(op),
             Instruction::AddAbs(op) => {
-                let carry = self.get_flag(RegisterFlag::Carry);
-                let acc = self.get_register(Register::AC);
-                let mem = self.read_memory(op).unwrap();
-                let res = (acc as u16) + (mem as u16) + (carry as u16);
-
-                // Set flags
-                self.update_flag_carry(res);
-                self.update_flag_zero(res);
-                self.update_flag_overflow(acc as u16, res);
-                self.update_flag_negative(res);
-
-                self.set_register(Register::AC, (res & 0xFF) as u8)
-            },
-            Instruction::AddAbsX(_) => todo!(),
-            Instruction::AddAbsY(_) => todo!(),
-            Instruction::AddZp(_) => todo!(),
-            Instruction::AddZpX(_) => todo!(),
-            Instruction::AddZpXInd(_) => todo!(),
-            Instruction::AddZpYInd(_) => todo!(),
-            Instruction::CmpACImm(_) => todo!(),
-            Instruction::CmpACAbs(_) => todo!(),
-            Instruction::CmpACAbsX(_) => todo!(),
-            Instruction::CmpACAbsY(_) => todo!(),
-            Instruction::CmpACZp(_) => todo!(),
-            Instruction::CmpACZpX(_) => todo!(),
-            Instruction::CmpACZpXInd(_) => todo!(),
-            Instruction::CmpACZpYInd(_) => todo!(),
-            Instruction::CmpXImm(_) => todo!(),
-            Instruction::CmpXAbs(_) => todo!(),
-            Instruction::CmpXZp(_) => todo!(),
-            Instruction::CmpYImm(_) => todo!(),
-            Instruction::CmpYAbs(_) => todo!(),
-            Instruction::CmpYZp(_) => todo!(),
-            Instruction::SubImm(_) => todo!(),
-            Instruction::SubAbs(_) => todo!(),
-            Instruction::SubAbsX(_) => todo!(),
-            Instruction::SubAbsY(_) => todo!(),
-            Instruction::SubZp(_) => todo!(),
-            Instruction::SubZpX(_) => todo!(),
-            Instruction::SubZpXInd(_) => todo!(),
-            Instruction::SubZpYInd(_) => todo!(),
-            Instruction::DecMemAbs(_) => todo!(),
-            Instruction::DecMemAbsX(_) => todo!(),
-            Instruction::DecMemZp(_) => todo!(),
-            Instruction::DecMemZpX(_) => todo!(),
-            Instruction::DecX => todo!(),
-            Instruction::DecY => todo!(),
-            Instruction::IncMemAbs(_) => todo!(),
-            Instruction::IncMemAbsX(_) => todo!(),
-            Instruction::IncMemZp(_) => todo!(),
-            Instruction::IncMemZpX(_) => todo!(),
-            Instruction::IncX => todo!(),
-            Instruction::IncY => todo!(),
-            Instruction::Break => todo!(),
-            Instruction::JumpAbs(op) => pc = op.into(),
-            Instruction::JumpAbsInc(_) => todo!(),
-            Instruction::JumpSubAbs(_) => todo!(),
-            Instruction::RetInt => todo!(),
-            Instruction::RetSub => todo!(),
+                let value = self.read_byte(op);
+                self.add_with_carry(value)
+            }
+            Instruction::AddAbsX(op) => {
+                let value = self.read_absolute_x(op);
+                self.add_with_carry(value)
+            }
+            Instruction::AddAbsY(op) => {
+                let value = self.read_absolute_y(op);
+                self.add_with_carry(value)
+            }
+            Instruction::AddZp(op) => {
+                let value = self.read_byte(op.into());
+                self.add_with_carry(value)
+            }
+            Instruction::AddZpX(op) => {
+                let value = self.read_zeropage_x(op);
+                self.add_with_carry(value)
+            }
+            Instruction::AddZpXInd(op) => {
+                let value = self.read_zeropage_x_indirect(op);
+                self.add_with_carry(value)
+            }
+            Instruction::AddZpYInd(op) => {
+                let value = self.read_zeropage_indirect_y(op);
+                self.add_with_carry(value)
+            }
+            Instruction::CmpACImm(op) => self.compare(Register::AC, op),
+            Instruction::CmpACAbs(op) => {
+                let value = self.read_byte(op);
+                self.compare(Register::AC, value)
+            }
+            Instruction::CmpACAbsX(op) => {
+                let value = self.read_absolute_x(op);
+                self.compare(Register::AC, value)
+            }
+            Instruction::CmpACAbsY(op) => {
+                let value = self.read_absolute_y(op);
+                self.compare(Register::AC, value)
+            }
+            Instruction::CmpACZp(op) => {
+                let value = self.read_byte(op.into());
+                self.compare(Register::AC, value)
+            }
+            Instruction::CmpACZpX(op) => {
+                let value = self.read_zeropage_x(op);
+                self.compare(Register::AC, value)
+            }
+            Instruction::CmpACZpXInd(op) => {
+                let value = self.read_zeropage_x_indirect(op);
+                self.compare(Register::AC, value)
+            }
+            Instruction::CmpACZpYInd(op) => {
+                let value = self.read_zeropage_indirect_y(op);
+                self.compare(Register::AC, value)
+            }
+            Instruction::CmpXImm(op) => self.compare(Register::X, op),
+            Instruction::CmpXAbs(op) => {
+                let value = self.read_byte(op);
+                self.compare(Register::X, value)
+            }
+            Instruction::CmpXZp(op) => {
+                let value = self.read_byte(op.into());
+                self.compare(Register::X, value)
+            }
+            Instruction::CmpYImm(op) => self.compare(Register::Y, op),
+            Instruction::CmpYAbs(op) => {
+                let value = self.read_byte(op);
+                self.compare(Register::Y, value)
+            }
+            Instruction::CmpYZp(op) => {
+                let value = self.read_byte(op.into());
+                self.compare(Register::Y, value)
+            }
+            Instruction::SubImm(op) => self.sub_with_carry(op),
+            Instruction::SubAbs(op) => {
+                let value = self.read_byte(op);
+                self.sub_with_carry(value)
+            }
+            Instruction::SubAbsX(op) => {
+                let value = self.read_absolute_x(op);
+                self.sub_with_carry(value)
+            }
+            Instruction::SubAbsY(op) => {
+                let value = self.read_absolute_y(op);
+                self.sub_with_carry(value)
+            }
+            Instruction::SubZp(op) => {
+                let value = self.read_byte(op.into());
+                self.sub_with_carry(value)
+            }
+            Instruction::SubZpX(op) => {
+                let value = self.read_zeropage_x(op);
+                self.sub_with_carry(value)
+            }
+            Instruction::SubZpXInd(op) => {
+                let value = self.read_zeropage_x_indirect(op);
+                self.sub_with_carry(value)
+            }
+            Instruction::SubZpYInd(op) => {
+                let value = self.read_zeropage_indirect_y(op);
+                self.sub_with_carry(value)
+            }
+            Instruction::DecMemAbs(op) => self.modify_memory(op, Vm::decrement)?,
+            Instruction::DecMemAbsX(op) => {
+                let addr = self.decode_absolute_x(op, BusAccess::Write);
+                self.modify_memory(addr, Vm::decrement)?
+            }
+            Instruction::DecMemZp(op) => self.modify_memory(op.into(), Vm::decrement)?,
+            Instruction::DecMemZpX(op) => {
+                let addr = self.decode_zeropage_x(op);
+                self.modify_memory(addr, Vm::decrement)?
+            }
+            Instruction::DecX => {
+                let value = self.decrement(self.get_register(Register::X));
+                self.set_register(Register::X, value)
+            }
+            Instruction::DecY => {
+                let value = self.decrement(self.get_register(Register::Y));
+                self.set_register(Register::Y, value)
+            }
+            Instruction::IncMemAbs(op) => self.modify_memory(op, Vm::increment)?,
+            Instruction::IncMemAbsX(op) => {
+                let addr = self.decode_absolute_x(op, BusAccess::Write);
+                self.modify_memory(addr, Vm::increment)?
+            }
+            Instruction::IncMemZp(op) => self.modify_memory(op.into(), Vm::increment)?,
+            Instruction::IncMemZpX(op) => {
+                let addr = self.decode_zeropage_x(op);
+                self.modify_memory(addr, Vm::increment)?
+            }
+            Instruction::IncX => {
+                let value = self.increment(self.get_register(Register::X));
+                self.set_register(Register::X, value)
+            }
+            Instruction::IncY => {
+                let value = self.increment(self.get_register(Register::Y));
+                self.set_register(Register::Y, value)
+            }
+
+            // Control
+            Instruction::Break => {
+                // BRK skips a padding byte, the pushed address is the opcode address + 2
+                self.push_address(pc.wrapping_add(1))?;
+                self.push(self.get_register(Register::SR) | 0b0011_0000)?;
+                self.set_flag(RegisterFlag::Interrupt, true);
+                pc = self.read_address(IRQ_VECTOR);
+            }
+            Instruction::JumpAbs(op) => pc = op,
+            Instruction::JumpAbsInc(op) => pc = self.decode_absolute_indirect(op),
+            Instruction::JumpSubAbs(op) => {
+                self.dummy_read_stack();
+                self.push_address(pc.wrapping_sub(1))?;
+                self.read_byte(pc.wrapping_sub(1));
+                pc = op;
+            }
+            Instruction::RetInt => {
+                self.dummy_read_stack();
+                let value = self.pull();
+                self.load_status(value);
+                pc = self.pull_address();
+            }
+            Instruction::RetSub => {
+                self.dummy_read_stack();
+                let addr = self.pull_address();
+                self.dummy_read(addr);
+                pc = addr.wrapping_add(1);
+            }
+
+            // Branch
             Instruction::BranchNotCarry(op) => {
-                if !self.get_flag(RegisterFlag::Carry) {
-                    pc = self.decode_relative(op)
-                }
+                pc = self.branch(!self.get_flag(RegisterFlag::Carry), pc, op)
             }
             Instruction::BranchCarry(op) => {
-                if self.get_flag(RegisterFlag::Carry) {
-                    pc = self.decode_relative(op)
-                }
+                pc = self.branch(self.get_flag(RegisterFlag::Carry), pc, op)
             }
             Instruction::BranchZero(op) => {
-                if self.get_flag(RegisterFlag::Zero) {
-                    pc = self.decode_relative(op)
-                }
+                pc = self.branch(self.get_flag(RegisterFlag::Zero), pc, op)
+            }
+            Instruction::BranchNeg(op) => {
+                pc = self.branch(self.get_flag(RegisterFlag::Negative), pc, op)
+            }
+            Instruction::BranchNotZero(op) => {
+                pc = self.branch(!self.get_flag(RegisterFlag::Zero), pc, op)
+            }
+            Instruction::BranchNotNeg(op) => {
+                pc = self.branch(!self.get_flag(RegisterFlag::Negative), pc, op)
+            }
+            Instruction::BranchNotOver(op) => {
+                pc = self.branch(!self.get_flag(RegisterFlag::Overflow), pc, op)
             }
-            Instruction::BranchNeg(_) => todo!(),
-            Instruction::BranchNotZero(_) => todo!(),
-            Instruction::BranchNotNeg(_) => todo!(),
-            Instruction::BranchNotOver(_) => todo!(),
-            Instruction::BranchOver(_) => todo!(),
+            Instruction::BranchOver(op) => {
+                pc = self.branch(self.get_flag(RegisterFlag::Overflow), pc, op)
+            }
+
+            // Flags
             Instruction::ClrCarry => self.set_flag(RegisterFlag::Carry, false),
-            Instruction::ClrDec => todo!(),
-            Instruction::ClrIntDis => todo!(),
-            Instruction::ClrOver => todo!(),
-            Instruction::SetCarry => todo!(),
-            Instruction::SetDec => todo!(),
-            Instruction::SetIntDis => todo!(),
+            Instruction::ClrDec => self.set_flag(RegisterFlag::Decimal, false),
+            Instruction::ClrIntDis => self.set_flag(RegisterFlag::Interrupt, false),
+            Instruction::ClrOver => self.set_flag(RegisterFlag::Overflow, false),
+            Instruction::SetCarry => self.set_flag(RegisterFlag::Carry, true),
+            Instruction::SetDec => self.set_flag(RegisterFlag::Decimal, true),
+            Instruction::SetIntDis => self.set_flag(RegisterFlag::Interrupt, true),
+
+            // Other
             Instruction::NoOp => {}
             Instruction::Jam => self.halt = true,
             Instruction::EmuSignal(op) => {
-                let fn_signal = self
+                // The handler is taken out of the Vm while it runs
+                let mut handler = self
                     .signal_handlers
-                    .get(&op)
+                    .remove(&op)
                     .ok_or(format!("Unknown signal : {}", op))?;
+                let res = handler.handle(self);
+                self.signal_handlers.entry(op).or_insert(handler);
+
+                match res? {
+                    SignalAction::Continue => {}
+                    SignalAction::Halt => self.halt = true,
+                    SignalAction::Break => self.signal_break = Some(op),
+                    SignalAction::Interrupt => self.request_irq(),
+                }
+            }
+        }
+
+        self.set_pc(pc);
+
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record_instruction(opcode_addr);
+            if instruction.addr_mode() == AddrMode::Relative {
+                // A taken branch costs at least one more cycle
+                coverage.record_branch(opcode_addr, self.cycles > cycles_after_fetch);
+            }
+        }
+
+        if let Some(profiler) = self.profiler.as_mut() {
+            let sp_after = self.registers[Register::SP as usize];
+            profiler.record(
+                opcode_addr,
+                instruction,
+                self.cycles - cycles_before,
+                (sp_before, sp_after),
+                pc,
+            );
+        }
+
+        self.tick_devices(self.cycles - cycles_before)?;
+        self.check_breakpoints();
+        Ok(())
+    }
+
+    fn tick_devices(&mut self, cycles: u64) -> Result<(), String> {
+        let mut host = HostPort::new(self.cycles, &mut self.input_mode);
+        self.bus.tick(cycles, &mut host);
+        let nmi_line = self.bus.nmi();
+        if nmi_line && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = nmi_line;
+        if let Some(err) = self.device_error.take().or(host.into_error()) {
+            return Err(err);
+        }
+        match self.memory_fault {
+            Some(fault) => {
+                self.halt = true;
+                Err(fault.into())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn check_breakpoints(&mut self) {
+        if !self.breakpoints.is_empty() {
+            let mut breakpoints = std::mem::take(&mut self.breakpoints);
+            self.last_break = breakpoints.check(self);
+            self.breakpoints = breakpoints;
+        }
+    }
+
+    // Interrupt sequence, as BRK without the padding byte and with the break flag clear
+    fn interrupt(&mut self, vector: u16) -> Result<(), String> {
+        let pc = self.get_pc();
+        self.dummy_read(pc);
+        self.dummy_read(pc);
+        self.push_address(pc)?;
+        self.push((self.get_register(Register::SR) & !0b0001_0000) | 0b0010_0000)?;
+        self.set_flag(RegisterFlag::Interrupt, true);
+        let target = self.read_address(vector);
+        self.set_pc(target);
+        self.cycles += 7;
+        Ok(())
+    }
+
+    // Memory access
+    fn read_byte(&mut self, addr: u16) -> u8 {
+        self.check_access(addr, BusAccess::Read);
+        self.read_bus(addr)
+    }
+
+    fn read_bus(&mut self, addr: u16) -> u8 {
+        let mut host = HostPort::new(self.cycles, &mut self.input_mode);
+        let value = self.bus.read(addr, &mut host);
+        if let Some(err) = host.into_error() {
+            self.device_error.get_or_insert(err);
+        }
+        self.record_bus_cycle(addr, value, BusAccess::Read);
+        value
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) -> Result<(), String> {
+        self.check_access(addr, BusAccess::Write);
+        self.write_bus(addr, value);
+        Ok(())
+    }
+
+    fn write_bus(&mut self, addr: u16, value: u8) {
+        self.record_bus_cycle(addr, value, BusAccess::Write);
+        self.store(addr, value);
+    }
+
+    fn store(&mut self, addr: u16, value: u8) {
+        // The registers of the devices are not memory, their whole state is kept
+        if let Some(history) = self.history.as_mut() {
+            match self.bus.is_mapped(addr) {
+                true => history.record_devices(|| self.bus.save_devices()),
+                false => history.record_write(addr, self.bus.peek(addr)),
+            }
+        }
+        self.bus.write(addr, value);
+    }
 
-                fn_signal(self)?
+    // Applies the policy of the faulty accesses, the bus then does what the hardware does
+    fn check_access(&mut self, addr: u16, access: BusAccess) {
+        if let Some(kind) = self.bus.fault(addr, access) {
+            let fault = MemoryFault {
+                kind,
+                access,
+                addr,
+                pc: self.get_pc(),
+            };
+            match self.access_policy(kind) {
+                AccessPolicy::Ignore => {}
+                AccessPolicy::Log => self.memory_log.push(fault),
+                AccessPolicy::Fault => {
+                    self.memory_fault.get_or_insert(fault);
+                }
             }
         }
+    }
 
-        self.set_register(Register::PCH, ((pc & 0xFF00) >> 8) as u8);
-        self.set_register(Register::PCL, (pc & 0xFF) as u8);
-        println!("{}", self);
+    // Reads and writes done by the hardware whose result is discarded, they are
+    // not faults of the program
+    fn dummy_read(&mut self, addr: u16) {
+        if self.cycle_accurate {
+            self.read_bus(addr);
+        }
+    }
 
+    fn dummy_write(&mut self, addr: u16, value: u8) -> Result<(), String> {
+        if self.cycle_accurate {
+            self.write_bus(addr, value);
+        }
         Ok(())
     }
 
+    fn record_bus_cycle(&mut self, addr: u16, value: u8, access: BusAccess) {
+        let bus_cycle = BusCycle {
+            addr,
+            value,
+            access,
+        };
+        if self.cycle_accurate {
+            self.bus_cycles.push(bus_cycle);
+        }
+        self.breakpoints.record_access(bus_cycle);
+    }
+
+    fn read_address(&mut self, addr: u16) -> u16 {
+        let mem_low = self.read_byte(addr);
+        let mem_high = self.read_byte(addr.wrapping_add(1));
+        ((mem_high as u16) << 8) | (mem_low as u16)
+    }
+
+    fn read_absolute_x(&mut self, value: u16) -> u8 {
+        let addr = self.decode_absolute_x(value, BusAccess::Read);
+        self.read_byte(addr)
+    }
+
+    fn read_absolute_y(&mut self, value: u16) -> u8 {
+        let addr = self.decode_absolute_y(value, BusAccess::Read);
+        self.read_byte(addr)
+    }
+
+    fn read_zeropage_x(&mut self, value: u8) -> u8 {
+        let addr = self.decode_zeropage_x(value);
+        self.read_byte(addr)
+    }
+
+    fn read_zeropage_y(&mut self, value: u8) -> u8 {
+        let addr = self.decode_zeropage_y(value);
+        self.read_byte(addr)
+    }
+
+    fn read_zeropage_x_indirect(&mut self, value: u8) -> u8 {
+        let addr = self.decode_zeropage_x_indirect(value);
+        self.read_byte(addr)
+    }
+
+    fn read_zeropage_indirect_y(&mut self, value: u8) -> u8 {
+        let addr = self.decode_zeropage_indirect_y(value, BusAccess::Read);
+        self.read_byte(addr)
+    }
+
+    fn modify_memory(&mut self, addr: u16, operation: fn(&mut Vm, u8) -> u8) -> Result<(), String> {
+        // The NMOS 6502 writes back the unmodified value before the result
+        let value = self.read_byte(addr);
+        self.dummy_write(addr, value)?;
+        let res = operation(self, value);
+        self.write_byte(addr, res)
+    }
+
+    // Stack
+    fn push(&mut self, value: u8) -> Result<(), String> {
+        let sp = self.get_register(Register::SP);
+        self.write_byte(STACK_PAGE | sp as u16, value)?;
+        self.set_register(Register::SP, sp.wrapping_sub(1));
+        Ok(())
+    }
+
+    fn pull(&mut self) -> u8 {
+        let sp = self.get_register(Register::SP).wrapping_add(1);
+        self.set_register(Register::SP, sp);
+        self.read_byte(STACK_PAGE | sp as u16)
+    }
+
+    fn push_address(&mut self, addr: u16) -> Result<(), String> {
+        self.push(((addr & 0xFF00) >> 8) as u8)?;
+        self.push((addr & 0xFF) as u8)
+    }
+
+    fn pull_address(&mut self) -> u16 {
+        let low = self.pull();
+        let high = self.pull();
+        ((high as u16) << 8) | (low as u16)
+    }
+
+    fn dummy_read_stack(&mut self) {
+        let sp = self.get_register(Register::SP);
+        self.dummy_read(STACK_PAGE | sp as u16);
+    }
+
+    fn branch(&mut self, condition: bool, pc: u16, value: u8) -> u16 {
+        if !condition {
+            return pc;
+        }
+
+        let target = self.decode_relative(pc, value);
+        self.dummy_read(pc);
+        self.cycles += 1;
+
+        if pc & 0xFF00 != target & 0xFF00 {
+            // The low byte of the target is first used with the old page
+            self.dummy_read((pc & 0xFF00) | (target & 0x00FF));
+            self.cycles += 1;
+        }
+        target
+    }
+
     // Addressing mode decoding
-    fn decode_absolute_x(&self, value: u16) -> u16 {
+    fn decode_absolute_x(&mut self, value: u16, access: BusAccess) -> u16 {
         let x = self.get_register(Register::X);
-        value + x as u16
+        self.decode_indexed(value, x, access)
     }
 
-    fn decode_absolute_y(&self, value: u16) -> u16 {
+    fn decode_absolute_y(&mut self, value: u16, access: BusAccess) -> u16 {
         let y = self.get_register(Register::Y);
-        value + y as u16
+        self.decode_indexed(value, y, access)
     }
 
-    fn decode_absolute_indirect(&self, value: u16) -> u16 {
-        let mem_low = self.read_memory(value).unwrap();
-        let mem_high = self.read_memory(value + 1).unwrap();
-        ((mem_high as u16) << 8) | (mem_low as u16)
+    fn decode_indexed(&mut self, base: u16, index: u8, access: BusAccess) -> u16 {
+        let addr = base.wrapping_add(index as u16);
+        let page_crossed = base & 0xFF00 != addr & 0xFF00;
+
+        // The CPU first reads with the high byte of the base address, which is only
+        // correct if no page was crossed. Writes always wait for the fixed address.
+        if page_crossed || access == BusAccess::Write {
+            self.dummy_read((base & 0xFF00) | (addr & 0x00FF));
+        }
+        if page_crossed && access == BusAccess::Read {
+            self.cycles += 1;
+        }
+        addr
     }
 
-    fn decode_relative(&self, value: u8) -> usize {
-        let pc: usize = (self.get_register(Register::PCH) as usize) << 8
-            | (self.get_register(Register::PCL) as usize);
+    fn decode_absolute_indirect(&mut self, value: u16) -> u16 {
+        // The NMOS 6502 does not carry into the high byte when fetching the pointer
+        let mem_low = self.read_byte(value);
+        let mem_high = self.read_byte((value & 0xFF00) | (value.wrapping_add(1) & 0x00FF));
+        ((mem_high as u16) << 8) | (mem_low as u16)
+    }
 
-        (pc as isize + isize::from((value) as i8)) as usize
+    fn decode_relative(&self, pc: u16, value: u8) -> u16 {
+        pc.wrapping_add_signed(value as i8 as i16)
     }
 
-    fn decode_zeropage_x(&self, value: u8) -> u8 {
+    fn decode_zeropage_x(&mut self, value: u8) -> u16 {
         let x = self.get_register(Register::X);
-        value.wrapping_add(x)
+        self.dummy_read(value.into());
+        value.wrapping_add(x).into()
     }
 
-    fn decode_zeropage_y(&self, value: u8) -> u8 {
+    fn decode_zeropage_y(&mut self, value: u8) -> u16 {
         let y = self.get_register(Register::Y);
-        value.wrapping_add(y)
+        self.dummy_read(value.into());
+        value.wrapping_add(y).into()
     }
 
-    fn decode_zeropage_x_indirect(&self, value: u8) -> u16 {
+    fn decode_zeropage_x_indirect(&mut self, value: u8) -> u16 {
         let x = self.get_register(Register::X);
-        let addr = value.wrapping_add(x) as u16;
+        self.dummy_read(value.into());
+        let addr = value.wrapping_add(x);
 
-        let mem_low = self.read_memory(addr).unwrap();
-        let mem_high = self.read_memory(addr + 1).unwrap();
+        let mem_low = self.read_byte(addr.into());
+        let mem_high = self.read_byte(addr.wrapping_add(1).into());
         ((mem_high as u16) << 8) | (mem_low as u16)
     }
 
-    fn decode_zeropage_indirect_y(&self, value: u8) -> u16 {
-        let addr = value as u16;
+    fn decode_zeropage_indirect_y(&mut self, value: u8, access: BusAccess) -> u16 {
+        let mem_low = self.read_byte(value.into());
+        let mem_high = self.read_byte(value.wrapping_add(1).into());
+
+        let y = self.get_register(Register::Y);
+        self.decode_indexed(((mem_high as u16) << 8) | (mem_low as u16), y, access)
+    }
+
+    // Operations
+    fn load_register(&mut self, register: Register, value: u8) {
+        self.set_register(register, value);
+        self.update_flag_zero(value.into());
+        self.update_flag_negative(value.into());
+    }
+
+    fn load_status(&mut self, value: u8) {
+        // The break flag only exists on the stack, bit 5 always reads as set
+        self.set_register(Register::SR, value | 0b0010_0000);
+        self.set_flag(RegisterFlag::Break, false);
+    }
+
+    fn and(&mut self, value: u8) {
+        let res = self.get_register(Register::AC) & value;
+        self.load_register(Register::AC, res)
+    }
+
+    fn eor(&mut self, value: u8) {
+        let res = self.get_register(Register::AC) ^ value;
+        self.load_register(Register::AC, res)
+    }
+
+    fn or(&mut self, value: u8) {
+        let res = self.get_register(Register::AC) | value;
+        self.load_register(Register::AC, res)
+    }
+
+    fn bit_test(&mut self, value: u8) {
+        let acc = self.get_register(Register::AC);
+        self.update_flag_zero((acc & value).into());
+        self.update_flag_negative(value.into());
+        self.set_flag(RegisterFlag::Overflow, value & 0x40 > 0);
+    }
+
+    fn compare(&mut self, register: Register, value: u8) {
+        let reg = self.get_register(register);
+        let res = reg.wrapping_sub(value);
+        self.set_flag(RegisterFlag::Carry, reg >= value);
+        self.update_flag_zero(res.into());
+        self.update_flag_negative(res.into());
+    }
+
+    fn add_with_carry(&mut self, value: u8) {
+        let carry = self.get_flag(RegisterFlag::Carry) as u16;
+        let acc = self.get_register(Register::AC);
+        let res = (acc as u16) + (value as u16) + carry;
+
+        if !self.get_flag(RegisterFlag::Decimal) {
+            // Set flags
+            self.update_flag_carry(res);
+            self.update_flag_zero(res);
+            self.update_flag_overflow(acc, value, res);
+            self.update_flag_negative(res);
+
+            self.set_register(Register::AC, (res & 0xFF) as u8);
+            return;
+        }
+
+        // NMOS decimal mode, N and V come from the intermediate high nibble and Z from the binary result
+        let mut low = (acc as u16 & 0x0F) + (value as u16 & 0x0F) + carry;
+        if low > 0x09 {
+            low += 0x06;
+        }
+        let mut high = (acc as u16 >> 4) + (value as u16 >> 4) + (low > 0x0F) as u16;
+
+        self.update_flag_zero(res);
+        self.set_flag(RegisterFlag::Negative, high & 0x08 > 0);
+        self.update_flag_overflow(acc, value, high << 4);
+        if high > 0x09 {
+            high += 0x06;
+        }
+        self.set_flag(RegisterFlag::Carry, high > 0x0F);
+
+        self.set_register(Register::AC, (((high << 4) | (low & 0x0F)) & 0xFF) as u8)
+    }
+
+    fn sub_with_carry(&mut self, value: u8) {
+        if !self.get_flag(RegisterFlag::Decimal) {
+            return self.add_with_carry(!value);
+        }
+
+        // NMOS decimal mode, all the flags come from the binary result
+        let borrow = !self.get_flag(RegisterFlag::Carry) as i16;
+        let acc = self.get_register(Register::AC);
+        let res = (acc as u16)
+            .wrapping_sub(value as u16)
+            .wrapping_sub(borrow as u16);
+
+        let mut low = (acc as i16 & 0x0F) - (value as i16 & 0x0F) - borrow;
+        if low < 0 {
+            low -= 0x06;
+        }
+        let mut high = (acc as i16 >> 4) - (value as i16 >> 4) - (low < 0) as i16;
+        if high < 0 {
+            high -= 0x06;
+        }
+
+        self.set_flag(RegisterFlag::Carry, res & 0xFF00 == 0);
+        self.update_flag_zero(res);
+        self.update_flag_overflow(acc, !value, res);
+        self.update_flag_negative(res);
+
+        self.set_register(Register::AC, (((high << 4) | (low & 0x0F)) & 0xFF) as u8)
+    }
+
+    fn shift_left(&mut self, value: u8) -> u8 {
+        let res = (value as u16) << 1;
+        self.update_flag_carry(res);
+        self.update_flag_zero(res);
+        self.update_flag_negative(res);
+        (res & 0xFF) as u8
+    }
 
-        let mem_low = self.read_memory(addr).unwrap();
-        let mem_high = self.read_memory(addr + 1).unwrap();
+    fn shift_right(&mut self, value: u8) -> u8 {
+        let res = value >> 1;
+        self.set_flag(RegisterFlag::Carry, value & 0x01 > 0);
+        self.update_flag_zero(res.into());
+        self.update_flag_negative(res.into());
+        res
+    }
 
-        let y = self.get_register(Register::X);
+    fn rotate_left(&mut self, value: u8) -> u8 {
+        let res = ((value as u16) << 1) | self.get_flag(RegisterFlag::Carry) as u16;
+        self.update_flag_carry(res);
+        self.update_flag_zero(res);
+        self.update_flag_negative(res);
+        (res & 0xFF) as u8
+    }
 
-        (((mem_high as u16) << 8) | (mem_low as u16)) + y as u16
+    fn rotate_right(&mut self, value: u8) -> u8 {
+        let res = (value >> 1) | ((self.get_flag(RegisterFlag::Carry) as u8) << 7);
+        self.set_flag(RegisterFlag::Carry, value & 0x01 > 0);
+        self.update_flag_zero(res.into());
+        self.update_flag_negative(res.into());
+        res
+    }
+
+    fn increment(&mut self, value: u8) -> u8 {
+        let res = value.wrapping_add(1);
+        self.update_flag_zero(res.into());
+        self.update_flag_negative(res.into());
+        res
+    }
+
+    fn decrement(&mut self, value: u8) -> u8 {
+        let res = value.wrapping_sub(1);
+        self.update_flag_zero(res.into());
+        self.update_flag_negative(res.into());
+        res
     }
 
     // Set flags - Use u16 to simplify flag checks
@@ -380,8 +1664,9 @@ impl Vm {
         }
     }
 
-    fn update_flag_overflow(&mut self, old: u16, value: u16) {
-        if value & 0x80 != old & 0x80 {
+    fn update_flag_overflow(&mut self, acc: u8, operand: u8, value: u16) {
+        // Overflow when both operands have the same sign and the result has another one
+        if !(acc ^ operand) & (acc ^ (value & 0xFF) as u8) & 0x80 > 0 {
             self.set_flag(RegisterFlag::Overflow, true)
         } else {
             self.set_flag(RegisterFlag::Overflow, false)
@@ -397,6 +1682,12 @@ impl Vm {
     }
 }
 
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl fmt::Display for Vm {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
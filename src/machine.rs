@@ -0,0 +1,623 @@
+//! Machine description, to build the `Vm` of a board from a TOML file.
+//!
+//! ```toml
+//! [cpu]
+//! variant = "6502"              # the only one emulated
+//! clock_hz = 1000000            # optional, the run binary then runs in real time
+//! cycle_accurate = false
+//! start = "$c000"               # optional, the reset vector is used by default
+//!
+//! [[ram]]
+//! start = "$0000"
+//! size = "$8000"
+//!
+//! [[rom]]                       # read-only for the CPU
+//! start = "$e000"
+//! size = "$2000"
+//! image = "monitor.rom"         # relative to the machine file
+//! offset = "$1000"              # optional, where the image goes in the region
+//!
+//! [[mirror]]                    # $0800-$1fff repeats the 2K from $0000
+//! start = "$0800"
+//! end = "$1fff"
+//! source = "$0000"
+//! size = "$0800"
+//!
+//! [memory]
+//! gaps = "unmapped"             # outside the regions : unmapped (default), open-bus or ram
+//! rom_write = "fault"           # ignore (default), log or fault
+//! unmapped_access = "log"       # ignore (default), log or fault
+//!
+//! [[device]]
+//! type = "via"                  # console, acia, via or riot
+//! base = "$6000"
+//! irq = "nmi"                   # irq (default), nmi or none
+//! ```
+//!
+//! Numbers are TOML integers or strings in the syntax of the assembler. The ACIA takes
+//! its host stream with `serial` (see `Acia::open`), the RIOT the address of its RAM
+//! with `ram`. A `banked` device is a ROM `image` or a RAM of `banks` banks behind a
+//! `mapper` (see `mapper`) : `fixed16k`, `banked8k`, or `ram-banks` with its `bank_size`
+//! and the address of its `registers`. A `screen` is a text screen of 40 `columns` and
+//! 25 `rows` by default. The gaps only exist when the file has RAM or ROM regions, the mirrors
+//! and the devices are reachable over them. The machine starts after the reset sequence
+//! of the CPU.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    acia::Acia,
+    bus::{AccessPolicy, FaultKind, IrqLine, MemoryAttribute},
+    console::Console,
+    mapper::{Banked, Banked8K, Fixed16K, Mapper, RamBanks},
+    riot::{Riot, RIOT_CHANNEL},
+    screen::{TextScreen, SCREEN_COLUMNS, SCREEN_ROWS},
+    symbols::parse_u32,
+    via::{Via, VIA_CHANNEL},
+    Vm,
+};
+
+/// An address or a size, a TOML integer or a string like `"$c000"`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Number {
+    Int(u32),
+    Text(String),
+}
+
+impl Number {
+    pub fn value(&self) -> Result<u32, String> {
+        match self {
+            Number::Int(value) => Ok(*value),
+            Number::Text(text) => parse_u32(text).ok_or(format!("Invalid number {}", text)),
+        }
+    }
+
+    pub fn address(&self) -> Result<u16, String> {
+        let value = self.value()?;
+        u16::try_from(value).map_err(|_| format!("Invalid address ${:x}", value))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuConfig {
+    #[serde(default = "default_variant")]
+    pub variant: String,
+    pub clock_hz: Option<u64>,
+    #[serde(default)]
+    pub cycle_accurate: bool,
+    pub start: Option<Number>,
+}
+
+fn default_variant() -> String {
+    "6502".to_string()
+}
+
+impl Default for CpuConfig {
+    fn default() -> Self {
+        Self {
+            variant: default_variant(),
+            clock_hz: None,
+            cycle_accurate: false,
+            start: None,
+        }
+    }
+}
+
+/// RAM or ROM, with the image loaded in it
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegionConfig {
+    pub start: Number,
+    pub size: Number,
+    pub image: Option<String>,
+    pub offset: Option<Number>,
+}
+
+/// Attributes of the addresses outside the regions, and the policies of the faulty accesses
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryConfig {
+    pub gaps: Option<MemoryAttribute>,
+    #[serde(default)]
+    pub rom_write: AccessPolicy,
+    #[serde(default)]
+    pub unmapped_access: AccessPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    pub start: Number,
+    pub end: Number,
+    pub source: Number,
+    pub size: Number,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum DeviceConfig {
+    Console {
+        base: Number,
+    },
+    Acia {
+        base: Number,
+        serial: Option<String>,
+        #[serde(default)]
+        irq: IrqLine,
+    },
+    Via {
+        base: Number,
+        #[serde(default)]
+        irq: IrqLine,
+    },
+    Riot {
+        base: Number,
+        ram: Number,
+        #[serde(default)]
+        irq: IrqLine,
+    },
+    Screen {
+        base: Number,
+        columns: Option<u8>,
+        rows: Option<u8>,
+    },
+    Banked {
+        base: Number,
+        mapper: MapperKind,
+        image: Option<String>,
+        banks: Option<Number>,
+        bank_size: Option<Number>,
+        registers: Option<Number>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MapperKind {
+    Fixed16k,
+    Banked8k,
+    RamBanks,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MachineConfig {
+    #[serde(default)]
+    pub cpu: CpuConfig,
+    #[serde(default)]
+    pub ram: Vec<RegionConfig>,
+    #[serde(default)]
+    pub rom: Vec<RegionConfig>,
+    #[serde(default)]
+    pub memory: MemoryConfig,
+    #[serde(default)]
+    pub mirror: Vec<MirrorConfig>,
+    #[serde(default)]
+    pub device: Vec<DeviceConfig>,
+    // Directory of the images
+    #[serde(skip)]
+    base_dir: PathBuf,
+}
+
+impl MachineConfig {
+    /// Parses a description, the images are relative to `base_dir`
+    pub fn parse<P: AsRef<Path>>(text: &str, base_dir: P) -> Result<Self, String> {
+        let mut config: MachineConfig = toml::from_str(text).map_err(|err| err.to_string())?;
+        config.base_dir = base_dir.as_ref().to_path_buf();
+        Ok(config)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text =
+            fs::read_to_string(path).map_err(|err| format!("{} : {}", path.display(), err))?;
+        Self::parse(&text, path.parent().unwrap_or(Path::new(".")))
+            .map_err(|err| format!("{} : {}", path.display(), err))
+    }
+
+    pub fn clock_hz(&self) -> Option<u64> {
+        self.cpu.clock_hz
+    }
+
+    /// Address, columns and rows of the first text screen
+    pub fn screen(&self) -> Result<Option<(u16, u8, u8)>, String> {
+        for device in self.device.iter() {
+            if let DeviceConfig::Screen {
+                base,
+                columns,
+                rows,
+            } = device
+            {
+                return Ok(Some((
+                    base.address()?,
+                    columns.unwrap_or(SCREEN_COLUMNS),
+                    rows.unwrap_or(SCREEN_ROWS),
+                )));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Builds the machine and runs the reset sequence
+    pub fn build(&self) -> Result<Vm, String> {
+        if self.cpu.variant != "6502" {
+            return Err(format!(
+                "Unsupported CPU variant {}, only the 6502 is emulated",
+                self.cpu.variant
+            ));
+        }
+
+        let mut vm = Vm::new();
+        vm.set_cycle_accurate(self.cpu.cycle_accurate);
+
+        let mut regions: Vec<(u32, u32)> = Vec::new();
+        for region in self.ram.iter().chain(self.rom.iter()) {
+            let (start, end) = self.load_region(&mut vm, region)?;
+            if let Some(other) = regions
+                .iter()
+                .find(|other| start <= other.1 && other.0 <= end)
+            {
+                return Err(format!(
+                    "Region ${:04x}-${:04x} overlaps the one at ${:04x}-${:04x}",
+                    start, end, other.0, other.1
+                ));
+            }
+            regions.push((start, end));
+        }
+
+        if !regions.is_empty() {
+            let gaps = self.memory.gaps.unwrap_or(MemoryAttribute::Unmapped);
+            vm.set_memory_attribute(0x0000, 0xFFFF, gaps)?;
+            for (idx, (start, end)) in regions.iter().enumerate() {
+                let attribute = match idx < self.ram.len() {
+                    true => MemoryAttribute::Ram,
+                    false => MemoryAttribute::ReadOnly,
+                };
+                vm.set_memory_attribute(*start as u16, *end as u16, attribute)?;
+            }
+        }
+        vm.set_access_policy(FaultKind::RomWrite, self.memory.rom_write);
+        vm.set_access_policy(FaultKind::Unmapped, self.memory.unmapped_access);
+
+        for mirror in self.mirror.iter() {
+            let size = u16::try_from(mirror.size.value()?)
+                .map_err(|_| "A mirror repeats less than 64K".to_string())?;
+            vm.mirror_memory(
+                mirror.start.address()?,
+                mirror.end.address()?,
+                mirror.source.address()?,
+                size,
+            )?;
+        }
+
+        let (mut vias, mut riots) = (0, 0);
+        for device in self.device.iter() {
+            let (base, irq) = match device {
+                DeviceConfig::Console { base } => {
+                    let base = base.address()?;
+                    vm.map_device(base, Console::stdio())?;
+                    (base, IrqLine::Disconnected)
+                }
+                DeviceConfig::Acia { base, serial, irq } => {
+                    let base = base.address()?;
+                    let mut acia = Acia::open(serial.as_deref().unwrap_or("stdio"))?;
+                    if let Some(clock_hz) = self.cpu.clock_hz {
+                        acia = acia.with_clock(clock_hz);
+                    }
+                    vm.map_device(base, acia)?;
+                    (base, *irq)
+                }
+                DeviceConfig::Via { base, irq } => {
+                    let base = base.address()?;
+                    // Each VIA records its two ports on its own channels
+                    let via = Via::new().with_channel(VIA_CHANNEL + 2 * vias);
+                    vias += 1;
+                    vm.map_device(base, via)?;
+                    (base, *irq)
+                }
+                DeviceConfig::Riot { base, ram, irq } => {
+                    let base = base.address()?;
+                    let riot = Riot::new().with_channel(RIOT_CHANNEL + 2 * riots);
+                    riots += 1;
+                    riot.map(&mut vm, ram.address()?, base)?;
+                    (base, *irq)
+                }
+                DeviceConfig::Screen {
+                    base,
+                    columns,
+                    rows,
+                } => {
+                    let base = base.address()?;
+                    let screen = TextScreen::with_size(
+                        columns.unwrap_or(SCREEN_COLUMNS),
+                        rows.unwrap_or(SCREEN_ROWS),
+                    );
+                    screen.map(&mut vm, base)?;
+                    (base, IrqLine::Disconnected)
+                }
+                DeviceConfig::Banked {
+                    base,
+                    mapper,
+                    image,
+                    banks,
+                    bank_size,
+                    registers,
+                } => {
+                    let base = base.address()?;
+                    let mapper: Box<dyn Mapper> = match mapper {
+                        MapperKind::Fixed16k => Box::new(Fixed16K::new()),
+                        MapperKind::Banked8k => Box::new(Banked8K::new()),
+                        MapperKind::RamBanks => {
+                            let bank_size = match bank_size.as_ref() {
+                                Some(bank_size) => bank_size.address()?,
+                                None => 0x2000,
+                            };
+                            Box::new(RamBanks::new(bank_size))
+                        }
+                    };
+                    let banked = match (image.as_ref(), banks.as_ref()) {
+                        (Some(image), None) => {
+                            let path = self.base_dir.join(image);
+                            let bytes = fs::read(&path)
+                                .map_err(|err| format!("{} : {}", path.display(), err))?;
+                            Banked::rom(bytes, mapper)
+                                .map_err(|err| format!("{} : {}", image, err))?
+                        }
+                        (None, Some(banks)) => Banked::ram(banks.value()? as usize, mapper)?,
+                        _ => {
+                            return Err(format!(
+                            "Banked device at ${:04x} needs either an image or a number of banks",
+                            base
+                        ))
+                        }
+                    };
+                    let registers = registers.as_ref().map(Number::address).transpose()?;
+                    banked.map(&mut vm, base, registers)?;
+                    (base, IrqLine::Disconnected)
+                }
+            };
+            vm.wire_device(base, irq);
+        }
+
+        vm.reset();
+        if let Some(start) = self.cpu.start.as_ref() {
+            vm.set_pc(start.address()?);
+        }
+        Ok(vm)
+    }
+
+    // Loads the image of a region, returns its first and last address
+    fn load_region(&self, vm: &mut Vm, region: &RegionConfig) -> Result<(u32, u32), String> {
+        let start = region.start.value()?;
+        let size = region.size.value()?;
+        let offset = match region.offset.as_ref() {
+            Some(offset) => offset.value()?,
+            None => 0,
+        };
+        let Some(end) = start
+            .checked_add(size)
+            .filter(|end| size > 0 && *end <= 0x10000)
+        else {
+            return Err(format!(
+                "Region ${:04x} of {} bytes does not fit in memory",
+                start, size
+            ));
+        };
+
+        if let Some(image) = region.image.as_ref() {
+            let path = self.base_dir.join(image);
+            let bytes = fs::read(&path).map_err(|err| format!("{} : {}", path.display(), err))?;
+            if offset as usize + bytes.len() > size as usize {
+                return Err(format!(
+                    "{} : {} bytes do not fit in the region ${:04x} of {} bytes from offset {}",
+                    image,
+                    bytes.len(),
+                    start,
+                    size,
+                    offset
+                ));
+            }
+            vm.copy_memory((start + offset) as usize, &bytes);
+        }
+        Ok((start, end - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::{
+        asm::assemble,
+        breakpoints::StopReason,
+        bus::{BusAccess, MemoryFault},
+        isa::{Register, RegisterFlag},
+    };
+
+    #[test]
+    fn test_build_machine() {
+        let dir = env::temp_dir().join(format!("rustemu_machine_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // JMP $E000 at $E000, the reset vector at the end
+        let mut rom = vec![0x4C, 0x00, 0xE0];
+        rom.resize(0x2000, 0xEA);
+        rom[0x1FFC..0x1FFE].copy_from_slice(&[0x00, 0xE0]);
+        fs::write(dir.join("monitor.rom"), &rom).unwrap();
+        fs::write(dir.join("data.bin"), [1, 2, 3]).unwrap();
+
+        let config = MachineConfig::parse(
+            r#"
+[cpu]
+clock_hz = 2000000
+
+[[ram]]
+start = 0
+size = "$0800"
+image = "data.bin"
+offset = "$0200"
+
+[[rom]]
+start = "$e000"
+size = 8192
+image = "monitor.rom"
+
+[[mirror]]
+start = "$0800"
+end = "$1fff"
+source = "$0000"
+size = "$0800"
+
+[[device]]
+type = "via"
+base = "$6000"
+irq = "nmi"
+
+[[device]]
+type = "riot"
+base = "$7000"
+ram = "$7080"
+
+[[device]]
+type = "banked"
+base = "$4000"
+mapper = "ram-banks"
+banks = 4
+bank_size = "$1000"
+registers = "$5000"
+
+[[device]]
+type = "screen"
+base = "$0400"
+columns = 20
+rows = 2
+"#,
+            &dir,
+        )
+        .unwrap();
+        assert_eq!(config.clock_hz(), Some(2_000_000));
+        assert_eq!(config.screen(), Ok(Some((0x0400, 20, 2))));
+
+        let mut vm = config.build().unwrap();
+        assert_eq!(vm.get_pc(), 0xE000);
+        assert_eq!(vm.get_register(Register::SP), 0xFD);
+        assert!(vm.get_flag(RegisterFlag::Interrupt));
+        assert_eq!(vm.read_memory(0x0201), Some(2));
+        assert_eq!(vm.read_memory(0x1202), Some(3));
+        assert_eq!(
+            vm.device_mappings(),
+            vec![
+                (0x6000, 0x600F),
+                (0x7080, 0x70FF),
+                (0x7000, 0x701F),
+                (0x4000, 0x4FFF),
+                (0x5000, 0x5000),
+                (0x0400, 0x0427)
+            ]
+        );
+        vm.write_memory(0x5000, 6).unwrap();
+        assert_eq!(vm.bank_at(0x4000), Some(2));
+
+        // The VIA timer drives the NMI, which ignores the I flag
+        vm.write_memory(0x600E, 0xC0).unwrap();
+        vm.write_memory(0x6004, 0x10).unwrap();
+        vm.write_memory(0x6005, 0x00).unwrap();
+        vm.copy_memory(0xFFFA, &[0x34, 0x12]);
+        while vm.get_pc() != 0x1234 && vm.cycle_count() < 100 {
+            vm.cycle().unwrap();
+        }
+        assert_eq!(vm.get_pc(), 0x1234);
+        assert_eq!(vm.read_memory(0x01FB), Some(0b0010_0100));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_faults() {
+        let program = assemble(
+            "
+    LoadACImm $42
+    StoreACAbs $f000
+    LoadACAbs $9000
+    Jam
+",
+            "faults.asm",
+        )
+        .unwrap();
+        let build = |policies: &str| {
+            let text = format!(
+                "[cpu]\nstart = \"$0200\"\n[[ram]]\nstart = 0\nsize = \"$1000\"\n\
+                 [[rom]]\nstart = \"$f000\"\nsize = \"$1000\"\n[memory]\n{}",
+                policies
+            );
+            let mut vm = MachineConfig::parse(&text, ".").unwrap().build().unwrap();
+            vm.copy_memory(0x0200, &program.bytes);
+            vm
+        };
+
+        let mut vm = build("rom_write = \"log\"\nunmapped_access = \"log\"");
+        assert_eq!(vm.run_until(100), Ok(StopReason::Halted));
+        assert_eq!(vm.read_memory(0xF000), Some(0));
+        assert_eq!(vm.get_register(Register::AC), 0xFF);
+        let log = vm.take_memory_log();
+        assert_eq!(log.len(), 2);
+        assert_eq!(
+            log[1].to_string(),
+            "Unmapped read at $9000 by the instruction at $0205"
+        );
+
+        let mut vm = build("rom_write = \"fault\"");
+        assert_eq!(
+            vm.run_until(100),
+            Ok(StopReason::MemoryFault(MemoryFault {
+                kind: FaultKind::RomWrite,
+                access: BusAccess::Write,
+                addr: 0xF000,
+                pc: 0x0202,
+            }))
+        );
+        assert!(vm.halt);
+        assert_eq!(vm.get_pc(), 0x0205);
+        assert!(vm
+            .write_memory(0xF000, 1)
+            .unwrap_err()
+            .contains("read-only"));
+        assert!(vm.take_memory_log().is_empty());
+    }
+
+    #[test]
+    fn test_machine_errors() {
+        let build = |text: &str| {
+            MachineConfig::parse(text, ".")
+                .and_then(|config| config.build())
+                .map(|_| ())
+        };
+
+        assert!(build("[cpu]\nvariant = \"65c02\"")
+            .unwrap_err()
+            .contains("Unsupported CPU variant 65c02"));
+        assert!(build("[[ram]]\nstart = \"$f000\"\nsize = \"$2000\"")
+            .unwrap_err()
+            .contains("does not fit"));
+        assert!(build("[[ram]]\nstart = 1\nsize = 4294967295")
+            .unwrap_err()
+            .contains("does not fit"));
+        assert!(
+            build("[[ram]]\nstart = 0\nsize = 16\n[[rom]]\nstart = 8\nsize = 16")
+                .unwrap_err()
+                .contains("overlaps")
+        );
+        assert!(build("[[device]]\ntype = \"via\"\nbase = \"$6000\"\nirq = \"fiq\"").is_err());
+        assert!(build("[[device]]\ntype = \"via\"\nbase = 1\nport = 2").is_err());
+        assert!(build("[[ram]]\nstart = \"$zz\"\nsize = 1")
+            .unwrap_err()
+            .contains("Invalid number $zz"));
+    }
+}
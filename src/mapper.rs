@@ -0,0 +1,419 @@
+//! Bank switching, to reach ROM and RAM images larger than the 64K of the address space.
+//!
+//! A `Banked` device shows its image through a window of the address space, cut in slots
+//! of the size of a bank. Its `Mapper` picks the bank seen in every slot from the values
+//! written to its registers : through the window as with most cartridges, or at
+//! addresses of their own given to `Banked::map`.
+//!
+//! | Mapper     | Window       | Slots                                                     |
+//! |------------|--------------|-----------------------------------------------------------|
+//! | `Fixed16K` | 32K          | a switchable 16K bank, then the last one                  |
+//! | `Banked8K` | 32K          | three switchable 8K banks, then the last one              |
+//! | `RamBanks` | one bank     | a switchable bank, selected by a register mapped apart    |
+//!
+//! The bank numbers wrap around the number of banks of the image, as the bank registers
+//! of the hardware ignore the bits they do not decode.
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    bus::{Device, HostPort},
+    state::{StateReader, StateWriter},
+    Vm,
+};
+
+/// Logic of the bank registers
+pub trait Mapper {
+    /// Number of addresses of the window
+    fn size(&self) -> u16;
+
+    /// Size of a bank, and of the slots of the window
+    fn bank_size(&self) -> u16;
+
+    /// Bank seen at an offset of the window, for an image of `banks` banks
+    fn bank(&self, offset: u16, banks: usize) -> usize;
+
+    /// Write of the CPU to the window, true when it went to a register instead of the memory
+    fn write(&mut self, offset: u16, value: u8) -> bool;
+
+    /// Number of registers mapped apart from the window
+    fn register_size(&self) -> u16 {
+        0
+    }
+
+    fn write_register(&mut self, _offset: u16, _value: u8) {}
+
+    /// Saves the banks selected by the registers
+    fn save_state(&self, writer: &mut StateWriter);
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String>;
+}
+
+/// 16K bank selected by any write to the window, the last bank is fixed after it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Fixed16K {
+    select: u8,
+}
+
+impl Fixed16K {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Mapper for Fixed16K {
+    fn size(&self) -> u16 {
+        0x8000
+    }
+
+    fn bank_size(&self) -> u16 {
+        0x4000
+    }
+
+    fn bank(&self, offset: u16, banks: usize) -> usize {
+        match offset < 0x4000 {
+            true => self.select as usize % banks,
+            false => banks - 1,
+        }
+    }
+
+    fn write(&mut self, _offset: u16, value: u8) -> bool {
+        self.select = value;
+        true
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u8(self.select);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.select = reader.get_u8()?;
+        Ok(())
+    }
+}
+
+/// Three 8K banks, each selected by the writes to its slot, the last bank is fixed
+/// in the fourth slot with the vectors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Banked8K {
+    slots: [u8; 3],
+}
+
+impl Banked8K {
+    pub fn new() -> Self {
+        Self { slots: [0, 1, 2] }
+    }
+}
+
+impl Default for Banked8K {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mapper for Banked8K {
+    fn size(&self) -> u16 {
+        0x8000
+    }
+
+    fn bank_size(&self) -> u16 {
+        0x2000
+    }
+
+    fn bank(&self, offset: u16, banks: usize) -> usize {
+        match self.slots.get((offset / 0x2000) as usize) {
+            Some(bank) => *bank as usize % banks,
+            None => banks - 1,
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) -> bool {
+        if let Some(bank) = self.slots.get_mut((offset / 0x2000) as usize) {
+            *bank = value;
+        }
+        true
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bytes(&self.slots);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.slots = reader.get_bytes(3)?.try_into().unwrap();
+        Ok(())
+    }
+}
+
+/// One bank of RAM in the window, selected by a register mapped apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RamBanks {
+    bank_size: u16,
+    select: u8,
+}
+
+impl RamBanks {
+    pub fn new(bank_size: u16) -> Self {
+        Self {
+            bank_size,
+            select: 0,
+        }
+    }
+}
+
+impl Mapper for RamBanks {
+    fn size(&self) -> u16 {
+        self.bank_size
+    }
+
+    fn bank_size(&self) -> u16 {
+        self.bank_size
+    }
+
+    fn bank(&self, _offset: u16, banks: usize) -> usize {
+        self.select as usize % banks
+    }
+
+    fn write(&mut self, _offset: u16, _value: u8) -> bool {
+        false
+    }
+
+    fn register_size(&self) -> u16 {
+        1
+    }
+
+    fn write_register(&mut self, _offset: u16, value: u8) {
+        self.select = value;
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u8(self.select);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.select = reader.get_u8()?;
+        Ok(())
+    }
+}
+
+/// ROM or RAM image larger than its window, switched by a mapper
+pub struct Banked {
+    image: Vec<u8>,
+    writable: bool,
+    mapper: Box<dyn Mapper>,
+}
+
+impl Banked {
+    /// ROM made of whole banks, the program can not write it
+    pub fn rom(image: Vec<u8>, mapper: Box<dyn Mapper>) -> Result<Self, String> {
+        let bank_size = mapper.bank_size() as usize;
+        if bank_size == 0 || image.is_empty() || !image.len().is_multiple_of(bank_size) {
+            return Err(format!(
+                "ROM image of {} bytes is not made of banks of {} bytes",
+                image.len(),
+                bank_size
+            ));
+        }
+        Ok(Self {
+            image,
+            writable: false,
+            mapper,
+        })
+    }
+
+    /// RAM of `banks` banks, cleared
+    pub fn ram(banks: usize, mapper: Box<dyn Mapper>) -> Result<Self, String> {
+        let bank_size = mapper.bank_size() as usize;
+        if bank_size == 0 || banks == 0 {
+            return Err("A banked RAM needs at least one bank".to_string());
+        }
+        Ok(Self {
+            image: vec![0; banks * bank_size],
+            writable: true,
+            mapper,
+        })
+    }
+
+    /// Maps the window, and the registers when the mapper has some apart from it.
+    /// The device stays shared with the host.
+    pub fn map(
+        self,
+        vm: &mut Vm,
+        window: u16,
+        registers: Option<u16>,
+    ) -> Result<Rc<RefCell<Banked>>, String> {
+        let needs_registers = self.mapper.register_size() > 0;
+        if needs_registers != registers.is_some() {
+            return Err(match needs_registers {
+                true => "The mapper needs the address of its registers".to_string(),
+                false => "The mapper has no registers apart from its window".to_string(),
+            });
+        }
+
+        let banked = Rc::new(RefCell::new(self));
+        vm.map_device(window, banked.clone())?;
+        if let Some(registers) = registers {
+            if let Err(err) = vm.map_device(registers, BankRegisters(banked.clone())) {
+                vm.unmap_device(window);
+                return Err(err);
+            }
+        }
+        Ok(banked)
+    }
+
+    pub fn banks(&self) -> usize {
+        self.image.len() / self.mapper.bank_size() as usize
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    fn position(&self, offset: u16) -> usize {
+        let bank_size = self.mapper.bank_size();
+        self.mapper.bank(offset, self.banks()) * bank_size as usize + (offset % bank_size) as usize
+    }
+}
+
+impl Device for Banked {
+    fn size(&self) -> u16 {
+        self.mapper.size()
+    }
+
+    fn read(&mut self, offset: u16, _host: &mut HostPort) -> u8 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        self.image[self.position(offset)]
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        if !self.mapper.write(offset, value) && self.writable {
+            let position = self.position(offset);
+            self.image[position] = value;
+        }
+    }
+
+    fn bank(&self, offset: u16) -> Option<usize> {
+        Some(self.mapper.bank(offset, self.banks()))
+    }
+
+    // The registers mapped apart are saved with the window, the ROM images are not saved
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.mapper.save_state(writer);
+        if self.writable {
+            writer.put_bytes(&self.image);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.mapper.load_state(reader)?;
+        if self.writable {
+            let image = reader.get_bytes(self.image.len())?;
+            self.image.copy_from_slice(image);
+        }
+        Ok(())
+    }
+}
+
+/// Bank registers of a shared `Banked` mapped apart from its window
+pub struct BankRegisters(pub Rc<RefCell<Banked>>);
+
+impl Device for BankRegisters {
+    fn size(&self) -> u16 {
+        self.0.borrow().mapper.register_size()
+    }
+
+    fn read(&mut self, offset: u16, _host: &mut HostPort) -> u8 {
+        self.peek(offset)
+    }
+
+    // The registers are write only
+    fn peek(&self, _offset: u16) -> u8 {
+        0
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        self.0.borrow_mut().mapper.write_register(offset, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // Banks filled with their number
+    fn numbered_banks(banks: u8, bank_size: usize) -> Vec<u8> {
+        (0..banks).flat_map(|bank| vec![bank; bank_size]).collect()
+    }
+
+    #[test]
+    fn test_rom_mappers() {
+        let mut rom = Banked::rom(numbered_banks(8, 0x4000), Box::new(Fixed16K::new())).unwrap();
+        assert_eq!(rom.banks(), 8);
+        assert_eq!((rom.peek(0x0000), rom.peek(0x7FFF)), (0, 7));
+        rom.write(0x1234, 0x0A);
+        assert_eq!((rom.peek(0x0000), rom.peek(0x4000)), (2, 7));
+        assert_eq!(rom.bank(0x3FFF), Some(2));
+
+        let mut rom = Banked::rom(numbered_banks(16, 0x2000), Box::new(Banked8K::new())).unwrap();
+        assert_eq!(rom.bank(0x2000), Some(1));
+        rom.write(0x4000, 9);
+        rom.write(0x6000, 3);
+        assert_eq!(
+            [0x0000, 0x2000, 0x4000, 0x6000].map(|offset| rom.peek(offset)),
+            [0, 1, 9, 15]
+        );
+
+        assert!(Banked::rom(vec![0; 0x3000], Box::new(Banked8K::new())).is_err());
+        assert!(Banked::ram(0, Box::new(RamBanks::new(0x100))).is_err());
+    }
+
+    #[test]
+    fn test_ram_banks() {
+        // Writes its number in the first byte of every bank, then reads back bank 1
+        let source = "
+BANK = $5fff
+WINDOW = $6000
+    LoadXImm 0
+fill:
+    StoreXAbs BANK
+    StoreXAbs WINDOW
+    IncX
+    CmpXImm 4
+    BranchNotZero fill
+    LoadACImm 1
+    StoreACAbs BANK
+    LoadACAbs WINDOW
+    Jam
+";
+        let mut vm = Vm::new();
+        vm.copy_memory(0x0200, &assemble(source, "banks.asm").unwrap().bytes);
+        vm.set_pc(0x0200);
+        let ram = Banked::ram(4, Box::new(RamBanks::new(0x2000)))
+            .unwrap()
+            .map(&mut vm, 0x6000, Some(0x5FFF))
+            .unwrap();
+        assert!(Banked::ram(1, Box::new(RamBanks::new(0x10)))
+            .unwrap()
+            .map(&mut vm, 0x9000, None)
+            .is_err());
+        assert_eq!(
+            vm.device_mappings(),
+            vec![(0x6000, 0x7FFF), (0x5FFF, 0x5FFF)]
+        );
+
+        while !vm.halt {
+            vm.cycle().unwrap();
+        }
+        assert_eq!(vm.get_register(crate::isa::Register::AC), 1);
+        assert_eq!(vm.bank_at(0x6000), Some(1));
+        assert_eq!(vm.bank_at(0x5FFF), None);
+        let image = ram.borrow().image().to_vec();
+        assert_eq!(
+            [image[0], image[0x2000], image[0x4000], image[0x6000]],
+            [0, 1, 2, 3]
+        );
+    }
+}
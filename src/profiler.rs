@@ -0,0 +1,402 @@
+//! Cycle profiler.
+//!
+//! The cycles of every instruction are attributed to its address, to the routines on the
+//! call stack and to the loops it belongs to. The call stack is rebuilt from the JSR and
+//! BRK instructions, a routine is left when the stack pointer goes back above its return
+//! address, so the routines which drop their return address are handled as well.
+//! Loops are found from the backward branches and jumps.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write,
+};
+
+use crate::{
+    isa::{AddrMode, Instruction},
+    symbols::SymbolTable,
+    Vm,
+};
+
+/// Cycles and executions of an address, a routine or a loop
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileEntry {
+    pub count: u64,
+    pub cycles: u64,
+}
+
+/// Cycles of a routine, the inclusive ones contain the routines it calls
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RoutineProfile {
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Frame {
+    routine: u16,
+    // Stack pointer before the call, it is back at this value after the return
+    return_sp: u8,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Profiler {
+    total: ProfileEntry,
+    instructions: BTreeMap<u16, ProfileEntry>,
+    routines: BTreeMap<u16, RoutineProfile>,
+    // First and last address of the loops
+    loops: BTreeSet<(u16, u16)>,
+    stacks: HashMap<Vec<u16>, u64>,
+    frames: Vec<Frame>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attributes the cycles of an executed instruction, the stack pointers are the
+    /// ones before and after the instruction and `next_pc` is where it continues
+    pub fn record(
+        &mut self,
+        addr: u16,
+        instruction: Instruction,
+        cycles: u64,
+        sp: (u8, u8),
+        next_pc: u16,
+    ) {
+        let (sp_before, sp_after) = sp;
+
+        self.total.count += 1;
+        self.total.cycles += cycles;
+        let entry = self.instructions.entry(addr).or_default();
+        entry.count += 1;
+        entry.cycles += cycles;
+
+        // The instruction belongs to the routine running before it, even a JSR or an RTS
+        let stack: Vec<u16> = self.frames.iter().map(|frame| frame.routine).collect();
+        *self.stacks.entry(stack).or_default() += cycles;
+        if let Some(frame) = self.frames.last() {
+            self.routines.entry(frame.routine).or_default().exclusive += cycles;
+        }
+        let mut seen = Vec::new();
+        for frame in self.frames.iter() {
+            if !seen.contains(&frame.routine) {
+                seen.push(frame.routine);
+                self.routines.entry(frame.routine).or_default().inclusive += cycles;
+            }
+        }
+
+        match instruction {
+            Instruction::JumpSubAbs(_) | Instruction::Break => {
+                self.routines.entry(next_pc).or_default().calls += 1;
+                self.frames.push(Frame {
+                    routine: next_pc,
+                    return_sp: sp_before,
+                });
+            }
+            Instruction::JumpAbs(_) if next_pc <= addr => {
+                self.loops.insert((next_pc, addr));
+            }
+            _ if instruction.addr_mode() == AddrMode::Relative && next_pc <= addr => {
+                self.loops.insert((next_pc, addr));
+            }
+            _ => {}
+        }
+
+        while let Some(frame) = self.frames.last() {
+            if (frame.return_sp.wrapping_sub(sp_after) as i8) > 0 {
+                break;
+            }
+            self.frames.pop();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn total(&self) -> ProfileEntry {
+        self.total
+    }
+
+    pub fn instruction(&self, addr: u16) -> Option<ProfileEntry> {
+        self.instructions.get(&addr).copied()
+    }
+
+    pub fn routine(&self, addr: u16) -> Option<RoutineProfile> {
+        self.routines.get(&addr).copied()
+    }
+
+    /// Executions and cycles of the instructions between the first and last address of a
+    /// loop, with its number of iterations : the executions of the first instruction, none
+    /// when the run stopped before it. The routines called from the loop are not counted
+    pub fn loops(&self) -> Vec<((u16, u16), u64, ProfileEntry)> {
+        self.loops
+            .iter()
+            .map(|range| {
+                let body = self.instructions.range(range.0..=range.1).fold(
+                    ProfileEntry::default(),
+                    |body, (_, entry)| ProfileEntry {
+                        count: body.count + entry.count,
+                        cycles: body.cycles + entry.cycles,
+                    },
+                );
+                let iterations = self
+                    .instructions
+                    .get(&range.0)
+                    .map_or(0, |entry| entry.count);
+                (*range, iterations, body)
+            })
+            .collect()
+    }
+
+    /// Cycles outside of any routine, the top level of the program
+    pub fn top_level(&self) -> u64 {
+        self.stacks.get(&Vec::new()).copied().unwrap_or_default()
+    }
+
+    fn percent(&self, cycles: u64) -> f64 {
+        if self.total.cycles == 0 {
+            0.0
+        } else {
+            cycles as f64 * 100.0 / self.total.cycles as f64
+        }
+    }
+
+    /// Text report with the routines, the loops and the `limit` hottest instructions,
+    /// each section sorted by decreasing cycles
+    pub fn report(&self, vm: &Vm, symbols: &SymbolTable, limit: usize) -> String {
+        let mut report = String::new();
+        writeln!(
+            report,
+            "Total : {} cycles, {} instructions",
+            self.total.cycles, self.total.count
+        )
+        .unwrap();
+
+        writeln!(
+            report,
+            "\nRoutines{:>26}{:>12}{:>8}{:>12}{:>8}",
+            "calls", "inclusive", "%", "exclusive", "%"
+        )
+        .unwrap();
+        let mut routines: Vec<(String, RoutineProfile)> = self
+            .routines
+            .iter()
+            .map(|(addr, routine)| (routine_name(symbols, *addr), *routine))
+            .collect();
+        routines.push((
+            "<top level>".to_string(),
+            RoutineProfile {
+                calls: 1,
+                inclusive: self.total.cycles,
+                exclusive: self.top_level(),
+            },
+        ));
+        routines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+        for (name, routine) in routines {
+            writeln!(
+                report,
+                "  {:<26}{:>6}{:>12}{:>7.2}%{:>12}{:>7.2}%",
+                name,
+                routine.calls,
+                routine.inclusive,
+                self.percent(routine.inclusive),
+                routine.exclusive,
+                self.percent(routine.exclusive)
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            report,
+            "\nLoops{:>29}{:>12}{:>8}{:>12}",
+            "iterations", "cycles", "%", "per iter"
+        )
+        .unwrap();
+        let mut loops = self.loops();
+        loops.sort_by(|a, b| b.2.cycles.cmp(&a.2.cycles).then(a.0.cmp(&b.0)));
+        for ((first, last), iterations, body) in loops {
+            let per_iteration = match iterations {
+                0 => "-".to_string(),
+                _ => format!("{:.1}", body.cycles as f64 / iterations as f64),
+            };
+            writeln!(
+                report,
+                "  {:<26}{:>6}{:>12}{:>7.2}%{:>12}",
+                format!("{}..${:04x}", symbols.describe(first), last),
+                iterations,
+                body.cycles,
+                self.percent(body.cycles),
+                per_iteration
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            report,
+            "\nInstructions{:>22}{:>12}{:>8}",
+            "count", "cycles", "%"
+        )
+        .unwrap();
+        let mut instructions: Vec<(u16, ProfileEntry)> = self
+            .instructions
+            .iter()
+            .map(|(addr, entry)| (*addr, *entry))
+            .collect();
+        instructions.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        for (addr, entry) in instructions.into_iter().take(limit) {
+            let instruction = vm
+                .decode_at(addr)
+                .map(|instruction| instruction.to_string())
+                .unwrap_or_default();
+            writeln!(
+                report,
+                "  {:<26}{:>6}{:>12}{:>7.2}%  {}",
+                symbols.describe(addr),
+                entry.count,
+                entry.cycles,
+                self.percent(entry.cycles),
+                instruction
+            )
+            .unwrap();
+        }
+
+        report
+    }
+
+    /// Cycles of every call stack in the folded format of the flame graph tools,
+    /// like `main;draw;plot 1234`
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| {
+                let mut names = vec!["main".to_string()];
+                names.extend(stack.iter().map(|addr| routine_name(symbols, *addr)));
+                format!("{} {}", names.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+
+        let mut folded = lines.join("\n");
+        folded.push('\n');
+        folded
+    }
+}
+
+fn routine_name(symbols: &SymbolTable, addr: u16) -> String {
+    match symbols.label_at(addr) {
+        Some(label) => label.to_string(),
+        None => format!("${:04x}", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    const SOURCE: &str = "
+    LoadXImm 0
+loop:
+    JumpSubAbs double
+    IncX
+    CmpXImm 3
+    BranchNotZero loop
+    Jam
+double:
+    JumpSubAbs inner
+    RetSub
+inner:
+    ArmLShfAC
+    RetSub
+";
+
+    fn profile() -> (Vm, SymbolTable) {
+        let program = assemble(SOURCE, "profile.asm").unwrap();
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &program.bytes);
+        vm.enable_profiler();
+        while !vm.halt {
+            vm.cycle().unwrap();
+        }
+        (vm, program.symbols)
+    }
+
+    #[test]
+    fn test_profiler_routines() {
+        let (vm, symbols) = profile();
+        let profiler = vm.profiler().unwrap();
+        let double = symbols.address_of("double").unwrap();
+        let inner = symbols.address_of("inner").unwrap();
+
+        assert_eq!(profiler.total().cycles, vm.cycle_count());
+        assert_eq!(profiler.instruction(0x0002).unwrap().count, 3);
+
+        // JSR 6 + RTS 6 in double, ASL 2 + RTS 6 in inner
+        let double = profiler.routine(double).unwrap();
+        let inner = profiler.routine(inner).unwrap();
+        assert_eq!(double.calls, 3);
+        assert_eq!(double.exclusive, 3 * 12);
+        assert_eq!(inner.exclusive, 3 * 8);
+        assert_eq!(double.inclusive, double.exclusive + inner.inclusive);
+        assert_eq!(
+            profiler.top_level() + double.inclusive,
+            profiler.total().cycles
+        );
+
+        let loops = profiler.loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].0, (0x0002, 0x0008));
+        assert_eq!(loops[0].1, 3);
+    }
+
+    #[test]
+    fn test_profiler_reports() {
+        let (vm, symbols) = profile();
+        let profiler = vm.profiler().unwrap();
+
+        let folded = profiler.folded(&symbols);
+        assert_eq!(
+            folded,
+            format!(
+                "main {}\nmain;double 36\nmain;double;inner 24\n",
+                profiler.top_level()
+            )
+        );
+
+        let report = profiler.report(&vm, &symbols, 3);
+        assert!(report.starts_with(&format!("Total : {} cycles, ", profiler.total().cycles)));
+        assert!(report.contains("  double                         3          60"));
+        assert!(report.contains("$0002 <loop>..$0008"));
+        assert_eq!(
+            report
+                .lines()
+                .skip_while(|line| !line.starts_with("Instructions"))
+                .count(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_profiler_loop_not_entered() {
+        // The JMP back to $0003 is the last instruction, the loop never ran
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &[0x4C, 0x04, 0x00, 0xF2, 0x4C, 0x03, 0x00]);
+        vm.enable_profiler();
+        vm.cycle().unwrap();
+        vm.cycle().unwrap();
+
+        let profiler = vm.profiler().unwrap();
+        assert_eq!(
+            profiler.loops(),
+            vec![((0x0003, 0x0004), 0, profiler.instruction(0x0004).unwrap())]
+        );
+        let report = profiler.report(&vm, &SymbolTable::default(), 3);
+        assert!(
+            report.contains("  $0003..$0004                   0           3  50.00%           -")
+        );
+    }
+}
@@ -0,0 +1,284 @@
+//! Recording and replay of the values given by the host to the emulated program.
+//!
+//! Signal handlers and devices ask for their input through `Vm::host_input`. When recording,
+//! every value is logged with the cycle it was delivered at; when replaying, the values are
+//! taken back from the log, so the run is identical without the host. The log is a text
+//! file with one entry per line. The states polled after every instruction through
+//! `HostPort::poll` are only logged when they change. The entries are :
+//! `input 1234 $01 $41` gives the value `$41` delivered at cycle 1234 on the channel `$01`,
+//! `hash 5678 0123456789abcdef` gives the state hash of the machine at the end of the run.
+
+use std::{fmt, str::FromStr};
+
+use crate::symbols::parse_number;
+
+/// Value given by the host, channels are chosen by the handlers and devices
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub cycle: u64,
+    pub channel: u16,
+    pub value: u8,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InputLog {
+    pub events: Vec<InputEvent>,
+    /// Cycle count and state hash at the end of the recorded run
+    pub final_state: Option<(u64, u64)>,
+}
+
+impl InputLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Where the values asked through `Vm::host_input` come from
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum InputMode {
+    /// From the host only
+    #[default]
+    Live,
+    /// From the host, and logged
+    Record(InputLog),
+    /// From the log, `next` is the index of the next event
+    Replay { log: InputLog, next: usize },
+}
+
+impl InputMode {
+    /// Value given by the host on a channel at a cycle, see `Vm::host_input`
+    pub fn input<F>(&mut self, cycle: u64, channel: u16, live: F) -> Result<u8, String>
+    where
+        F: FnOnce() -> u8,
+    {
+        match self {
+            InputMode::Live => Ok(live()),
+            InputMode::Record(log) => {
+                let value = live();
+                log.events.push(InputEvent {
+                    cycle,
+                    channel,
+                    value,
+                });
+                Ok(value)
+            }
+            InputMode::Replay { log, next } => {
+                let event = log.events.get(*next).ok_or(format!(
+                    "Replay diverged : input asked on channel ${:02x} at cycle {} after the end of the log",
+                    channel, cycle
+                ))?;
+                if event.cycle != cycle || event.channel != channel {
+                    return Err(format!(
+                        "Replay diverged : input asked on channel ${:02x} at cycle {}, recorded on channel ${:02x} at cycle {}",
+                        channel, cycle, event.channel, event.cycle
+                    ));
+                }
+                *next += 1;
+                Ok(event.value)
+            }
+        }
+    }
+
+    /// Value given by the host only when it has something new, see `HostPort::poll`.
+    /// Nothing is logged while it has not, a replay takes the value logged at this cycle.
+    pub fn poll<F>(&mut self, cycle: u64, channel: u16, live: F) -> Result<Option<u8>, String>
+    where
+        F: FnOnce() -> Option<u8>,
+    {
+        match self {
+            InputMode::Live => Ok(live()),
+            InputMode::Record(log) => {
+                let value = live();
+                if let Some(value) = value {
+                    log.events.push(InputEvent {
+                        cycle,
+                        channel,
+                        value,
+                    });
+                }
+                Ok(value)
+            }
+            InputMode::Replay { log, next } => match log.events.get(*next) {
+                Some(event) if event.cycle < cycle => Err(format!(
+                    "Replay diverged : input recorded on channel ${:02x} at cycle {} was not asked",
+                    event.channel, event.cycle
+                )),
+                Some(event) if event.cycle == cycle && event.channel == channel => {
+                    *next += 1;
+                    Ok(Some(event.value))
+                }
+                _ => Ok(None),
+            },
+        }
+    }
+}
+
+/// FNV-1a hash, stable across platforms and versions of Rust
+pub fn state_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl fmt::Display for InputLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for event in self.events.iter() {
+            writeln!(
+                f,
+                "input {} ${:02x} ${:02x}",
+                event.cycle, event.channel, event.value
+            )?;
+        }
+        if let Some((cycle, hash)) = self.final_state {
+            writeln!(f, "hash {} {:016x}", cycle, hash)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for InputLog {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut log = InputLog::new();
+
+        for (idx, line) in s.lines().enumerate() {
+            let error = || format!("Line {} : invalid input log entry {}", idx + 1, line);
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+
+            match tokens.as_slice() {
+                [] => {}
+                ["input", cycle, channel, value] => {
+                    let value = parse_number(value)
+                        .and_then(|value| u8::try_from(value).ok())
+                        .ok_or_else(error)?;
+                    log.events.push(InputEvent {
+                        cycle: cycle.parse().map_err(|_| error())?,
+                        channel: parse_number(channel).ok_or_else(error)?,
+                        value,
+                    });
+                }
+                ["hash", cycle, hash] => {
+                    log.final_state = Some((
+                        cycle.parse().map_err(|_| error())?,
+                        u64::from_str_radix(hash, 16).map_err(|_| error())?,
+                    ));
+                }
+                _ => return Err(error()),
+            }
+        }
+
+        Ok(log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU8, Ordering};
+
+    use super::*;
+    use crate::{
+        isa::Register,
+        via::{Control, Via},
+        Vm,
+    };
+
+    static NEXT_KEY: AtomicU8 = AtomicU8::new(0x41);
+
+    fn read_key(vm: &mut Vm) -> Result<(), String> {
+        let key = vm.host_input(1, || NEXT_KEY.fetch_add(1, Ordering::Relaxed))?;
+        vm.set_register(Register::AC, key);
+        Ok(())
+    }
+
+    fn run(mode: InputMode) -> Vm {
+        #[rustfmt::skip]
+        let prog = [
+            0xFF, 0x00,       // SIG $00
+            0x85, 0x10,       // STA $10
+            0xFF, 0x00,       // SIG $00
+            0x85, 0x11,       // STA $11
+            0xF2,             // JAM
+        ];
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &prog);
+        vm.define_handler(0, read_key);
+        vm.set_input_mode(mode);
+        while !vm.halt {
+            vm.cycle().unwrap();
+        }
+        vm
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let mut recorded = run(InputMode::Record(InputLog::new()));
+        let log = recorded.finish_recording().unwrap();
+        assert_eq!(log.events.len(), 2);
+        assert_eq!(log.events[0].cycle, 2);
+        assert_eq!(log.events[1].cycle, 7);
+        assert_eq!(log.final_state, Some((12, recorded.state_hash())));
+
+        // The host gives other values now, the replay must not ask for them
+        NEXT_KEY.store(0x00, Ordering::Relaxed);
+        let log: InputLog = log.to_string().parse().unwrap();
+        let replayed = run(InputMode::Replay { log, next: 0 });
+        assert_eq!(
+            replayed.read_memory(0x10).unwrap(),
+            recorded.read_memory(0x10).unwrap()
+        );
+        assert_eq!(replayed.state_hash(), recorded.state_hash());
+        assert_eq!(replayed.check_replay(), Ok(()));
+    }
+
+    #[test]
+    fn test_replay_divergence() {
+        let log: InputLog = "input 3 $01 $41\nhash 11 0\n".parse().unwrap();
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &[0xFF, 0x00]);
+        vm.define_handler(0, read_key);
+        vm.set_input_mode(InputMode::Replay { log, next: 0 });
+        assert!(vm
+            .cycle()
+            .unwrap_err()
+            .contains("at cycle 2, recorded on channel $01 at cycle 3"));
+
+        assert!("input 3 $01".parse::<InputLog>().is_err());
+        assert!("input 3 $01 $100".parse::<InputLog>().is_err());
+    }
+
+    fn run_via(via: Via, mode: InputMode) -> Vm {
+        // LDA #$10 / STA $9005 / JAM : starts the T1 timer of the VIA
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &[0xA9, 0x10, 0x8D, 0x05, 0x90, 0xF2]);
+        vm.map_device(0x9000, via).unwrap();
+        vm.set_input_mode(mode);
+        while !vm.halt {
+            vm.cycle().unwrap();
+        }
+        vm
+    }
+
+    #[test]
+    fn test_replay_device_divergence() {
+        let mut recorded = run_via(Via::new(), InputMode::Record(InputLog::new()));
+        let log = recorded.finish_recording().unwrap();
+        assert!(log.events.is_empty());
+
+        let replay = InputMode::Replay { log, next: 0 };
+        assert_eq!(run_via(Via::new(), replay.clone()).check_replay(), Ok(()));
+
+        // An edge on CA1 only sets a flag of the VIA, the CPU does not see it
+        let mut via = Via::new();
+        via.set_control(Control::CA1, false);
+        let replayed = run_via(via, replay);
+        assert_eq!(
+            (replayed.get_pc(), replayed.cycle_count()),
+            (recorded.get_pc(), recorded.cycle_count())
+        );
+        assert!(replayed
+            .check_replay()
+            .unwrap_err()
+            .starts_with("Replay diverged : state hash"));
+    }
+}
@@ -0,0 +1,407 @@
+//! MOS 6532 RAM-I/O-Timer.
+//!
+//! The RAM (128 bytes) and the I/O registers (32 addresses) are selected by different
+//! pins of the chip, so they are mapped separately with `Riot::map`. The I/O addresses
+//! decode A0 to A4 :
+//!
+//! | Address     | Read                         | Write                               |
+//! |-------------|------------------------------|-------------------------------------|
+//! | `xxx00`     | Port A                       | Port A output                       |
+//! | `xxx01`     | DDRA                         | DDRA                                |
+//! | `xxx10`     | Port B                       | Port B output                       |
+//! | `xxx11`     | DDRB                         | DDRB                                |
+//! | `xx1x1`     | Interrupt flags, clears PA7  |                                     |
+//! | `xI1x0`     | Timer, I enables its IRQ     |                                     |
+//! | `0x1EP`     |                              | PA7 edge : E enables its IRQ,       |
+//! |             |                              | P selects the positive edge         |
+//! | `1I1DD`     |                              | Starts the timer with the divider   |
+//! |             |                              | DD (1, 8, 64, 1024), I enables IRQ  |
+//!
+//! The timer counts the CPU cycles from the instruction following its write. Once it
+//! passes zero its flag is set and it counts down every cycle, until it is read or written.
+//! The ports are connected to the host like the ones of the VIA.
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    bus::{Device, HostPort},
+    state::{StateReader, StateWriter},
+    via::{NoPorts, Port, ViaPorts},
+    Vm,
+};
+
+pub const RIOT_RAM_SIZE: u16 = 0x80;
+pub const RIOT_IO_SIZE: u16 = 0x20;
+/// Channel of port A for the record and replay, port B is on the next one
+pub const RIOT_CHANNEL: u16 = 0x0400;
+
+pub const FLAG_TIMER: u8 = 0b1000_0000;
+pub const FLAG_PA7: u8 = 0b0100_0000;
+
+const DIVIDERS: [u16; 4] = [1, 8, 64, 1024];
+
+pub struct Riot {
+    ram: [u8; RIOT_RAM_SIZE as usize],
+    ports: Box<dyn ViaPorts>,
+    channel: u16,
+    ora: u8,
+    orb: u8,
+    ddra: u8,
+    ddrb: u8,
+    // Last levels read from the host, for the peeks
+    input_a: u8,
+    input_b: u8,
+    timer: u8,
+    divider: u16,
+    // Cycles before the next decrement of the timer
+    countdown: u16,
+    timer_loaded: bool,
+    timer_irq: bool,
+    pa7: bool,
+    pa7_positive: bool,
+    pa7_irq: bool,
+    flags: u8,
+}
+
+impl Riot {
+    pub fn new() -> Self {
+        Self {
+            ram: [0; RIOT_RAM_SIZE as usize],
+            ports: Box::new(NoPorts),
+            channel: RIOT_CHANNEL,
+            ora: 0,
+            orb: 0,
+            ddra: 0,
+            ddrb: 0,
+            input_a: 0xFF,
+            input_b: 0xFF,
+            timer: 0,
+            divider: 1024,
+            countdown: 1024,
+            timer_loaded: false,
+            timer_irq: false,
+            pa7: true,
+            pa7_positive: false,
+            pa7_irq: false,
+            flags: 0,
+        }
+    }
+
+    pub fn with_ports(mut self, ports: Box<dyn ViaPorts>) -> Self {
+        self.ports = ports;
+        self
+    }
+
+    /// Channels of the ports for the record and replay, each RIOT of a machine needs its own
+    pub fn with_channel(mut self, channel: u16) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    /// Maps the RAM and the I/O registers, the RIOT stays shared with the host
+    pub fn map(self, vm: &mut Vm, ram: u16, io: u16) -> Result<Rc<RefCell<Riot>>, String> {
+        let riot = Rc::new(RefCell::new(self));
+        vm.map_device(ram, RiotRam(riot.clone()))?;
+        if let Err(err) = vm.map_device(io, RiotIo(riot.clone())) {
+            vm.unmap_device(ram);
+            return Err(err);
+        }
+        Ok(riot)
+    }
+
+    /// Level of PA7 driven by the host, the active edge sets the PA7 flag
+    pub fn set_pa7(&mut self, level: bool) {
+        if self.pa7 != level && level == self.pa7_positive {
+            self.flags |= FLAG_PA7;
+        }
+        self.pa7 = level;
+    }
+
+    fn start_timer(&mut self, value: u8, divider: u16) {
+        self.timer = value;
+        self.divider = divider;
+        self.countdown = 1;
+        self.timer_loaded = true;
+        self.flags &= !FLAG_TIMER;
+    }
+
+    fn step_timer(&mut self, cycles: u64) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            let elapsed = cycles.min(self.countdown as u64);
+            cycles -= elapsed;
+            self.countdown -= elapsed as u16;
+            if self.countdown > 0 {
+                break;
+            }
+
+            self.timer = self.timer.wrapping_sub(1);
+            if self.timer == 0xFF {
+                self.flags |= FLAG_TIMER;
+            }
+            self.countdown = match self.flags & FLAG_TIMER > 0 {
+                true => 1,
+                false => self.divider,
+            };
+        }
+    }
+
+    fn input(&self, port: Port) -> u8 {
+        match port {
+            Port::A => (self.ora & self.ddra) | (self.input_a & !self.ddra),
+            Port::B => (self.orb & self.ddrb) | (self.input_b & !self.ddrb),
+        }
+    }
+
+    fn read_io(&mut self, offset: u16, host: &mut HostPort) -> u8 {
+        let ports = &mut self.ports;
+        match offset & 0b0_0111 {
+            0b000 => {
+                self.input_a = host.input(self.channel, || ports.read_port(Port::A));
+                self.input(Port::A)
+            }
+            0b010 => {
+                self.input_b =
+                    host.input(self.channel.wrapping_add(1), || ports.read_port(Port::B));
+                self.input(Port::B)
+            }
+            0b100 | 0b110 => {
+                // Reading the timer gives back its divider once it expired
+                self.timer_irq = offset & 0b0_1000 > 0;
+                if self.flags & FLAG_TIMER > 0 {
+                    self.flags &= !FLAG_TIMER;
+                    self.countdown = self.divider;
+                }
+                self.timer
+            }
+            0b101 | 0b111 => {
+                let flags = self.flags;
+                self.flags &= !FLAG_PA7;
+                flags
+            }
+            _ => self.peek_io(offset),
+        }
+    }
+
+    fn peek_io(&self, offset: u16) -> u8 {
+        match offset & 0b0_0111 {
+            0b000 => self.input(Port::A),
+            0b001 => self.ddra,
+            0b010 => self.input(Port::B),
+            0b011 => self.ddrb,
+            0b100 | 0b110 => self.timer,
+            _ => self.flags,
+        }
+    }
+
+    fn write_io(&mut self, offset: u16, value: u8) {
+        match offset & 0b0_0111 {
+            0b000 => {
+                self.ora = value;
+                self.ports.write_port(Port::A, self.ora, self.ddra);
+            }
+            0b001 => {
+                self.ddra = value;
+                self.ports.write_port(Port::A, self.ora, self.ddra);
+            }
+            0b010 => {
+                self.orb = value;
+                self.ports.write_port(Port::B, self.orb, self.ddrb);
+            }
+            0b011 => {
+                self.ddrb = value;
+                self.ports.write_port(Port::B, self.orb, self.ddrb);
+            }
+            _ if offset & 0b1_0000 > 0 => {
+                self.timer_irq = offset & 0b0_1000 > 0;
+                self.start_timer(value, DIVIDERS[(offset & 0b11) as usize]);
+            }
+            _ => {
+                self.pa7_positive = offset & 0b01 > 0;
+                self.pa7_irq = offset & 0b10 > 0;
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        (self.timer_irq && self.flags & FLAG_TIMER > 0)
+            || (self.pa7_irq && self.flags & FLAG_PA7 > 0)
+    }
+}
+
+impl Default for Riot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// RAM of a shared RIOT
+pub struct RiotRam(pub Rc<RefCell<Riot>>);
+
+impl Device for RiotRam {
+    fn size(&self) -> u16 {
+        RIOT_RAM_SIZE
+    }
+
+    fn read(&mut self, offset: u16, _host: &mut HostPort) -> u8 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        self.0.borrow().ram[offset as usize]
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        self.0.borrow_mut().ram[offset as usize] = value
+    }
+}
+
+/// I/O registers and timer of a shared RIOT, which counts the cycles
+pub struct RiotIo(pub Rc<RefCell<Riot>>);
+
+impl Device for RiotIo {
+    fn size(&self) -> u16 {
+        RIOT_IO_SIZE
+    }
+
+    fn read(&mut self, offset: u16, host: &mut HostPort) -> u8 {
+        self.0.borrow_mut().read_io(offset, host)
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        self.0.borrow().peek_io(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        self.0.borrow_mut().write_io(offset, value)
+    }
+
+    fn tick(&mut self, cycles: u64, _host: &mut HostPort) {
+        let mut riot = self.0.borrow_mut();
+        // The timer written by the instruction starts counting after it
+        if !std::mem::take(&mut riot.timer_loaded) {
+            riot.step_timer(cycles);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.0.borrow().irq()
+    }
+
+    // The chunk of the I/O registers has the RAM too, the RIOT is a single chip
+    fn save_state(&self, writer: &mut StateWriter) {
+        let riot = self.0.borrow();
+        writer.put_bytes(&riot.ram);
+        writer.put_bytes(&[riot.ora, riot.orb, riot.ddra, riot.ddrb]);
+        writer.put_bytes(&[riot.input_a, riot.input_b, riot.timer]);
+        writer.put_u16(riot.divider);
+        writer.put_u16(riot.countdown);
+        writer.put_bool(riot.timer_loaded);
+        writer.put_bool(riot.timer_irq);
+        writer.put_bool(riot.pa7);
+        writer.put_bool(riot.pa7_positive);
+        writer.put_bool(riot.pa7_irq);
+        writer.put_u8(riot.flags);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        let mut riot = self.0.borrow_mut();
+        riot.ram
+            .copy_from_slice(reader.get_bytes(RIOT_RAM_SIZE as usize)?);
+        [riot.ora, riot.orb, riot.ddra, riot.ddrb] = reader.get_bytes(4)?.try_into().unwrap();
+        [riot.input_a, riot.input_b, riot.timer] = reader.get_bytes(3)?.try_into().unwrap();
+        riot.divider = reader.get_u16()?;
+        riot.countdown = reader.get_u16()?;
+        riot.timer_loaded = reader.get_bool()?;
+        riot.timer_irq = reader.get_bool()?;
+        riot.pa7 = reader.get_bool()?;
+        riot.pa7_positive = reader.get_bool()?;
+        riot.pa7_irq = reader.get_bool()?;
+        riot.flags = reader.get_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, isa::Register};
+
+    #[test]
+    fn test_riot_timer() {
+        let mut riot = Riot::new();
+        // Divide by 8 with the interrupt, at $xx1D
+        riot.write_io(0x1D, 3);
+        riot.timer_loaded = false;
+
+        riot.step_timer(1);
+        assert_eq!(riot.peek_io(0x04), 2);
+        riot.step_timer(8 * 2);
+        assert_eq!(riot.peek_io(0x04), 0);
+        assert!(!riot.irq());
+        riot.step_timer(8);
+        assert_eq!(riot.peek_io(0x04), 0xFF);
+        assert_eq!(riot.peek_io(0x05), FLAG_TIMER);
+        assert!(riot.irq());
+
+        // Every cycle once expired, until the timer is read
+        riot.step_timer(5);
+        assert_eq!(riot.peek_io(0x04), 0xFA);
+        let mut input_mode = crate::replay::InputMode::Live;
+        let mut host = HostPort::new(0, &mut input_mode);
+        assert_eq!(riot.read_io(0x04, &mut host), 0xFA);
+        assert!(!riot.irq());
+        riot.step_timer(7);
+        assert_eq!(riot.peek_io(0x04), 0xFA);
+        riot.step_timer(1);
+        assert_eq!(riot.peek_io(0x04), 0xF9);
+
+        // PA7 on its positive edge
+        riot.write_io(0x07, 0);
+        riot.set_pa7(false);
+        assert_eq!(riot.peek_io(0x05), 0);
+        riot.set_pa7(true);
+        assert!(riot.irq());
+        assert_eq!(riot.read_io(0x05, &mut host), FLAG_PA7);
+        assert_eq!(riot.peek_io(0x05), 0);
+    }
+
+    #[test]
+    fn test_riot_mapping() {
+        // Waits for the timer divided by 64 in the RAM of the RIOT, as on the 2600
+        let source = "
+INTIM = $0284
+TIMINT = $0285
+TIM64T = $0296
+    LoadACImm 10
+    StoreACAbs TIM64T
+wait:
+    LoadACAbs TIMINT
+    BranchNotNeg wait
+    StoreACZp $81
+    LoadACAbs INTIM
+    StoreACZp $80
+    Jam
+";
+        let mut vm = Vm::new();
+        vm.copy_memory(0x1000, &assemble(source, "riot.asm").unwrap().bytes);
+        vm.set_pc(0x1000);
+        vm.set_register(Register::SP, 0xFF);
+        let riot = Riot::new().map(&mut vm, 0x0080, 0x0280).unwrap();
+        assert!(Riot::new().map(&mut vm, 0x0200, 0x0290).is_err());
+        assert_eq!(
+            vm.device_mappings(),
+            vec![(0x0080, 0x00FF), (0x0280, 0x029F)]
+        );
+
+        while !vm.halt {
+            vm.cycle().unwrap();
+        }
+        assert!(vm.cycle_count() > 10 * 64);
+        assert_eq!(vm.read_memory(0x0081), Some(FLAG_TIMER));
+        assert!(riot.borrow().ram[0] > 0xF0);
+        assert_eq!(riot.borrow().flags, 0);
+        // The RAM under the RIOT is not used
+        assert_eq!(vm.read_memory(0x0200), Some(0));
+    }
+}
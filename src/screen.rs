@@ -0,0 +1,392 @@
+//! Memory mapped text screen, without any window.
+//!
+//! The screen RAM holds one byte per character, row after row from the top left corner :
+//! 40 columns of 25 rows by default, as on most 8 bits computers. The characters are
+//! ASCII, bit 7 shows them in reverse video and the control characters are blank. The
+//! host takes a snapshot as text, to check what a program drew, or as a PNG drawn with
+//! the 8x8 font of the IBM PC (from the public domain font8x8 by Daniel Hepper).
+
+use std::{cell::RefCell, fs, path::Path, rc::Rc};
+
+use crate::{
+    bus::{Device, HostPort},
+    state::{StateReader, StateWriter},
+    Vm,
+};
+
+pub const SCREEN_COLUMNS: u8 = 40;
+pub const SCREEN_ROWS: u8 = 25;
+/// Screen RAM of the Commodore 64
+pub const SCREEN_DEFAULT_ADDR: u16 = 0x0400;
+
+const GLYPH_SIZE: usize = 8;
+const REVERSE: u8 = 0b1000_0000;
+
+pub struct TextScreen {
+    columns: u8,
+    rows: u8,
+    cells: Vec<u8>,
+}
+
+impl TextScreen {
+    /// Blank screen of 40 columns and 25 rows
+    pub fn new() -> Self {
+        Self::with_size(SCREEN_COLUMNS, SCREEN_ROWS)
+    }
+
+    pub fn with_size(columns: u8, rows: u8) -> Self {
+        Self {
+            columns,
+            rows,
+            cells: vec![b' '; columns as usize * rows as usize],
+        }
+    }
+
+    /// Copy of a screen RAM as the CPU sees it, with the device or in plain RAM
+    pub fn capture(vm: &Vm, base: u16, columns: u8, rows: u8) -> Self {
+        let mut screen = Self::with_size(columns, rows);
+        for (offset, cell) in screen.cells.iter_mut().enumerate() {
+            *cell = vm
+                .read_memory(base.wrapping_add(offset as u16))
+                .unwrap_or_default();
+        }
+        screen
+    }
+
+    /// Maps the screen RAM, the screen stays shared with the host
+    pub fn map(self, vm: &mut Vm, base: u16) -> Result<Rc<RefCell<TextScreen>>, String> {
+        let screen = Rc::new(RefCell::new(self));
+        vm.map_device(base, screen.clone())?;
+        Ok(screen)
+    }
+
+    pub fn columns(&self) -> u8 {
+        self.columns
+    }
+
+    pub fn rows(&self) -> u8 {
+        self.rows
+    }
+
+    /// Character shown in a cell, without the reverse video
+    pub fn char_at(&self, column: u8, row: u8) -> char {
+        let code = self.cells[row as usize * self.columns as usize + column as usize] & !REVERSE;
+        match code {
+            0x20..=0x7E => code as char,
+            _ => ' ',
+        }
+    }
+
+    /// Characters of a row, with all its columns
+    pub fn row(&self, row: u8) -> String {
+        (0..self.columns)
+            .map(|column| self.char_at(column, row))
+            .collect()
+    }
+
+    /// Rows of the screen without the trailing blanks, the empty rows at the bottom
+    /// are left out
+    pub fn text(&self) -> String {
+        let rows: Vec<String> = (0..self.rows)
+            .map(|row| self.row(row).trim_end().to_string())
+            .collect();
+        let len = rows
+            .iter()
+            .rposition(|row| !row.is_empty())
+            .map_or(0, |last| last + 1);
+        rows[..len].join("\n")
+    }
+
+    /// Snapshot of the screen in a PNG, light grey on black with 8x8 pixels characters
+    pub fn to_png(&self) -> Vec<u8> {
+        let width = self.columns as usize * GLYPH_SIZE;
+        let height = self.rows as usize * GLYPH_SIZE;
+
+        // Every line of pixels starts with the byte of its filter, none here
+        let mut pixels = Vec::with_capacity((width + 1) * height);
+        for y in 0..height {
+            pixels.push(0);
+            for x in 0..width {
+                let cell = self.cells[(y / GLYPH_SIZE) * self.columns as usize + x / GLYPH_SIZE];
+                let lit = glyph(cell & !REVERSE)[y % GLYPH_SIZE] & (1 << (x % GLYPH_SIZE)) > 0;
+                pixels.push(match lit != (cell & REVERSE > 0) {
+                    true => 0xC0,
+                    false => 0x00,
+                });
+            }
+        }
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&(width as u32).to_be_bytes());
+        header.extend_from_slice(&(height as u32).to_be_bytes());
+        // 8 bits grayscale, no interlacing
+        header.extend_from_slice(&[8, 0, 0, 0, 0]);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut png, b"IHDR", &header);
+        png_chunk(&mut png, b"IDAT", &zlib_stored(&pixels));
+        png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    /// Writes a PNG when the path ends with `.png`, the text otherwise
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let data = match path.extension().is_some_and(|ext| ext == "png") {
+            true => self.to_png(),
+            false => (self.text() + "\n").into_bytes(),
+        };
+        fs::write(path, data).map_err(|err| format!("{} : {}", path.display(), err))
+    }
+}
+
+impl Default for TextScreen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for TextScreen {
+    fn size(&self) -> u16 {
+        self.cells.len() as u16
+    }
+
+    fn read(&mut self, offset: u16, _host: &mut HostPort) -> u8 {
+        self.peek(offset)
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        self.cells[offset as usize]
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        self.cells[offset as usize] = value
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bytes(&self.cells);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        let cells = reader.get_bytes(self.cells.len())?;
+        self.cells.copy_from_slice(cells);
+        Ok(())
+    }
+}
+
+// Rows of a character from the top, the lowest bit is the leftmost pixel
+fn glyph(code: u8) -> [u8; GLYPH_SIZE] {
+    match code {
+        0x20..=0x7E => FONT[(code - 0x20) as usize],
+        _ => [0; GLYPH_SIZE],
+    }
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xEDB8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+// Zlib stream made of deflate blocks without compression, the screens are small
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        stream.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    stream.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    stream
+}
+
+// From ' ' to '~'
+const FONT: [[u8; GLYPH_SIZE]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x18, 0x3C, 0x3C, 0x18, 0x18, 0x00, 0x18, 0x00],
+    [0x36, 0x36, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x36, 0x36, 0x7F, 0x36, 0x7F, 0x36, 0x36, 0x00],
+    [0x0C, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x0C, 0x00],
+    [0x00, 0x63, 0x33, 0x18, 0x0C, 0x66, 0x63, 0x00],
+    [0x1C, 0x36, 0x1C, 0x6E, 0x3B, 0x33, 0x6E, 0x00],
+    [0x06, 0x06, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x18, 0x0C, 0x06, 0x06, 0x06, 0x0C, 0x18, 0x00],
+    [0x06, 0x0C, 0x18, 0x18, 0x18, 0x0C, 0x06, 0x00],
+    [0x00, 0x66, 0x3C, 0xFF, 0x3C, 0x66, 0x00, 0x00],
+    [0x00, 0x0C, 0x0C, 0x3F, 0x0C, 0x0C, 0x00, 0x00],
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x06],
+    [0x00, 0x00, 0x00, 0x3F, 0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x00],
+    [0x60, 0x30, 0x18, 0x0C, 0x06, 0x03, 0x01, 0x00],
+    [0x3E, 0x63, 0x73, 0x7B, 0x6F, 0x67, 0x3E, 0x00],
+    [0x0C, 0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x3F, 0x00],
+    [0x1E, 0x33, 0x30, 0x1C, 0x06, 0x33, 0x3F, 0x00],
+    [0x1E, 0x33, 0x30, 0x1C, 0x30, 0x33, 0x1E, 0x00],
+    [0x38, 0x3C, 0x36, 0x33, 0x7F, 0x30, 0x78, 0x00],
+    [0x3F, 0x03, 0x1F, 0x30, 0x30, 0x33, 0x1E, 0x00],
+    [0x1C, 0x06, 0x03, 0x1F, 0x33, 0x33, 0x1E, 0x00],
+    [0x3F, 0x33, 0x30, 0x18, 0x0C, 0x0C, 0x0C, 0x00],
+    [0x1E, 0x33, 0x33, 0x1E, 0x33, 0x33, 0x1E, 0x00],
+    [0x1E, 0x33, 0x33, 0x3E, 0x30, 0x18, 0x0E, 0x00],
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x00],
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x06],
+    [0x18, 0x0C, 0x06, 0x03, 0x06, 0x0C, 0x18, 0x00],
+    [0x00, 0x00, 0x3F, 0x00, 0x00, 0x3F, 0x00, 0x00],
+    [0x06, 0x0C, 0x18, 0x30, 0x18, 0x0C, 0x06, 0x00],
+    [0x1E, 0x33, 0x30, 0x18, 0x0C, 0x00, 0x0C, 0x00],
+    [0x3E, 0x63, 0x7B, 0x7B, 0x7B, 0x03, 0x1E, 0x00],
+    [0x0C, 0x1E, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x00],
+    [0x3F, 0x66, 0x66, 0x3E, 0x66, 0x66, 0x3F, 0x00],
+    [0x3C, 0x66, 0x03, 0x03, 0x03, 0x66, 0x3C, 0x00],
+    [0x1F, 0x36, 0x66, 0x66, 0x66, 0x36, 0x1F, 0x00],
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x46, 0x7F, 0x00],
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x06, 0x0F, 0x00],
+    [0x3C, 0x66, 0x03, 0x03, 0x73, 0x66, 0x7C, 0x00],
+    [0x33, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x33, 0x00],
+    [0x1E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00],
+    [0x78, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E, 0x00],
+    [0x67, 0x66, 0x36, 0x1E, 0x36, 0x66, 0x67, 0x00],
+    [0x0F, 0x06, 0x06, 0x06, 0x46, 0x66, 0x7F, 0x00],
+    [0x63, 0x77, 0x7F, 0x7F, 0x6B, 0x63, 0x63, 0x00],
+    [0x63, 0x67, 0x6F, 0x7B, 0x73, 0x63, 0x63, 0x00],
+    [0x1C, 0x36, 0x63, 0x63, 0x63, 0x36, 0x1C, 0x00],
+    [0x3F, 0x66, 0x66, 0x3E, 0x06, 0x06, 0x0F, 0x00],
+    [0x1E, 0x33, 0x33, 0x33, 0x3B, 0x1E, 0x38, 0x00],
+    [0x3F, 0x66, 0x66, 0x3E, 0x36, 0x66, 0x67, 0x00],
+    [0x1E, 0x33, 0x07, 0x0E, 0x38, 0x33, 0x1E, 0x00],
+    [0x3F, 0x2D, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00],
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x3F, 0x00],
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00],
+    [0x63, 0x63, 0x63, 0x6B, 0x7F, 0x77, 0x63, 0x00],
+    [0x63, 0x63, 0x36, 0x1C, 0x1C, 0x36, 0x63, 0x00],
+    [0x33, 0x33, 0x33, 0x1E, 0x0C, 0x0C, 0x1E, 0x00],
+    [0x7F, 0x63, 0x31, 0x18, 0x4C, 0x66, 0x7F, 0x00],
+    [0x1E, 0x06, 0x06, 0x06, 0x06, 0x06, 0x1E, 0x00],
+    [0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x40, 0x00],
+    [0x1E, 0x18, 0x18, 0x18, 0x18, 0x18, 0x1E, 0x00],
+    [0x08, 0x1C, 0x36, 0x63, 0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF],
+    [0x0C, 0x0C, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x1E, 0x30, 0x3E, 0x33, 0x6E, 0x00],
+    [0x07, 0x06, 0x06, 0x3E, 0x66, 0x66, 0x3B, 0x00],
+    [0x00, 0x00, 0x1E, 0x33, 0x03, 0x33, 0x1E, 0x00],
+    [0x38, 0x30, 0x30, 0x3E, 0x33, 0x33, 0x6E, 0x00],
+    [0x00, 0x00, 0x1E, 0x33, 0x3F, 0x03, 0x1E, 0x00],
+    [0x1C, 0x36, 0x06, 0x0F, 0x06, 0x06, 0x0F, 0x00],
+    [0x00, 0x00, 0x6E, 0x33, 0x33, 0x3E, 0x30, 0x1F],
+    [0x07, 0x06, 0x36, 0x6E, 0x66, 0x66, 0x67, 0x00],
+    [0x0C, 0x00, 0x0E, 0x0C, 0x0C, 0x0C, 0x1E, 0x00],
+    [0x30, 0x00, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E],
+    [0x07, 0x06, 0x66, 0x36, 0x1E, 0x36, 0x67, 0x00],
+    [0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00],
+    [0x00, 0x00, 0x33, 0x7F, 0x7F, 0x6B, 0x63, 0x00],
+    [0x00, 0x00, 0x1F, 0x33, 0x33, 0x33, 0x33, 0x00],
+    [0x00, 0x00, 0x1E, 0x33, 0x33, 0x33, 0x1E, 0x00],
+    [0x00, 0x00, 0x3B, 0x66, 0x66, 0x3E, 0x06, 0x0F],
+    [0x00, 0x00, 0x6E, 0x33, 0x33, 0x3E, 0x30, 0x78],
+    [0x00, 0x00, 0x3B, 0x6E, 0x66, 0x06, 0x0F, 0x00],
+    [0x00, 0x00, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x00],
+    [0x08, 0x0C, 0x3E, 0x0C, 0x0C, 0x2C, 0x18, 0x00],
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x33, 0x6E, 0x00],
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00],
+    [0x00, 0x00, 0x63, 0x6B, 0x7F, 0x7F, 0x36, 0x00],
+    [0x00, 0x00, 0x63, 0x36, 0x1C, 0x36, 0x63, 0x00],
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x3E, 0x30, 0x1F],
+    [0x00, 0x00, 0x3F, 0x19, 0x0C, 0x26, 0x3F, 0x00],
+    [0x38, 0x0C, 0x0C, 0x07, 0x0C, 0x0C, 0x38, 0x00],
+    [0x18, 0x18, 0x18, 0x00, 0x18, 0x18, 0x18, 0x00],
+    [0x07, 0x0C, 0x0C, 0x38, 0x0C, 0x0C, 0x07, 0x00],
+    [0x6E, 0x3B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn test_screen_text() {
+        // Prints a message on the second row, then a reversed '!'
+        let source = "
+SCREEN = $0428
+MESSAGE = $0300
+    LoadXImm 0
+print:
+    LoadACAbsX MESSAGE
+    BranchZero done
+    StoreACAbsX SCREEN
+    IncX
+    JumpAbs print
+done:
+    LoadACImm $a1
+    StoreACAbsX SCREEN
+    Jam
+";
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &assemble(source, "screen.asm").unwrap().bytes);
+        vm.copy_memory(0x0300, b"HELLO\0");
+        let screen = TextScreen::new().map(&mut vm, SCREEN_DEFAULT_ADDR).unwrap();
+        assert_eq!(vm.device_mappings(), vec![(0x0400, 0x07E7)]);
+        while !vm.halt {
+            vm.cycle().unwrap();
+        }
+
+        let screen = screen.borrow();
+        assert_eq!(screen.text(), "\nHELLO!");
+        assert_eq!(screen.row(1).len(), 40);
+        assert_eq!(screen.char_at(5, 1), '!');
+        assert_eq!(vm.read_memory(0x042D), Some(0xA1));
+        let capture = TextScreen::capture(&vm, 0x0428, 3, 2);
+        assert_eq!(capture.text(), "HEL\nLO!");
+    }
+
+    #[test]
+    fn test_screen_png() {
+        let mut screen = TextScreen::with_size(2, 1);
+        screen.write(0, b'I');
+        screen.write(1, b' ' | REVERSE);
+        let png = screen.to_png();
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        // IHDR of 16x8 pixels
+        assert_eq!(&png[12..24], b"IHDR\0\0\0\x10\0\0\0\x08");
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+
+        // The stored pixels : the top of the I, then the reversed blank
+        let pixels = &png[41 + 7..];
+        assert_eq!(pixels[0], 0);
+        assert_eq!(
+            &pixels[1..17],
+            &[0, 0xC0, 0xC0, 0xC0, 0xC0, 0, 0, 0, 0xC0, 0xC0, 0xC0, 0xC0, 0xC0, 0xC0, 0xC0, 0xC0]
+        );
+    }
+}
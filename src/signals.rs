@@ -0,0 +1,130 @@
+//! Handlers of the `EmuSignal` instruction.
+//!
+//! A handler is any type implementing `SignalHandler`, which includes the closures taking
+//! the Vm, so a handler can keep host side state like an output buffer or open files.
+//! Its `SignalAction` tells the Vm what to do once the signal is handled.
+
+use crate::Vm;
+
+/// What the Vm does after a signal handler returned
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SignalAction {
+    /// Goes on with the next instruction
+    #[default]
+    Continue,
+    /// Halts the machine, as the JAM instruction
+    Halt,
+    /// Stops `Vm::run_until` and the debuggers after the instruction
+    Break,
+    /// Requests an IRQ, serviced before the next instruction when not masked
+    Interrupt,
+}
+
+pub trait SignalHandler {
+    /// Handles the signal, an error stops the instruction
+    fn handle(&mut self, vm: &mut Vm) -> Result<SignalAction, String>;
+}
+
+impl<F> SignalHandler for F
+where
+    F: FnMut(&mut Vm) -> Result<SignalAction, String>,
+{
+    fn handle(&mut self, vm: &mut Vm) -> Result<SignalAction, String> {
+        self(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        breakpoints::StopReason,
+        isa::{Register, RegisterFlag},
+    };
+
+    struct Counter {
+        calls: usize,
+    }
+
+    impl SignalHandler for Counter {
+        fn handle(&mut self, vm: &mut Vm) -> Result<SignalAction, String> {
+            self.calls += 1;
+            vm.set_register(Register::X, self.calls as u8);
+            match self.calls {
+                3 => Err("Too many calls".to_string()),
+                _ => Ok(SignalAction::Continue),
+            }
+        }
+    }
+
+    #[test]
+    fn test_closure_and_trait_handlers() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let captured = output.clone();
+
+        let mut vm = Vm::new();
+        vm.set_signal_handler(1, move |vm: &mut Vm| {
+            captured.borrow_mut().push(vm.get_register(Register::AC));
+            Ok(SignalAction::Continue)
+        });
+        vm.set_signal_handler(2, Counter { calls: 0 });
+
+        #[rustfmt::skip]
+        vm.copy_memory(0, &[
+            0xA9, 0x41,       // LDA #$41
+            0xFF, 0x01,       // SIG $01
+            0xFF, 0x02,       // SIG $02
+            0xFF, 0x02,       // SIG $02
+            0xFF, 0x02,       // SIG $02
+        ]);
+        for _ in 0..4 {
+            vm.cycle().unwrap();
+        }
+        assert_eq!(output.borrow().as_slice(), &[0x41]);
+        assert_eq!(vm.get_register(Register::X), 2);
+        assert_eq!(vm.cycle(), Err("Too many calls".to_string()));
+
+        assert!(vm.remove_signal_handler(1));
+        vm.set_pc(2);
+        assert_eq!(vm.cycle(), Err("Unknown signal : 1".to_string()));
+    }
+
+    #[test]
+    fn test_signal_actions() {
+        let mut vm = Vm::new();
+        vm.set_signal_handler(0, |_: &mut Vm| Ok(SignalAction::Break));
+        vm.set_signal_handler(1, |_: &mut Vm| Ok(SignalAction::Interrupt));
+        vm.set_signal_handler(2, |_: &mut Vm| Ok(SignalAction::Halt));
+
+        #[rustfmt::skip]
+        vm.copy_memory(0, &[
+            0xFF, 0x00,       // SIG $00
+            0x58,             // CLI
+            0xFF, 0x01,       // SIG $01
+            0xEA,             // NOP, not run before the interrupt
+        ]);
+        vm.copy_memory(0x0300, &[0xFF, 0x02]);
+        vm.copy_memory(0xFFFE, &[0x00, 0x03]);
+        vm.set_register(Register::SP, 0xFF);
+        vm.set_flag(RegisterFlag::Interrupt, true);
+
+        assert_eq!(vm.run_until(1000), Ok(StopReason::Signal(0)));
+        assert_eq!(vm.get_pc(), 0x0002);
+
+        // The IRQ is serviced right after the signal, as its own step
+        vm.cycle().unwrap();
+        vm.cycle().unwrap();
+        assert_eq!(vm.get_pc(), 0x0005);
+        vm.cycle().unwrap();
+        assert_eq!(vm.get_pc(), 0x0300);
+        assert!(vm.get_flag(RegisterFlag::Interrupt));
+        assert_eq!(vm.read_memory(0x01FF), Some(0x00));
+        assert_eq!(vm.read_memory(0x01FE), Some(0x05));
+        assert_eq!(vm.read_memory(0x01FD), Some(0b0010_0000));
+
+        assert_eq!(vm.run_until(1000), Ok(StopReason::Halted));
+        assert_eq!(vm.get_pc(), 0x0302);
+    }
+}
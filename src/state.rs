@@ -0,0 +1,289 @@
+//! Binary format of the save states.
+//!
+//! A save state starts with the `R65S` magic and a format version, followed by chunks.
+//! Every chunk is made of a 4 bytes tag, a little endian u32 length and its content,
+//! which lets each part of the machine (CPU, memory, devices) own its section.
+
+pub const STATE_MAGIC: &[u8; 4] = b"R65S";
+pub const STATE_VERSION: u16 = 2;
+
+pub struct StateWriter {
+    data: Vec<u8>,
+    chunk_start: Option<usize>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut data = Vec::new();
+        data.extend_from_slice(STATE_MAGIC);
+        data.extend_from_slice(&STATE_VERSION.to_le_bytes());
+
+        Self {
+            data,
+            chunk_start: None,
+        }
+    }
+
+    pub fn begin_chunk(&mut self, tag: &[u8; 4]) {
+        self.end_chunk();
+        self.data.extend_from_slice(tag);
+        self.data.extend_from_slice(&[0; 4]);
+        self.chunk_start = Some(self.data.len());
+    }
+
+    fn end_chunk(&mut self) {
+        if let Some(start) = self.chunk_start.take() {
+            let len = (self.data.len() - start) as u32;
+            self.data[start - 4..start].copy_from_slice(&len.to_le_bytes());
+        }
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.data.push(value)
+    }
+
+    pub fn put_bool(&mut self, value: bool) {
+        self.data.push(value as u8)
+    }
+
+    pub fn put_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes())
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes())
+    }
+
+    pub fn put_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes())
+    }
+
+    pub fn put_bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value)
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.end_chunk();
+        self.data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    /// Checks the header and returns a reader positioned on the first chunk
+    pub fn new(data: &'a [u8]) -> Result<Self, String> {
+        if data.len() < 6 || &data[0..4] != STATE_MAGIC {
+            return Err("This is not a save state".to_string());
+        }
+
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != STATE_VERSION {
+            return Err(format!(
+                "Unsupported save state version {} (expected {})",
+                version, STATE_VERSION
+            ));
+        }
+
+        Ok(Self { data, pos: 6 })
+    }
+
+    /// Reads the header of the next chunk and checks its tag,
+    /// returns a reader limited to the content of the chunk
+    pub fn chunk(&mut self, tag: &[u8; 4]) -> Result<StateReader<'a>, String> {
+        let found = self.get_bytes(4)?;
+        if found != tag {
+            return Err(format!(
+                "Wrong save state chunk, expected {} found {}",
+                String::from_utf8_lossy(tag),
+                String::from_utf8_lossy(found)
+            ));
+        }
+
+        let len = self.get_u32()? as usize;
+        let content = self.get_bytes(len)?;
+        Ok(StateReader {
+            data: content,
+            pos: 0,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, String> {
+        Ok(self.get_bytes(1)?[0])
+    }
+
+    pub fn get_bool(&mut self) -> Result<bool, String> {
+        Ok(self.get_u8()? != 0)
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16, String> {
+        let bytes = self.get_bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, String> {
+        let bytes = self.get_bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, String> {
+        let bytes = self.get_bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn get_bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.pos + len > self.data.len() {
+            return Err("Truncated save state".to_string());
+        }
+
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        asm::assemble,
+        isa::Register,
+        mapper::{Banked, RamBanks},
+        via::{Via, REG_T1CL},
+        Vm,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_state_chunks() {
+        let mut writer = StateWriter::new();
+        writer.begin_chunk(b"TST1");
+        writer.put_u8(0x12);
+        writer.put_u16(0x3456);
+        writer.begin_chunk(b"TST2");
+        writer.put_u64(0x0123_4567_89AB_CDEF);
+        writer.put_bytes(&[1, 2, 3]);
+        let data = writer.finish();
+
+        let mut reader = StateReader::new(&data).unwrap();
+        let mut chunk = reader.chunk(b"TST1").unwrap();
+        assert_eq!(chunk.get_u8().unwrap(), 0x12);
+        assert_eq!(chunk.get_u16().unwrap(), 0x3456);
+        assert!(chunk.is_empty());
+
+        assert!(reader.chunk(b"TST3").is_err());
+
+        let mut reader = StateReader::new(&data).unwrap();
+        reader.chunk(b"TST1").unwrap();
+        let mut chunk = reader.chunk(b"TST2").unwrap();
+        assert_eq!(chunk.get_u64().unwrap(), 0x0123_4567_89AB_CDEF);
+        assert_eq!(chunk.get_bytes(3).unwrap(), &[1, 2, 3]);
+        assert!(chunk.get_u8().is_err());
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn test_state_header() {
+        assert!(StateReader::new(b"").is_err());
+        assert!(StateReader::new(b"NOPE\x01\x00").is_err());
+        assert!(StateReader::new(b"R65S\x01\x00").is_err());
+        assert!(StateReader::new(b"R65S\x02\x00").is_ok());
+    }
+
+    #[test]
+    fn test_vm_save_load_state() {
+        let mut vm = Vm::new();
+        vm.copy_memory(0x0200, &[0xA9, 0x42, 0x85, 0x10, 0xF2]);
+        vm.set_pc(0x0200);
+        vm.set_register(Register::SP, 0xFD);
+        vm.cycle().unwrap();
+
+        let data = vm.save_state();
+
+        let mut restored = Vm::new();
+        restored.load_state(&data).unwrap();
+        assert_eq!(restored.get_pc(), 0x0202);
+        assert_eq!(restored.get_register(Register::AC), 0x42);
+        assert_eq!(restored.get_register(Register::SP), 0xFD);
+        assert_eq!(restored.cycle_count(), 2);
+        assert_eq!(restored.read_memory(0x0204), Some(0xF2));
+        assert_eq!(restored.save_state(), data);
+
+        // Both machines continue the same way
+        vm.cycle().unwrap();
+        restored.cycle().unwrap();
+        assert_eq!(restored.read_memory(0x0010), Some(0x42));
+        assert_eq!(restored.save_state(), vm.save_state());
+
+        assert!(restored.load_state(&data[..data.len() - 1]).is_err());
+    }
+
+    // VIA at $7000 with a banked RAM of 4 banks at $6000, selected at $5fff
+    fn machine() -> Vm {
+        let source = "
+    LoadACImm $40
+    StoreACAbs $7004
+    LoadACImm $01
+    StoreACAbs $7005
+    LoadACImm 2
+    StoreACAbs $5fff
+    LoadACImm $AB
+    StoreACAbs $6000
+loop:
+    JumpAbs loop
+";
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &assemble(source, "devices.asm").unwrap().bytes);
+        vm.map_device(0x7000, Via::new()).unwrap();
+        Banked::ram(4, Box::new(RamBanks::new(0x1000)))
+            .unwrap()
+            .map(&mut vm, 0x6000, Some(0x5FFF))
+            .unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_device_save_load_state() {
+        let mut vm = machine();
+        for _ in 0..10 {
+            vm.cycle().unwrap();
+        }
+        let data = vm.save_state();
+
+        let mut restored = machine();
+        restored.load_state(&data).unwrap();
+        assert_eq!(restored.bank_at(0x6000), Some(2));
+        assert_eq!(restored.read_memory(0x6000), Some(0xAB));
+        assert_eq!(
+            restored.read_memory(0x7000 + REG_T1CL),
+            vm.read_memory(0x7000 + REG_T1CL)
+        );
+        assert_eq!(restored.save_state(), data);
+
+        // The timer keeps running the same way after the load
+        for _ in 0..100 {
+            vm.cycle().unwrap();
+            restored.cycle().unwrap();
+        }
+        assert_eq!(restored.save_state(), vm.save_state());
+
+        // The devices must be the same, a failed load changes nothing
+        let mut other = Vm::new();
+        let before = other.save_state();
+        assert!(other.load_state(&data).is_err());
+        assert_eq!(other.save_state(), before);
+    }
+}
@@ -0,0 +1,202 @@
+//! Symbols produced by the assembler, used by the debugging tools.
+//!
+//! The symbol file is a text file with one entry per line :
+//! `label $0202 loop` gives the address of a label and
+//! `line $0202 12 prog/loop.asm` gives the source line of an instruction.
+
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct LineInfo {
+    pub addr: u16,
+    pub line: usize,
+    pub file: String,
+}
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct SymbolTable {
+    labels: BTreeMap<String, u16>,
+    lines: Vec<LineInfo>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert_label(&mut self, name: &str, addr: u16) {
+        self.labels.insert(name.to_string(), addr);
+    }
+
+    pub fn insert_line(&mut self, addr: u16, line: usize, file: &str) {
+        self.lines.push(LineInfo {
+            addr,
+            line,
+            file: file.to_string(),
+        });
+    }
+
+    pub fn labels(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.labels
+            .iter()
+            .map(|(name, addr)| (name.as_str(), *addr))
+    }
+
+    pub fn lines(&self) -> &[LineInfo] {
+        &self.lines
+    }
+
+    pub fn address_of(&self, label: &str) -> Option<u16> {
+        self.labels.get(label).copied()
+    }
+
+    pub fn label_at(&self, addr: u16) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(_, label_addr)| **label_addr == addr)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Closest label at or before the address, with the offset from it
+    pub fn nearest_label(&self, addr: u16) -> Option<(&str, u16)> {
+        self.labels
+            .iter()
+            .filter(|(_, label_addr)| **label_addr <= addr)
+            .max_by_key(|(_, label_addr)| **label_addr)
+            .map(|(name, label_addr)| (name.as_str(), addr - label_addr))
+    }
+
+    /// Address formatted with the nearest label, like `$0205 <loop+3>`
+    pub fn describe(&self, addr: u16) -> String {
+        match self.nearest_label(addr) {
+            Some((name, 0)) => format!("${:04x} <{}>", addr, name),
+            Some((name, offset)) => format!("${:04x} <{}+{}>", addr, name, offset),
+            None => format!("${:04x}", addr),
+        }
+    }
+
+    pub fn line_of(&self, addr: u16) -> Option<&LineInfo> {
+        self.lines.iter().find(|info| info.addr == addr)
+    }
+
+    /// Addresses of the instructions assembled from a source line
+    pub fn addresses_of_line(&self, file: &str, line: usize) -> Vec<u16> {
+        self.lines
+            .iter()
+            .filter(|info| info.line == line && info.file == file)
+            .map(|info| info.addr)
+            .collect()
+    }
+
+    /// Parses an address given as a number (`$0200`, `%0101`, `512`) or a label
+    pub fn parse_address(&self, value: &str) -> Result<u16, String> {
+        if let Some(addr) = self.address_of(value) {
+            return Ok(addr);
+        }
+
+        parse_number(value).ok_or(format!("Unknown address or label : {}", value))
+    }
+}
+
+/// Parses a number in the syntax of the assembler, `0x` is also accepted for hexadecimal
+pub fn parse_number(value: &str) -> Option<u16> {
+    parse_u32(value).and_then(|value| u16::try_from(value).ok())
+}
+
+/// Same syntax as `parse_number`, for the sizes and counts larger than 16 bits
+pub fn parse_u32(value: &str) -> Option<u32> {
+    if let Some(hex) = value.strip_prefix('$').or(value.strip_prefix("0x")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = value.strip_prefix('%') {
+        u32::from_str_radix(bin, 2).ok()
+    } else {
+        value.parse::<u32>().ok()
+    }
+}
+
+impl fmt::Display for SymbolTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, addr) in self.labels.iter() {
+            writeln!(f, "label ${:04x} {}", addr, name)?;
+        }
+        for info in self.lines.iter() {
+            writeln!(f, "line ${:04x} {} {}", info.addr, info.line, info.file)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SymbolTable {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut symbols = SymbolTable::new();
+
+        for (idx, line) in value.lines().enumerate() {
+            let tokens: Vec<&str> = line.splitn(4, ' ').collect();
+            let addr = tokens.get(1).and_then(|addr| parse_number(addr));
+
+            match (tokens.as_slice(), addr) {
+                ([], _) | ([""], _) => {}
+                (["label", _, name], Some(addr)) => symbols.insert_label(name, addr),
+                (["line", _, line, file], Some(addr)) => {
+                    let line = line
+                        .parse()
+                        .map_err(|_| format!("Wrong line number on line {}", idx + 1))?;
+                    symbols.insert_line(addr, line, file)
+                }
+                _ => return Err(format!("Wrong symbol entry on line {}", idx + 1)),
+            }
+        }
+
+        Ok(symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbols_to_from_string() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert_label("start", 0x0200);
+        symbols.insert_label("loop", 0x0202);
+        symbols.insert_line(0x0200, 3, "prog/my loop.asm");
+        symbols.insert_line(0x0202, 5, "prog/my loop.asm");
+
+        let text = symbols.to_string();
+        assert_eq!(
+            text,
+            "label $0202 loop\nlabel $0200 start\nline $0200 3 prog/my loop.asm\nline $0202 5 prog/my loop.asm\n"
+        );
+        assert_eq!(text.parse::<SymbolTable>().unwrap(), symbols);
+
+        assert!("label $02".parse::<SymbolTable>().is_err());
+        assert!("line $0200 x file".parse::<SymbolTable>().is_err());
+        assert!("other $0200 x".parse::<SymbolTable>().is_err());
+    }
+
+    #[test]
+    fn test_symbols_lookup() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert_label("start", 0x0200);
+        symbols.insert_label("loop", 0x0210);
+        symbols.insert_line(0x0210, 8, "a.asm");
+
+        assert_eq!(symbols.parse_address("loop").unwrap(), 0x0210);
+        assert_eq!(symbols.parse_address("$1234").unwrap(), 0x1234);
+        assert_eq!(symbols.parse_address("0x1234").unwrap(), 0x1234);
+        assert_eq!(symbols.parse_address("%11").unwrap(), 3);
+        assert_eq!(symbols.parse_address("42").unwrap(), 42);
+        assert!(symbols.parse_address("nowhere").is_err());
+        assert!(symbols.parse_address("$10000").is_err());
+        assert_eq!(parse_u32("$10000"), Some(0x10000));
+
+        assert_eq!(symbols.label_at(0x0200), Some("start"));
+        assert_eq!(symbols.describe(0x0213), "$0213 <loop+3>");
+        assert_eq!(symbols.describe(0x0100), "$0100");
+        assert_eq!(symbols.line_of(0x0210).unwrap().line, 8);
+        assert_eq!(symbols.addresses_of_line("a.asm", 8), vec![0x0210]);
+    }
+}
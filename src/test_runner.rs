@@ -0,0 +1,438 @@
+//! Unit tests for assembly routines, described in a TOML spec.
+//!
+//! ```toml
+//! program = "math.asm"          # assembled, or `binary` and `symbols` files
+//! max_cycles = 100000           # default limit of every case
+//!
+//! [[test]]
+//! name = "double 21"
+//! call = "double"               # label or address of the routine
+//! registers = { a = 21 }
+//! flags = { c = false }
+//! memory = { "table" = [1, 2, 3], "$0200" = 5 }
+//!
+//! [test.expect]
+//! registers = { a = 42 }
+//! memory = { "result" = 42 }
+//! max_cycles = 20               # optional, fails when the call is slower
+//! ```
+//!
+//! Every case runs in a fresh `Vm` with the program loaded at address 0. The routine is
+//! called as with a JSR and the case ends when it returns, the memory and registers are
+//! then compared to the expected ones.
+
+use std::{collections::BTreeMap, fmt::Write, fs, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    asm::assemble,
+    isa::{Register, RegisterFlag},
+    symbols::SymbolTable,
+    Vm,
+};
+
+const DEFAULT_MAX_CYCLES: u64 = 1_000_000;
+const INITIAL_SP: u8 = 0xFF;
+// Return address of the routine, stops the case once reached with the initial stack
+const RETURN_ADDR: u16 = 0xFFFF;
+
+/// One byte or a list of bytes stored from an address
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Bytes {
+    One(u8),
+    Many(Vec<u8>),
+}
+
+impl Bytes {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Bytes::One(value) => std::slice::from_ref(value),
+            Bytes::Many(values) => values,
+        }
+    }
+}
+
+/// Registers, flags and memory expected after the call
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expectation {
+    #[serde(default)]
+    pub registers: BTreeMap<String, u8>,
+    #[serde(default)]
+    pub flags: BTreeMap<String, bool>,
+    #[serde(default)]
+    pub memory: BTreeMap<String, Bytes>,
+    pub max_cycles: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestCase {
+    pub name: String,
+    pub call: String,
+    pub max_cycles: Option<u64>,
+    #[serde(default)]
+    pub registers: BTreeMap<String, u8>,
+    #[serde(default)]
+    pub flags: BTreeMap<String, bool>,
+    #[serde(default)]
+    pub memory: BTreeMap<String, Bytes>,
+    #[serde(default)]
+    pub expect: Expectation,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestSpec {
+    pub program: Option<String>,
+    pub binary: Option<String>,
+    pub symbols: Option<String>,
+    pub max_cycles: Option<u64>,
+    #[serde(rename = "test", default)]
+    pub tests: Vec<TestCase>,
+}
+
+/// Outcome of a case, with the cycles used by the call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub name: String,
+    pub cycles: u64,
+    pub failure: Option<String>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// Results of a spec, the name is the one of the spec file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuiteResult {
+    pub name: String,
+    pub results: Vec<TestResult>,
+}
+
+impl SuiteResult {
+    pub fn failures(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| !result.passed())
+            .count()
+    }
+}
+
+impl TestSpec {
+    /// Loads the program of the spec, its files are relative to `base_dir`
+    pub fn load_program(&self, base_dir: &Path) -> Result<(Vec<u8>, SymbolTable), String> {
+        let read =
+            |file: &str| fs::read(base_dir.join(file)).map_err(|err| format!("{} : {}", file, err));
+
+        match (&self.program, &self.binary) {
+            (Some(source), None) => {
+                let text = String::from_utf8(read(source)?)
+                    .map_err(|err| format!("{} : {}", source, err))?;
+                let program = assemble(&text, source)?;
+                Ok((program.bytes, program.symbols))
+            }
+            (None, Some(binary)) => {
+                let symbols = match &self.symbols {
+                    Some(file) => String::from_utf8(read(file)?)
+                        .map_err(|err| format!("{} : {}", file, err))?
+                        .parse()?,
+                    None => SymbolTable::new(),
+                };
+                Ok((read(binary)?, symbols))
+            }
+            _ => Err("The spec needs either a program or a binary".to_string()),
+        }
+    }
+
+    /// Runs every case, each one in a fresh Vm
+    pub fn run(&self, program: &[u8], symbols: &SymbolTable) -> Vec<TestResult> {
+        self.tests
+            .iter()
+            .map(|case| {
+                let max_cycles = case
+                    .max_cycles
+                    .or(self.max_cycles)
+                    .unwrap_or(DEFAULT_MAX_CYCLES);
+                match run_case(case, program, symbols, max_cycles) {
+                    Ok((cycles, failure)) => TestResult {
+                        name: case.name.clone(),
+                        cycles,
+                        failure,
+                    },
+                    Err(err) => TestResult {
+                        name: case.name.clone(),
+                        cycles: 0,
+                        failure: Some(err),
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+fn register_by_name(name: &str) -> Result<Register, String> {
+    match name.to_lowercase().as_str() {
+        "a" | "ac" => Ok(Register::AC),
+        "x" => Ok(Register::X),
+        "y" => Ok(Register::Y),
+        "sp" => Ok(Register::SP),
+        "sr" => Ok(Register::SR),
+        _ => Err(format!("Unknown register : {}", name)),
+    }
+}
+
+fn flag_by_name(name: &str) -> Result<RegisterFlag, String> {
+    match name.to_lowercase().as_str() {
+        "n" => Ok(RegisterFlag::Negative),
+        "v" => Ok(RegisterFlag::Overflow),
+        "b" => Ok(RegisterFlag::Break),
+        "d" => Ok(RegisterFlag::Decimal),
+        "i" => Ok(RegisterFlag::Interrupt),
+        "z" => Ok(RegisterFlag::Zero),
+        "c" => Ok(RegisterFlag::Carry),
+        _ => Err(format!("Unknown flag : {}", name)),
+    }
+}
+
+/// Runs one case, gives the cycles of the call and the failure if any.
+/// An error means the case itself is wrong, like an unknown label.
+fn run_case(
+    case: &TestCase,
+    program: &[u8],
+    symbols: &SymbolTable,
+    max_cycles: u64,
+) -> Result<(u64, Option<String>), String> {
+    let mut vm = Vm::new();
+    vm.copy_memory(0, program);
+
+    for (location, bytes) in case.memory.iter() {
+        let addr = symbols.parse_address(location)?;
+        vm.copy_memory(addr as usize, bytes.as_slice());
+    }
+    vm.set_register(Register::SP, INITIAL_SP);
+    for (name, value) in case.registers.iter() {
+        vm.set_register(register_by_name(name)?, *value);
+    }
+    for (name, value) in case.flags.iter() {
+        vm.set_flag(flag_by_name(name)?, *value);
+    }
+
+    // Same stack as after a JSR, the return address is pushed minus one
+    let sp = vm.get_register(Register::SP);
+    let [low, high] = RETURN_ADDR.wrapping_sub(1).to_le_bytes();
+    vm.copy_memory(0x0100 | sp as usize, &[high]);
+    vm.copy_memory(0x0100 | sp.wrapping_sub(1) as usize, &[low]);
+    vm.set_register(Register::SP, sp.wrapping_sub(2));
+    vm.set_pc(symbols.parse_address(&case.call)?);
+
+    loop {
+        if vm.get_pc() == RETURN_ADDR && vm.get_register(Register::SP) == sp {
+            break;
+        }
+        if vm.halt {
+            return Ok((
+                vm.cycle_count(),
+                Some(format!("halted at ${:04x} before returning", vm.get_pc())),
+            ));
+        }
+        if vm.cycle_count() >= max_cycles {
+            return Ok((
+                vm.cycle_count(),
+                Some(format!(
+                    "no return after {} cycles, at ${:04x}",
+                    max_cycles,
+                    vm.get_pc()
+                )),
+            ));
+        }
+        if let Err(err) = vm.cycle() {
+            return Ok((vm.cycle_count(), Some(err)));
+        }
+    }
+
+    let cycles = vm.cycle_count();
+    let mut failures = Vec::new();
+    for (name, expected) in case.expect.registers.iter() {
+        let value = vm.get_register(register_by_name(name)?);
+        if value != *expected {
+            failures.push(format!(
+                "register {} is ${:02x}, expected ${:02x}",
+                name, value, expected
+            ));
+        }
+    }
+    for (name, expected) in case.expect.flags.iter() {
+        let value = vm.get_flag(flag_by_name(name)?);
+        if value != *expected {
+            failures.push(format!("flag {} is {}, expected {}", name, value, expected));
+        }
+    }
+    for (location, bytes) in case.expect.memory.iter() {
+        let addr = symbols.parse_address(location)?;
+        for (offset, expected) in bytes.as_slice().iter().enumerate() {
+            let byte_addr = addr.wrapping_add(offset as u16);
+            let value = vm.read_memory(byte_addr).unwrap_or_default();
+            if value != *expected {
+                failures.push(format!(
+                    "memory {} is ${:02x}, expected ${:02x}",
+                    symbols.describe(byte_addr),
+                    value,
+                    expected
+                ));
+            }
+        }
+    }
+    if let Some(limit) = case.expect.max_cycles {
+        if cycles > limit {
+            failures.push(format!(
+                "took {} cycles, expected at most {}",
+                cycles, limit
+            ));
+        }
+    }
+
+    let failure = (!failures.is_empty()).then(|| failures.join(", "));
+    Ok((cycles, failure))
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Report of the suites in the JUnit XML format, understood by the CI tools
+pub fn junit_xml(suites: &[SuiteResult]) -> String {
+    let tests: usize = suites.iter().map(|suite| suite.results.len()).sum();
+    let failures: usize = suites.iter().map(|suite| suite.failures()).sum();
+
+    let mut xml = String::new();
+    writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(
+        xml,
+        r#"<testsuites tests="{}" failures="{}">"#,
+        tests, failures
+    )
+    .unwrap();
+    for suite in suites {
+        let name = escape_xml(&suite.name);
+        writeln!(
+            xml,
+            r#"  <testsuite name="{}" tests="{}" failures="{}">"#,
+            name,
+            suite.results.len(),
+            suite.failures()
+        )
+        .unwrap();
+        for result in suite.results.iter() {
+            write!(
+                xml,
+                r#"    <testcase name="{}" classname="{}">"#,
+                escape_xml(&result.name),
+                name
+            )
+            .unwrap();
+            if let Some(failure) = result.failure.as_ref() {
+                write!(xml, r#"<failure message="{}"/>"#, escape_xml(failure)).unwrap();
+            }
+            writeln!(
+                xml,
+                r#"<system-out>{} cycles</system-out></testcase>"#,
+                result.cycles
+            )
+            .unwrap();
+        }
+        writeln!(xml, "  </testsuite>").unwrap();
+    }
+    writeln!(xml, "</testsuites>").unwrap();
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "
+double:
+    ArmLShfAC
+    StoreACZp result
+    RetSub
+forever:
+    JumpAbs forever
+result:
+";
+
+    const SPEC: &str = r#"
+program = "math.asm"
+
+[[test]]
+name = "double 21"
+call = "double"
+registers = { a = 21 }
+expect = { registers = { a = 42 }, flags = { c = false }, memory = { result = 42 } }
+
+[[test]]
+name = "double <overflow>"
+call = "double"
+registers = { a = 0x81 }
+expect = { registers = { a = 2 }, memory = { "result" = [3] } }
+
+[[test]]
+name = "timeout"
+call = "forever"
+max_cycles = 100
+"#;
+
+    #[test]
+    fn test_run_spec() {
+        let spec: TestSpec = toml::from_str(SPEC).unwrap();
+        let program = assemble(SOURCE, "math.asm").unwrap();
+        let results = spec.run(&program.bytes, &program.symbols);
+
+        assert_eq!(results.len(), 3);
+        assert!(results[0].passed());
+        assert_eq!(results[0].cycles, 2 + 3 + 6);
+        assert_eq!(
+            results[1].failure.as_deref(),
+            Some("memory $0007 <result> is $02, expected $03")
+        );
+        assert!(results[2]
+            .failure
+            .as_ref()
+            .unwrap()
+            .starts_with("no return after 100 cycles"));
+
+        let xml = junit_xml(&[SuiteResult {
+            name: "math.toml".to_string(),
+            results,
+        }]);
+        assert!(xml.contains(r#"<testsuites tests="3" failures="2">"#));
+        assert!(xml.contains(r#"<testcase name="double &lt;overflow&gt;" classname="math.toml"><failure message="memory $0007 &lt;result&gt; is $02, expected $03"/>"#));
+    }
+
+    #[test]
+    fn test_spec_errors() {
+        assert!(
+            toml::from_str::<TestSpec>("[[test]]\nname = \"a\"\ncall = \"b\"\nunknown = 1")
+                .is_err()
+        );
+
+        let spec: TestSpec =
+            toml::from_str("binary = \"a.bin\"\n[[test]]\nname = \"a\"\ncall = \"nowhere\"")
+                .unwrap();
+        let results = spec.run(&[0x60], &SymbolTable::new());
+        assert_eq!(
+            results[0].failure.as_deref(),
+            Some("Unknown address or label : nowhere")
+        );
+    }
+}
@@ -0,0 +1,498 @@
+//! Execution trace in the style of the nestest log of Nintendulator.
+//!
+//! Every line describes the next instruction before it runs :
+//! `C72C  AD 00 02  LDA $0200 = 5A                  A:00 X:00 Y:00 P:24 SP:FD CYC:14`.
+//! The effective address is given after `@` for the indexed and indirect modes, and the
+//! value in memory at this address, before the instruction, after `=`.
+
+use std::{fmt::Display, str::FromStr};
+
+use crate::{
+    isa::{AddrMode, Instruction, Register},
+    Vm,
+};
+
+/// Column where the registers start, as in the nestest log
+const REGISTERS_COLUMN: usize = 48;
+
+fn peek(vm: &Vm, addr: u16) -> u8 {
+    vm.read_memory(addr).unwrap_or_default()
+}
+
+fn peek_address(vm: &Vm, low_addr: u16, high_addr: u16) -> u16 {
+    u16::from_le_bytes([peek(vm, low_addr), peek(vm, high_addr)])
+}
+
+/// Disassembly of the instruction at PC in the usual 6502 syntax, with its effective address
+pub fn disassemble_effective(vm: &Vm, pc: u16, instruction: Instruction) -> String {
+    let bytes: Vec<u8> = instruction.into();
+    let byte = bytes.get(1).copied().unwrap_or_default();
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or_default()]);
+    let mnemonic = instruction.mnemonic();
+    let x = vm.get_register(Register::X);
+    let y = vm.get_register(Register::Y);
+
+    match instruction.addr_mode() {
+        AddrMode::Implied => match instruction {
+            Instruction::ArmLShfAC
+            | Instruction::LogRShfAC
+            | Instruction::LRotAC
+            | Instruction::RRotAC => format!("{} A", mnemonic),
+            _ => mnemonic.to_string(),
+        },
+        AddrMode::Immediate => format!("{} #${:02X}", mnemonic, byte),
+        AddrMode::Absolute => match instruction {
+            Instruction::JumpAbs(_) | Instruction::JumpSubAbs(_) => {
+                format!("{} ${:04X}", mnemonic, word)
+            }
+            _ => format!("{} ${:04X} = {:02X}", mnemonic, word, peek(vm, word)),
+        },
+        AddrMode::AbsoluteIndirect => {
+            // The high byte of the pointer does not cross the page
+            let high_addr = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+            let target = peek_address(vm, word, high_addr);
+            format!("{} (${:04X}) = {:04X}", mnemonic, word, target)
+        }
+        AddrMode::AbsoluteX | AddrMode::AbsoluteY => {
+            let (index, name) = if instruction.addr_mode() == AddrMode::AbsoluteX {
+                (x, 'X')
+            } else {
+                (y, 'Y')
+            };
+            let addr = word.wrapping_add(index as u16);
+            format!(
+                "{} ${:04X},{} @ {:04X} = {:02X}",
+                mnemonic,
+                word,
+                name,
+                addr,
+                peek(vm, addr)
+            )
+        }
+        AddrMode::ZeroPage => format!("{} ${:02X} = {:02X}", mnemonic, byte, peek(vm, byte as u16)),
+        AddrMode::ZeroPageX | AddrMode::ZeroPageY => {
+            let (index, name) = if instruction.addr_mode() == AddrMode::ZeroPageX {
+                (x, 'X')
+            } else {
+                (y, 'Y')
+            };
+            let addr = byte.wrapping_add(index);
+            format!(
+                "{} ${:02X},{} @ {:02X} = {:02X}",
+                mnemonic,
+                byte,
+                name,
+                addr,
+                peek(vm, addr as u16)
+            )
+        }
+        AddrMode::ZeroPageXIndirect => {
+            let pointer = byte.wrapping_add(x);
+            let addr = peek_address(vm, pointer as u16, pointer.wrapping_add(1) as u16);
+            format!(
+                "{} (${:02X},X) @ {:02X} = {:04X} = {:02X}",
+                mnemonic,
+                byte,
+                pointer,
+                addr,
+                peek(vm, addr)
+            )
+        }
+        AddrMode::ZeroPageIndirectY => {
+            let base = peek_address(vm, byte as u16, byte.wrapping_add(1) as u16);
+            let addr = base.wrapping_add(y as u16);
+            format!(
+                "{} (${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+                mnemonic,
+                byte,
+                base,
+                addr,
+                peek(vm, addr)
+            )
+        }
+        AddrMode::Relative => {
+            let next = pc.wrapping_add(2);
+            let target = next.wrapping_add(byte as i8 as u16);
+            format!("{} ${:04X}", mnemonic, target)
+        }
+    }
+}
+
+/// Trace line of the next instruction, the Vm is not modified
+pub fn trace_line(vm: &Vm) -> Result<String, String> {
+    let pc = vm.get_pc();
+    let instruction = vm.decode_at(pc)?;
+    let bytes: Vec<u8> = instruction.into();
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+
+    let mut line = format!(
+        "{:04X}  {:<8}  {}",
+        pc,
+        bytes.join(" "),
+        disassemble_effective(vm, pc, instruction)
+    );
+    while line.len() < REGISTERS_COLUMN {
+        line.push(' ');
+    }
+    line.push_str(&format!(
+        "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+        vm.get_register(Register::AC),
+        vm.get_register(Register::X),
+        vm.get_register(Register::Y),
+        vm.get_register(Register::SR),
+        vm.get_register(Register::SP),
+        vm.cycle_count()
+    ));
+
+    Ok(line)
+}
+
+/// Line of a trace file, parsed back
+///
+/// The columns after the cycle count, like the PPU position of the nestest log, are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceLine {
+    pub pc: u16,
+    pub bytes: Vec<u8>,
+    pub disassembly: String,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    pub cycles: Option<u64>,
+    text: String,
+}
+
+impl TraceLine {
+    /// Effective address and memory values of the disassembly, empty when the operand has none
+    pub fn memory_effect(&self) -> &str {
+        self.disassembly
+            .find(['@', '='])
+            .map(|index| &self.disassembly[index..])
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for TraceLine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let registers_start = s
+            .find("A:")
+            .ok_or_else(|| format!("No registers in the trace line : {}", s))?;
+        let (instruction, registers) = s.split_at(registers_start);
+
+        let pc = instruction
+            .get(0..4)
+            .and_then(|pc| u16::from_str_radix(pc, 16).ok())
+            .ok_or_else(|| format!("Invalid PC in the trace line : {}", s))?;
+        let bytes_column = instruction.get(4..16).unwrap_or_default();
+        let bytes = bytes_column
+            .split_whitespace()
+            .map(|byte| u8::from_str_radix(byte, 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| format!("Invalid bytes in the trace line : {}", s))?;
+        let disassembly = instruction.get(16..).unwrap_or_default().trim().to_string();
+
+        let register = |name: &str| -> Result<u8, String> {
+            registers
+                .split_whitespace()
+                .find_map(|field| field.strip_prefix(name))
+                .and_then(|value| u8::from_str_radix(value, 16).ok())
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid register {} in the trace line : {}",
+                        name.trim_end_matches(':'),
+                        s
+                    )
+                })
+        };
+        let cycles = registers
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("CYC:"))
+            .and_then(|value| value.parse().ok());
+
+        Ok(TraceLine {
+            pc,
+            bytes,
+            disassembly,
+            a: register("A:")?,
+            x: register("X:")?,
+            y: register("Y:")?,
+            p: register("P:")?,
+            sp: register("SP:")?,
+            cycles,
+            text: s.trim_end().to_string(),
+        })
+    }
+}
+
+impl Display for TraceLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Parse a whole trace file, the empty lines are skipped
+pub fn parse_trace(text: &str) -> Result<Vec<TraceLine>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            line.parse()
+                .map_err(|err| format!("Line {} : {}", index + 1, err))
+        })
+        .collect()
+}
+
+/// Kind of difference between two lines of trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DivergenceKind {
+    /// Not the same instruction : the control flow or the code differs
+    Flow,
+    /// The same instruction with other register values
+    Registers,
+    /// The same instruction with another effective address or value in memory
+    Memory,
+    /// The same instruction started at another cycle
+    Cycles,
+    /// One of the traces stops earlier
+    Length,
+}
+
+impl Display for DivergenceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            DivergenceKind::Flow => "flow",
+            DivergenceKind::Registers => "registers",
+            DivergenceKind::Memory => "memory",
+            DivergenceKind::Cycles => "cycles",
+            DivergenceKind::Length => "length",
+        })
+    }
+}
+
+/// Differences between two lines, the registers are listed by name
+pub fn compare_lines(
+    left: &TraceLine,
+    right: &TraceLine,
+) -> (Vec<DivergenceKind>, Vec<&'static str>) {
+    let mut kinds = Vec::new();
+    if left.pc != right.pc || left.bytes != right.bytes {
+        kinds.push(DivergenceKind::Flow);
+    }
+
+    let registers: Vec<&'static str> = [
+        ("A", left.a, right.a),
+        ("X", left.x, right.x),
+        ("Y", left.y, right.y),
+        ("P", left.p, right.p),
+        ("SP", left.sp, right.sp),
+    ]
+    .into_iter()
+    .filter(|(_, left, right)| left != right)
+    .map(|(name, _, _)| name)
+    .collect();
+    if !registers.is_empty() {
+        kinds.push(DivergenceKind::Registers);
+    }
+
+    if !kinds.contains(&DivergenceKind::Flow) && left.memory_effect() != right.memory_effect() {
+        kinds.push(DivergenceKind::Memory);
+    }
+    if left.cycles.is_some() && right.cycles.is_some() && left.cycles != right.cycles {
+        kinds.push(DivergenceKind::Cycles);
+    }
+
+    (kinds, registers)
+}
+
+/// Comparison of two traces, aligned instruction by instruction
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TraceDiff {
+    /// Index of the first line that differs, with the kinds of differences and the registers
+    pub first: Option<(usize, Vec<DivergenceKind>, Vec<&'static str>)>,
+    /// Number of lines with each kind of difference, with the index of the first one
+    pub counts: Vec<(DivergenceKind, usize, usize)>,
+    /// Number of lines with a difference on each register
+    pub registers: Vec<(&'static str, usize)>,
+}
+
+impl TraceDiff {
+    pub fn is_identical(&self) -> bool {
+        self.first.is_none()
+    }
+}
+
+/// Compare two traces line by line
+pub fn diff_traces(left: &[TraceLine], right: &[TraceLine]) -> TraceDiff {
+    let mut diff = TraceDiff::default();
+
+    let count = |diff: &mut TraceDiff, kind: DivergenceKind, index: usize| match diff
+        .counts
+        .iter_mut()
+        .find(|(other, _, _)| *other == kind)
+    {
+        Some((_, count, _)) => *count += 1,
+        None => diff.counts.push((kind, 1, index)),
+    };
+
+    for (index, (left_line, right_line)) in left.iter().zip(right).enumerate() {
+        let (kinds, registers) = compare_lines(left_line, right_line);
+        if kinds.is_empty() {
+            continue;
+        }
+
+        for kind in kinds.iter() {
+            count(&mut diff, *kind, index);
+        }
+        for register in registers.iter() {
+            match diff.registers.iter_mut().find(|(name, _)| name == register) {
+                Some((_, count)) => *count += 1,
+                None => diff.registers.push((register, 1)),
+            }
+        }
+        if diff.first.is_none() {
+            diff.first = Some((index, kinds, registers));
+        }
+    }
+
+    if left.len() != right.len() {
+        let index = left.len().min(right.len());
+        count(&mut diff, DivergenceKind::Length, index);
+        if diff.first.is_none() {
+            diff.first = Some((index, vec![DivergenceKind::Length], Vec::new()));
+        }
+    }
+
+    diff.counts.sort();
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace_program(prog: &[u8], setup: fn(&mut Vm)) -> Vec<String> {
+        let mut vm = Vm::new();
+        vm.copy_memory(0xC000, prog);
+        vm.set_pc(0xC000);
+        vm.set_register(Register::SP, 0xFD);
+        vm.set_register(Register::SR, 0x24);
+        setup(&mut vm);
+
+        let mut lines = Vec::new();
+        while !vm.halt {
+            lines.push(trace_line(&vm).unwrap());
+            vm.cycle().unwrap();
+        }
+        lines
+    }
+
+    #[test]
+    fn test_trace_nestest_format() {
+        #[rustfmt::skip]
+        let prog = [
+            0xA2, 0x02,       // LDX #$02
+            0xB5, 0x10,       // LDA $10,X
+            0x8D, 0x01, 0x02, // STA $0201
+            0xA1, 0x20,       // LDA ($20,X)
+            0xA0, 0x01,       // LDY #$01
+            0x91, 0x22,       // STA ($22),Y
+            0x4A,             // LSR A
+            0x6C, 0xFF, 0x02, // JMP ($02FF)
+            0xF2,             // JAM
+        ];
+        let lines = trace_program(&prog, |vm| {
+            vm.copy_memory(0x0012, &[0x5A]);
+            vm.copy_memory(0x0022, &[0x00, 0x03]);
+            vm.copy_memory(0x02FF, &[0x11]);
+            vm.copy_memory(0x0200, &[0xC0, 0x77]);
+        });
+
+        assert_eq!(
+            lines,
+            vec![
+                "C000  A2 02     LDX #$02                        A:00 X:00 Y:00 P:24 SP:FD CYC:0",
+                "C002  B5 10     LDA $10,X @ 12 = 5A             A:00 X:02 Y:00 P:24 SP:FD CYC:2",
+                "C004  8D 01 02  STA $0201 = 77                  A:5A X:02 Y:00 P:24 SP:FD CYC:6",
+                "C007  A1 20     LDA ($20,X) @ 22 = 0300 = 00    A:5A X:02 Y:00 P:24 SP:FD CYC:10",
+                "C009  A0 01     LDY #$01                        A:00 X:02 Y:00 P:26 SP:FD CYC:16",
+                "C00B  91 22     STA ($22),Y = 0300 @ 0301 = 00  A:00 X:02 Y:01 P:24 SP:FD CYC:18",
+                "C00D  4A        LSR A                           A:00 X:02 Y:01 P:24 SP:FD CYC:24",
+                "C00E  6C FF 02  JMP ($02FF) = C011              A:00 X:02 Y:01 P:26 SP:FD CYC:26",
+                "C011  F2        JAM                             A:00 X:02 Y:01 P:26 SP:FD CYC:31",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trace_branch_and_jump() {
+        #[rustfmt::skip]
+        let prog = [
+            0xD0, 0x02,       // BNE $C004
+            0xF2,             // JAM
+            0xF2,             // JAM
+            0x20, 0x08, 0xC0, // JSR $C008
+            0xF2,             // JAM
+            0xF0, 0xFD,       // BEQ $C007
+            0xF2,             // JAM
+        ];
+        let lines = trace_program(&prog, |_| {});
+
+        assert!(lines[0].starts_with("C000  D0 02     BNE $C004 "));
+        assert!(lines[1].starts_with("C004  20 08 C0  JSR $C008 "));
+        assert!(lines[2].starts_with("C008  F0 FD     BEQ $C007 "));
+    }
+
+    #[test]
+    fn test_parse_trace_line() {
+        let line: TraceLine =
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7"
+                .parse()
+                .unwrap();
+        assert_eq!(line.pc, 0xC000);
+        assert_eq!(line.bytes, vec![0x4C, 0xF5, 0xC5]);
+        assert_eq!(line.disassembly, "JMP $C5F5");
+        assert_eq!((line.p, line.sp, line.cycles), (0x24, 0xFD, Some(7)));
+        assert_eq!(line.memory_effect(), "");
+
+        let line: TraceLine =
+            "C007  A1 20     LDA ($20,X) @ 22 = 0300 = 00    A:5A X:02 Y:00 P:24 SP:FD CYC:10"
+                .parse()
+                .unwrap();
+        assert_eq!(line.memory_effect(), "@ 22 = 0300 = 00");
+        assert!("C000  garbage".parse::<TraceLine>().is_err());
+    }
+
+    #[test]
+    fn test_diff_traces() {
+        let left = parse_trace(
+            "C000  A2 02     LDX #$02                        A:00 X:00 Y:00 P:24 SP:FD CYC:0\n\
+             C002  B5 10     LDA $10,X @ 12 = 5A             A:00 X:02 Y:00 P:24 SP:FD CYC:2\n\
+             C004  8D 01 02  STA $0201 = 77                  A:5A X:02 Y:00 P:24 SP:FD CYC:6\n",
+        )
+        .unwrap();
+        let right = parse_trace(
+            "C000  A2 02     LDX #$02                        A:00 X:00 Y:00 P:24 SP:FD CYC:0\n\
+             C002  B5 10     LDA $10,X @ 12 = 00             A:00 X:02 Y:00 P:24 SP:FD CYC:2\n\
+             C004  8D 01 02  STA $0201 = 77                  A:00 X:02 Y:00 P:26 SP:FD CYC:6\n\
+             C007  F2        JAM                             A:00 X:02 Y:00 P:26 SP:FD CYC:10\n",
+        )
+        .unwrap();
+
+        let diff = diff_traces(&left, &right);
+        assert_eq!(diff.first, Some((1, vec![DivergenceKind::Memory], vec![])));
+        assert_eq!(
+            diff.counts,
+            vec![
+                (DivergenceKind::Registers, 1, 2),
+                (DivergenceKind::Memory, 1, 1),
+                (DivergenceKind::Length, 1, 3),
+            ]
+        );
+        assert_eq!(diff.registers, vec![("A", 1), ("P", 1)]);
+        assert!(diff_traces(&left, &left).is_identical());
+    }
+}
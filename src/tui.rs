@@ -0,0 +1,626 @@
+//! Full screen debugger in the terminal.
+//!
+//! The screen shows the disassembly around PC, the registers and flags, the stack page,
+//! a memory view and a watch list. The program is run by slices of cycles so a key can
+//! break into it, `next` and `finish` included. `:` opens a command line taking the commands of the debugger, plus
+//! `display ADDR`, `undisplay N` for the watch list and `view ADDR|pc|ptr ZP` for the memory view.
+
+use std::{io, time::Duration};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Paragraph},
+    Frame,
+};
+
+use crate::{
+    breakpoints::{BreakpointKind, StopReason},
+    debugger::{disassemble, Debugger, StepTarget},
+    isa::{Register, RegisterFlag},
+    symbols::SymbolTable,
+    Vm, STACK_PAGE,
+};
+
+/// Number of cycles run between two checks of the keyboard
+const SLICE_CYCLES: u64 = 20_000;
+
+const LOG_LINES: usize = 3;
+
+const HELP: &str =
+    " s step  n next  f finish  r run  b break  m memory view  PgUp/PgDn scroll  : command  q quit";
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum MemoryView {
+    Fixed(u16),
+    FollowPc,
+    /// Follows the address stored in the zero page at this location
+    FollowPointer(u8),
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum RunMode {
+    Continue,
+    /// Run until the target of a next or a finish
+    Step(StepTarget),
+}
+
+pub struct TuiDebugger {
+    debugger: Debugger,
+    memory_view: MemoryView,
+    watches: Vec<u16>,
+    input: Option<String>,
+    log: Vec<String>,
+    running: Option<RunMode>,
+}
+
+impl TuiDebugger {
+    pub fn new(symbols: SymbolTable) -> Self {
+        Self {
+            debugger: Debugger::new(symbols),
+            memory_view: MemoryView::FollowPc,
+            watches: Vec::new(),
+            input: None,
+            log: Vec::new(),
+            running: None,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.debugger.is_finished()
+    }
+
+    pub fn memory_view(&self) -> MemoryView {
+        self.memory_view
+    }
+
+    pub fn handle_key(&mut self, vm: &mut Vm, key: KeyEvent) {
+        if let Some(input) = self.input.as_mut() {
+            match key.code {
+                KeyCode::Char(c) => input.push(c),
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Enter => {
+                    let command = self.input.take().unwrap_or_default();
+                    self.command(vm, &command);
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        match key.code {
+            KeyCode::Char('s') => self.command(vm, "step"),
+            KeyCode::Char('n') => match StepTarget::next(vm) {
+                Some(target) => self.start(vm, RunMode::Step(target)),
+                None => self.command(vm, "step"),
+            },
+            KeyCode::Char('f') => self.start(vm, RunMode::Step(StepTarget::finish(vm))),
+            KeyCode::Char('r') | KeyCode::F(5) => self.start(vm, RunMode::Continue),
+            KeyCode::Char('b') | KeyCode::Char(' ') | KeyCode::Esc if self.is_running() => {
+                self.running = None;
+                let location = self.debugger.location(vm);
+                self.print(format!("Break at {}", location));
+            }
+            KeyCode::Char('m') => {
+                self.memory_view = match self.memory_view {
+                    MemoryView::Fixed(_) => MemoryView::FollowPc,
+                    MemoryView::FollowPc => MemoryView::FollowPointer(0),
+                    MemoryView::FollowPointer(_) => MemoryView::Fixed(self.memory_start(vm)),
+                }
+            }
+            KeyCode::PageUp => {
+                self.memory_view = MemoryView::Fixed(self.memory_start(vm).wrapping_sub(0x40))
+            }
+            KeyCode::PageDown => {
+                self.memory_view = MemoryView::Fixed(self.memory_start(vm).wrapping_add(0x40))
+            }
+            KeyCode::Char(':') => self.input = Some(String::new()),
+            KeyCode::Char('q') => self.command(vm, "quit"),
+            _ => {}
+        }
+    }
+
+    fn start(&mut self, vm: &Vm, mode: RunMode) {
+        self.running = (!vm.halt).then_some(mode);
+    }
+
+    /// Runs the program for a slice of cycles when it is running
+    pub fn run_slice(&mut self, vm: &mut Vm) {
+        let Some(mode) = self.running else {
+            return;
+        };
+
+        let limit = vm.cycle_count() + SLICE_CYCLES;
+        let stop = match mode {
+            RunMode::Continue => vm.run_until(limit).map(Some),
+            RunMode::Step(target) => run_to(vm, target, limit),
+        };
+        match stop {
+            Ok(Some(StopReason::CycleLimit)) => return,
+            Ok(None) => {
+                let location = self.debugger.location(vm);
+                self.print(location)
+            }
+            Ok(Some(StopReason::Breakpoint(hit))) => {
+                let location = self.debugger.location(vm);
+                self.print(format!("Breakpoint #{} at {}", hit.id, location))
+            }
+            Ok(Some(StopReason::Signal(signal))) => {
+                let location = self.debugger.location(vm);
+                self.print(format!("Signal ${:02x} break at {}", signal, location))
+            }
+            Ok(Some(StopReason::MemoryFault(fault))) => self.print(fault.to_string()),
+            Ok(Some(StopReason::Halted)) => {
+                let location = self.debugger.location(vm);
+                self.print(format!("Machine halted at {}", location))
+            }
+            Err(err) => self.print(err),
+        }
+        self.running = None;
+    }
+
+    fn command(&mut self, vm: &mut Vm, line: &str) {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let result = match tokens.as_slice() {
+            ["c"] | ["continue"] => {
+                self.start(vm, RunMode::Continue);
+                Ok(String::new())
+            }
+            ["display", addr] => self.debugger.symbols.parse_address(addr).map(|addr| {
+                self.watches.push(addr);
+                String::new()
+            }),
+            ["undisplay", idx] => match idx.parse::<usize>() {
+                Ok(idx) if idx > 0 && idx <= self.watches.len() => {
+                    self.watches.remove(idx - 1);
+                    Ok(String::new())
+                }
+                _ => Err(format!("No watch number {}", idx)),
+            },
+            ["view", "pc"] => {
+                self.memory_view = MemoryView::FollowPc;
+                Ok(String::new())
+            }
+            ["view", "ptr", addr] => match self.debugger.symbols.parse_address(addr) {
+                Ok(addr) if addr <= 0xFF => {
+                    self.memory_view = MemoryView::FollowPointer(addr as u8);
+                    Ok(String::new())
+                }
+                Ok(_) => Err("The pointer must be in the zero page".to_string()),
+                Err(err) => Err(err),
+            },
+            ["view", addr] => self.debugger.symbols.parse_address(addr).map(|addr| {
+                self.memory_view = MemoryView::Fixed(addr);
+                String::new()
+            }),
+            _ => self.debugger.execute(vm, line),
+        };
+
+        match result {
+            Ok(text) => text.lines().for_each(|line| self.print(line.to_string())),
+            Err(err) => self.print(err),
+        }
+    }
+
+    fn print(&mut self, text: String) {
+        self.log.push(text);
+        if self.log.len() > LOG_LINES {
+            self.log.remove(0);
+        }
+    }
+
+    fn memory_start(&self, vm: &Vm) -> u16 {
+        match self.memory_view {
+            MemoryView::Fixed(addr) => addr,
+            MemoryView::FollowPc => vm.get_pc() & 0xFFF0,
+            MemoryView::FollowPointer(zp) => {
+                let low = vm.read_memory(zp as u16).unwrap_or_default();
+                let high = vm
+                    .read_memory(zp.wrapping_add(1) as u16)
+                    .unwrap_or_default();
+                u16::from_le_bytes([low, high]) & 0xFFF0
+            }
+        }
+    }
+
+    pub fn render(&self, frame: &mut Frame, vm: &Vm) {
+        let [top, middle, log, help] = Layout::vertical([
+            Constraint::Min(8),
+            Constraint::Length(10),
+            Constraint::Length(LOG_LINES as u16 + 2),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [disassembly, right] =
+            Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(45)]).areas(top);
+        let [registers, stack] =
+            Layout::vertical([Constraint::Length(6), Constraint::Min(3)]).areas(right);
+        let [memory, watches] =
+            Layout::horizontal([Constraint::Min(40), Constraint::Length(30)]).areas(middle);
+
+        self.render_disassembly(frame, vm, disassembly);
+        self.render_registers(frame, vm, registers);
+        self.render_stack(frame, vm, stack);
+        self.render_memory(frame, vm, memory);
+        self.render_watches(frame, vm, watches);
+
+        let log_lines: Vec<Line> = self
+            .log
+            .iter()
+            .map(|text| Line::raw(text.as_str()))
+            .collect();
+        frame.render_widget(
+            Paragraph::new(log_lines).block(Block::bordered().title(" Log ")),
+            log,
+        );
+
+        let status = match self.input.as_ref() {
+            Some(input) => Line::raw(format!(":{}", input)),
+            None if self.is_running() => {
+                Line::styled(" Running, b to break", Style::new().fg(Color::Yellow))
+            }
+            None => Line::styled(HELP, Style::new().add_modifier(Modifier::REVERSED)),
+        };
+        frame.render_widget(Paragraph::new(status), help);
+    }
+
+    fn render_disassembly(&self, frame: &mut Frame, vm: &Vm, area: Rect) {
+        let height = area.height.saturating_sub(2) as usize;
+        let pc = vm.get_pc();
+        let start = self.debugger.start_before(vm, pc, height / 3);
+        let breakpoints: Vec<u16> = vm
+            .breakpoints()
+            .iter()
+            .filter_map(|(_, breakpoint)| match breakpoint.kind {
+                BreakpointKind::Execute(addr) => Some(addr),
+                _ => None,
+            })
+            .collect();
+
+        let mut lines = Vec::new();
+        for line in disassemble(vm, start, height) {
+            if let Some(label) = self.debugger.symbols.label_at(line.addr) {
+                lines.push(Line::styled(
+                    format!("{}:", label),
+                    Style::new().fg(Color::Cyan),
+                ));
+            }
+
+            let marker = match (line.addr == pc, breakpoints.contains(&line.addr)) {
+                (true, _) => "=>",
+                (false, true) => " *",
+                (false, false) => "  ",
+            };
+            let style = if line.addr == pc {
+                Style::new().add_modifier(Modifier::REVERSED)
+            } else if breakpoints.contains(&line.addr) {
+                Style::new().fg(Color::Red)
+            } else {
+                Style::new()
+            };
+            lines.push(Line::styled(format!("{} {}", marker, line), style));
+        }
+
+        // Labels take rows, keep the PC in sight
+        let pc_row = lines
+            .iter()
+            .position(|line| line.spans.iter().any(|span| span.content.starts_with("=>")))
+            .unwrap_or_default();
+        let skip = (lines.len().saturating_sub(height)).min(pc_row.saturating_sub(height / 3));
+
+        frame.render_widget(
+            Paragraph::new(lines.into_iter().skip(skip).collect::<Vec<_>>())
+                .block(Block::bordered().title(" Disassembly ")),
+            area,
+        );
+    }
+
+    fn render_registers(&self, frame: &mut Frame, vm: &Vm, area: Rect) {
+        let mut flags = vec![Span::raw("Flags ")];
+        for (flag, name) in [
+            (RegisterFlag::Negative, "N"),
+            (RegisterFlag::Overflow, "V"),
+            (RegisterFlag::Break, "B"),
+            (RegisterFlag::Decimal, "D"),
+            (RegisterFlag::Interrupt, "I"),
+            (RegisterFlag::Zero, "Z"),
+            (RegisterFlag::Carry, "C"),
+        ] {
+            let style = if vm.get_flag(flag) {
+                Style::new().fg(Color::Green).add_modifier(Modifier::BOLD)
+            } else {
+                Style::new().fg(Color::DarkGray)
+            };
+            flags.push(Span::styled(name, style));
+            flags.push(Span::raw(" "));
+        }
+
+        let lines = vec![
+            Line::raw(format!(
+                "A  ${:02x}   X  ${:02x}   Y  ${:02x}",
+                vm.get_register(Register::AC),
+                vm.get_register(Register::X),
+                vm.get_register(Register::Y)
+            )),
+            Line::raw(format!(
+                "SP ${:02x}   SR ${:02x}   PC ${:04x}",
+                vm.get_register(Register::SP),
+                vm.get_register(Register::SR),
+                vm.get_pc()
+            )),
+            Line::from(flags),
+            Line::raw(format!("Cycles {}", vm.cycle_count())),
+        ];
+
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Registers ")),
+            area,
+        );
+    }
+
+    fn render_stack(&self, frame: &mut Frame, vm: &Vm, area: Rect) {
+        let height = area.height.saturating_sub(2);
+        let sp = vm.get_register(Register::SP) as u16;
+
+        let lines: Vec<Line> = (sp..sp + height)
+            .take_while(|offset| *offset <= 0xFF)
+            .map(|offset| {
+                let addr = STACK_PAGE + offset;
+                let value = vm.read_memory(addr).unwrap_or_default();
+                if offset == sp {
+                    Line::styled(
+                        format!("${:04x}  -- <- SP", addr),
+                        Style::new().fg(Color::DarkGray),
+                    )
+                } else {
+                    Line::raw(format!("${:04x}  {:02x}", addr, value))
+                }
+            })
+            .collect();
+
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Stack ")),
+            area,
+        );
+    }
+
+    fn render_memory(&self, frame: &mut Frame, vm: &Vm, area: Rect) {
+        let height = area.height.saturating_sub(2) as usize;
+        // Address, bytes and characters take 4 columns per byte plus 8
+        let width = if area.width >= 16 * 4 + 10 { 16 } else { 8 };
+        let start = self.memory_start(vm);
+
+        let lines: Vec<Line> = (0..height)
+            .map(|row| {
+                let line_addr = start.wrapping_add((row * width) as u16);
+                let bytes: Vec<u8> = (0..width)
+                    .map(|idx| {
+                        vm.read_memory(line_addr.wrapping_add(idx as u16))
+                            .unwrap_or_default()
+                    })
+                    .collect();
+
+                let mut spans = vec![Span::raw(format!("${:04x} ", line_addr))];
+                for (idx, value) in bytes.iter().enumerate() {
+                    let style = if line_addr.wrapping_add(idx as u16) == vm.get_pc() {
+                        Style::new().add_modifier(Modifier::REVERSED)
+                    } else {
+                        Style::new()
+                    };
+                    spans.push(Span::raw(" "));
+                    spans.push(Span::styled(format!("{:02x}", value), style));
+                }
+                let chars: String = bytes
+                    .iter()
+                    .map(|value| {
+                        if value.is_ascii_graphic() || *value == b' ' {
+                            *value as char
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect();
+                spans.push(Span::raw(format!("  {}", chars)));
+                Line::from(spans)
+            })
+            .collect();
+
+        let title = match self.memory_view {
+            MemoryView::Fixed(_) => " Memory ".to_string(),
+            MemoryView::FollowPc => " Memory (follow PC) ".to_string(),
+            MemoryView::FollowPointer(zp) => format!(" Memory (follow pointer ${:02x}) ", zp),
+        };
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    fn render_watches(&self, frame: &mut Frame, vm: &Vm, area: Rect) {
+        let lines: Vec<Line> = self
+            .watches
+            .iter()
+            .enumerate()
+            .map(|(idx, addr)| {
+                let name = match self.debugger.symbols.label_at(*addr) {
+                    Some(label) => label.to_string(),
+                    None => format!("${:04x}", addr),
+                };
+                let low = vm.read_memory(*addr).unwrap_or_default();
+                let high = vm.read_memory(addr.wrapping_add(1)).unwrap_or_default();
+                Line::raw(format!(
+                    "{} {:<10} ${:02x} ${:04x}",
+                    idx + 1,
+                    name,
+                    low,
+                    u16::from_le_bytes([low, high])
+                ))
+            })
+            .collect();
+
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Watch ")),
+            area,
+        );
+    }
+}
+
+/// Runs one instruction at a time until the target of a next or a finish, none when it is
+/// reached, or until the cycle limit, a breakpoint or the halt
+fn run_to(vm: &mut Vm, target: StepTarget, limit: u64) -> Result<Option<StopReason>, String> {
+    while vm.cycle_count() < limit {
+        let instruction = vm.decode_at(vm.get_pc());
+        let stop = vm.run_until(vm.cycle_count() + 1)?;
+        if instruction.is_ok_and(|instruction| target.reached(vm, instruction)) {
+            return Ok(None);
+        }
+        if !matches!(stop, StopReason::CycleLimit) {
+            return Ok(Some(stop));
+        }
+    }
+    Ok(Some(StopReason::CycleLimit))
+}
+
+/// Takes over the terminal until the user quits
+pub fn run(vm: &mut Vm, symbols: SymbolTable) -> io::Result<()> {
+    let mut app = TuiDebugger::new(symbols);
+    let mut terminal = ratatui::init();
+
+    let result = loop {
+        if let Err(err) = terminal.draw(|frame| app.render(frame, vm)) {
+            break Err(err);
+        }
+        if app.is_finished() {
+            break Ok(());
+        }
+
+        let timeout = if app.is_running() {
+            Duration::ZERO
+        } else {
+            Duration::from_millis(250)
+        };
+        match event::poll(timeout) {
+            Ok(true) => match event::read() {
+                Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => app.handle_key(vm, key),
+                Ok(_) => {}
+                Err(err) => break Err(err),
+            },
+            Ok(false) => {}
+            Err(err) => break Err(err),
+        }
+
+        app.run_slice(vm);
+    };
+
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::{backend::TestBackend, crossterm::event::KeyModifiers, Terminal};
+
+    use super::*;
+    use crate::asm::assemble;
+
+    const SOURCE: &str = "\
+start:
+    LoadXImm 0
+loop:
+    JumpSubAbs double
+    IncX
+    CmpXImm 3
+    BranchNotZero loop
+    Jam
+double:
+    TransXAC
+    StoreACZp $80
+    RetSub
+";
+
+    fn press(app: &mut TuiDebugger, vm: &mut Vm, keys: &str) {
+        for c in keys.chars() {
+            let code = match c {
+                '\n' => KeyCode::Enter,
+                c => KeyCode::Char(c),
+            };
+            app.handle_key(vm, KeyEvent::new(code, KeyModifiers::NONE));
+        }
+    }
+
+    fn screen(app: &TuiDebugger, vm: &Vm) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(100, 30)).unwrap();
+        terminal.draw(|frame| app.render(frame, vm)).unwrap();
+
+        let buffer = terminal.backend().buffer();
+        let mut text = String::new();
+        for y in 0..buffer.area.height {
+            for x in 0..buffer.area.width {
+                text.push_str(buffer[(x, y)].symbol());
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    #[test]
+    fn test_tui_keys_and_panes() {
+        let program = assemble(SOURCE, "double.asm").unwrap();
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &program.bytes);
+        let mut app = TuiDebugger::new(program.symbols);
+
+        press(&mut app, &mut vm, "ss:display $80\n:b double\n");
+        assert_eq!(vm.get_pc(), 0x000b);
+
+        let text = screen(&app, &vm);
+        assert!(text.contains("=> $000b  8a        TransXAC"));
+        assert!(text.contains(" * $000b") || text.contains("double:"));
+        assert!(text.contains("SP $fe"));
+        assert!(text.contains("$01ff  04"));
+        assert!(text.contains("1 $0080"));
+        assert!(text.contains("Memory (follow PC)"));
+        assert!(text.contains("#1 break $000b <double>"));
+
+        // Finish runs by slices like run, from the stack wrapped by the JSR
+        press(&mut app, &mut vm, "f");
+        assert!(app.is_running());
+        app.run_slice(&mut vm);
+        assert!(!app.is_running());
+        assert_eq!(vm.get_pc(), 0x0005);
+        assert_eq!(vm.get_register(Register::SP), 0x00);
+
+        press(&mut app, &mut vm, "r");
+        assert!(app.is_running());
+        app.run_slice(&mut vm);
+        assert!(!app.is_running());
+        assert_eq!(vm.get_pc(), 0x000b);
+        assert_eq!(vm.get_register(Register::X), 1);
+        assert!(screen(&app, &vm).contains("Breakpoint #1 at $000b <double>"));
+
+        // The break key stops a next or a finish which is not done
+        press(&mut app, &mut vm, "fb");
+        assert!(!app.is_running());
+        assert_eq!(vm.get_pc(), 0x000b);
+        assert!(screen(&app, &vm).contains("Break at $000b <double>"));
+
+        press(&mut app, &mut vm, ":view ptr $80\n");
+        assert_eq!(app.memory_view(), MemoryView::FollowPointer(0x80));
+        press(&mut app, &mut vm, ":view 12\nm");
+        assert_eq!(app.memory_view(), MemoryView::FollowPc);
+        press(&mut app, &mut vm, ":undisplay 2\n");
+        assert!(screen(&app, &vm).contains("No watch number 2"));
+
+        press(&mut app, &mut vm, "q");
+        assert!(app.is_finished());
+    }
+}
@@ -0,0 +1,709 @@
+//! MOS 6522 Versatile Interface Adapter.
+//!
+//! The 16 registers are mapped from the base address of the device :
+//!
+//! | Offset | Register   | Offset | Register                     |
+//! |--------|------------|--------|------------------------------|
+//! | `$0`   | ORB / IRB  | `$8`   | T2 low counter / latch       |
+//! | `$1`   | ORA / IRA  | `$9`   | T2 high counter              |
+//! | `$2`   | DDRB       | `$A`   | Shift register               |
+//! | `$3`   | DDRA       | `$B`   | Auxiliary control (ACR)      |
+//! | `$4`   | T1 low     | `$C`   | Peripheral control (PCR)     |
+//! | `$5`   | T1 high    | `$D`   | Interrupt flags (IFR)        |
+//! | `$6`   | T1 latch L | `$E`   | Interrupt enable (IER)       |
+//! | `$7`   | T1 latch H | `$F`   | ORA / IRA without handshake  |
+//!
+//! The timers count the CPU cycles from the instruction following the write which starts
+//! them. T1 runs in one-shot or free-run mode (ACR bit 6) and can drive PB7 (ACR bit 7),
+//! T2 is a one-shot timer or counts the pulses on PB6 (ACR bit 5). The shift register runs
+//! under T2, the clock (one bit every 2 cycles) or CB1, in or out of CB2.
+//!
+//! The pins are given by the host : the ports through `ViaPorts`, whose reads go through
+//! `HostPort::input` so they are recorded and replayed, and the control lines with
+//! `Via::set_control`. The latching of the ports and the handshake outputs of CA2 and CB2
+//! are not emulated.
+
+use crate::{
+    bus::{Device, HostPort},
+    state::{StateReader, StateWriter},
+};
+
+pub const VIA_SIZE: u16 = 0x10;
+/// Channel of port A for the record and replay, port B is on the next one
+pub const VIA_CHANNEL: u16 = 0x0200;
+
+pub const REG_ORB: u16 = 0x0;
+pub const REG_ORA: u16 = 0x1;
+pub const REG_DDRB: u16 = 0x2;
+pub const REG_DDRA: u16 = 0x3;
+pub const REG_T1CL: u16 = 0x4;
+pub const REG_T1CH: u16 = 0x5;
+pub const REG_T1LL: u16 = 0x6;
+pub const REG_T1LH: u16 = 0x7;
+pub const REG_T2CL: u16 = 0x8;
+pub const REG_T2CH: u16 = 0x9;
+pub const REG_SR: u16 = 0xA;
+pub const REG_ACR: u16 = 0xB;
+pub const REG_PCR: u16 = 0xC;
+pub const REG_IFR: u16 = 0xD;
+pub const REG_IER: u16 = 0xE;
+pub const REG_ORA_NH: u16 = 0xF;
+
+pub const IRQ_CA2: u8 = 0b0000_0001;
+pub const IRQ_CA1: u8 = 0b0000_0010;
+pub const IRQ_SR: u8 = 0b0000_0100;
+pub const IRQ_CB2: u8 = 0b0000_1000;
+pub const IRQ_CB1: u8 = 0b0001_0000;
+pub const IRQ_T2: u8 = 0b0010_0000;
+pub const IRQ_T1: u8 = 0b0100_0000;
+pub const IRQ_ANY: u8 = 0b1000_0000;
+
+const ACR_PB7: u8 = 0b1000_0000;
+const ACR_T1_FREE_RUN: u8 = 0b0100_0000;
+const ACR_T2_PULSES: u8 = 0b0010_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    A,
+    B,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    CA1,
+    CA2,
+    CB1,
+    CB2,
+}
+
+/// Host side of the ports
+pub trait ViaPorts {
+    /// Levels of the pins of a port, only the ones set as inputs are used
+    fn read_port(&mut self, _port: Port) -> u8 {
+        0xFF
+    }
+
+    /// The output register or the data direction of a port changed
+    fn write_port(&mut self, _port: Port, _output: u8, _ddr: u8) {}
+
+    /// Bit shifted out on CB2
+    fn shift_out(&mut self, _bit: bool) {}
+}
+
+/// Ports left unconnected, the inputs read high
+pub struct NoPorts;
+
+impl ViaPorts for NoPorts {}
+
+pub struct Via {
+    ports: Box<dyn ViaPorts>,
+    channel: u16,
+    ora: u8,
+    orb: u8,
+    ddra: u8,
+    ddrb: u8,
+    // Last levels read from the host, for the peeks
+    input_a: u8,
+    input_b: u8,
+    t1_counter: u16,
+    t1_latch: u16,
+    t1_armed: bool,
+    t1_reload: bool,
+    t1_loaded: bool,
+    pb7: bool,
+    t2_counter: u16,
+    t2_latch_low: u8,
+    t2_armed: bool,
+    t2_loaded: bool,
+    sr: u8,
+    sr_bits: u8,
+    sr_timer: u16,
+    acr: u8,
+    pcr: u8,
+    ifr: u8,
+    ier: u8,
+    controls: [bool; 4],
+}
+
+impl Via {
+    pub fn new() -> Self {
+        Self {
+            ports: Box::new(NoPorts),
+            channel: VIA_CHANNEL,
+            ora: 0,
+            orb: 0,
+            ddra: 0,
+            ddrb: 0,
+            input_a: 0xFF,
+            input_b: 0xFF,
+            t1_counter: 0xFFFF,
+            t1_latch: 0xFFFF,
+            t1_armed: false,
+            t1_reload: false,
+            t1_loaded: false,
+            pb7: true,
+            t2_counter: 0xFFFF,
+            t2_latch_low: 0xFF,
+            t2_armed: false,
+            t2_loaded: false,
+            sr: 0,
+            sr_bits: 0,
+            sr_timer: 0,
+            acr: 0,
+            pcr: 0,
+            ifr: 0,
+            ier: 0,
+            controls: [true; 4],
+        }
+    }
+
+    pub fn with_ports(mut self, ports: Box<dyn ViaPorts>) -> Self {
+        self.ports = ports;
+        self
+    }
+
+    /// Channels of the ports for the record and replay, each VIA of a machine needs its own
+    pub fn with_channel(mut self, channel: u16) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    /// Level of a control line driven by the host, an active edge sets its interrupt
+    /// flag and CB1 clocks the shift register in external mode
+    pub fn set_control(&mut self, control: Control, level: bool) {
+        let idx = control as usize;
+        let previous = std::mem::replace(&mut self.controls[idx], level);
+        if previous == level {
+            return;
+        }
+
+        let (positive, flag) = match control {
+            Control::CA1 => (self.pcr & 0b0000_0001 > 0, IRQ_CA1),
+            Control::CA2 => (self.pcr & 0b0000_1100 == 0b0000_0100, IRQ_CA2),
+            Control::CB1 => (self.pcr & 0b0001_0000 > 0, IRQ_CB1),
+            Control::CB2 => (self.pcr & 0b1100_0000 == 0b0100_0000, IRQ_CB2),
+        };
+        let is_input = match control {
+            Control::CA2 => self.pcr & 0b0000_1000 == 0,
+            Control::CB2 => self.pcr & 0b1000_0000 == 0,
+            _ => true,
+        };
+        if is_input && level == positive {
+            self.ifr |= flag;
+        }
+
+        if control == Control::CB1 && level && self.sr_mode() & 0b011 == 0b011 {
+            self.shift();
+        }
+    }
+
+    /// Falling edge on PB6, counted by T2 in pulse counting mode
+    pub fn pulse_pb6(&mut self) {
+        if self.acr & ACR_T2_PULSES > 0 {
+            self.t2_counter = self.t2_counter.wrapping_sub(1);
+            if self.t2_counter == 0 && self.t2_armed {
+                self.t2_armed = false;
+                self.ifr |= IRQ_T2;
+            }
+        }
+    }
+
+    fn sr_mode(&self) -> u8 {
+        (self.acr >> 2) & 0b111
+    }
+
+    // Cycles between two bits of the shift register, for the internal clocks
+    fn sr_period(&self) -> Option<u16> {
+        match self.sr_mode() {
+            0b001 | 0b100 | 0b101 => Some(2 * (self.t2_latch_low as u16 + 2)),
+            0b010 | 0b110 => Some(2),
+            _ => None,
+        }
+    }
+
+    fn start_shift(&mut self) {
+        self.ifr &= !IRQ_SR;
+        self.sr_bits = 8;
+        self.sr_timer = self.sr_period().unwrap_or_default();
+    }
+
+    fn shift(&mut self) {
+        let free_run = self.sr_mode() == 0b100;
+        if self.sr_bits == 0 && !free_run {
+            return;
+        }
+
+        if self.sr_mode() & 0b100 > 0 {
+            // The bit shifted out comes back in, as on the real chip
+            let bit = self.sr & 0x80 > 0;
+            self.sr = self.sr.rotate_left(1);
+            self.ports.shift_out(bit);
+        } else {
+            self.sr = (self.sr << 1) | self.controls[Control::CB2 as usize] as u8;
+        }
+
+        if !free_run {
+            self.sr_bits -= 1;
+            if self.sr_bits == 0 {
+                self.ifr |= IRQ_SR;
+            }
+        }
+    }
+
+    fn step(&mut self, count_t1: bool, count_t2: bool) {
+        if count_t1 {
+            if self.t1_reload {
+                self.t1_reload = false;
+                self.t1_counter = self.t1_latch;
+            } else {
+                self.t1_counter = self.t1_counter.wrapping_sub(1);
+                if self.t1_counter == 0xFFFF && self.t1_armed {
+                    self.ifr |= IRQ_T1;
+                    if self.acr & ACR_T1_FREE_RUN > 0 {
+                        self.t1_reload = true;
+                        self.set_pb7(!self.pb7);
+                    } else {
+                        self.t1_armed = false;
+                        self.set_pb7(true);
+                    }
+                }
+            }
+        }
+
+        if count_t2 && self.acr & ACR_T2_PULSES == 0 {
+            self.t2_counter = self.t2_counter.wrapping_sub(1);
+            if self.t2_counter == 0xFFFF && self.t2_armed {
+                self.t2_armed = false;
+                self.ifr |= IRQ_T2;
+            }
+        }
+
+        if let Some(period) = self.sr_period() {
+            if self.sr_bits > 0 || self.sr_mode() == 0b100 {
+                self.sr_timer = self.sr_timer.saturating_sub(1);
+                if self.sr_timer == 0 {
+                    self.sr_timer = period;
+                    self.shift();
+                }
+            }
+        }
+    }
+
+    fn set_pb7(&mut self, level: bool) {
+        let changed = self.pb7 != level;
+        self.pb7 = level;
+        if changed && self.acr & ACR_PB7 > 0 {
+            self.ports
+                .write_port(Port::B, self.output_b(), self.ddrb | 0x80);
+        }
+    }
+
+    fn output_b(&self) -> u8 {
+        match self.acr & ACR_PB7 > 0 {
+            true => (self.orb & 0x7F) | ((self.pb7 as u8) << 7),
+            false => self.orb,
+        }
+    }
+
+    fn input(&self, port: Port) -> u8 {
+        match port {
+            Port::A => (self.ora & self.ddra) | (self.input_a & !self.ddra),
+            Port::B => {
+                let value = (self.orb & self.ddrb) | (self.input_b & !self.ddrb);
+                match self.acr & ACR_PB7 > 0 {
+                    true => (value & 0x7F) | ((self.pb7 as u8) << 7),
+                    false => value,
+                }
+            }
+        }
+    }
+
+    fn read_port(&mut self, port: Port, host: &mut HostPort) -> u8 {
+        let ports = &mut self.ports;
+        match port {
+            Port::A => self.input_a = host.input(self.channel, || ports.read_port(Port::A)),
+            Port::B => {
+                self.input_b = host.input(self.channel.wrapping_add(1), || ports.read_port(Port::B))
+            }
+        }
+        self.input(port)
+    }
+
+    // Reading or writing a port register clears the flags of its control lines,
+    // except for CA2 and CB2 in independent mode
+    fn clear_port_flags(&mut self, port: Port) {
+        match port {
+            Port::A => {
+                self.ifr &= !IRQ_CA1;
+                if self.pcr & 0b0000_1010 != 0b0000_0010 {
+                    self.ifr &= !IRQ_CA2;
+                }
+            }
+            Port::B => {
+                self.ifr &= !IRQ_CB1;
+                if self.pcr & 0b1010_0000 != 0b0010_0000 {
+                    self.ifr &= !IRQ_CB2;
+                }
+            }
+        }
+    }
+
+    fn ifr(&self) -> u8 {
+        match self.ifr & self.ier & 0x7F {
+            0 => self.ifr,
+            _ => self.ifr | IRQ_ANY,
+        }
+    }
+}
+
+impl Default for Via {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Via {
+    fn size(&self) -> u16 {
+        VIA_SIZE
+    }
+
+    fn read(&mut self, offset: u16, host: &mut HostPort) -> u8 {
+        match offset {
+            REG_ORB => {
+                self.clear_port_flags(Port::B);
+                self.read_port(Port::B, host)
+            }
+            REG_ORA => {
+                self.clear_port_flags(Port::A);
+                self.read_port(Port::A, host)
+            }
+            REG_ORA_NH => self.read_port(Port::A, host),
+            REG_T1CL => {
+                self.ifr &= !IRQ_T1;
+                self.t1_counter as u8
+            }
+            REG_T2CL => {
+                self.ifr &= !IRQ_T2;
+                self.t2_counter as u8
+            }
+            REG_SR => {
+                self.start_shift();
+                self.sr
+            }
+            _ => self.peek(offset),
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            REG_ORB => self.input(Port::B),
+            REG_ORA | REG_ORA_NH => self.input(Port::A),
+            REG_DDRB => self.ddrb,
+            REG_DDRA => self.ddra,
+            REG_T1CL => self.t1_counter as u8,
+            REG_T1CH => (self.t1_counter >> 8) as u8,
+            REG_T1LL => self.t1_latch as u8,
+            REG_T1LH => (self.t1_latch >> 8) as u8,
+            REG_T2CL => self.t2_counter as u8,
+            REG_T2CH => (self.t2_counter >> 8) as u8,
+            REG_SR => self.sr,
+            REG_ACR => self.acr,
+            REG_PCR => self.pcr,
+            REG_IFR => self.ifr(),
+            REG_IER => self.ier | IRQ_ANY,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset {
+            REG_ORB => {
+                self.clear_port_flags(Port::B);
+                self.orb = value;
+                self.ports.write_port(Port::B, self.output_b(), self.ddrb);
+            }
+            REG_ORA | REG_ORA_NH => {
+                if offset == REG_ORA {
+                    self.clear_port_flags(Port::A);
+                }
+                self.ora = value;
+                self.ports.write_port(Port::A, self.ora, self.ddra);
+            }
+            REG_DDRB => {
+                self.ddrb = value;
+                self.ports.write_port(Port::B, self.output_b(), self.ddrb);
+            }
+            REG_DDRA => {
+                self.ddra = value;
+                self.ports.write_port(Port::A, self.ora, self.ddra);
+            }
+            REG_T1CL | REG_T1LL => self.t1_latch = (self.t1_latch & 0xFF00) | value as u16,
+            REG_T1CH => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | (value as u16) << 8;
+                self.t1_counter = self.t1_latch;
+                self.t1_armed = true;
+                self.t1_reload = false;
+                self.t1_loaded = true;
+                self.ifr &= !IRQ_T1;
+                self.set_pb7(false);
+            }
+            REG_T1LH => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | (value as u16) << 8;
+                self.ifr &= !IRQ_T1;
+            }
+            REG_T2CL => self.t2_latch_low = value,
+            REG_T2CH => {
+                self.t2_counter = (value as u16) << 8 | self.t2_latch_low as u16;
+                self.t2_armed = true;
+                self.t2_loaded = true;
+                self.ifr &= !IRQ_T2;
+            }
+            REG_SR => {
+                self.sr = value;
+                self.start_shift();
+            }
+            REG_ACR => {
+                self.acr = value;
+                self.ports.write_port(Port::B, self.output_b(), self.ddrb);
+            }
+            REG_PCR => self.pcr = value,
+            REG_IFR => self.ifr &= !(value & 0x7F),
+            REG_IER => match value & IRQ_ANY > 0 {
+                true => self.ier |= value & 0x7F,
+                false => self.ier &= !value,
+            },
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, cycles: u64, _host: &mut HostPort) {
+        // A timer written by the instruction starts counting after it
+        let count_t1 = !std::mem::take(&mut self.t1_loaded);
+        let count_t2 = !std::mem::take(&mut self.t2_loaded);
+        for _ in 0..cycles {
+            self.step(count_t1, count_t2);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.ifr & self.ier & 0x7F > 0
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bytes(&[self.ora, self.orb, self.ddra, self.ddrb]);
+        writer.put_bytes(&[self.input_a, self.input_b]);
+        writer.put_u16(self.t1_counter);
+        writer.put_u16(self.t1_latch);
+        writer.put_bool(self.t1_armed);
+        writer.put_bool(self.t1_reload);
+        writer.put_bool(self.t1_loaded);
+        writer.put_bool(self.pb7);
+        writer.put_u16(self.t2_counter);
+        writer.put_u8(self.t2_latch_low);
+        writer.put_bool(self.t2_armed);
+        writer.put_bool(self.t2_loaded);
+        writer.put_bytes(&[self.sr, self.sr_bits]);
+        writer.put_u16(self.sr_timer);
+        writer.put_bytes(&[self.acr, self.pcr, self.ifr, self.ier]);
+        for level in self.controls {
+            writer.put_bool(level);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        [self.ora, self.orb, self.ddra, self.ddrb] = reader.get_bytes(4)?.try_into().unwrap();
+        [self.input_a, self.input_b] = reader.get_bytes(2)?.try_into().unwrap();
+        self.t1_counter = reader.get_u16()?;
+        self.t1_latch = reader.get_u16()?;
+        self.t1_armed = reader.get_bool()?;
+        self.t1_reload = reader.get_bool()?;
+        self.t1_loaded = reader.get_bool()?;
+        self.pb7 = reader.get_bool()?;
+        self.t2_counter = reader.get_u16()?;
+        self.t2_latch_low = reader.get_u8()?;
+        self.t2_armed = reader.get_bool()?;
+        self.t2_loaded = reader.get_bool()?;
+        [self.sr, self.sr_bits] = reader.get_bytes(2)?.try_into().unwrap();
+        self.sr_timer = reader.get_u16()?;
+        [self.acr, self.pcr, self.ifr, self.ier] = reader.get_bytes(4)?.try_into().unwrap();
+        for level in self.controls.iter_mut() {
+            *level = reader.get_bool()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{asm::assemble, isa::Register, replay::InputMode, Vm};
+
+    fn tick(via: &mut Via, cycles: u64) {
+        let mut input_mode = InputMode::Live;
+        via.tick(cycles, &mut HostPort::new(0, &mut input_mode));
+    }
+
+    #[test]
+    fn test_via_timers() {
+        let mut via = Via::new();
+        via.write(REG_T1CL, 0x10);
+        via.write(REG_T1CH, 0x00);
+        tick(&mut via, 4);
+        assert_eq!(via.peek(REG_T1CL), 0x10);
+        assert_eq!(via.peek(REG_ORB) & 0x80, 0x80);
+
+        // One-shot : the flag is set once, after N + 1 cycles
+        tick(&mut via, 0x10);
+        assert_eq!(via.peek(REG_IFR) & IRQ_T1, 0);
+        tick(&mut via, 1);
+        assert_eq!(via.peek(REG_IFR), IRQ_T1);
+        assert!(!via.irq());
+        via.write(REG_IFR, IRQ_T1);
+        tick(&mut via, 0x20000);
+        assert_eq!(via.peek(REG_IFR), 0);
+
+        // Free-run with PB7 : a period of N + 2 cycles
+        via.write(REG_ACR, ACR_T1_FREE_RUN | ACR_PB7);
+        via.write(REG_IER, IRQ_ANY | IRQ_T1);
+        via.write(REG_T1CH, 0x00);
+        assert_eq!(via.peek(REG_ORB) & 0x80, 0x00);
+        tick(&mut via, 0);
+        tick(&mut via, 0x11);
+        assert!(via.irq());
+        assert_eq!(via.peek(REG_IFR), IRQ_ANY | IRQ_T1);
+        assert_eq!(via.peek(REG_ORB) & 0x80, 0x80);
+        via.write(REG_IFR, IRQ_T1);
+        tick(&mut via, 0x12);
+        assert!(via.irq());
+        assert_eq!(via.peek(REG_ORB) & 0x80, 0x00);
+
+        // T2 one-shot, then counting the pulses on PB6
+        via.write(REG_T2CL, 0x05);
+        via.write(REG_T2CH, 0x00);
+        tick(&mut via, 0);
+        tick(&mut via, 6);
+        assert_eq!(via.peek(REG_IFR) & IRQ_T2, IRQ_T2);
+        via.write(REG_ACR, ACR_T2_PULSES);
+        via.write(REG_T2CL, 0x02);
+        via.write(REG_T2CH, 0x00);
+        via.pulse_pb6();
+        assert_eq!(via.peek(REG_IFR) & IRQ_T2, 0);
+        via.pulse_pb6();
+        assert_eq!(via.peek(REG_IFR) & IRQ_T2, IRQ_T2);
+    }
+
+    #[derive(Default)]
+    struct Pins {
+        inputs: u8,
+        outputs: Vec<(Port, u8, u8)>,
+        shifted: Vec<bool>,
+    }
+
+    impl ViaPorts for Rc<RefCell<Pins>> {
+        fn read_port(&mut self, _port: Port) -> u8 {
+            self.borrow().inputs
+        }
+
+        fn write_port(&mut self, port: Port, output: u8, ddr: u8) {
+            self.borrow_mut().outputs.push((port, output, ddr));
+        }
+
+        fn shift_out(&mut self, bit: bool) {
+            self.borrow_mut().shifted.push(bit);
+        }
+    }
+
+    #[test]
+    fn test_via_ports_and_shift_register() {
+        let pins = Rc::new(RefCell::new(Pins {
+            inputs: 0b1010_0101,
+            ..Pins::default()
+        }));
+        let mut via = Via::new().with_ports(Box::new(pins.clone()));
+        let mut input_mode = InputMode::Live;
+        let mut host = HostPort::new(0, &mut input_mode);
+
+        via.write(REG_DDRA, 0x0F);
+        via.write(REG_ORA, 0x33);
+        assert_eq!(pins.borrow().outputs.last(), Some(&(Port::A, 0x33, 0x0F)));
+        assert_eq!(via.read(REG_ORA, &mut host), 0b1010_0011);
+
+        // CA1 on its positive edge, cleared by reading the port
+        via.write(REG_PCR, 0x01);
+        via.set_control(Control::CA1, false);
+        assert_eq!(via.peek(REG_IFR), 0);
+        via.set_control(Control::CA1, true);
+        assert_eq!(via.peek(REG_IFR), IRQ_CA1);
+        via.read(REG_ORA, &mut host);
+        assert_eq!(via.peek(REG_IFR), 0);
+
+        // Shift out under the clock, one bit every 2 cycles
+        via.write(REG_ACR, 0b0001_1000);
+        via.write(REG_SR, 0b1100_1010);
+        tick(&mut via, 15);
+        assert_eq!(via.peek(REG_IFR), 0);
+        tick(&mut via, 1);
+        assert_eq!(via.peek(REG_IFR), IRQ_SR);
+        assert_eq!(
+            pins.borrow().shifted,
+            vec![true, true, false, false, true, false, true, false]
+        );
+        assert_eq!(via.peek(REG_SR), 0b1100_1010);
+
+        // Shift in under CB1, from CB2
+        via.write(REG_ACR, 0b0000_1100);
+        via.read(REG_SR, &mut host);
+        for bit in [true, false, true, true, false, false, true, false] {
+            via.set_control(Control::CB2, bit);
+            via.set_control(Control::CB1, false);
+            via.set_control(Control::CB1, true);
+        }
+        assert_eq!(via.peek(REG_SR), 0b1011_0010);
+        assert_eq!(via.peek(REG_IFR) & IRQ_SR, IRQ_SR);
+    }
+
+    #[test]
+    fn test_via_interrupt() {
+        let source = "
+VIA = $9000
+T1CL = $9004
+T1CH = $9005
+IER = $900E
+    LoadXImm 0
+    LoadACImm $C0
+    StoreACAbs IER
+    LoadACImm $40
+    StoreACAbs $900B
+    LoadACImm 100
+    StoreACAbs T1CL
+    LoadACImm 0
+    StoreACAbs T1CH
+    ClrIntDis
+wait:
+    CmpXImm 3
+    BranchNotZero wait
+    Jam
+handler:
+    IncX
+    LoadACAbs T1CL
+    RetInt
+";
+        let program = assemble(source, "via.asm").unwrap();
+        let handler = program.symbols.address_of("handler").unwrap();
+        let mut vm = Vm::new();
+        vm.copy_memory(0, &program.bytes);
+        vm.copy_memory(0xFFFE, &handler.to_le_bytes());
+        vm.set_register(Register::SP, 0xFF);
+        vm.map_device(0x9000, Via::new()).unwrap();
+
+        while !vm.halt {
+            vm.cycle().unwrap();
+        }
+        assert_eq!(vm.get_register(Register::X), 3);
+        // Started at cycle 24, three periods of 102 cycles
+        assert!(vm.cycle_count() >= 24 + 3 * 102);
+        assert!(vm.cycle_count() < 24 + 4 * 102);
+    }
+}
@@ -0,0 +1,261 @@
+//! Single step conformance tests using the ProcessorTests JSON format from Tom Harte
+//! (https://github.com/SingleStepTests/ProcessorTests/tree/main/6502).
+//!
+//! Each file of the fixture directory is named after an opcode and contains a list of
+//! tests describing the state of the CPU before and after the execution of one
+//! instruction, with the bus activity of every cycle. Every test is run twice, the bus
+//! activity is only compared in cycle accurate mode.
+//!
+//! A small set of fixtures is shipped in `tests/fixtures/6502`, which does not cover
+//! every opcode. The full suite is used by pointing the `PROCESSOR_TESTS_DIR` environment
+//! variable to a local copy of the `6502/v1` folder, it must then have a file for every
+//! documented opcode. The CI must run the full suite : the test fails when the `CI`
+//! environment variable is set without `PROCESSOR_TESTS_DIR`.
+
+use std::{env, fs, path::PathBuf};
+
+use rustemu::{
+    bus::{BusAccess, BusCycle},
+    isa::{Instruction, Register},
+    Vm,
+};
+use serde::Deserialize;
+
+const MAX_REPORTED_FAILURES: usize = 20;
+
+#[derive(Deserialize)]
+struct ProcessorTest {
+    name: String,
+    initial: CpuState,
+    #[serde(rename = "final")]
+    expected: CpuState,
+    cycles: Vec<(u16, u8, String)>,
+}
+
+#[derive(Deserialize)]
+struct CpuState {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+/// Directory of the fixtures, and whether it is the full suite
+fn fixtures_dir() -> (PathBuf, bool) {
+    match env::var("PROCESSOR_TESTS_DIR") {
+        Ok(dir) => (PathBuf::from(dir), true),
+        Err(_) => {
+            assert!(
+                env::var_os("CI").is_none(),
+                "PROCESSOR_TESTS_DIR must point to the 6502/v1 folder of ProcessorTests in CI, \
+                 the shipped fixtures do not cover every documented opcode"
+            );
+            (
+                PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/6502"),
+                false,
+            )
+        }
+    }
+}
+
+/// Opcodes of the documented instructions, the emulator specific ones aside
+fn is_documented(opcode: u8) -> bool {
+    Instruction::try_from([opcode, 0, 0].as_slice())
+        .is_ok_and(|ins| !matches!(ins, Instruction::Jam | Instruction::EmuSignal(_)))
+}
+
+fn load_state(vm: &mut Vm, state: &CpuState) {
+    vm.set_pc(state.pc);
+    vm.set_register(Register::SP, state.s);
+    vm.set_register(Register::AC, state.a);
+    vm.set_register(Register::X, state.x);
+    vm.set_register(Register::Y, state.y);
+    vm.set_register(Register::SR, state.p);
+
+    for (addr, value) in state.ram.iter() {
+        vm.copy_memory(*addr as usize, &[*value]);
+    }
+}
+
+fn check_state(vm: &Vm, state: &CpuState, errors: &mut Vec<String>) {
+    let registers = [
+        ("PC", vm.get_pc(), state.pc),
+        ("SP", vm.get_register(Register::SP).into(), state.s.into()),
+        ("AC", vm.get_register(Register::AC).into(), state.a.into()),
+        ("X", vm.get_register(Register::X).into(), state.x.into()),
+        ("Y", vm.get_register(Register::Y).into(), state.y.into()),
+        ("SR", vm.get_register(Register::SR).into(), state.p.into()),
+    ];
+
+    for (name, got, expected) in registers {
+        if got != expected {
+            errors.push(format!(
+                "register {} expected 0x{:02x} got 0x{:02x}",
+                name, expected, got
+            ));
+        }
+    }
+
+    for (addr, expected) in state.ram.iter() {
+        let got = vm.read_memory(*addr).unwrap();
+        if got != *expected {
+            errors.push(format!(
+                "memory 0x{:04x} expected 0x{:02x} got 0x{:02x}",
+                addr, expected, got
+            ));
+        }
+    }
+}
+
+fn check_bus_cycles(got: &[BusCycle], expected: &[(u16, u8, String)], errors: &mut Vec<String>) {
+    for (idx, (addr, value, access)) in expected.iter().enumerate() {
+        let expected_cycle = BusCycle {
+            addr: *addr,
+            value: *value,
+            access: match access.as_str() {
+                "write" => BusAccess::Write,
+                _ => BusAccess::Read,
+            },
+        };
+
+        match got.get(idx) {
+            Some(cycle) if *cycle == expected_cycle => {}
+            Some(cycle) => errors.push(format!(
+                "bus cycle {} expected {} got {}",
+                idx + 1,
+                expected_cycle,
+                cycle
+            )),
+            None => errors.push(format!(
+                "bus cycle {} expected {} got nothing",
+                idx + 1,
+                expected_cycle
+            )),
+        }
+    }
+
+    for (idx, cycle) in got.iter().enumerate().skip(expected.len()) {
+        errors.push(format!(
+            "bus cycle {} expected nothing got {}",
+            idx + 1,
+            cycle
+        ));
+    }
+}
+
+/// Runs one test case and returns the list of differences with the expected state
+fn run_test(test: &ProcessorTest, cycle_accurate: bool) -> Vec<String> {
+    let mut vm = Vm::new();
+    vm.set_cycle_accurate(cycle_accurate);
+    load_state(&mut vm, &test.initial);
+
+    let mut errors = Vec::new();
+    let start = vm.cycle_count();
+    if let Err(err) = vm.cycle() {
+        errors.push(format!("execution error : {}", err));
+        return errors;
+    }
+
+    check_state(&vm, &test.expected, &mut errors);
+
+    let cycles = vm.cycle_count() - start;
+    if cycles != test.cycles.len() as u64 {
+        errors.push(format!(
+            "cycle count expected {} got {}",
+            test.cycles.len(),
+            cycles
+        ));
+    }
+
+    if cycle_accurate {
+        check_bus_cycles(vm.bus_cycles(), &test.cycles, &mut errors);
+    }
+
+    errors
+}
+
+#[test]
+fn test_single_step_processor_tests() {
+    let (dir, full_suite) = fixtures_dir();
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|err| panic!("Cannot read fixture dir {} : {}", dir.display(), err))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+
+    let mut failures = Vec::new();
+    let mut total = 0;
+    let mut missing: Vec<u8> = (0..=0xFF).filter(|opcode| is_documented(*opcode)).collect();
+
+    for path in paths {
+        // Only the documented opcodes are implemented, skip the files of the other ones
+        let Some(opcode) = path
+            .file_stem()
+            .and_then(|stem| u8::from_str_radix(&stem.to_string_lossy(), 16).ok())
+            .filter(|opcode| is_documented(*opcode))
+        else {
+            continue;
+        };
+        missing.retain(|other| *other != opcode);
+
+        let content = fs::read_to_string(&path).unwrap();
+        let tests: Vec<ProcessorTest> = serde_json::from_str(&content)
+            .unwrap_or_else(|err| panic!("Cannot parse {} : {}", path.display(), err));
+
+        for test in tests.iter() {
+            for cycle_accurate in [false, true] {
+                total += 1;
+                let errors = run_test(test, cycle_accurate);
+                if !errors.is_empty() {
+                    failures.push(format!(
+                        "{} [{}]{} : {}",
+                        path.file_name().unwrap().to_string_lossy(),
+                        test.name,
+                        if cycle_accurate {
+                            " (cycle accurate)"
+                        } else {
+                            ""
+                        },
+                        errors.join(", ")
+                    ));
+                }
+            }
+        }
+    }
+
+    assert!(total > 0, "No test found in {}", dir.display());
+    let missing = missing
+        .iter()
+        .map(|opcode| format!("{:02x}", opcode))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if full_suite {
+        assert!(
+            missing.is_empty(),
+            "No fixture in {} for the opcodes {}",
+            dir.display(),
+            missing
+        );
+    } else if !missing.is_empty() {
+        eprintln!(
+            "Only the shipped fixtures were run, set PROCESSOR_TESTS_DIR to test the opcodes {}",
+            missing
+        );
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} tests failed :\n{}",
+        failures.len(),
+        total,
+        failures
+            .iter()
+            .take(MAX_REPORTED_FAILURES)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+}